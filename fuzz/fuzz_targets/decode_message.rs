@@ -0,0 +1,32 @@
+// decode_message decodes the input as a wire message, checks it encodes back
+// to the same bytes, and applies it to an engine as if it were verified.
+//
+// Seed it from the simulator, which writes realistic messages of a whole run
+// with Simulation::write_corpus(), to fuzz/corpus/decode_message:
+//
+//   cargo fuzz run decode_message
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tendermint_rs::config::ConsensusConfig;
+use tendermint_rs::test_utils::{validator_set, KeySigner, KeyVerifier};
+use tendermint_rs::{wire, Engine, Input, Verified};
+
+use std::time::Duration;
+
+fuzz_target!(|data: &[u8]| {
+    let msg = match wire::decode(data) {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+    assert_eq!(wire::encode(&msg), data);
+
+    let validators = validator_set(&[1, 1, 1, 1]);
+    let signer = Box::new(KeySigner::new(&validators, 0));
+    let verifier = Box::new(KeyVerifier(validators.clone()));
+    let config = ConsensusConfig::default();
+    let mut engine = Engine::new_stepped(config, 1, validators, signer, verifier);
+    engine.step(Input::StartRound(0, None), Duration::from_millis(0));
+    let input = Input::Verified(Verified::new_unchecked(msg));
+    engine.step(input, Duration::from_millis(1));
+});
@@ -0,0 +1,348 @@
+use super::evidence::EvidenceConfig;
+use super::signature::SignFormat;
+use super::state_machine::TimeoutStep;
+
+use std::time::Duration;
+
+// ConsensusConfig holds the parameters of the engine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusConfig {
+    // Number of rounds before the current round whose votes are still tallied.
+    // A polka for a value in one of these rounds still updates the valid value.
+    // Votes for older rounds are dropped as stale.
+    pub late_vote_rounds: i64,
+
+    // Whether to wait for timeout commit after deciding before moving to
+    // the next height, to gather more precommits into the commit.
+    pub wait_timeout_commit: bool,
+
+    // Whether to skip the rest of timeout commit once precommits
+    // from all validators for the decided value are in.
+    pub skip_timeout_commit: bool,
+
+    // Number of recently rejected messages to keep for inspection.
+    // 0 disables the log.
+    pub rejected_log_size: usize,
+
+    // Maximum number of messages applied by one call to the engine, counting
+    // our own proposals and votes applied back to it. Any more are dropped
+    // and logged as rejected, rather than applied without bound.
+    pub max_messages_per_call: usize,
+
+    // Number of rounds ahead of the current round whose proposals and
+    // votes are kept. Ones for later rounds are dropped, so peers can't
+    // make us store messages for any number of rounds.
+    pub future_rounds: i64,
+
+    // Number of heights ahead of the current height whose proposals and
+    // votes are buffered, and applied once we start the height.
+    // Ones for later heights are dropped.
+    pub future_heights: i64,
+
+    // Most buffered messages for future heights per peer, or per signer for
+    // messages executed without a peer. The sender's oldest message is
+    // evicted to make room.
+    pub max_future_per_peer: usize,
+
+    // How long to extend timeout propose by, once per round, if it elapses
+    // while parts of the round's proposal are arriving but it's incomplete.
+    // None disables the extension.
+    pub propose_extension: Option<Duration>,
+
+    // Number of decided heights the application may be behind in committing.
+    // Height h + k isn't started, and its messages are buffered, until the
+    // application acknowledges committing height h, so undelivered decisions
+    // can't pile up. It must be at least 1, as at 0 no height would start.
+    // None doesn't wait.
+    pub max_uncommitted_heights: Option<i64>,
+
+    // How proposals and votes are encoded for signing. All validators of the
+    // chain must use the same format.
+    pub sign_format: SignFormat,
+
+    // Consensus parameters of the first height.
+    pub params: ConsensusParams,
+
+    // Limits the validator set must be within.
+    pub validator_limits: ValidatorSetLimits,
+
+    // Height after which to halt, eg. for a coordinated upgrade.
+    // Once it's decided, the engine stops taking part in consensus.
+    pub halt_height: Option<i64>,
+
+    // What to do about proposals for the round that can't be prevoted for.
+    pub invalid_proposal: InvalidProposalPolicy,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> ConsensusConfig {
+        ConsensusConfig {
+            late_vote_rounds: 1,
+            wait_timeout_commit: false,
+            skip_timeout_commit: false,
+            rejected_log_size: 0,
+            max_messages_per_call: 16,
+            future_rounds: 10,
+            future_heights: 1,
+            max_future_per_peer: 16,
+            propose_extension: None,
+            max_uncommitted_heights: None,
+            sign_format: SignFormat::Binary,
+            params: ConsensusParams::default(),
+            validator_limits: ValidatorSetLimits::default(),
+            halt_height: None,
+            invalid_proposal: InvalidProposalPolicy::default(),
+        }
+    }
+}
+
+impl ConsensusConfig {
+    // validate returns an error if the engine couldn't make progress with
+    // the config, eg. because it drops every message or never starts a height.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_messages_per_call == 0 {
+            return Err("zero max_messages_per_call".to_string());
+        }
+        if self.future_rounds < 0 {
+            return Err(format!("bad future_rounds {}", self.future_rounds));
+        }
+        if self.future_heights < 0 {
+            return Err(format!("bad future_heights {}", self.future_heights));
+        }
+        if let Some(k) = self.max_uncommitted_heights.filter(|&k| k < 1) {
+            return Err(format!("bad max_uncommitted_heights {}", k));
+        }
+        Ok(())
+    }
+}
+
+// ProposalAction is what the engine does about a proposal for the round
+// that it can't prevote for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProposalAction {
+    PrevoteNil,     // Prevote nil right away.
+    WaitForTimeout, // Prevote nil at timeout propose, even if a good proposal arrives first.
+    Ignore,         // Drop it, and prevote for a good proposal if one arrives in time.
+}
+
+// InvalidProposalPolicy is the action for each way a proposal can fail.
+//
+// None of the actions risks safety: a nil prevote never conflicts with a
+// lock, and waiting or ignoring signs nothing. They trade liveness against
+// how easily others can sway our prevote. Prevoting nil right away ends a
+// round that can't decide sooner, but whoever can produce the proposal
+// makes us skip the round's value; ignoring it leaves the round open for the
+// real proposal, at the cost of waiting out timeout propose when there's none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidProposalPolicy {
+    // From the round's proposer, but the application rejects its value. Only
+    // the proposer could have signed it, and it won't sign another for the
+    // round, so there's nothing to wait for: prevote nil right away.
+    pub invalid_value: ProposalAction,
+
+    // Its signature doesn't verify, though it's for the current height and
+    // round horizon, and names the round's proposer. Any peer can send one,
+    // so acting on it would let any peer make us prevote nil: ignore it.
+    // One whose own signature verifies, but not the prevotes it carries, is
+    // rejected as an invalid polka instead.
+    pub invalid_signature: ProposalAction,
+
+    // Validly signed, but by a validator other than the round's proposer.
+    // Acting on it would let any one validator make us prevote nil in every
+    // round: ignore it. Proposals the driver drops with
+    // Engine::prefilter_proposal() are always ignored.
+    pub wrong_proposer: ProposalAction,
+}
+
+impl Default for InvalidProposalPolicy {
+    fn default() -> InvalidProposalPolicy {
+        InvalidProposalPolicy {
+            invalid_value: ProposalAction::PrevoteNil,
+            invalid_signature: ProposalAction::Ignore,
+            wrong_proposer: ProposalAction::Ignore,
+        }
+    }
+}
+
+// Reconfig is the part of the ConsensusConfig an operator may change on a
+// live engine with Engine::reconfigure(): its timeouts and how much it
+// buffers, but nothing that decides what counts as a quorum. Fields that
+// are None are left as they are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reconfig {
+    pub timeout_propose: Option<Duration>,
+    pub timeout_prevote: Option<Duration>,
+    pub timeout_precommit: Option<Duration>,
+    pub timeout_commit: Option<Duration>,
+    pub max_messages_per_call: Option<usize>,
+    pub future_rounds: Option<i64>,
+    pub future_heights: Option<i64>,
+    pub max_future_per_peer: Option<usize>,
+}
+
+impl Reconfig {
+    // validate returns an error if the changes can't be used.
+    pub fn validate(&self) -> Result<(), String> {
+        let timeouts = [
+            (TimeoutStep::Propose, self.timeout_propose),
+            (TimeoutStep::Prevote, self.timeout_prevote),
+            (TimeoutStep::Precommit, self.timeout_precommit),
+            (TimeoutStep::Commit, self.timeout_commit),
+        ];
+        if let Some((step, _)) = timeouts
+            .iter()
+            .find(|(_, t)| *t == Some(Duration::from_secs(0)))
+        {
+            return Err(format!("zero timeout for {:?}", step));
+        }
+        if self.max_messages_per_call == Some(0) {
+            return Err("zero max_messages_per_call".to_string());
+        }
+        if self.future_rounds.is_some_and(|r| r < 0) {
+            return Err(format!("bad future_rounds {}", self.future_rounds.unwrap()));
+        }
+        if self.future_heights.is_some_and(|h| h < 0) {
+            return Err(format!(
+                "bad future_heights {}",
+                self.future_heights.unwrap()
+            ));
+        }
+        Ok(())
+    }
+
+    // merge returns the changes, updated with the later ones.
+    pub fn merge(&self, later: &Reconfig) -> Reconfig {
+        Reconfig {
+            timeout_propose: later.timeout_propose.or(self.timeout_propose),
+            timeout_prevote: later.timeout_prevote.or(self.timeout_prevote),
+            timeout_precommit: later.timeout_precommit.or(self.timeout_precommit),
+            timeout_commit: later.timeout_commit.or(self.timeout_commit),
+            max_messages_per_call: later.max_messages_per_call.or(self.max_messages_per_call),
+            future_rounds: later.future_rounds.or(self.future_rounds),
+            future_heights: later.future_heights.or(self.future_heights),
+            max_future_per_peer: later.max_future_per_peer.or(self.max_future_per_peer),
+        }
+    }
+
+    // apply_timeouts sets the changed timeouts in the params.
+    pub fn apply_timeouts(&self, params: &mut ConsensusParams) {
+        let timeouts = [
+            (&mut params.timeout_propose, self.timeout_propose),
+            (&mut params.timeout_prevote, self.timeout_prevote),
+            (&mut params.timeout_precommit, self.timeout_precommit),
+            (&mut params.timeout_commit, self.timeout_commit),
+        ];
+        for (timeout, changed) in timeouts {
+            if let Some(changed) = changed {
+                *timeout = changed;
+            }
+        }
+    }
+
+    // apply sets the changes in the config.
+    pub fn apply(&self, config: &mut ConsensusConfig) {
+        self.apply_timeouts(&mut config.params);
+        if let Some(n) = self.max_messages_per_call {
+            config.max_messages_per_call = n;
+        }
+        if let Some(r) = self.future_rounds {
+            config.future_rounds = r;
+        }
+        if let Some(h) = self.future_heights {
+            config.future_heights = h;
+        }
+        if let Some(n) = self.max_future_per_peer {
+            config.max_future_per_peer = n;
+        }
+    }
+}
+
+// ConsensusParams are the parameters the application may change from one
+// height to the next, in its response to committing a height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusParams {
+    pub timeout_propose: Duration,
+    pub timeout_prevote: Duration,
+    pub timeout_precommit: Duration,
+    pub timeout_commit: Duration,
+
+    // Largest value that may be proposed, in bytes. The engine only sees
+    // value ids, so it's up to the driver to check it when validating proposals.
+    pub max_value_bytes: usize,
+
+    // How long evidence is kept, and how much, for the driver to give its
+    // EvidencePool with set_config() as the params change.
+    pub evidence: EvidenceConfig,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> ConsensusParams {
+        ConsensusParams {
+            timeout_propose: Duration::from_millis(3000),
+            timeout_prevote: Duration::from_millis(1000),
+            timeout_precommit: Duration::from_millis(1000),
+            timeout_commit: Duration::from_millis(1000),
+            max_value_bytes: 1 << 20,
+            evidence: EvidenceConfig::default(),
+        }
+    }
+}
+
+impl ConsensusParams {
+    // timeout returns how long to wait for the timeout of the step.
+    pub fn timeout(&self, step: TimeoutStep) -> Duration {
+        match step {
+            TimeoutStep::Propose => self.timeout_propose,
+            TimeoutStep::Prevote => self.timeout_prevote,
+            TimeoutStep::Precommit => self.timeout_precommit,
+            TimeoutStep::Commit => self.timeout_commit,
+        }
+    }
+
+    // validate returns an error if the params can't be used.
+    pub fn validate(&self) -> Result<(), String> {
+        let steps = [
+            TimeoutStep::Propose,
+            TimeoutStep::Prevote,
+            TimeoutStep::Precommit,
+            TimeoutStep::Commit,
+        ];
+        if let Some(step) = steps
+            .iter()
+            .find(|s| self.timeout(**s) == Duration::from_secs(0))
+        {
+            return Err(format!("zero timeout for {:?}", step));
+        }
+        if self.max_value_bytes == 0 {
+            return Err("zero max_value_bytes".to_string());
+        }
+        self.evidence.validate()
+    }
+}
+
+// ValidatorSetLimits are the limits a validator set, and each update the
+// application makes to it, must be within. ValidatorSet::validate() checks them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatorSetLimits {
+    // Least voting power of a validator. Validators with less should be
+    // removed from the set rather than kept with no say.
+    pub min_power: i64,
+
+    // Most total voting power of the set, well below i64::MAX,
+    // so tallies of it can't overflow.
+    pub max_total_power: i64,
+
+    // Largest share of the total voting power a single validator may have,
+    // in percent. None doesn't cap it.
+    pub max_power_percent: Option<i64>,
+}
+
+impl Default for ValidatorSetLimits {
+    fn default() -> ValidatorSetLimits {
+        ValidatorSetLimits {
+            min_power: 1,
+            max_total_power: i64::MAX / 8,
+            max_power_percent: None,
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// Dev presets the engine for local development chains of one or two
+// validators, each with voting power 1.
+//
+// With so few validators the quorum is all of them: more than 2/3 of 1 is 1,
+// and of 2 is 2. A single validator decides each height within the call
+// that starts its round, as its own proposal and votes make every quorum.
+// Two validators need each other for every quorum, so neither decides while
+// the other is down; they time out round after round, and decide once
+// they're both back.
+//
+//   let dev = DevConfig::single_node(public_key);
+//   let mut engine = dev.engine(signer, verifier)?;
+//   engine.step(Input::StartRound(0, Some(value)), now);
+
+use super::config::{ConsensusConfig, ConsensusParams};
+use super::engine::Engine;
+use super::signature::{Signer, Verifier};
+use super::validators::{Validator, ValidatorSet};
+
+use std::time::Duration;
+
+// DevConfig is the config and validator set of a development chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DevConfig {
+    pub consensus: ConsensusConfig,
+    pub validators: ValidatorSet,
+}
+
+impl DevConfig {
+    // single_node returns the preset for a chain of one validator, with the
+    // public key: there's no one to wait for, so the next height starts
+    // right after a decision.
+    pub fn single_node(public_key: Vec<u8>) -> DevConfig {
+        DevConfig {
+            consensus: ConsensusConfig {
+                wait_timeout_commit: false,
+                ..dev_consensus()
+            },
+            validators: dev_validators(vec![public_key]),
+        }
+    }
+
+    // two_nodes returns the preset for a chain of two validators, with the
+    // public keys. The next height starts once both precommits are in.
+    pub fn two_nodes(public_keys: [Vec<u8>; 2]) -> DevConfig {
+        DevConfig {
+            consensus: ConsensusConfig {
+                wait_timeout_commit: true,
+                skip_timeout_commit: true,
+                ..dev_consensus()
+            },
+            validators: dev_validators(public_keys.to_vec()),
+        }
+    }
+
+    // engine creates the engine of the validator the signer signs as,
+    // at height 1, to drive with step(). Returns an error if the consensus
+    // config, eg. as changed from the preset, doesn't validate.
+    pub fn engine(
+        &self,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+    ) -> Result<Engine, String> {
+        let validators = self.validators.clone();
+        Engine::new_stepped(self.consensus.clone(), 1, validators, signer, verifier)
+    }
+}
+
+// dev_consensus returns the config both presets start from: short
+// timeouts, as messages only cross a machine or a LAN.
+fn dev_consensus() -> ConsensusConfig {
+    let ms = Duration::from_millis;
+    ConsensusConfig {
+        rejected_log_size: 64,
+        params: ConsensusParams {
+            timeout_propose: ms(500),
+            timeout_prevote: ms(200),
+            timeout_precommit: ms(200),
+            timeout_commit: ms(100),
+            ..ConsensusParams::default()
+        },
+        ..ConsensusConfig::default()
+    }
+}
+
+fn dev_validators(public_keys: Vec<Vec<u8>>) -> ValidatorSet {
+    let vals = public_keys
+        .into_iter()
+        .map(|public_key| Validator {
+            public_key,
+            voting_power: 1,
+        })
+        .collect();
+    ValidatorSet::new(vals)
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Input, Message, Output};
+    use crate::scenario::{TestSigner, TestVerifier};
+    use crate::signature::Verified;
+    use crate::state_machine as sm;
+    use crate::Value;
+
+    fn decisions(outputs: &[Output]) -> Vec<i64> {
+        outputs
+            .iter()
+            .filter_map(|o| match o {
+                Output::Decision(d) => Some(d.round),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_node() {
+        let dev = DevConfig::single_node(vec![1; 32]);
+        dev.validators
+            .validate(&dev.consensus.validator_limits)
+            .unwrap();
+        let mut engine = dev
+            .engine(Box::new(TestSigner(0)), Box::new(TestVerifier))
+            .unwrap();
+
+        // each height decides in round 0, within the call that starts it
+        for height in 1..=5 {
+            assert_eq!(engine.height(), height);
+            let value = Value::new([height as u8; 32]);
+            let now = Duration::from_millis(height as u64);
+            let outputs = engine.step(Input::StartRound(0, Some(value)), now);
+            assert_eq!(decisions(&outputs), vec![0]);
+            assert_eq!(engine.height(), height + 1);
+        }
+    }
+
+    #[test]
+    fn two_nodes() {
+        let dev = DevConfig::two_nodes([vec![1; 32], vec![2; 32]]);
+        let mut nodes: Vec<_> = (0..2)
+            .map(|i| {
+                dev.engine(Box::new(TestSigner(i)), Box::new(TestVerifier))
+                    .unwrap()
+            })
+            .collect();
+        let ms = Duration::from_millis;
+
+        // step runs the input on the node, then delivers what it sent to
+        // the other, if it's up, until they're both done
+        let step = |nodes: &mut Vec<Engine>, node: usize, input, up: bool| {
+            let mut pending = vec![(node, input)];
+            let mut decided = Vec::new();
+            while let Some((node, input)) = pending.pop() {
+                let outputs = nodes[node].step(input, ms(0));
+                decided.extend(decisions(&outputs).iter().map(|_| node));
+                for output in outputs {
+                    let msg = match output {
+                        Output::Proposal(p) => Message::Proposal(p),
+                        Output::Vote(v) => Message::Vote(v),
+                        _ => continue,
+                    };
+                    if up {
+                        let msg = Verified::new_unchecked(msg);
+                        pending.insert(0, (1 - node, Input::Verified(msg)));
+                    }
+                }
+            }
+            decided
+        };
+
+        // both up: each height decides in round 0, on both nodes
+        for height in 1..=3 {
+            let proposer = height as usize % 2;
+            let value = Value::new([height as u8; 32]);
+            step(&mut nodes, 1 - proposer, Input::StartRound(0, None), true);
+            let decided = step(
+                &mut nodes,
+                proposer,
+                Input::StartRound(0, Some(value)),
+                true,
+            );
+            assert_eq!(decided.len(), 2);
+            assert!(nodes.iter().all(|n| n.height() == height + 1));
+        }
+
+        // one vote of two is no quorum: with the other down, the rounds
+        // time out instead of deciding
+        let value = Value::new([4; 32]);
+        assert!(step(&mut nodes, 0, Input::StartRound(0, Some(value)), false).is_empty());
+        assert_eq!(nodes[0].state().step(), sm::Step::Prevote);
+        for kind in [sm::TimeoutStep::Prevote, sm::TimeoutStep::Precommit] {
+            let timeout = sm::Timeout {
+                height: 4,
+                round: 0,
+                step: kind,
+            };
+            assert!(step(&mut nodes, 0, Input::Timeout(timeout), false).is_empty());
+        }
+        let state = nodes[0].state();
+        assert_eq!((state.height(), state.round()), (4, 1));
+    }
+}
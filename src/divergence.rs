@@ -0,0 +1,323 @@
+// Divergence compares the state of two nodes at the same height, to find out
+// why one decided or moved on while the other stalled.
+//
+// Each node provides a NodeReport: its RoundStateSnapshot and its tally of
+// each round of the height. divergence() returns only what differs: the
+// round, step, locked and valid values, and for each vote type of each round,
+// the thresholds, the votes one node has and the other is missing, and the
+// votes the nodes have for different values from the same validator.
+// The report also has a stable, line-based text encoding. For example:
+//
+//   divergence v1
+//   height 1
+//   step precommit prevote
+//   prevote 0 thresh value 0101..01 | any
+//   prevote 0 missing_b 2 0101..01 1
+//   prevote 0 conflict 3 0101..01 nil
+//
+// reads as node b missing validator 2's prevote for the value in round 0,
+// and having a prevote for nil from validator 3 where node a has one for
+// the value, so only node a saw the polka and moved on to precommit.
+
+use super::engine::{Engine, RoundStateSnapshot};
+use super::round_votes::{
+    fmt_thresh, fmt_value, RoundVotesDump, Thresh, ValidatorVote, VoteCountDump,
+};
+use super::state_machine as sm;
+use super::VoteType;
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+// NodeReport is the state of a node at a height, to compare with another's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeReport {
+    pub snapshot: RoundStateSnapshot,
+    pub votes: Vec<RoundVotesDump>, // the tally of each round, by round
+}
+
+impl NodeReport {
+    // of returns the report of the engine's current state.
+    pub fn of(engine: &Engine) -> NodeReport {
+        NodeReport {
+            snapshot: engine.snapshot(),
+            votes: engine.round_votes(),
+        }
+    }
+}
+
+// Diff is a field that differs between node a and node b.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff<T> {
+    pub a: T,
+    pub b: T,
+}
+
+// diff returns the Diff if a and b differ.
+fn diff<T: PartialEq>(a: T, b: T) -> Option<Diff<T>> {
+    if a == b {
+        None
+    } else {
+        Some(Diff { a, b })
+    }
+}
+
+// TallyDiff is how the nodes' tallies of a vote type in a round differ.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TallyDiff {
+    pub round: i64,
+    pub typ: VoteType,
+    pub thresh: Option<Diff<Thresh>>,
+    pub missing_a: Vec<ValidatorVote>, // votes b has, and a is missing
+    pub missing_b: Vec<ValidatorVote>, // votes a has, and b is missing
+    pub conflicting: Vec<Diff<ValidatorVote>>, // votes of the same validator for different values
+}
+
+// DivergenceReport is how the state of two nodes at a height differs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DivergenceReport {
+    pub height: i64,
+    pub round: Option<Diff<i64>>,
+    pub step: Option<Diff<sm::Step>>,
+    pub locked: Option<Diff<Option<sm::RoundValue>>>,
+    pub valid: Option<Diff<Option<sm::RoundValue>>>,
+    pub tallies: Vec<TallyDiff>, // by round, then prevotes before precommits
+}
+
+impl DivergenceReport {
+    // is_empty returns true if the nodes' states are the same.
+    pub fn is_empty(&self) -> bool {
+        self.round.is_none()
+            && self.step.is_none()
+            && self.locked.is_none()
+            && self.valid.is_none()
+            && self.tallies.is_empty()
+    }
+}
+
+// divergence compares the reports of node a and node b,
+// which must be at the same height.
+pub fn divergence(a: &NodeReport, b: &NodeReport) -> Result<DivergenceReport, String> {
+    let (sa, sb) = (&a.snapshot, &b.snapshot);
+    if sa.height != sb.height {
+        return Err(format!("heights differ: {} and {}", sa.height, sb.height));
+    }
+    let rounds: BTreeSet<i64> = a.votes.iter().chain(&b.votes).map(|v| v.round).collect();
+    let mut tallies = Vec::new();
+    for round in rounds {
+        let find = |votes: &[RoundVotesDump]| votes.iter().find(|v| v.round == round).cloned();
+        let (va, vb) = (find(&a.votes), find(&b.votes));
+        for typ in [VoteType::Prevote, VoteType::Precommit] {
+            let count = |votes: &Option<RoundVotesDump>| match (votes, typ) {
+                (Some(v), VoteType::Prevote) => v.prevotes.clone(),
+                (Some(v), VoteType::Precommit) => v.precommits.clone(),
+                (None, _) => VoteCountDump {
+                    thresh: Thresh::Init,
+                    votes: Vec::new(),
+                },
+            };
+            if let Some(d) = diff_tally(round, typ, &count(&va), &count(&vb)) {
+                tallies.push(d);
+            }
+        }
+    }
+    Ok(DivergenceReport {
+        height: sa.height,
+        round: diff(sa.round, sb.round),
+        step: diff(sa.step, sb.step),
+        locked: diff(sa.locked, sb.locked),
+        valid: diff(sa.valid, sb.valid),
+        tallies,
+    })
+}
+
+fn diff_tally(
+    round: i64,
+    typ: VoteType,
+    a: &VoteCountDump,
+    b: &VoteCountDump,
+) -> Option<TallyDiff> {
+    let find = |count: &VoteCountDump, validator| {
+        count
+            .votes
+            .iter()
+            .find(|v| v.validator == validator)
+            .cloned()
+    };
+    let (mut missing_a, mut missing_b, mut conflicting) = (Vec::new(), Vec::new(), Vec::new());
+    for va in &a.votes {
+        match find(b, va.validator) {
+            None => missing_b.push(*va),
+            Some(vb) if vb.value != va.value => conflicting.push(Diff { a: *va, b: vb }),
+            Some(_) => {}
+        }
+    }
+    for vb in &b.votes {
+        if find(a, vb.validator).is_none() {
+            missing_a.push(*vb);
+        }
+    }
+    for votes in [&mut missing_a, &mut missing_b] {
+        votes.sort_by_key(|v| v.validator);
+    }
+    conflicting.sort_by_key(|d| d.a.validator);
+    let d = TallyDiff {
+        round,
+        typ,
+        thresh: diff(a.thresh, b.thresh),
+        missing_a,
+        missing_b,
+        conflicting,
+    };
+    let same = d.thresh.is_none()
+        && d.missing_a.is_empty()
+        && d.missing_b.is_empty()
+        && d.conflicting.is_empty();
+    if same {
+        None
+    } else {
+        Some(d)
+    }
+}
+
+//---------------------------------------------------------------------
+// Text
+
+const REPORT_HEADER: &str = "divergence v1";
+
+fn fmt_step(step: sm::Step) -> String {
+    format!("{:?}", step).to_lowercase()
+}
+
+fn fmt_round_value(rv: Option<sm::RoundValue>) -> String {
+    match rv {
+        Some(rv) => format!("{}:{}", rv.round, rv.value),
+        None => "nil".to_string(),
+    }
+}
+
+fn fmt_vote(v: &ValidatorVote) -> String {
+    format!("{} {} {}", v.validator, fmt_value(v.value), v.weight)
+}
+
+impl fmt::Display for DivergenceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", REPORT_HEADER)?;
+        writeln!(f, "height {}", self.height)?;
+        if let Some(d) = &self.round {
+            writeln!(f, "round {} {}", d.a, d.b)?;
+        }
+        if let Some(d) = &self.step {
+            writeln!(f, "step {} {}", fmt_step(d.a), fmt_step(d.b))?;
+        }
+        if let Some(d) = &self.locked {
+            let (a, b) = (fmt_round_value(d.a), fmt_round_value(d.b));
+            writeln!(f, "locked {} {}", a, b)?;
+        }
+        if let Some(d) = &self.valid {
+            let (a, b) = (fmt_round_value(d.a), fmt_round_value(d.b));
+            writeln!(f, "valid {} {}", a, b)?;
+        }
+        for t in &self.tallies {
+            let typ = match t.typ {
+                VoteType::Prevote => "prevote",
+                VoteType::Precommit => "precommit",
+            };
+            if let Some(d) = &t.thresh {
+                let (a, b) = (fmt_thresh(d.a), fmt_thresh(d.b));
+                writeln!(f, "{} {} thresh {} | {}", typ, t.round, a, b)?;
+            }
+            for v in &t.missing_a {
+                writeln!(f, "{} {} missing_a {}", typ, t.round, fmt_vote(v))?;
+            }
+            for v in &t.missing_b {
+                writeln!(f, "{} {} missing_b {}", typ, t.round, fmt_vote(v))?;
+            }
+            for d in &t.conflicting {
+                let (a, b) = (fmt_value(d.a.value), fmt_value(d.b.value));
+                writeln!(
+                    f,
+                    "{} {} conflict {} {} {}",
+                    typ, t.round, d.a.validator, a, b
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::scenario::{proposal, test_engine, vote};
+    use crate::scheduler::Timers;
+    use crate::{Proposal, Value, Vote};
+
+    #[test]
+    fn report() {
+        let value = Value::new([1; 32]);
+        let new_engine = || {
+            let config = ConsensusConfig::default();
+            let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+            engine.start_round(0, None);
+            engine
+        };
+        let execute = |engine: &mut Engine, msg| {
+            engine.execute(msg);
+        };
+        let prevote = |signer, value| vote(signer, Vote::new_prevote(1, 0, value));
+        let proposal = proposal(1, Proposal::new(1, 0, value, -1));
+
+        // a sees the polka, b is missing a prevote and has another for nil
+        let (mut a, mut b) = (new_engine(), new_engine());
+        execute(&mut a, proposal.clone());
+        execute(&mut b, proposal);
+        for (signer, value) in [(1, Some(value)), (2, Some(value)), (3, Some(value))] {
+            execute(&mut a, prevote(signer, value));
+        }
+        execute(&mut b, prevote(1, Some(value)));
+        execute(&mut b, prevote(3, None));
+
+        let (ra, rb) = (NodeReport::of(&a), NodeReport::of(&b));
+        assert!(divergence(&ra, &ra).unwrap().is_empty());
+        let report = divergence(&ra, &rb).unwrap();
+        assert_eq!(
+            report.step,
+            Some(Diff {
+                a: sm::Step::Precommit,
+                b: sm::Step::Prevote
+            })
+        );
+        let prevotes = &report.tallies[0];
+        assert_eq!((prevotes.round, prevotes.typ), (0, VoteType::Prevote));
+        assert_eq!(
+            prevotes
+                .missing_b
+                .iter()
+                .map(|v| v.validator)
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(prevotes.conflicting[0].b.value, None);
+
+        let id = "01".repeat(32);
+        let text = report.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[..3],
+            ["divergence v1", "height 1", "step precommit prevote"]
+        );
+        assert!(lines.contains(&format!("prevote 0 thresh value {} | any", id).as_str()));
+        assert!(lines.contains(&format!("prevote 0 missing_b 2 {} 1", id).as_str()));
+        assert!(lines.contains(&format!("prevote 0 conflict 3 {} nil", id).as_str()));
+
+        // reports are only comparable at the same height
+        let mut other = rb.clone();
+        other.snapshot.height = 2;
+        assert!(divergence(&ra, &other).is_err());
+    }
+}
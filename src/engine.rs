@@ -0,0 +1,3592 @@
+// The engine drives consensus for one validator.
+//
+// It verifies signed proposals and votes, stores proposals, tallies votes
+// by the voting power of their signers into state machine events, and applies
+// them. Its own proposals and votes are signed and applied back to itself,
+// through a work queue, before being output for the caller to broadcast.
+// Timeouts are scheduled with the caller's Scheduler, and once a value is
+// decided the engine moves to the next height.
+
+use super::config::{ConsensusConfig, ConsensusParams, ProposalAction, Reconfig};
+use super::evidence::Evidence;
+use super::gossip::PeerId;
+use super::hash::Hash;
+use super::rejected::{MessageKind, RejectReason, Rejected, RejectedLog};
+use super::round_votes as rv;
+use super::round_votes::Thresh;
+use super::scheduler::{Scheduled, Scheduler, TimeoutHandle, Timers};
+use super::signature::{
+    SignBytes, SignFormat, SignState, SignStep, Signed, Signer, Verified, Verifier,
+};
+use super::state_machine as sm;
+use super::validators::{ProposerSelector, ValidatorSet};
+use super::{Commit, Heartbeat, Proposal, Value, Vote, VoteType};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Engine drives the consensus state machine for one validator.
+pub struct Engine {
+    config: ConsensusConfig,
+    validators: ValidatorSet,
+    signer: Option<Box<dyn Signer>>, // None for observers
+    verifier: Box<dyn Verifier>,
+    scheduler: Box<dyn Scheduler>,
+
+    // the proposal, votes and timeouts of each round of the height that's kept.
+    rounds: Rounds,
+    state: sm::State,
+
+    // the decided value, while we wait for timeout commit.
+    decision: Option<sm::RoundValue>,
+
+    // params from the application, to use from the next height.
+    next_params: Option<ConsensusParams>,
+
+    // the validator set from the application, and the verifier for it,
+    // to use from the next height.
+    next_validators: Option<(ValidatorSet, Box<dyn Verifier>)>,
+
+    // the operator's changes to the config so far, and the ones to make at
+    // the next round.
+    reconfig: Reconfig,
+    next_reconfig: Option<Reconfig>,
+
+    // the height and round we received parts of the proposal for,
+    // and the ones we extended timeout propose in.
+    proposal_parts: Option<(i64, i64)>,
+    propose_extended: Option<(i64, i64)>,
+
+    // the height the application marked as the last before an upgrade,
+    // with its app hash once it's known, and whether we output the upgrade
+    // package yet.
+    upgrade: Option<(i64, Option<Hash>)>,
+    upgrade_output: bool,
+
+    // the latest height the application acknowledged committing, and the
+    // round start we deferred until it catches up, with the value to propose.
+    committed: i64,
+    deferred_start: Option<(i64, Option<Value>)>,
+
+    // the sequence of our latest heartbeat.
+    heartbeat_sequence: u64,
+
+    // our latest proposal and votes at this height, to rebroadcast if we stall.
+    own_proposal: Option<Signed<Proposal>>,
+    own_votes: Vec<Signed<Vote>>,
+
+    // the prevotes for our valid value, to carry with our re-proposals of it.
+    pol: Option<(sm::RoundValue, Vec<Signed<Vote>>)>,
+
+    // read-only tally of the previous height, kept alive
+    // so late precommits still make it into its commit.
+    last_commit: Option<LastCommit>,
+
+    // transitions taken by the state machine, if recording is enabled.
+    path: Option<Vec<sm::PathStep>>,
+
+    // recently rejected messages.
+    rejected: RejectedLog,
+
+    // messages for the next heights, applied once we start them.
+    future: FutureBuffer,
+
+    // the id of the latest call, and the peer its message came from, if
+    // the caller said.
+    correlation_id: CorrelationId,
+    peer: Option<PeerId>,
+
+    // the timers of an engine driven by step(), and the time of the
+    // current step, which stands in for the clock.
+    timers: Option<Timers>,
+    now: Option<Timestamp>,
+
+    // picks the round's proposer, to reject proposals from anyone else.
+    proposer_selector: Option<Box<dyn ProposerSelector>>,
+
+    // checks proposed values, to prevote nil on ones the application rejects.
+    value_validator: Option<Box<dyn ValueValidator>>,
+
+    // the last proposal or vote we signed, so we never sign a conflicting one.
+    sign_state: Option<SignState>,
+    replaying: bool, // in replay(), so older proposals and votes are skipped
+}
+
+// Message is an input to the engine: a signed proposal or vote received
+// from a peer, or a timeout that elapsed. A re-proposal may carry the
+// prevotes for its value in its pol_round, so validators that missed them
+// can still accept it; they're verified and counted before the proposal.
+// Heartbeats only count towards the signer's liveness. Timeouts come back
+// from the Scheduler they were scheduled with; ones for a height, round or
+// step the engine has since moved past are ignored.
+// More inputs will be added, so it is non-exhaustive.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Proposal(Signed<Proposal>),
+    Vote(Signed<Vote>),
+    TimeoutElapsed(sm::Timeout),
+    ProposalWithPol(Signed<Proposal>, Vec<Signed<Vote>>),
+    Heartbeat(Signed<Heartbeat>),
+}
+
+// Timestamp is the time an embedder driving the engine with step() gives
+// it, as the duration since the Unix epoch.
+pub type Timestamp = Duration;
+
+// Input is an input to Engine::step(): a message, a timeout the embedder
+// scheduled itself, a call the driver would otherwise make, or just the
+// time moving on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Message(Message),               // A message to verify and apply.
+    Verified(Verified<Message>),    // A message whose signatures were verified.
+    Timeout(sm::Timeout),           // A timeout that elapsed.
+    StartRound(i64, Option<Value>), // Start the round, with the value to propose.
+    CommitDone(i64),                // The application committed the height.
+    Tick,                           // Only fire the timeouts that are due.
+}
+
+// WalEntry is an input the driver wrote to its write-ahead log before
+// stepping the engine with it: the height the engine was at, the time it
+// was stepped at, and the input. After a crash, Engine::replay() of the
+// entries since the last committed height restores the engine's state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalEntry {
+    pub height: i64,
+    pub at: Timestamp,
+    pub input: Input,
+}
+
+impl From<Signed<Proposal>> for Message {
+    fn from(p: Signed<Proposal>) -> Message {
+        Message::Proposal(p)
+    }
+}
+
+impl From<Signed<Vote>> for Message {
+    fn from(v: Signed<Vote>) -> Message {
+        Message::Vote(v)
+    }
+}
+
+impl Message {
+    // is_valid returns true if the message's signatures are valid in the
+    // format. Timeouts have none.
+    pub fn is_valid(&self, verifier: &dyn Verifier, format: &SignFormat) -> bool {
+        match self {
+            Message::Proposal(p) => p.is_valid_as(verifier, format),
+            Message::Vote(v) => v.is_valid_as(verifier, format),
+            Message::Heartbeat(h) => h.is_valid_as(verifier, format),
+            Message::TimeoutElapsed(_) => true,
+            Message::ProposalWithPol(p, pol) => {
+                p.is_valid_as(verifier, format)
+                    && pol.iter().all(|v| v.is_valid_as(verifier, format))
+            }
+        }
+    }
+
+    // header returns the kind of a signed message, its signer, height and
+    // round. Timeouts have none.
+    pub fn header(&self) -> Option<(MessageKind, usize, i64, i64)> {
+        match self {
+            Message::Proposal(p) | Message::ProposalWithPol(p, _) => {
+                Some((MessageKind::Proposal, p.signer, p.msg.height, p.msg.round))
+            }
+            Message::Vote(v) => Some((
+                MessageKind::Vote(v.msg.typ),
+                v.signer,
+                v.msg.height,
+                v.msg.round,
+            )),
+            Message::Heartbeat(h) => {
+                Some((MessageKind::Heartbeat, h.signer, h.msg.height, h.msg.round))
+            }
+            Message::TimeoutElapsed(_) => None,
+        }
+    }
+
+    // signers returns the indices of the validators that signed the message.
+    pub fn signers(&self) -> Vec<usize> {
+        match self {
+            Message::Proposal(p) => vec![p.signer],
+            Message::Vote(v) => vec![v.signer],
+            Message::Heartbeat(h) => vec![h.signer],
+            Message::TimeoutElapsed(_) => Vec::new(),
+            Message::ProposalWithPol(p, pol) => {
+                let mut signers = vec![p.signer];
+                signers.extend(pol.iter().map(|v| v.signer));
+                signers
+            }
+        }
+    }
+}
+
+// CorrelationId identifies a call to the engine, and the message it took.
+// Everything the message caused, eg. our own votes applied back to the
+// engine and the quorums they completed, happens within the call, so its
+// outputs and rejections share the id. Drivers log them with it to trace
+// what a single message led to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CorrelationId(pub u64);
+
+// RecoveryState is the state an operator restarts an engine from,
+// when the chain can't be recovered otherwise.
+#[derive(Clone, Debug)]
+pub struct RecoveryState {
+    pub height: i64, // the height to decide next
+    pub validators: ValidatorSet,
+    pub last_commit: Option<Commit>, // the commit for height - 1
+}
+
+// TrustedState is a state to start an engine from mid-chain, eg. after
+// state sync or when restoring a backup: the height to decide next, the hash
+// of its validator set, and the commit of the height before it.
+#[derive(Clone, Debug)]
+pub struct TrustedState {
+    pub height: i64,
+    pub validators_hash: Hash,
+    pub last_commit: Commit,
+}
+
+// RoundStateSnapshot is the engine's state when it shut down. Node software
+// persists it so that after a restart it doesn't sign a proposal or vote
+// conflicting with the ones it already signed at the height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoundStateSnapshot {
+    pub height: i64,
+    pub round: i64,
+    pub step: sm::Step,
+    pub locked: Option<sm::RoundValue>,
+    pub valid: Option<sm::RoundValue>,
+    pub own_proposal: Option<Signed<Proposal>>, // our latest proposal at the height
+    pub own_votes: Vec<Signed<Vote>>,           // our latest vote of each type at the height
+    pub last_commit: Option<Commit>,
+}
+
+// Output is an output of the engine, in the order it happened.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    NewRound(i64),                // Moved to the new round.
+    Proposal(Signed<Proposal>),   // Broadcast our proposal.
+    Vote(Signed<Vote>),           // Broadcast our vote.
+    ScheduleTimeout(sm::Timeout), // Scheduled the timeout with the Scheduler.
+    Decision(sm::RoundValue),     // Decided the value.
+    Evidence(Evidence),           // A validator signed conflicting votes.
+    Halt(UpgradePackage),         // Halted for an upgrade: persist the package.
+}
+
+// UpgradePackage is the state a replacement binary starts from after a
+// coordinated upgrade: the last height decided before it, the validator set
+// and app hash after it, and its commit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpgradePackage {
+    pub height: i64,
+    pub validators: ValidatorSet,
+    pub app_hash: Hash,
+    pub last_commit: Commit,
+}
+
+impl UpgradePackage {
+    // trusted_state returns the state to create the replacement's engine
+    // from with Engine::from_trusted().
+    pub fn trusted_state(&self) -> TrustedState {
+        TrustedState {
+            height: self.height + 1,
+            validators_hash: self.validators.hash(),
+            last_commit: self.last_commit.clone(),
+        }
+    }
+}
+
+// ValueValidator is the application's check of proposed values, eg. that
+// the value was received in full, is within max_value_bytes, and its
+// transactions are well-formed. Every correct node must reach the same
+// answer for the same value.
+pub trait ValueValidator {
+    // is_valid returns true if the value may be decided at the height.
+    fn is_valid(&self, height: i64, round: i64, value: &Value) -> bool;
+}
+
+//---------------------------------------------------------------------
+// Rounds
+
+// RoundState is everything the engine keeps for one round of a height.
+struct RoundState {
+    proposal: Option<Signed<Proposal>>, // the first proposal received
+    votes: rv::RoundVotes,
+    thresholds: Vec<(VoteType, Thresh)>, // thresholds the votes crossed, in order
+    timeouts: Scheduled,                 // timeouts of the round that haven't fired
+    step: Option<sm::Step>,              // our last step in the round, if we were in it
+    heartbeats: BTreeMap<usize, u64>,    // latest heartbeat sequence by validator
+    prevote_nil: bool,                   // prevote nil at timeout propose, whatever the proposal
+}
+
+impl RoundState {
+    // is_live returns true if we heard from the validator in the round:
+    // its proposal, a vote or a heartbeat.
+    fn is_live(&self, validator: usize) -> bool {
+        let voted = |typ| {
+            self.votes
+                .votes(typ)
+                .iter()
+                .any(|v| v.validator == validator)
+        };
+        self.proposal.as_ref().map(|p| p.signer) == Some(validator)
+            || self.heartbeats.contains_key(&validator)
+            || voted(VoteType::Prevote)
+            || voted(VoteType::Precommit)
+    }
+}
+
+// RoundSummary is what happened in a round of the current height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoundSummary {
+    pub round: i64,
+    pub has_proposal: bool,
+    pub thresholds: Vec<(VoteType, Thresh)>, // thresholds crossed, in order
+    pub step: Option<sm::Step>,              // our last step in the round, if we were in it
+    pub heartbeats: Vec<usize>,              // validators that sent heartbeats
+}
+
+// Rounds holds the state of each round of a height, and counts the votes
+// into state machine events. Pruning a round drops all of its state at once,
+// except its proposal, which is kept for the height.
+struct Rounds {
+    height: i64,
+    total_weight: i64,
+    rounds: BTreeMap<i64, RoundState>,
+    proposals: BTreeMap<i64, Signed<Proposal>>, // of the pruned rounds
+    pruned: i64,                                // rounds before it were pruned
+}
+
+impl Rounds {
+    fn new(height: i64, total_weight: i64) -> Rounds {
+        Rounds {
+            height,
+            total_weight,
+            rounds: BTreeMap::new(),
+            proposals: BTreeMap::new(),
+            pruned: 0,
+        }
+    }
+
+    fn get(&self, round: i64) -> Option<&RoundState> {
+        self.rounds.get(&round)
+    }
+
+    // proposal returns the proposal received for the round, if any,
+    // even if the round was pruned.
+    fn proposal(&self, round: i64) -> Option<&Signed<Proposal>> {
+        match self.get(round) {
+            Some(state) => state.proposal.as_ref(),
+            None => self.proposals.get(&round),
+        }
+    }
+
+    // entry returns the state of the round, starting it if it's new.
+    // Returns None if the round was pruned: it's never started again.
+    fn entry(&mut self, round: i64) -> Option<&mut RoundState> {
+        if self.is_pruned(round) {
+            return None;
+        }
+        let (height, total_weight) = (self.height, self.total_weight);
+        let state = self.rounds.entry(round).or_insert_with(|| RoundState {
+            proposal: None,
+            votes: rv::RoundVotes::new(height, round, total_weight),
+            thresholds: Vec::new(),
+            timeouts: Scheduled::default(),
+            step: None,
+            heartbeats: BTreeMap::new(),
+            prevote_nil: false,
+        });
+        Some(state)
+    }
+
+    // is_pruned returns true if the round was pruned.
+    fn is_pruned(&self, round: i64) -> bool {
+        round < self.pruned
+    }
+
+    // add the vote, returning how it was tallied, and the event for the
+    // current threshold, if any. The event is for the current threshold rather
+    // than only the one the vote crossed, as the state machine may only act on
+    // it once it reaches the right step, eg. after applying our own vote.
+    // Returns None if the vote's round was pruned.
+    fn add(
+        &mut self,
+        vote: &Signed<Vote>,
+        weight: i64,
+    ) -> Option<(rv::TallyOutcome, Option<sm::Event>)> {
+        let state = self.entry(vote.msg.round)?;
+        let outcome = state.votes.add_signed_vote(vote, weight);
+        // kept for rounds(), so the timeline shows when thresholds were crossed
+        if let Some(thresh) = outcome.new_threshold {
+            state.thresholds.push((vote.msg.typ, thresh));
+        }
+        let event = if outcome.vote_accepted {
+            Rounds::to_event(vote.msg.typ, outcome.current)
+        } else {
+            None
+        };
+        Some((outcome, event))
+    }
+
+    // precommits returns the precommits counted in the round.
+    fn precommits(&self, round: i64) -> Vec<rv::ValidatorVote> {
+        match self.get(round) {
+            Some(state) => state.votes.votes(VoteType::Precommit).to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    // signed_votes returns the signed votes of the type counted for the
+    // value in the round, eg. the prevotes of a proof of lock.
+    fn signed_votes(&self, round: i64, typ: VoteType, value: Value) -> Vec<Signed<Vote>> {
+        let votes = match self.get(round) {
+            Some(state) => &state.votes,
+            None => return Vec::new(),
+        };
+        votes
+            .votes(typ)
+            .iter()
+            .filter(|v| v.value == Some(value))
+            .filter_map(|v| votes.signed_vote(v.validator, typ))
+            .cloned()
+            .collect()
+    }
+
+    // dump returns the tally of each round, by round.
+    fn dump(&self) -> Vec<rv::RoundVotesDump> {
+        self.rounds
+            .values()
+            .map(|state| state.votes.dump())
+            .collect()
+    }
+
+    // summaries returns the summary of each round, by round.
+    fn summaries(&self) -> Vec<RoundSummary> {
+        self.rounds
+            .iter()
+            .map(|(round, state)| RoundSummary {
+                round: *round,
+                has_proposal: state.proposal.is_some(),
+                thresholds: state.thresholds.clone(),
+                step: state.step,
+                heartbeats: state.heartbeats.keys().cloned().collect(),
+            })
+            .collect()
+    }
+
+    // drain_timeouts_if removes and returns the handles of the timeouts
+    // of any round matching f.
+    fn drain_timeouts_if<F>(&mut self, f: F) -> Vec<TimeoutHandle>
+    where
+        F: Fn(&sm::Timeout) -> bool,
+    {
+        let f = &f;
+        let mut handles = Vec::new();
+        for state in self.rounds.values_mut() {
+            handles.extend(state.timeouts.drain_if(f));
+        }
+        handles
+    }
+
+    // prune drops the state of rounds before the given round, returning
+    // the handles of their timeouts that are still scheduled.
+    fn prune(&mut self, round: i64) -> Vec<TimeoutHandle> {
+        let kept = self.rounds.split_off(&round);
+        let pruned = std::mem::replace(&mut self.rounds, kept);
+        self.pruned = self.pruned.max(round);
+        let mut handles = Vec::new();
+        for (round, mut state) in pruned {
+            if let Some(proposal) = state.proposal.take() {
+                self.proposals.insert(round, proposal);
+            }
+            handles.extend(state.timeouts.drain_if(|_| true));
+        }
+        handles
+    }
+
+    // map a vote type and threshold to a state machine event.
+    fn to_event(typ: VoteType, thresh: Thresh) -> Option<sm::Event> {
+        match (typ, thresh) {
+            (_, Thresh::Init) => None,
+            (VoteType::Prevote, Thresh::Any) => Some(sm::Event::PolkaAny),
+            (VoteType::Prevote, Thresh::Nil) => Some(sm::Event::PolkaNil),
+            (VoteType::Prevote, Thresh::Value(v)) => Some(sm::Event::PolkaValue(v)),
+            (VoteType::Precommit, Thresh::Any) => Some(sm::Event::PrecommitAny),
+            // 47: +2/3 precommits for nil are precommits for any
+            (VoteType::Precommit, Thresh::Nil) => Some(sm::Event::PrecommitAny),
+            (VoteType::Precommit, Thresh::Value(v)) => Some(sm::Event::PrecommitValue(v)),
+        }
+    }
+}
+
+//---------------------------------------------------------------------
+// Future messages
+
+// FutureBuffer holds messages for heights we haven't reached yet, up to a
+// number per sender. Room is made by evicting the sender's oldest message.
+// They're verified again when taken, as the keys validators sign with at
+// the height may have rotated since.
+struct FutureBuffer {
+    per_peer: usize,
+    messages: VecDeque<(Sender, i64, Message)>, // sender, height and message
+    evicted: u64,
+}
+
+// Sender is who a buffered message counts against: the peer it came from,
+// or its signer if the caller didn't say.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Sender {
+    Peer(PeerId),
+    Signer(usize),
+}
+
+impl Sender {
+    fn peer(self) -> Option<PeerId> {
+        match self {
+            Sender::Peer(peer) => Some(peer),
+            Sender::Signer(_) => None,
+        }
+    }
+}
+
+impl FutureBuffer {
+    fn new(per_peer: usize) -> FutureBuffer {
+        FutureBuffer {
+            per_peer,
+            messages: VecDeque::new(),
+            evicted: 0,
+        }
+    }
+
+    // add the message, returning the one it evicted, if any.
+    fn add(&mut self, sender: Sender, height: i64, msg: Message) -> Option<Message> {
+        if self.per_peer == 0 {
+            self.evicted += 1;
+            return Some(msg);
+        }
+        let count = self.messages.iter().filter(|m| m.0 == sender).count();
+        let mut evicted = None;
+        if count >= self.per_peer {
+            let oldest = self.messages.iter().position(|m| m.0 == sender)?;
+            evicted = self.messages.remove(oldest).map(|m| m.2);
+            self.evicted += 1;
+        }
+        self.messages.push_back((sender, height, msg));
+        evicted
+    }
+
+    // take returns the messages for the height with their senders, oldest
+    // first, and drops the ones for earlier heights.
+    fn take(&mut self, height: i64) -> Vec<(Sender, Message)> {
+        let messages = std::mem::take(&mut self.messages);
+        let (ready, later): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .filter(|m| m.1 >= height)
+            .partition(|m| m.1 == height);
+        self.messages = later.into();
+        ready.into_iter().map(|m| (m.0, m.2)).collect()
+    }
+}
+
+//---------------------------------------------------------------------
+// LastCommit
+
+// LastCommit tallies precommits for a height that was already decided.
+// It never produces events; it only completes the stored commit.
+struct LastCommit {
+    commit: Commit,
+}
+
+impl LastCommit {
+    // add_precommit adds a late precommit to the commit if it is
+    // for the decided value and we don't have it yet.
+    // Returns true if the precommit was added.
+    fn add_precommit(&mut self, signed: &Signed<Vote>) -> bool {
+        let (commit, vote) = (&mut self.commit, &signed.msg);
+        let for_commit = vote.typ == VoteType::Precommit
+            && vote.height == commit.height
+            && vote.round == commit.round
+            && vote.value == Some(commit.value);
+        if !for_commit || commit.precommits.iter().any(|v| v.signer == signed.signer) {
+            return false;
+        }
+        commit.precommits.push(signed.clone());
+        true
+    }
+}
+
+impl Engine {
+    // new creates an Engine at the given height, for the validator set.
+    // The signer signs our proposals and votes, the verifier checks signatures
+    // of messages passed to execute(), and the scheduler schedules and cancels timeouts.
+    // Returns an error if the config doesn't validate.
+    pub fn new(
+        config: ConsensusConfig,
+        height: i64,
+        validators: ValidatorSet,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        let signer = Some(signer);
+        Engine::with_signer(config, height, validators, signer, verifier, scheduler)
+    }
+
+    // new_observer creates an Engine that follows consensus without taking
+    // part in it, eg. for full nodes and indexers. It tallies votes, moves
+    // through rounds and heights and outputs decisions like a validator,
+    // but never signs, so it outputs no proposals or votes.
+    pub fn new_observer(
+        config: ConsensusConfig,
+        height: i64,
+        validators: ValidatorSet,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        Engine::with_signer(config, height, validators, None, verifier, scheduler)
+    }
+
+    fn with_signer(
+        config: ConsensusConfig,
+        height: i64,
+        validators: ValidatorSet,
+        signer: Option<Box<dyn Signer>>,
+        verifier: Box<dyn Verifier>,
+        mut scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        config.validate()?;
+        scheduler.set_params(&config.params);
+        let rejected = RejectedLog::new(config.rejected_log_size);
+        let future = FutureBuffer::new(config.max_future_per_peer);
+        let rounds = Rounds::new(height, validators.total_voting_power());
+        Ok(Engine {
+            config,
+            validators,
+            signer,
+            verifier,
+            scheduler,
+            rounds,
+            state: sm::State::new(height),
+            decision: None,
+            next_params: None,
+            next_validators: None,
+            reconfig: Reconfig::default(),
+            next_reconfig: None,
+            proposal_parts: None,
+            propose_extended: None,
+            upgrade: None,
+            upgrade_output: false,
+            committed: height - 1,
+            deferred_start: None,
+            heartbeat_sequence: 0,
+            own_proposal: None,
+            own_votes: Vec::new(),
+            pol: None,
+            last_commit: None,
+            path: None,
+            rejected,
+            future,
+            correlation_id: CorrelationId::default(),
+            peer: None,
+            timers: None,
+            now: None,
+            proposer_selector: None,
+            value_validator: None,
+            sign_state: None,
+            replaying: false,
+        })
+    }
+
+    // new_stepped creates an Engine for embedders that drive it one input at
+    // a time with step(), eg. deterministic runtimes. It keeps its timeouts
+    // itself, with deadlines from the times it's given, so it never reads the
+    // clock or needs a driver to schedule anything.
+    pub fn new_stepped(
+        config: ConsensusConfig,
+        height: i64,
+        validators: ValidatorSet,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+    ) -> Result<Engine, String> {
+        let timers = Timers::default();
+        let scheduler = Box::new(timers.clone());
+        let mut engine = Engine::new(config, height, validators, signer, verifier, scheduler)?;
+        engine.timers = Some(timers);
+        Ok(engine)
+    }
+
+    // recover creates an Engine from a state provided by an operator, to
+    // restart a chain after a catastrophic failure: the height to decide next,
+    // its validator set, and the commit of the height before it.
+    // Returns an error if the state is inconsistent, the last commit isn't a
+    // quorum of precommits signed by the set, or the validator set is outside
+    // the config's validator_limits.
+    pub fn recover(
+        config: ConsensusConfig,
+        state: RecoveryState,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        let RecoveryState {
+            height,
+            validators,
+            last_commit,
+        } = state;
+        if height < 1 {
+            return Err(format!("bad height {}", height));
+        }
+        validators
+            .validate(&config.validator_limits)
+            .map_err(|e| e.to_string())?;
+        if let Some(commit) = &last_commit {
+            if commit.height != height - 1 {
+                return Err(format!(
+                    "last commit is for height {}, not {}",
+                    commit.height,
+                    height - 1
+                ));
+            }
+            commit
+                .verify(&validators, verifier.as_ref(), &config.sign_format)
+                .map_err(|e| format!("bad last commit: {}", e))?;
+        }
+        let mut engine = Engine::new(config, height, validators, signer, verifier, scheduler)?;
+        engine.last_commit = last_commit.map(|commit| LastCommit { commit });
+        Ok(engine)
+    }
+
+    // from_trusted creates an Engine from a trusted state, for the validator
+    // set with its hash. The last commit must be a quorum of precommits signed
+    // by the set, which is taken to be the validator set of the height before.
+    pub fn from_trusted(
+        config: ConsensusConfig,
+        trusted: TrustedState,
+        validators: ValidatorSet,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        if validators.hash() != trusted.validators_hash {
+            return Err("validator set doesn't match the trusted hash".to_string());
+        }
+        let state = RecoveryState {
+            height: trusted.height,
+            validators,
+            last_commit: Some(trusted.last_commit),
+        };
+        Engine::recover(config, state, signer, verifier, scheduler)
+    }
+
+    // from_snapshot creates an Engine in the middle of a height, from the
+    // snapshot of its state and the tallies of its rounds, as snapshot() and
+    // round_votes() return them, eg. for tests of late-round behavior to
+    // start from a fixture rather than replay the messages that led there.
+    // The tallied votes have no signatures, so they can't be carried as a
+    // proof of lock, and no timeouts are scheduled: tests fire the ones they
+    // need. Returns an error if the state is inconsistent, or the tallies
+    // don't match the validator set. Only built for tests, or with the
+    // test_utils feature: a production engine resumes from its WAL instead.
+    #[cfg(any(test, feature = "test_utils"))]
+    pub fn from_snapshot(
+        config: ConsensusConfig,
+        snapshot: RoundStateSnapshot,
+        tallies: &[rv::RoundVotesDump],
+        validators: ValidatorSet,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        let (height, round) = (snapshot.height, snapshot.round);
+        let state = sm::State::restore(
+            height,
+            round,
+            snapshot.step,
+            snapshot.locked,
+            snapshot.valid,
+        )?;
+        if snapshot
+            .last_commit
+            .as_ref()
+            .is_some_and(|c| c.height != height - 1)
+        {
+            return Err(format!("last commit isn't for height {}", height - 1));
+        }
+        let mut engine = Engine::new(config, height, validators, signer, verifier, scheduler)?;
+        for tally in tallies {
+            if tally.height != height || tally.total != engine.validators.total_voting_power() {
+                return Err(format!(
+                    "round {} tally isn't of this height and set",
+                    tally.round
+                ));
+            }
+            let counts = [
+                (VoteType::Prevote, &tally.prevotes),
+                (VoteType::Precommit, &tally.precommits),
+            ];
+            for (typ, count) in counts {
+                for v in &count.votes {
+                    let power = engine
+                        .validators
+                        .get(v.validator)
+                        .map(|val| val.voting_power);
+                    if power != Some(v.weight) {
+                        return Err(format!(
+                            "round {} vote of validator {} has weight {}",
+                            tally.round, v.validator, v.weight
+                        ));
+                    }
+                    let msg = match typ {
+                        VoteType::Prevote => Vote::new_prevote(height, tally.round, v.value),
+                        VoteType::Precommit => Vote::new_precommit(height, tally.round, v.value),
+                    };
+                    let vote = Signed {
+                        signer: v.validator,
+                        msg,
+                        signature: Vec::new(),
+                    };
+                    engine.rounds.add(&vote, v.weight);
+                }
+                let dump = match engine.rounds.get(tally.round) {
+                    Some(state) => state.votes.dump(),
+                    None => return Err(format!("round {} has no votes", tally.round)),
+                };
+                let thresh = match typ {
+                    VoteType::Prevote => dump.prevotes.thresh,
+                    VoteType::Precommit => dump.precommits.thresh,
+                };
+                if thresh != count.thresh {
+                    return Err(format!(
+                        "round {} {:?} threshold doesn't match its votes",
+                        tally.round, typ
+                    ));
+                }
+            }
+        }
+        engine.state = state;
+        if let Some(round) = engine.rounds.entry(round) {
+            round.step = Some(state.step());
+        }
+        engine.own_proposal = snapshot.own_proposal;
+        engine.own_votes = snapshot.own_votes;
+        engine.last_commit = snapshot.last_commit.map(|commit| LastCommit { commit });
+        engine.keep_pol();
+        Ok(engine)
+    }
+
+    // height returns the height we're currently deciding.
+    pub fn height(&self) -> i64 {
+        self.state.height()
+    }
+
+    // is_observer returns true if the engine has no signer.
+    pub fn is_observer(&self) -> bool {
+        self.signer.is_none()
+    }
+
+    // is_halted returns true once the config's halt_height, or the height
+    // marked or expected for an upgrade, is decided.
+    // From then on, only late precommits for its commit are taken.
+    pub fn is_halted(&self) -> bool {
+        let upgrade = self.upgrade.map(|(h, _)| h);
+        let halt = |h: &i64| self.state.height() > *h;
+        self.config.halt_height.as_ref().is_some_and(halt) || upgrade.as_ref().is_some_and(halt)
+    }
+
+    // proposal_part_received records that parts of the proposal for the
+    // round are arriving, eg. block parts, though it's not complete yet.
+    // With the config's propose_extension, timeout propose in the round is
+    // then extended once rather than prevoting nil right away.
+    pub fn proposal_part_received(&mut self, height: i64, round: i64) {
+        if height == self.state.height() {
+            self.proposal_parts = Some((height, round));
+        }
+    }
+
+    // set_proposer_selector sets how the proposer of each round is picked,
+    // so proposals from other validators are rejected. Without one, the
+    // engine takes a proposal from any validator, and leaves checking the
+    // proposer to the driver.
+    pub fn set_proposer_selector(&mut self, selector: Box<dyn ProposerSelector>) {
+        self.proposer_selector = Some(selector);
+    }
+
+    // set_value_validator sets how proposed values are checked, so the
+    // config's invalid_proposal policy applies to ones that fail. Without
+    // one, the engine takes any value, and leaves checking it to the driver.
+    pub fn set_value_validator(&mut self, validator: Box<dyn ValueValidator>) {
+        self.value_validator = Some(validator);
+    }
+
+    // expected_proposer returns the proposer of the round at the current
+    // height, if a ProposerSelector was set.
+    pub fn expected_proposer(&self, round: i64) -> Option<usize> {
+        let selector = self.proposer_selector.as_ref()?;
+        Some(selector.proposer(&self.validators, self.state.height(), round))
+    }
+
+    // prefilter_proposal checks a proposal's header before its signature is
+    // verified or its value, which may be large, is received and decoded:
+    // that it's for the current height or one of the next future_heights,
+    // within future_rounds, from a validator, and at the current height from
+    // the round's proposer, for a round we don't have a proposal for yet.
+    // Returns false, and logs the proposal as rejected, if it fails, so the
+    // driver can drop it, and its parts, early.
+    pub fn prefilter_proposal(&mut self, proposal: &Signed<Proposal>) -> bool {
+        match self.check_proposal_header(proposal) {
+            Ok(()) => true,
+            Err(reason) => {
+                self.reject(reason, &Message::Proposal(proposal.clone()));
+                false
+            }
+        }
+    }
+
+    fn check_proposal_header(&self, proposal: &Signed<Proposal>) -> Result<(), RejectReason> {
+        let (signer, height, round) = (proposal.signer, proposal.msg.height, proposal.msg.round);
+        if self.is_halted() {
+            return Err(RejectReason::Halted);
+        }
+        if self.validators.get(signer).is_none() {
+            return Err(RejectReason::UnknownValidator);
+        }
+        self.check_horizon(height, round)?;
+        if height > self.state.height() {
+            // the rounds of a future height aren't known yet
+            return Ok(());
+        }
+        if self.expected_proposer(round).is_some_and(|e| e != signer) {
+            return Err(RejectReason::WrongProposer);
+        }
+        if self.rounds.is_pruned(round) {
+            return Err(RejectReason::StaleRound);
+        }
+        if self.rounds.get(round).is_some_and(|r| r.proposal.is_some()) {
+            return Err(RejectReason::DuplicateProposal);
+        }
+        Ok(())
+    }
+
+    // expect_upgrade marks a height as the last before an upgrade ahead of
+    // deciding it, eg. from the upgrade plan the chain agreed on. The engine
+    // halts once it's decided, instead of starting the next height, even
+    // without timeout commit, and waits for mark_upgrade() to give its app
+    // hash. Returns an error if the height is already decided.
+    pub fn expect_upgrade(&mut self, height: i64) -> Result<(), String> {
+        if height < self.state.height() || self.upgrade.is_some_and(|(_, h)| h.is_some()) {
+            return Err(format!("height {} is already decided", height));
+        }
+        self.upgrade = Some((height, None));
+        Ok(())
+    }
+
+    // mark_upgrade marks the decided height as the last before an upgrade,
+    // in the application's response to committing it, with its app hash.
+    // The engine halts once it moves past the height, instead of starting
+    // the next one, and outputs the UpgradePackage for the driver to persist.
+    // Without timeout commit, the engine moves past the height as it decides
+    // it, before the driver can mark it: the height must then be expected
+    // with expect_upgrade(). Returns the Halt output if the engine already
+    // halted at the height.
+    pub fn mark_upgrade(&mut self, height: i64, app_hash: Hash) -> Result<Vec<Output>, String> {
+        let waiting = self.decision.is_some() && height == self.state.height();
+        let decided = self.last_commit().map(|c| c.height) == Some(height)
+            && self.state.height() == height + 1;
+        if !waiting && !decided {
+            return Err(format!("height {} is not the last decided", height));
+        }
+        let expected = self.upgrade.map(|(h, _)| h) == Some(height);
+        if decided && !expected {
+            return Err(format!(
+                "already started height {}: expect_upgrade() before deciding {}",
+                height + 1,
+                height
+            ));
+        }
+        self.upgrade = Some((height, Some(app_hash)));
+        let mut outputs = Vec::new();
+        self.output_upgrade(&mut outputs);
+        Ok(outputs)
+    }
+
+    // state returns the state of the state machine.
+    pub fn state(&self) -> sm::State {
+        self.state
+    }
+
+    pub fn validators(&self) -> &ValidatorSet {
+        &self.validators
+    }
+
+    // params returns the consensus params of the current height.
+    pub fn params(&self) -> &ConsensusParams {
+        &self.config.params
+    }
+
+    // update_params validates the params from the application's response to
+    // committing a height. If they're valid, they take effect all at once
+    // when the engine moves to its next height; otherwise nothing changes.
+    // Updating the params again before then replaces the earlier update.
+    pub fn update_params(&mut self, params: ConsensusParams) -> Result<(), String> {
+        params.validate()?;
+        self.next_params = Some(params);
+        Ok(())
+    }
+
+    // update_validators validates the validator set from the application's
+    // response to committing a height, eg. with new validators or key
+    // rotations. If it's within the config's validator_limits, it takes
+    // effect with the verifier when the engine moves to its next height, so
+    // signatures are checked with the keys validators sign with from then on;
+    // otherwise nothing changes. Updating it again before then replaces the
+    // earlier update.
+    pub fn update_validators(
+        &mut self,
+        validators: ValidatorSet,
+        verifier: Box<dyn Verifier>,
+    ) -> Result<(), String> {
+        validators
+            .validate(&self.config.validator_limits)
+            .map_err(|e| e.to_string())?;
+        self.next_validators = Some((validators, verifier));
+        Ok(())
+    }
+
+    // reconfigure changes the operational part of the config, eg. as an
+    // operator tunes a live validator, from the next round on, so it never
+    // changes mid-step. If the changes are valid, they take effect all at once;
+    // otherwise nothing changes. Changing it again before then adds to the
+    // earlier changes. Changed timeouts are kept over the application's
+    // params at later heights.
+    pub fn reconfigure(&mut self, reconfig: Reconfig) -> Result<(), String> {
+        reconfig.validate()?;
+        let next = self.next_reconfig.take().unwrap_or_default();
+        self.next_reconfig = Some(next.merge(&reconfig));
+        Ok(())
+    }
+
+    // apply_reconfig makes the operator's changes, at a round boundary.
+    fn apply_reconfig(&mut self) {
+        let reconfig = match self.next_reconfig.take() {
+            Some(reconfig) => reconfig,
+            None => return,
+        };
+        reconfig.apply(&mut self.config);
+        self.future.per_peer = self.config.max_future_per_peer;
+        self.scheduler.set_params(&self.config.params);
+        self.reconfig = self.reconfig.merge(&reconfig);
+    }
+
+    // proposal returns the proposal received for the round at this height, if any.
+    pub fn proposal(&self, round: i64) -> Option<&Signed<Proposal>> {
+        self.rounds.proposal(round)
+    }
+
+    // round_votes returns the tally of each round at this height that's kept,
+    // by round, eg. to compare with another node's.
+    pub fn round_votes(&self) -> Vec<rv::RoundVotesDump> {
+        self.rounds.dump()
+    }
+
+    // rounds returns what happened in each round at this height that's kept,
+    // by round, eg. to debug a stalled height.
+    pub fn rounds(&self) -> Vec<RoundSummary> {
+        self.rounds.summaries()
+    }
+
+    // heartbeat signs a heartbeat for the current round, for the driver to
+    // broadcast while we're idle in it, eg. as the proposer waiting for
+    // transactions. Returns None for observers, or once we're halted.
+    pub fn heartbeat(&mut self) -> Option<Signed<Heartbeat>> {
+        if self.is_halted() {
+            return None;
+        }
+        let (height, round) = (self.state.height(), self.state.round());
+        let heartbeat = Heartbeat::new(height, round, self.heartbeat_sequence + 1);
+        let signed = self.sign(heartbeat)?;
+        self.heartbeat_sequence += 1;
+        if let Some(state) = self.rounds.entry(round) {
+            state.heartbeats.insert(signed.signer, heartbeat.sequence());
+        }
+        Some(signed)
+    }
+
+    // silent returns the validators we haven't heard from in the round at
+    // this height: no proposal, vote or heartbeat, eg. for monitoring to
+    // alert on before the round fails. A silent proposer is likely offline,
+    // rather than waiting for transactions.
+    pub fn silent(&self, round: i64) -> Vec<usize> {
+        let state = self.rounds.get(round);
+        (0..self.validators.len())
+            .filter(|v| !state.is_some_and(|s| s.is_live(*v)))
+            .collect()
+    }
+
+    // last_commit returns the commit for the previous height, including any
+    // precommits that arrived after we decided.
+    pub fn last_commit(&self) -> Option<&Commit> {
+        self.last_commit.as_ref().map(|lc| &lc.commit)
+    }
+
+    // record_path enables or disables recording the transitions taken
+    // by the state machine, eg. to render them with sm::path_to_dot.
+    pub fn record_path(&mut self, enable: bool) {
+        self.path = if enable { Some(Vec::new()) } else { None };
+    }
+
+    // take_path returns the transitions recorded so far, across heights,
+    // and clears them.
+    pub fn take_path(&mut self) -> Vec<sm::PathStep> {
+        self.path.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // pol_votes returns the prevotes that justify the proposal's pol_round,
+    // for the caller to send along as a ProposalWithPol. It's empty unless
+    // the proposal re-proposes our valid value, eg. our own re-proposal.
+    pub fn pol_votes(&self, proposal: &Proposal) -> Vec<Signed<Vote>> {
+        match &self.pol {
+            Some((valid, votes))
+                if proposal.height == self.state.height()
+                    && valid.round == proposal.pol_round
+                    && valid.value == proposal.value =>
+            {
+                votes.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // rebroadcast returns our latest proposal and votes at this height,
+    // for the caller to send again, eg. when the round stalls.
+    pub fn rebroadcast(&self) -> Vec<Output> {
+        let proposal = self.own_proposal.iter().cloned().map(Output::Proposal);
+        let votes = self.own_votes.iter().cloned().map(Output::Vote);
+        proposal.chain(votes).collect()
+    }
+
+    // sign_state returns the last proposal or vote we signed. The driver
+    // persists it after each call, before sending the outputs, and gives it
+    // back with restore_sign_state() after a restart.
+    pub fn sign_state(&self) -> Option<&SignState> {
+        self.sign_state.as_ref()
+    }
+
+    // restore_sign_state restores the sign state persisted before a restart,
+    // so we don't sign anything conflicting with what we signed before it.
+    pub fn restore_sign_state(&mut self, state: SignState) {
+        self.sign_state = Some(state);
+    }
+
+    // future_evictions returns how many messages for future heights were
+    // dropped to stay within the config's max_future_per_peer.
+    pub fn future_evictions(&self) -> u64 {
+        self.future.evicted
+    }
+
+    // rejected returns the most recently rejected messages, oldest first,
+    // up to the config's rejected_log_size.
+    pub fn rejected(&self) -> impl Iterator<Item = &Rejected> {
+        self.rejected.iter()
+    }
+
+    // shutdown stops the engine: it cancels the timeouts it scheduled,
+    // and returns its final state for the caller to persist.
+    pub fn shutdown(mut self) -> RoundStateSnapshot {
+        for handle in self.rounds.drain_timeouts_if(|_| true) {
+            self.scheduler.cancel(handle);
+        }
+        self.snapshot()
+    }
+
+    // snapshot returns the engine's current state, as shutdown() would.
+    pub fn snapshot(&self) -> RoundStateSnapshot {
+        let state = self.state;
+        RoundStateSnapshot {
+            height: state.height(),
+            round: state.round(),
+            step: state.step(),
+            locked: state.locked(),
+            valid: state.valid(),
+            own_proposal: self.own_proposal.clone(),
+            own_votes: self.own_votes.clone(),
+            last_commit: self.last_commit().cloned(),
+        }
+    }
+}
+
+impl Engine {
+    // execute the message in full. may result in multiple state transitions.
+    // Messages with invalid signatures are dropped, though a proposal's may
+    // still make us prevote nil, by the config's invalid_proposal policy.
+    // Returns the outputs, in order: our proposals and votes for the caller
+    // to broadcast, and timeouts, new rounds and decisions for information.
+    pub fn execute(&mut self, msg: Message) -> Vec<Output> {
+        self.next_correlation_id();
+        let mut outputs = Vec::new();
+        match self.verify(msg) {
+            Ok(msg) => self.run(vec![msg].into(), &mut outputs),
+            // a proposal that passes the header checks other than its
+            // signature, so only the round's proposer can make us act on it
+            Err(Some(p)) => {
+                let action = self.config.invalid_proposal.invalid_signature;
+                let current = p.msg.height == self.state.height();
+                if current && self.check_proposal_header(&p).is_ok() {
+                    if let Some(msg) = self.invalid_proposal(action, p.msg.round) {
+                        let mut queue = VecDeque::new();
+                        self.handle_output(msg, &mut outputs, &mut queue);
+                        self.run(queue, &mut outputs);
+                    }
+                }
+            }
+            Err(None) => {}
+        }
+        outputs
+    }
+
+    // execute_verified is like execute, but for messages whose signatures
+    // were already verified, eg. by the networking layer.
+    pub fn execute_verified(&mut self, msg: Verified<Message>) -> Vec<Output> {
+        self.next_correlation_id();
+        let mut outputs = Vec::new();
+        self.run(vec![msg].into(), &mut outputs);
+        outputs
+    }
+
+    // execute_from is like execute, for a message received from the peer.
+    // Messages the call rejects are logged with the peer, so the driver can
+    // tell which peers send it bad ones.
+    pub fn execute_from(&mut self, peer: PeerId, msg: Message) -> Vec<Output> {
+        self.peer = Some(peer);
+        let outputs = self.execute(msg);
+        self.peer = None;
+        outputs
+    }
+
+    // execute_verified_from is like execute_verified, for a message received
+    // from the peer.
+    pub fn execute_verified_from(&mut self, peer: PeerId, msg: Verified<Message>) -> Vec<Output> {
+        self.peer = Some(peer);
+        let outputs = self.execute_verified(msg);
+        self.peer = None;
+        outputs
+    }
+
+    // start_round starts the given round. If we're the proposer, value is the
+    // value to propose; otherwise we wait for the proposal.
+    // If we have a valid value, we re-propose it instead, with its round as
+    // the pol_round, so the driver can check state().valid() before getting
+    // a new value from the application. With a ProposerSelector, value may be
+    // None in rounds we propose in with a valid value.
+    // With the config's max_uncommitted_heights, starting a round is deferred
+    // while the application is too far behind in committing, until
+    // commit_done() catches it up.
+    pub fn start_round(&mut self, round: i64, value: Option<Value>) -> Vec<Output> {
+        self.next_correlation_id();
+        if self.is_halted() {
+            return Vec::new();
+        }
+        if self.held_back() {
+            self.deferred_start = Some((round, value));
+            return Vec::new();
+        }
+        self.deferred_start = None;
+        // an observer never proposes, so it waits for the proposal even if
+        // it's given a value
+        let value = value.filter(|_| !self.is_observer());
+        let event = match value.or_else(|| self.reproposal(round)) {
+            Some(v) => sm::Event::NewRoundProposer(v),
+            None => sm::Event::NewRound,
+        };
+        let (mut outputs, mut queue) = (Vec::new(), VecDeque::new());
+        if let Some(msg) = self.apply_event(round, event) {
+            self.handle_output(msg, &mut outputs, &mut queue);
+        }
+        self.run(queue, &mut outputs);
+
+        // messages buffered for the height, verified again, each with its
+        // own work limit and logged with the peer it came from
+        for (sender, msg) in self.future.take(self.state.height()) {
+            self.peer = sender.peer();
+            if msg.is_valid(self.verifier.as_ref(), &self.config.sign_format) {
+                self.run(vec![Verified::new_unchecked(msg)].into(), &mut outputs);
+            } else {
+                self.reject(RejectReason::InvalidSignature, &msg);
+            }
+        }
+        self.peer = None;
+        outputs
+    }
+
+    // reproposal returns our valid value if we're the round's proposer,
+    // to re-propose without a new value from the driver.
+    fn reproposal(&self, round: i64) -> Option<Value> {
+        let valid = self.state.valid()?;
+        let index = self.signer.as_ref()?.index();
+        (self.expected_proposer(round) == Some(index)).then_some(valid.value)
+    }
+
+    // commit_done acknowledges that the application committed the decided
+    // height. If that catches it up enough, the deferred round start goes
+    // ahead, and its outputs are returned.
+    pub fn commit_done(&mut self, height: i64) -> Vec<Output> {
+        self.committed = self.committed.max(height.min(self.state.height() - 1));
+        match self.deferred_start {
+            Some((round, value)) => self.start_round(round, value),
+            None => Vec::new(),
+        }
+    }
+
+    // step applies one input at the given time, and returns all its effects
+    // as outputs. The timeouts of an engine created with new_stepped() that
+    // are due by then fire first, so the same inputs at the same times always
+    // give the same outputs. Other engines fire timeouts from their scheduler,
+    // and take the ones that elapsed as Input::Timeout. now must not go back.
+    pub fn step(&mut self, input: Input, now: Timestamp) -> Vec<Output> {
+        self.now = Some(now);
+        let mut outputs = Vec::new();
+        let due = match &self.timers {
+            Some(timers) => timers.elapsed(now),
+            None => Vec::new(),
+        };
+        for msg in due {
+            outputs.extend(self.execute_verified(Verified::new_unchecked(msg)));
+        }
+        outputs.extend(match input {
+            Input::Message(msg) => self.execute(msg),
+            Input::Verified(msg) => self.execute_verified(msg),
+            Input::Timeout(t) => self.execute(Message::TimeoutElapsed(t)),
+            Input::StartRound(round, value) => self.start_round(round, value),
+            Input::CommitDone(height) => self.commit_done(height),
+            Input::Tick => Vec::new(),
+        });
+        self.now = None;
+        outputs
+    }
+
+    // replay steps the engine through the entries of the write-ahead log,
+    // after a restart, and returns their outputs. They were acted on before
+    // the crash, so the driver only needs the decisions it hasn't committed
+    // yet, and to send rebroadcast() in case it crashed before sending them.
+    // The sign state should be restored first: the proposals and votes from
+    // before it were sent already and aren't signed again, and nothing
+    // conflicting with it is signed.
+    pub fn replay(&mut self, entries: &[WalEntry]) -> Vec<Output> {
+        self.replaying = true;
+        let mut outputs = Vec::new();
+        for entry in entries {
+            outputs.extend(self.step(entry.input.clone(), entry.at));
+        }
+        self.replaying = false;
+        outputs
+    }
+
+    // next_deadline returns when the next timeout of an engine created with
+    // new_stepped() is due, for the embedder to step it with Input::Tick by then.
+    pub fn next_deadline(&self) -> Option<Timestamp> {
+        self.timers.as_ref().and_then(|t| t.next_deadline())
+    }
+
+    // uncommitted returns the number of decided heights the application
+    // hasn't acknowledged committing yet.
+    pub fn uncommitted(&self) -> i64 {
+        self.state.height() - 1 - self.committed
+    }
+
+    // held_back returns true if the current height can't start until the
+    // application commits, per the config's max_uncommitted_heights.
+    fn held_back(&self) -> bool {
+        self.config
+            .max_uncommitted_heights
+            .is_some_and(|k| self.uncommitted() >= k)
+    }
+
+    // correlation_id returns the id of the latest call to execute(),
+    // execute_verified() or start_round(), which its outputs belong to.
+    pub fn correlation_id(&self) -> CorrelationId {
+        self.correlation_id
+    }
+
+    fn next_correlation_id(&mut self) {
+        self.correlation_id = CorrelationId(self.correlation_id.0 + 1);
+    }
+
+    // run applies the queued messages in order, handling their outputs,
+    // which may queue more. At most max_messages_per_call are applied,
+    // counting the prevotes a proposal carries; the rest are dropped and
+    // logged as rejected. Our own proposals and votes were already output,
+    // so they're always applied, and don't count.
+    fn run(&mut self, mut queue: VecDeque<Verified<Message>>, outputs: &mut Vec<Output>) {
+        let mut applied = 0;
+        while let Some(msg) = queue.pop_front() {
+            if !self.is_own(&msg) {
+                if applied == self.config.max_messages_per_call {
+                    self.reject(RejectReason::WorkLimit, &msg);
+                    continue;
+                }
+                applied += 1;
+            }
+            if let Message::ProposalWithPol(p, pol) = &*msg {
+                if !self.apply_pol(p, pol, &mut applied, outputs, &mut queue) {
+                    continue;
+                }
+            }
+            if let Some(msg) = self.apply_msg(msg, outputs) {
+                self.handle_output(msg, outputs, &mut queue);
+            }
+        }
+        self.output_upgrade(outputs);
+    }
+
+    // is_own returns true if the message is our own proposal or vote.
+    fn is_own(&self, msg: &Message) -> bool {
+        let index = match &self.signer {
+            Some(signer) => signer.index(),
+            None => return false,
+        };
+        match msg {
+            Message::Proposal(p) => p.signer == index,
+            Message::Vote(v) => v.signer == index,
+            _ => false,
+        }
+    }
+
+    // output_upgrade outputs the upgrade package, once we halted for it.
+    fn output_upgrade(&mut self, outputs: &mut Vec<Output>) {
+        let (height, app_hash) = match self.upgrade {
+            Some((height, Some(app_hash))) if !self.upgrade_output && self.is_halted() => {
+                (height, app_hash)
+            }
+            _ => return,
+        };
+        let last_commit = match self.last_commit() {
+            Some(commit) => commit.clone(),
+            None => return,
+        };
+        self.upgrade_output = true;
+        outputs.push(Output::Halt(UpgradePackage {
+            height,
+            validators: self.validators.clone(),
+            app_hash,
+            last_commit,
+        }));
+    }
+
+    // apply_pol counts the prevotes carried by the proposal, before it's
+    // applied, even if their round is stale. They must be a quorum of
+    // prevotes for the proposal's value in its pol_round; otherwise the
+    // proposal is rejected and false is returned. Each prevote applied
+    // counts towards max_messages_per_call; once it's reached, the rest are
+    // rejected, but the proposal is still applied.
+    fn apply_pol(
+        &mut self,
+        proposal: &Signed<Proposal>,
+        pol: &[Signed<Vote>],
+        applied: &mut usize,
+        outputs: &mut Vec<Output>,
+        queue: &mut VecDeque<Verified<Message>>,
+    ) -> bool {
+        let p = &proposal.msg;
+        if self.is_halted() || p.height != self.state.height() {
+            // the proposal is rejected for it
+            return true;
+        }
+        let mut signers = Vec::new();
+        let mut weight = 0;
+        for vote in pol {
+            let v = &vote.msg;
+            let for_pol = v.typ == VoteType::Prevote
+                && v.height == p.height
+                && v.round == p.pol_round
+                && v.value == Some(p.value);
+            match self.validators.get(vote.signer) {
+                Some(val) if for_pol && !signers.contains(&vote.signer) => {
+                    signers.push(vote.signer);
+                    weight += val.voting_power;
+                }
+                _ => {
+                    let msg = Message::ProposalWithPol(proposal.clone(), pol.to_vec());
+                    self.reject(RejectReason::InvalidPol, &msg);
+                    return false;
+                }
+            }
+        }
+        if p.pol_round < 0 || !rv::is_quorum(weight, self.validators.total_voting_power()) {
+            let msg = Message::ProposalWithPol(proposal.clone(), pol.to_vec());
+            self.reject(RejectReason::InvalidPol, &msg);
+            return false;
+        }
+        for vote in pol {
+            if *applied >= self.config.max_messages_per_call {
+                self.reject(RejectReason::WorkLimit, &Message::Vote(vote.clone()));
+                continue;
+            }
+            *applied += 1;
+            if let Some(msg) = self.apply_vote(vote, true, outputs) {
+                self.handle_output(msg, outputs, queue);
+            }
+        }
+        true
+    }
+
+    // handle_output acts on the state machine output and adds it to outputs.
+    // Our own proposals and votes are signed and queued to be applied,
+    // so they count like anyone else's. Observers drop them.
+    fn handle_output(
+        &mut self,
+        msg: sm::Message,
+        outputs: &mut Vec<Output>,
+        queue: &mut VecDeque<Verified<Message>>,
+    ) {
+        match msg {
+            sm::Message::NewRound(round) => {
+                self.apply_reconfig();
+                // check if we're the proposer
+                let pruned = self.rounds.prune(round - self.config.late_vote_rounds);
+                for handle in pruned {
+                    self.scheduler.cancel(handle);
+                }
+                outputs.push(Output::NewRound(round));
+            }
+            sm::Message::Proposal(p) => {
+                let round = p.round;
+                let signed = match self.sign_checked(p, round, SignStep::Proposal) {
+                    Some(signed) => signed,
+                    None => return,
+                };
+                self.own_proposal = Some(signed.clone());
+                outputs.push(Output::Proposal(signed.clone()));
+                queue.push_back(Verified::new_unchecked(Message::Proposal(signed)));
+            }
+            sm::Message::Vote(v) => {
+                let step = match v.typ {
+                    VoteType::Prevote => SignStep::Prevote,
+                    VoteType::Precommit => SignStep::Precommit,
+                };
+                let signed = match self.sign_checked(v, v.round, step) {
+                    Some(signed) => signed,
+                    None => return,
+                };
+                self.own_votes.retain(|o| o.msg.typ != v.typ);
+                self.own_votes.push(signed.clone());
+                outputs.push(Output::Vote(signed.clone()));
+                queue.push_back(Verified::new_unchecked(Message::Vote(signed)));
+            }
+            sm::Message::Timeout(t) => {
+                let handle = self.scheduler.schedule(t);
+                if let Some(state) = self.rounds.entry(t.round) {
+                    state.timeouts.add(t, handle);
+                }
+                outputs.push(Output::ScheduleTimeout(t));
+            }
+            sm::Message::Decision(d) => {
+                outputs.push(Output::Decision(d));
+                if let Some(msg) = self.decide(d) {
+                    self.handle_output(msg, outputs, queue);
+                }
+            }
+        }
+    }
+
+    // sign the message as our validator, unless we're an observer.
+    fn sign<T: SignBytes>(&self, msg: T) -> Option<Signed<T>> {
+        let signer = self.signer.as_ref()?;
+        let signature = signer.sign(&msg.sign_bytes_as(&self.config.sign_format));
+        Some(Signed {
+            signer: signer.index(),
+            msg,
+            signature,
+        })
+    }
+
+    // sign_checked signs our proposal or vote, and keeps it as the sign state.
+    // One that conflicts with the sign state isn't signed, and is logged as
+    // rejected; signing the same again gives the same signature. In replay(),
+    // the ones from before the sign state were signed and sent before the
+    // crash: they're skipped rather than signed again, as replay may not
+    // rebuild them byte for byte.
+    fn sign_checked<T>(&mut self, msg: T, round: i64, step: SignStep) -> Option<Signed<T>>
+    where
+        T: SignBytes,
+        Signed<T>: Into<Message>,
+    {
+        let index = self.signer.as_ref()?.index();
+        let (height, sign_bytes) = (msg.height(), msg.sign_bytes_as(&self.config.sign_format));
+        let checked = match &self.sign_state {
+            Some(state) => state.check(height, round, step, &sign_bytes),
+            None => Ok(None),
+        };
+        let earlier = match &self.sign_state {
+            Some(state) => (height, round, step) < (state.height, state.round, state.step),
+            None => false,
+        };
+        let signed = match checked {
+            // replaying what we signed before the last one
+            Err(_) if self.replaying && earlier => return None,
+            Ok(Some(signature)) => Signed {
+                signer: index,
+                msg,
+                signature,
+            },
+            Ok(None) => self.sign(msg)?,
+            Err(_) => {
+                let unsigned = Signed {
+                    signer: index,
+                    msg,
+                    signature: Vec::new(),
+                };
+                self.reject(RejectReason::ConflictingSign, &unsigned.into());
+                return None;
+            }
+        };
+        self.sign_state = Some(SignState {
+            height,
+            round,
+            step,
+            sign_bytes,
+            signature: signed.signature.clone(),
+        });
+        Some(signed)
+    }
+
+    // decide moves to the next height, after timeout commit if it's enabled.
+    // Precommits keep being tallied while we wait for it.
+    // Returns NewRound(0) if we moved to the next height right away.
+    fn decide(&mut self, decision: sm::RoundValue) -> Option<sm::Message> {
+        self.decision = Some(decision);
+        if !self.config.wait_timeout_commit || self.skip_timeout_commit() {
+            return self.next_height();
+        }
+        let timeout = sm::Timeout {
+            height: self.state.height(),
+            round: self.state.round(),
+            step: sm::TimeoutStep::Commit,
+        };
+        let handle = self.scheduler.schedule(timeout);
+        if let Some(state) = self.rounds.entry(timeout.round) {
+            state.timeouts.add(timeout, handle);
+        }
+        None
+    }
+
+    // skip_timeout_commit returns true if we can move to the next height without
+    // waiting for timeout commit, since all validators precommitted the decided value.
+    fn skip_timeout_commit(&self) -> bool {
+        let decision = match self.decision {
+            Some(d) if self.config.skip_timeout_commit => d,
+            _ => return false,
+        };
+        let weight: i64 = self
+            .rounds
+            .precommits(decision.round)
+            .iter()
+            .filter(|v| v.value == Some(decision.value))
+            .map(|v| v.weight)
+            .sum();
+        weight == self.validators.total_voting_power()
+    }
+
+    // next_height stores the commit for the decided value and moves to the next height.
+    // The precommits we have so far are kept as the last commit, which keeps
+    // absorbing late precommits until we decide the next height.
+    // Returns NewRound(0) for the new height, or None if nothing was decided.
+    fn next_height(&mut self) -> Option<sm::Message> {
+        let decision = self.decision.take()?;
+        let height = self.state.height();
+        let precommits =
+            self.rounds
+                .signed_votes(decision.round, VoteType::Precommit, decision.value);
+        let commit = Commit {
+            height,
+            round: decision.round,
+            value: decision.value,
+            precommits,
+        };
+        self.last_commit = Some(LastCommit { commit });
+        self.own_proposal = None;
+        self.own_votes.clear();
+        self.pol = None;
+
+        if let Some(mut params) = self.next_params.take() {
+            self.reconfig.apply_timeouts(&mut params);
+            self.scheduler.set_params(&params);
+            self.config.params = params;
+        }
+        if let Some((validators, verifier)) = self.next_validators.take() {
+            self.validators = validators;
+            self.verifier = verifier;
+        }
+
+        let height = height + 1;
+        self.state = sm::State::new(height);
+        for handle in self.rounds.drain_timeouts_if(|_| true) {
+            self.scheduler.cancel(handle);
+        }
+        self.rounds = Rounds::new(height, self.validators.total_voting_power());
+        if self.is_halted() {
+            return None;
+        }
+        Some(sm::Message::NewRound(0))
+    }
+}
+
+impl Engine {
+    // verify the signatures of the message, if it has any. A proposal's
+    // signature is verified apart from the prevotes it carries: anyone may
+    // have relayed those, so bad ones only reject the message as an invalid
+    // polka. Returns the proposal as the error if its own signature doesn't
+    // verify, for the invalid proposal policy.
+    fn verify(&mut self, msg: Message) -> Result<Verified<Message>, Option<Signed<Proposal>>> {
+        let (verifier, format) = (self.verifier.as_ref(), &self.config.sign_format);
+        let (valid, reason) = match &msg {
+            Message::Proposal(p) | Message::ProposalWithPol(p, _)
+                if !p.is_valid_as(verifier, format) =>
+            {
+                let proposal = p.clone();
+                self.reject(RejectReason::InvalidSignature, &msg);
+                return Err(Some(proposal));
+            }
+            Message::ProposalWithPol(_, pol) => (
+                pol.iter().all(|v| v.is_valid_as(verifier, format)),
+                RejectReason::InvalidPol,
+            ),
+            msg => (
+                msg.is_valid(verifier, format),
+                RejectReason::InvalidSignature,
+            ),
+        };
+        if !valid {
+            self.reject(reason, &msg);
+            return Err(None);
+        }
+        Ok(Verified::new_unchecked(msg))
+    }
+
+    // invalid_proposal takes the action on a proposal for the round at the
+    // current height that we can't prevote for. Prevoting nil right away is
+    // only possible in the current round; for a later one, we prevote nil
+    // at its timeout propose instead.
+    fn invalid_proposal(&mut self, action: ProposalAction, round: i64) -> Option<sm::Message> {
+        if action == ProposalAction::Ignore || self.rounds.is_pruned(round) {
+            return None;
+        }
+        if round < self.state.round() {
+            return None;
+        }
+        if action == ProposalAction::PrevoteNil && round == self.state.round() {
+            return self.apply_event(round, sm::Event::ProposalInvalid);
+        }
+        if let Some(state) = self.rounds.entry(round) {
+            state.prevote_nil = true;
+        }
+        None
+    }
+
+    // reject records that the message was dropped, and why.
+    fn reject(&mut self, reason: RejectReason, msg: &Message) {
+        let (kind, signer, height, round) = match msg.header() {
+            Some(header) => header,
+            None => return,
+        };
+        self.rejected.push(Rejected {
+            reason,
+            kind,
+            signer,
+            height,
+            round,
+            at: match self.now {
+                Some(now) => UNIX_EPOCH + now,
+                None => SystemTime::now(),
+            },
+            correlation_id: self.correlation_id,
+            peer: self.peer,
+        });
+    }
+
+    // apply a single verified consensus message against the state
+    // Evidence of equivocation found along the way is added to outputs.
+    fn apply_msg(
+        &mut self,
+        msg: Verified<Message>,
+        outputs: &mut Vec<Output>,
+    ) -> Option<sm::Message> {
+        let msg = msg.into_inner();
+        match &msg {
+            Message::Proposal(p) | Message::ProposalWithPol(p, _) => {
+                let (round, pol_round, value) = (p.msg.round, p.msg.pol_round, p.msg.value);
+                if self.is_halted() {
+                    self.reject(RejectReason::Halted, &msg);
+                    return None;
+                }
+                // a proposal from outside the set isn't buffered
+                if self.validators.get(p.signer).is_none() {
+                    self.reject(RejectReason::UnknownValidator, &msg);
+                    return None;
+                }
+                if !self.within_horizon(&msg, p.signer, p.msg.height, round) {
+                    return None;
+                }
+                if self.expected_proposer(round).is_some_and(|e| e != p.signer) {
+                    self.reject(RejectReason::WrongProposer, &msg);
+                    let action = self.config.invalid_proposal.wrong_proposer;
+                    return self.invalid_proposal(action, round);
+                }
+                // keep the first proposal for the round
+                let state = match self.rounds.entry(round) {
+                    Some(state) => state,
+                    None => {
+                        self.reject(RejectReason::StaleRound, &msg);
+                        return None;
+                    }
+                };
+                if state.proposal.is_some() {
+                    self.reject(RejectReason::DuplicateProposal, &msg);
+                    return None;
+                }
+                let height = p.msg.height;
+                let valid = match &self.value_validator {
+                    Some(validator) => validator.is_valid(height, round, &value),
+                    None => true,
+                };
+                if !valid {
+                    // keep it, unless ignoring it, so the proposer can't
+                    // propose another value in the round
+                    self.reject(RejectReason::InvalidValue, &msg);
+                    let action = self.config.invalid_proposal.invalid_value;
+                    if action != ProposalAction::Ignore {
+                        self.rounds.entry(round)?.proposal = Some(p.clone());
+                    }
+                    return self.invalid_proposal(action, round);
+                }
+                let state = self.rounds.entry(round)?;
+                state.proposal = Some(p.clone());
+                if state.prevote_nil {
+                    return None;
+                }
+                let event = sm::Event::Proposal(pol_round, value);
+                self.apply_event(round, event)
+            }
+            Message::Vote(signed) => self.apply_vote(signed, false, outputs),
+            Message::Heartbeat(h) => {
+                let (round, sequence) = (h.msg.round, h.msg.sequence);
+                if self.is_halted() {
+                    self.reject(RejectReason::Halted, &msg);
+                    return None;
+                }
+                if !self.within_horizon(&msg, h.signer, h.msg.height, round) {
+                    return None;
+                }
+                if self.validators.get(h.signer).is_none() {
+                    self.reject(RejectReason::UnknownValidator, &msg);
+                    return None;
+                }
+                let latest = match self.rounds.entry(round) {
+                    Some(state) => state.heartbeats.entry(h.signer).or_insert(0),
+                    None => {
+                        self.reject(RejectReason::StaleRound, &msg);
+                        return None;
+                    }
+                };
+                if sequence <= *latest {
+                    self.reject(RejectReason::OldHeartbeat, &msg);
+                    return None;
+                }
+                *latest = sequence;
+                None
+            }
+            Message::TimeoutElapsed(t) => {
+                let t = *t;
+                if let Some(state) = self.rounds.rounds.get_mut(&t.round) {
+                    state.timeouts.fired(&t);
+                }
+                if t.is_outdated(&self.state) {
+                    return None;
+                }
+                if t.step == sm::TimeoutStep::Propose && self.extend_propose(&t, outputs) {
+                    return None;
+                }
+                let event = match t.step {
+                    sm::TimeoutStep::Propose => sm::Event::TimeoutPropose,
+                    sm::TimeoutStep::Prevote => sm::Event::TimeoutPrevote,
+                    sm::TimeoutStep::Precommit => sm::Event::TimeoutPrecommit,
+                    sm::TimeoutStep::Commit => return self.next_height(),
+                };
+                self.apply_event(t.round, event)
+            }
+        }
+    }
+
+    // apply a single signed vote against the state. pol is whether
+    // it was carried by a proposal to justify its pol_round.
+    fn apply_vote(
+        &mut self,
+        signed: &Signed<Vote>,
+        pol: bool,
+        outputs: &mut Vec<Output>,
+    ) -> Option<sm::Message> {
+        let msg = || Message::Vote(signed.clone());
+        let (validator, v) = (signed.signer, signed.msg);
+        let weight = match self.validators.get(validator) {
+            Some(val) => val.voting_power,
+            None => {
+                self.reject(RejectReason::UnknownValidator, &msg());
+                return None;
+            }
+        };
+        if let Some(lc) = self.last_commit.as_mut() {
+            if v.height == lc.commit.height {
+                if !lc.add_precommit(signed) {
+                    self.reject(RejectReason::NotForCommit, &msg());
+                }
+                return None;
+            }
+        }
+        if self.is_halted() {
+            self.reject(RejectReason::Halted, &msg());
+            return None;
+        }
+        if !self.within_horizon(&msg(), validator, v.height, v.round) {
+            return None;
+        }
+        // votes from before the late vote window are stale,
+        // unless they justify a proposal
+        if !pol && v.round < self.state.round() - self.config.late_vote_rounds {
+            self.reject(RejectReason::StaleRound, &msg());
+            return None;
+        }
+        let (outcome, event) = match self.rounds.add(signed, weight) {
+            Some(added) => added,
+            None => {
+                // a proof of lock for a round that was pruned
+                self.reject(RejectReason::StaleRound, &msg());
+                return None;
+            }
+        };
+        match outcome.evidence {
+            _ if outcome.vote_accepted => {}
+            Some(evidence) => {
+                self.reject(RejectReason::Equivocation, &msg());
+                outputs.push(Output::Evidence(evidence));
+                return None;
+            }
+            None if pol => return None,
+            None => {
+                self.reject(RejectReason::DuplicateVote, &msg());
+                return None;
+            }
+        }
+        if self.state.step() == sm::Step::Commit {
+            // waiting for timeout commit, unless this was the last precommit
+            if self.skip_timeout_commit() {
+                return self.next_height();
+            }
+            return None;
+        }
+        self.apply_event(v.round, event?)
+    }
+
+    // extend_propose schedules the elapsed timeout propose again after the
+    // config's propose_extension, if parts of the round's proposal arrived
+    // and it wasn't extended yet. Returns true if it was extended.
+    fn extend_propose(&mut self, t: &sm::Timeout, outputs: &mut Vec<Output>) -> bool {
+        let extension = match self.config.propose_extension {
+            Some(extension) => extension,
+            None => return false,
+        };
+        let key = Some((t.height, t.round));
+        if self.proposal_parts != key || self.propose_extended == key {
+            return false;
+        }
+        self.propose_extended = key;
+        let handle = self.scheduler.schedule_after(*t, extension);
+        if let Some(state) = self.rounds.entry(t.round) {
+            state.timeouts.add(*t, handle);
+        }
+        outputs.push(Output::ScheduleTimeout(*t));
+        true
+    }
+
+    // within_horizon returns true if the message is for the current height,
+    // and at most future_rounds rounds ahead. Messages for the next
+    // future_heights heights are buffered until we start them instead,
+    // counted against the peer they came from, or their signer. So are the
+    // current height's while it's held back by max_uncommitted_heights.
+    fn within_horizon(&mut self, msg: &Message, signer: usize, height: i64, round: i64) -> bool {
+        if let Err(reason) = self.check_horizon(height, round) {
+            self.reject(reason, msg);
+            return false;
+        }
+        if height > self.state.height() || self.held_back() {
+            let sender = match self.peer {
+                Some(peer) => Sender::Peer(peer),
+                None => Sender::Signer(signer),
+            };
+            if let Some(evicted) = self.future.add(sender, height, msg.clone()) {
+                self.reject(RejectReason::Evicted, &evicted);
+            }
+            return false;
+        }
+        true
+    }
+
+    // check_horizon returns why a message for the height and round is
+    // outside the horizon, if it is.
+    fn check_horizon(&self, height: i64, round: i64) -> Result<(), RejectReason> {
+        let (current, future_rounds) = (self.state.height(), self.config.future_rounds);
+        let ahead = height - current;
+        if ahead < 0 || ahead > self.config.future_heights {
+            return Err(RejectReason::WrongHeight);
+        }
+        let max_round = if ahead == 0 {
+            self.state.round() + future_rounds
+        } else {
+            future_rounds
+        };
+        if round > max_round {
+            return Err(RejectReason::TooFarAhead);
+        }
+        Ok(())
+    }
+
+    // apply the event, update the state.
+    fn apply_event(&mut self, round: i64, event: sm::Event) -> Option<sm::Message> {
+        let kind = event.kind();
+        let (s, msg) = self.state.apply(round, event);
+        if let Some(path) = self.path.as_mut() {
+            let from = self.state;
+            if msg.is_some() || (from.round(), from.step()) != (s.round(), s.step()) {
+                path.push(sm::PathStep {
+                    height: from.height(),
+                    from_round: from.round(),
+                    from: from.step(),
+                    event: kind,
+                    to_round: s.round(),
+                    to: s.step(),
+                });
+            }
+        }
+        self.state = s;
+        if let Some(state) = self.rounds.entry(s.round()) {
+            state.step = Some(s.step());
+        }
+        self.keep_pol();
+        self.cancel_outdated_timeouts();
+        msg
+    }
+
+    // keep_pol keeps the prevotes for a new valid value, before
+    // their round is pruned, to carry with our re-proposals of it.
+    fn keep_pol(&mut self) {
+        let valid = match self.state.valid() {
+            Some(valid) => valid,
+            None => return,
+        };
+        if self.pol.as_ref().map(|(v, _)| *v) != Some(valid) {
+            let votes = self
+                .rounds
+                .signed_votes(valid.round, VoteType::Prevote, valid.value);
+            self.pol = Some((valid, votes));
+        }
+    }
+
+    // cancel the timeouts for steps we've moved past.
+    fn cancel_outdated_timeouts(&mut self) {
+        let state = self.state;
+        for handle in self.rounds.drain_timeouts_if(|t| t.is_outdated(&state)) {
+            self.scheduler.cancel(handle);
+        }
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InvalidProposalPolicy;
+    use crate::hash::Sha256;
+    use crate::scenario::{proposal, test_engine, validators, vote, TestSigner, TestVerifier};
+    use crate::scheduler::{TimeoutHandle, Timers};
+    use crate::test_utils;
+    use crate::validators::RoundRobin;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    // TestScheduler records the timeouts that are pending.
+    #[derive(Clone, Default)]
+    struct TestScheduler(Rc<RefCell<Vec<(TimeoutHandle, sm::Timeout)>>>, u64);
+
+    impl Scheduler for TestScheduler {
+        fn schedule(&mut self, timeout: sm::Timeout) -> TimeoutHandle {
+            self.1 += 1;
+            let handle = TimeoutHandle(self.1);
+            self.0.borrow_mut().push((handle, timeout));
+            handle
+        }
+
+        fn cancel(&mut self, handle: TimeoutHandle) {
+            self.0.borrow_mut().retain(|(h, _)| *h != handle);
+        }
+    }
+
+    impl TestScheduler {
+        fn pending(&self) -> Vec<sm::Timeout> {
+            self.0.borrow().iter().map(|(_, t)| *t).collect()
+        }
+    }
+
+    // new_engine returns an engine for validator 0 of 4.
+    fn new_engine(height: i64) -> Engine {
+        new_engine_with_scheduler(height, TestScheduler::default())
+    }
+
+    fn new_engine_with_scheduler(height: i64, scheduler: TestScheduler) -> Engine {
+        new_engine_with_config(ConsensusConfig::default(), height, scheduler)
+    }
+
+    fn new_engine_with_config(
+        config: ConsensusConfig,
+        height: i64,
+        scheduler: TestScheduler,
+    ) -> Engine {
+        test_engine(config, height, 4, Box::new(scheduler))
+    }
+
+    #[test]
+    fn late_precommits() {
+        let val = Some(Value::new([1; 32]));
+        let mut engine = new_engine(1);
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert_eq!(engine.height(), 2);
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 3);
+
+        // a late precommit for the decided value completes the commit
+        engine.execute(vote(3, Vote::new_precommit(1, 0, val)));
+        let commit = engine.last_commit().unwrap();
+        assert_eq!(commit.height, 1);
+        assert_eq!(commit.precommits.len(), 4);
+
+        // duplicates and votes for other values or rounds are ignored
+        engine.execute(vote(3, Vote::new_precommit(1, 0, val)));
+        engine.execute(vote(2, Vote::new_precommit(1, 0, None)));
+        engine.execute(vote(1, Vote::new_precommit(1, 1, val)));
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 4);
+    }
+
+    #[test]
+    fn verified_messages() {
+        let val = Some(Value::new([1; 32]));
+        let mut engine = new_engine(1);
+
+        // votes with bad signatures are dropped
+        for i in 0..3 {
+            let mut msg = Signed {
+                signer: i,
+                msg: Vote::new_precommit(1, 0, val),
+                signature: vec![],
+            };
+            engine.execute(Message::Vote(msg.clone()));
+            assert_eq!(engine.height(), 1);
+
+            // unless they were already verified
+            msg.signature = vec![0xff];
+            engine.execute_verified(Verified::new_unchecked(Message::Vote(msg)));
+        }
+        assert_eq!(engine.height(), 2);
+    }
+
+    #[test]
+    fn record_path() {
+        let val = Some(Value::new([1; 32]));
+        let mut engine = new_engine(1);
+        engine.record_path(true);
+        engine.start_round(0, None);
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        let path = engine.take_path();
+        let steps: Vec<_> = path.iter().map(|p| (p.from, p.event, p.to)).collect();
+        use sm::{EventKind as E, Step as S};
+        assert_eq!(
+            steps,
+            vec![
+                (S::NewRound, E::NewRound, S::Propose),
+                (S::Propose, E::PrecommitValue, S::Commit),
+            ]
+        );
+        assert!(sm::path_to_dot(&path).contains("\"0/Propose\" -> \"0/Commit\""));
+        assert!(engine.take_path().is_empty());
+    }
+
+    #[test]
+    fn cancel_timeouts() {
+        let value = Value::new([1; 32]);
+        let val = Some(value);
+        let scheduler = TestScheduler::default();
+        let mut engine = new_engine_with_scheduler(1, scheduler.clone());
+        let timeout = |round, step| sm::Timeout {
+            height: 1,
+            round,
+            step,
+        };
+
+        engine.start_round(0, None);
+        assert_eq!(
+            scheduler.pending(),
+            vec![timeout(0, sm::TimeoutStep::Propose)]
+        );
+
+        // receiving the proposal moves us past the propose step
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+        assert!(scheduler.pending().is_empty());
+
+        // timeout precommit is cancelled once we decide
+        engine.execute(vote(1, Vote::new_precommit(1, 0, None)));
+        engine.execute(vote(2, Vote::new_precommit(1, 0, val)));
+        engine.execute(vote(3, Vote::new_precommit(1, 0, val)));
+        assert_eq!(
+            scheduler.pending(),
+            vec![timeout(0, sm::TimeoutStep::Precommit)]
+        );
+        engine.execute(vote(0, Vote::new_precommit(1, 0, val)));
+        assert_eq!(engine.height(), 2);
+        assert!(scheduler.pending().is_empty());
+    }
+
+    #[test]
+    fn rejected_messages() {
+        let val = Some(Value::new([1; 32]));
+        let config = ConsensusConfig {
+            rejected_log_size: 2,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+
+        engine.execute(Message::Vote(Signed {
+            signer: 0,
+            msg: Vote::new_prevote(1, 0, val),
+            signature: vec![],
+        }));
+        engine.execute(vote(1, Vote::new_prevote(3, 0, val)));
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![RejectReason::InvalidSignature, RejectReason::WrongHeight]
+        );
+        // each call has its own correlation id
+        let ids: Vec<_> = engine.rejected().map(|r| r.correlation_id).collect();
+        assert_eq!(ids, vec![CorrelationId(1), CorrelationId(2)]);
+
+        // the oldest rejection is dropped once the log is full
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        engine.execute(vote(2, Vote::new_precommit(1, 0, None)));
+        let rejected: Vec<_> = engine.rejected().collect();
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].reason, RejectReason::WrongHeight);
+        assert_eq!(rejected[1].reason, RejectReason::NotForCommit);
+        assert_eq!(rejected[1].kind, MessageKind::Vote(VoteType::Precommit));
+        assert_eq!((rejected[1].signer, rejected[1].height), (2, 1));
+        assert_eq!(rejected[1].correlation_id, engine.correlation_id());
+        assert_eq!(rejected[1].peer, None);
+
+        // with the peer it came from, if the caller said
+        engine.execute_from(7, vote(2, Vote::new_precommit(1, 0, None)));
+        assert_eq!(engine.rejected().last().unwrap().peer, Some(7));
+    }
+
+    #[test]
+    fn own_votes_count() {
+        let value = Value::new([1; 32]);
+        let val = Some(value);
+        let mut engine = new_engine(1);
+        let own = |msg| Signed {
+            signer: 0,
+            msg,
+            signature: vec![0],
+        };
+
+        // our proposal is applied back to us, so we prevote for it right away
+        let outputs = engine.start_round(0, Some(value));
+        assert_eq!(
+            outputs,
+            vec![
+                Output::Proposal(Signed {
+                    signer: 0,
+                    msg: Proposal::new(1, 0, value, -1),
+                    signature: vec![0],
+                }),
+                Output::Vote(own(Vote::new_prevote(1, 0, val))),
+            ]
+        );
+        assert!(engine.proposal(0).is_some());
+
+        // with our own prevote, two more make a polka
+        engine.execute(vote(1, Vote::new_prevote(1, 0, val)));
+        let outputs = engine.execute(vote(2, Vote::new_prevote(1, 0, val)));
+        assert_eq!(
+            outputs,
+            vec![Output::Vote(own(Vote::new_precommit(1, 0, val)))]
+        );
+
+        // and with our own precommit, two more decide
+        engine.execute(vote(1, Vote::new_precommit(1, 0, val)));
+        let outputs = engine.execute(vote(2, Vote::new_precommit(1, 0, val)));
+        assert_eq!(
+            outputs,
+            vec![
+                Output::Decision(sm::RoundValue { round: 0, value }),
+                Output::NewRound(0),
+            ]
+        );
+        assert_eq!(engine.height(), 2);
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 3);
+    }
+
+    #[test]
+    fn equivocation() {
+        let (v1, v2) = (Value::new([1; 32]), Value::new([2; 32]));
+        let config = ConsensusConfig {
+            rejected_log_size: 2,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+
+        // the same precommit twice only counts once
+        engine.execute(vote(1, Vote::new_precommit(1, 0, Some(v1))));
+        engine.execute(vote(1, Vote::new_precommit(1, 0, Some(v1))));
+        engine.execute(vote(2, Vote::new_precommit(1, 0, Some(v1))));
+        assert_eq!(engine.height(), 1);
+
+        // a conflicting one is output as evidence, and not counted
+        let outputs = engine.execute(vote(2, Vote::new_precommit(1, 0, Some(v2))));
+        let first = Signed {
+            signer: 2,
+            msg: Vote::new_precommit(1, 0, Some(v1)),
+            signature: vec![2],
+        };
+        let second = Signed {
+            msg: Vote::new_precommit(1, 0, Some(v2)),
+            ..first.clone()
+        };
+        assert_eq!(
+            outputs,
+            vec![Output::Evidence(Evidence::DuplicateVote(first, second))]
+        );
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![RejectReason::DuplicateVote, RejectReason::Equivocation]
+        );
+
+        engine.execute(vote(3, Vote::new_precommit(1, 0, Some(v1))));
+        assert_eq!(engine.height(), 2);
+    }
+
+    #[test]
+    fn halt_height() {
+        let val = Some(Value::new([1; 32]));
+        let config = ConsensusConfig {
+            halt_height: Some(1),
+            rejected_log_size: 1,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        assert!(!engine.is_halted());
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert!(engine.is_halted());
+        assert!(engine.start_round(0, Some(Value::new([2; 32]))).is_empty());
+
+        // the halt height's commit is still completed, but nothing else is taken
+        engine.execute(vote(3, Vote::new_precommit(1, 0, val)));
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 4);
+        engine.execute(vote(1, Vote::new_prevote(2, 0, val)));
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::Halted]);
+        assert_eq!(engine.state().step(), sm::Step::NewRound);
+    }
+
+    #[test]
+    fn recover() {
+        let value = Value::new([1; 32]);
+        let commit = |height, signers| Commit {
+            height,
+            round: 2,
+            value,
+            precommits: (0..signers)
+                .map(|signer| Signed {
+                    signer,
+                    msg: Vote::new_precommit(height, 2, Some(value)),
+                    signature: vec![signer as u8],
+                })
+                .collect(),
+        };
+        let recover = |height, last_commit| {
+            let state = RecoveryState {
+                height,
+                validators: validators(4),
+                last_commit,
+            };
+            Engine::recover(
+                ConsensusConfig::default(),
+                state,
+                Box::new(TestSigner(0)),
+                Box::new(TestVerifier),
+                Box::new(TestScheduler::default()),
+            )
+        };
+
+        assert!(recover(0, None).is_err());
+        assert!(recover(10, Some(commit(8, 3))).is_err());
+        assert!(recover(10, Some(commit(9, 2))).is_err());
+        let mut engine = recover(10, Some(commit(9, 3))).unwrap();
+        assert_eq!(engine.height(), 10);
+
+        // late precommits for the recovered commit are still taken
+        engine.execute(vote(3, Vote::new_precommit(9, 2, Some(value))));
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 4);
+    }
+
+    #[test]
+    fn from_trusted() {
+        let value = Value::new([1; 32]);
+        let commit = |validators: &[usize]| Commit {
+            height: 9,
+            round: 0,
+            value,
+            precommits: validators
+                .iter()
+                .map(|&signer| Signed {
+                    signer,
+                    msg: Vote::new_precommit(9, 0, Some(value)),
+                    signature: vec![signer as u8],
+                })
+                .collect(),
+        };
+        let from_trusted = |validators_hash, last_commit| {
+            let trusted = TrustedState {
+                height: 10,
+                validators_hash,
+                last_commit,
+            };
+            Engine::from_trusted(
+                ConsensusConfig::default(),
+                trusted,
+                validators(4),
+                Box::new(TestSigner(0)),
+                Box::new(TestVerifier),
+                Box::new(TestScheduler::default()),
+            )
+        };
+
+        let hash = validators(4).hash();
+        assert!(from_trusted(validators(3).hash(), commit(&[0, 1, 2])).is_err());
+        assert!(from_trusted(hash, commit(&[0, 1])).is_err());
+        assert!(from_trusted(hash, commit(&[0, 1, 1])).is_err());
+        assert!(from_trusted(hash, commit(&[0, 1, 4])).is_err());
+        let mut forged = commit(&[0, 1, 2]);
+        forged.precommits[2].signature = vec![0];
+        assert!(from_trusted(hash, forged).is_err());
+        let engine = from_trusted(hash, commit(&[0, 1, 2])).unwrap();
+        assert_eq!(engine.height(), 10);
+        assert_eq!(engine.last_commit(), Some(&commit(&[0, 1, 2])));
+    }
+
+    #[test]
+    fn propose_extension() {
+        let config = ConsensusConfig {
+            propose_extension: Some(Duration::from_millis(500)),
+            ..ConsensusConfig::default()
+        };
+        let new_engine = |timers: &Timers| {
+            let mut engine = test_engine(config.clone(), 1, 4, Box::new(timers.clone()));
+            engine.start_round(0, None);
+            engine
+        };
+        let ms = Duration::from_millis;
+        let propose = Message::TimeoutElapsed(sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Propose,
+        });
+
+        // the timeout is extended once, then we prevote nil
+        let timers = Timers::default();
+        let mut engine = new_engine(&timers);
+        engine.proposal_part_received(1, 0);
+        assert_eq!(timers.elapsed(ms(3000)), vec![propose.clone()]);
+        let outputs = engine.execute(propose.clone());
+        assert!(matches!(outputs[..], [Output::ScheduleTimeout(_)]));
+        assert_eq!(engine.state().step(), sm::Step::Propose);
+        assert!(timers.elapsed(ms(3499)).is_empty());
+        assert_eq!(timers.elapsed(ms(3500)), vec![propose.clone()]);
+        engine.execute(propose.clone());
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+
+        // parts of another round's proposal don't extend it
+        let timers = Timers::default();
+        let mut engine = new_engine(&timers);
+        engine.proposal_part_received(1, 1);
+        assert_eq!(timers.elapsed(ms(3000)), vec![propose.clone()]);
+        engine.execute(propose);
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+    }
+
+    #[test]
+    fn upgrade() {
+        let val = Some(Value::new([1; 32]));
+        let app_hash = [7; 32];
+        let config = ConsensusConfig {
+            wait_timeout_commit: true,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        assert!(engine.mark_upgrade(1, app_hash).is_err());
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert!(engine.mark_upgrade(0, app_hash).is_err());
+        assert_eq!(engine.mark_upgrade(1, app_hash), Ok(Vec::new()));
+        assert!(!engine.is_halted());
+
+        // at the end of timeout commit, we halt instead of starting height 2
+        let timeout = sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Commit,
+        };
+        let outputs = engine.execute(Message::TimeoutElapsed(timeout));
+        assert!(engine.is_halted());
+        let package = match &outputs[..] {
+            [Output::Halt(package)] => package.clone(),
+            _ => panic!("unexpected {:?}", outputs),
+        };
+        assert_eq!((package.height, package.app_hash), (1, app_hash));
+        assert_eq!(Some(&package.last_commit), engine.last_commit());
+        assert!(engine.start_round(0, val).is_empty());
+
+        // the replacement starts from the package
+        let replacement = Engine::from_trusted(
+            ConsensusConfig::default(),
+            package.trusted_state(),
+            package.validators.clone(),
+            Box::new(TestSigner(0)),
+            Box::new(TestVerifier),
+            Box::new(TestScheduler::default()),
+        )
+        .unwrap();
+        assert_eq!(replacement.height(), 2);
+
+        // without timeout commit, we're already at the next height, so the
+        // upgrade must be expected before we decide
+        let mut engine = new_engine(1);
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert!(engine.mark_upgrade(1, app_hash).is_err());
+
+        let mut engine = new_engine(1);
+        assert!(engine.expect_upgrade(0).is_err());
+        assert_eq!(engine.expect_upgrade(1), Ok(()));
+        let value = Value::new([1; 32]);
+        engine.start_round(0, None);
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        let mut outputs = Vec::new();
+        for i in 1..4 {
+            outputs.extend(engine.execute(vote(i, Vote::new_precommit(1, 0, val))));
+        }
+        // nothing is proposed or voted for at height 2
+        assert!(engine.is_halted());
+        let decided = sm::RoundValue { round: 0, value };
+        assert_eq!(outputs, vec![Output::Decision(decided)]);
+        let outputs = engine.mark_upgrade(1, app_hash).unwrap();
+        assert!(matches!(outputs[..], [Output::Halt(_)]));
+    }
+
+    #[test]
+    fn shutdown() {
+        let scheduler = TestScheduler::default();
+        let mut engine = new_engine_with_scheduler(1, scheduler.clone());
+        let value = Value::new([1; 32]);
+        engine.start_round(0, None);
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        engine.execute(vote(1, Vote::new_prevote(1, 0, None)));
+        engine.execute(vote(2, Vote::new_prevote(1, 0, None)));
+        assert_eq!(scheduler.pending().len(), 1);
+
+        let snapshot = engine.shutdown();
+        assert!(scheduler.pending().is_empty());
+        let prevote = Signed {
+            signer: 0,
+            msg: Vote::new_prevote(1, 0, Some(value)),
+            signature: vec![0],
+        };
+        assert_eq!(
+            snapshot,
+            RoundStateSnapshot {
+                height: 1,
+                round: 0,
+                step: sm::Step::Prevote,
+                locked: None,
+                valid: None,
+                own_proposal: None,
+                own_votes: vec![prevote],
+                last_commit: None,
+            }
+        );
+    }
+
+    #[test]
+    fn future_messages() {
+        let config = ConsensusConfig {
+            future_rounds: 2,
+            max_future_per_peer: 2,
+            rejected_log_size: 8,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        let v = Value::new([1; 32]);
+        let value = Some(v);
+        engine.start_round(0, None);
+        engine.execute(vote(1, Vote::new_prevote(1, 3, None)));
+
+        // messages for the next height are buffered, up to 2 per validator
+        engine.execute(vote(1, Vote::new_prevote(2, 0, None)));
+        engine.execute(vote(1, Vote::new_precommit(2, 0, value)));
+        engine.execute(vote(1, Vote::new_prevote(2, 1, value)));
+        engine.execute(vote(2, Vote::new_prevote(2, 3, value)));
+        engine.execute(vote(2, Vote::new_prevote(2, 0, value)));
+        engine.execute(proposal(3, Proposal::new(2, 0, v, -1)));
+        assert_eq!(engine.future_evictions(), 1);
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        use RejectReason::*;
+        assert_eq!(reasons, vec![TooFarAhead, Evicted, TooFarAhead]);
+
+        // and applied once we start it
+        for i in 1..4 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, value)));
+        }
+        assert_eq!(engine.height(), 2);
+        let outputs = engine.start_round(0, None);
+        let prevote = Vote::new_prevote(2, 0, value);
+        assert!(outputs.contains(&Output::Vote(Signed {
+            signer: 0,
+            msg: prevote,
+            signature: vec![0],
+        })));
+        assert_eq!(engine.proposal(0).unwrap().signer, 3);
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+    }
+
+    #[test]
+    fn future_senders() {
+        let config = ConsensusConfig {
+            max_future_per_peer: 1,
+            rejected_log_size: 8,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        let v = Value::new([1; 32]);
+        let value = Some(v);
+        engine.start_round(0, None);
+
+        // a peer's messages count against it, whatever their signers
+        engine.execute_from(7, vote(1, Vote::new_prevote(2, 0, value)));
+        engine.execute_from(7, vote(2, Vote::new_prevote(2, 0, value)));
+        engine.execute_from(8, vote(3, Vote::new_prevote(2, 0, value)));
+        assert_eq!(engine.future_evictions(), 1);
+
+        // proposals from outside the set aren't buffered
+        engine.execute(proposal(9, Proposal::new(2, 0, v, -1)));
+
+        // buffered messages are verified again once we start the height
+        let forged = Message::Vote(Signed {
+            signer: 1,
+            msg: Vote::new_precommit(2, 0, value),
+            signature: vec![9],
+        });
+        engine.execute_verified(Verified::new_unchecked(forged));
+        for i in 1..4 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, value)));
+        }
+        engine.start_round(0, None);
+        assert_eq!(engine.round_votes()[0].prevotes.votes.len(), 2);
+        assert_eq!(engine.round_votes()[0].precommits.votes.len(), 0);
+
+        let rejected: Vec<_> = engine.rejected().map(|r| (r.reason, r.peer)).collect();
+        use RejectReason::*;
+        assert_eq!(
+            rejected,
+            vec![
+                (Evicted, Some(7)),
+                (UnknownValidator, None),
+                (InvalidSignature, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn work_limit() {
+        let config = ConsensusConfig {
+            rejected_log_size: 1,
+            max_messages_per_call: 1,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+
+        // our proposal and the prevote it leads to were output, so they're
+        // both applied, whatever the limit
+        let value = Some(Value::new([1; 32]));
+        let outputs = engine.start_round(0, value);
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+        assert_eq!(engine.rejected().count(), 0);
+        engine.execute(vote(1, Vote::new_prevote(1, 0, value)));
+        let outputs = engine.execute(vote(2, Vote::new_prevote(1, 0, value)));
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(engine.state().step(), sm::Step::Precommit);
+        let precommits = engine.round_votes()[0].precommits.votes.len();
+        assert_eq!(precommits, 1);
+        assert_eq!(engine.rejected().count(), 0);
+    }
+
+    #[test]
+    fn update_params() {
+        let val = Some(Value::new([1; 32]));
+        let mut engine = new_engine(1);
+        let params = ConsensusParams {
+            timeout_propose: Duration::from_secs(5),
+            ..ConsensusParams::default()
+        };
+
+        let mut bad = params.clone();
+        bad.evidence.max_age_heights = 0;
+        assert!(engine.update_params(bad).is_err());
+        assert!(engine.update_params(params.clone()).is_ok());
+        assert_eq!(engine.params(), &ConsensusParams::default());
+
+        // the new params take effect at the next height
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert_eq!(engine.height(), 2);
+        assert_eq!(engine.params(), &params);
+    }
+
+    #[test]
+    fn key_rotation() {
+        let vals = test_utils::validator_set(&[1, 1, 1, 1]);
+        let signer = Box::new(test_utils::KeySigner::new(&vals, 0));
+        let verifier = Box::new(test_utils::KeyVerifier(vals.clone()));
+        let scheduler = Box::new(TestScheduler::default());
+        let config = ConsensusConfig::default();
+        let mut engine = Engine::new(config, 1, vals.clone(), signer, verifier, scheduler).unwrap();
+        let val = Some(Value::new([1; 32]));
+        let old = test_utils::KeySigner::new(&vals, 1);
+        let new = test_utils::KeySigner::with_key(1, test_utils::key(10));
+        let precommit = |signer: &test_utils::KeySigner, height| {
+            let msg = Vote::new_precommit(height, 0, val);
+            let signature = signer.sign(&msg.sign_bytes());
+            Message::Vote(Signed {
+                signer: 1,
+                msg,
+                signature,
+            })
+        };
+
+        // validator 1 rotates its key from height 2, as the application
+        // tells us in its response to committing height 1
+        let mut rotated = vals.clone();
+        let address = vals.get(1).unwrap().address::<Sha256>();
+        assert!(rotated.rotate_key(&address, 2, test_utils::key(10)));
+        let verifier = Box::new(test_utils::KeyVerifier(rotated.clone()));
+        engine.update_validators(rotated.clone(), verifier).unwrap();
+
+        // the old key is accepted until the activation height, the new one
+        // from then on
+        for (height, bad, good) in [(1, &new, &old), (2, &old, &new)] {
+            assert_eq!(engine.height(), height);
+            engine.start_round(0, None);
+            for i in 2..4 {
+                engine.execute(Message::Vote(test_utils::precommit(
+                    &rotated, i, height, 0, val,
+                )));
+            }
+            engine.execute(precommit(bad, height));
+            assert_eq!(engine.height(), height);
+            engine.execute(precommit(good, height));
+            assert_eq!(engine.height(), height + 1);
+        }
+        assert_eq!(engine.validators(), &rotated);
+    }
+
+    #[test]
+    fn commit_back_pressure() {
+        let config = ConsensusConfig {
+            max_uncommitted_heights: Some(1),
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        let val = Some(Value::new([1; 32]));
+        assert!(!engine.start_round(0, None).is_empty());
+        for i in 0..3 {
+            engine.execute(vote(i, Vote::new_precommit(1, 0, val)));
+        }
+        assert_eq!((engine.height(), engine.uncommitted()), (2, 1));
+
+        // height 2 waits for the application to commit height 1, and so
+        // do its messages
+        assert!(engine.start_round(0, None).is_empty());
+        assert_eq!(engine.state().step(), sm::Step::NewRound);
+        let value = Value::new([2; 32]);
+        assert!(engine
+            .execute(proposal(1, Proposal::new(2, 0, value, -1)))
+            .is_empty());
+        assert!(engine.proposal(0).is_none());
+        assert!(engine.commit_done(0).is_empty());
+        let outputs = engine.commit_done(1);
+        assert_eq!(engine.uncommitted(), 0);
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+        assert_eq!(engine.proposal(0).unwrap().msg.value, value);
+        let prevote = Vote::new_prevote(2, 0, Some(value));
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, Output::Vote(v) if v.msg == prevote)));
+        assert!(engine.commit_done(1).is_empty());
+    }
+
+    #[test]
+    fn config_validation() {
+        let new_engine = |config: ConsensusConfig| {
+            let (signer, verifier) = (Box::new(TestSigner(0)), Box::new(TestVerifier));
+            let scheduler = Box::new(TestScheduler::default());
+            Engine::new(config, 1, validators(4), signer, verifier, scheduler)
+        };
+        assert!(new_engine(ConsensusConfig::default()).is_ok());
+
+        // no height past the first would ever start
+        for k in [0, -1] {
+            let config = ConsensusConfig {
+                max_uncommitted_heights: Some(k),
+                ..ConsensusConfig::default()
+            };
+            assert!(config.validate().is_err());
+            assert!(new_engine(config).is_err());
+        }
+        let config = ConsensusConfig {
+            max_uncommitted_heights: Some(1),
+            ..ConsensusConfig::default()
+        };
+        assert!(new_engine(config).is_ok());
+
+        // every message from a peer would be dropped, or none kept ahead
+        let configs = [
+            ConsensusConfig {
+                max_messages_per_call: 0,
+                ..ConsensusConfig::default()
+            },
+            ConsensusConfig {
+                future_rounds: -1,
+                ..ConsensusConfig::default()
+            },
+            ConsensusConfig {
+                future_heights: -1,
+                ..ConsensusConfig::default()
+            },
+        ];
+        for config in configs {
+            assert!(new_engine(config).is_err());
+        }
+        let config = ConsensusConfig {
+            future_rounds: 0,
+            future_heights: 0,
+            ..ConsensusConfig::default()
+        };
+        assert!(new_engine(config).is_ok());
+    }
+
+    #[test]
+    fn step_driven() {
+        let new_engine = || {
+            let config = ConsensusConfig {
+                rejected_log_size: 4,
+                ..ConsensusConfig::default()
+            };
+            let signer = Box::new(TestSigner(0));
+            Engine::new_stepped(config, 1, validators(4), signer, Box::new(TestVerifier)).unwrap()
+        };
+        let ms = Duration::from_millis;
+        let inputs = vec![
+            (Input::StartRound(0, None), ms(0)),
+            (Input::Tick, ms(2999)),
+            (
+                Input::Message(vote(9, Vote::new_prevote(1, 0, None))),
+                ms(3000),
+            ),
+            (
+                Input::Message(vote(1, Vote::new_prevote(1, 0, None))),
+                ms(3100),
+            ),
+        ];
+
+        let mut engine = new_engine();
+        let mut outputs = Vec::new();
+        for (input, now) in inputs.clone() {
+            outputs.push(engine.step(input, now));
+        }
+        let propose = sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Propose,
+        };
+        assert_eq!(outputs[0], vec![Output::ScheduleTimeout(propose)]);
+        assert!(outputs[1].is_empty());
+
+        // timeout propose is due by the third input, and fires before it
+        let prevote = Vote::new_prevote(1, 0, None);
+        assert!(matches!(&outputs[2][0], Output::Vote(v) if v.msg == prevote));
+        assert_eq!(engine.state().step(), sm::Step::Prevote);
+        let rejected = engine.rejected().next().unwrap();
+        assert_eq!(rejected.reason, RejectReason::UnknownValidator);
+        assert_eq!(rejected.at, UNIX_EPOCH + ms(3000));
+        assert_eq!(engine.next_deadline(), None);
+
+        // the same inputs at the same times give the same outputs
+        let mut replay = new_engine();
+        let replayed: Vec<_> = inputs
+            .into_iter()
+            .map(|(input, now)| replay.step(input, now))
+            .collect();
+        assert_eq!(replayed, outputs);
+    }
+
+    #[test]
+    fn sign_state() {
+        let config = ConsensusConfig {
+            rejected_log_size: 4,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config.clone(), 1, TestScheduler::default());
+        let ms = Duration::from_millis;
+        let propose = sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Propose,
+        };
+        let wal = vec![
+            WalEntry {
+                height: 1,
+                at: ms(0),
+                input: Input::StartRound(0, None),
+            },
+            WalEntry {
+                height: 1,
+                at: ms(3000),
+                input: Input::Timeout(propose),
+            },
+        ];
+        let mut outputs = Vec::new();
+        for entry in &wal {
+            outputs.extend(engine.step(entry.input.clone(), entry.at));
+        }
+        let state = engine.sign_state().unwrap().clone();
+        assert_eq!((state.height, state.round), (1, 0));
+        assert_eq!(state.step, SignStep::Prevote);
+
+        // replayed from the WAL, the prevote is signed again as it was
+        let mut restarted = new_engine_with_config(config.clone(), 1, TestScheduler::default());
+        restarted.restore_sign_state(state.clone());
+        assert_eq!(restarted.replay(&wal), outputs);
+        assert_eq!(restarted.sign_state(), Some(&state));
+        assert_eq!(restarted.state().step(), sm::Step::Prevote);
+
+        // what was signed before the sign state isn't signed again
+        let later = SignState {
+            step: SignStep::Precommit,
+            ..state.clone()
+        };
+        let mut restarted = new_engine_with_config(config.clone(), 1, TestScheduler::default());
+        restarted.restore_sign_state(later.clone());
+        let replayed = restarted.replay(&wal);
+        assert!(!replayed.iter().any(|o| matches!(o, Output::Vote(_))));
+        assert_eq!(restarted.rejected().count(), 0);
+        assert_eq!(restarted.sign_state(), Some(&later));
+
+        // with the timeout lost from the WAL, the proposal that arrives
+        // instead doesn't get us to prevote for it too
+        let mut restarted = new_engine_with_config(config, 1, TestScheduler::default());
+        restarted.restore_sign_state(state.clone());
+        restarted.replay(&wal[..1]);
+        let value = Value::new([1; 32]);
+        let outputs = restarted.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        assert!(!outputs.iter().any(|o| matches!(o, Output::Vote(_))));
+        let rejected = restarted.rejected().next().unwrap();
+        assert_eq!(rejected.reason, RejectReason::ConflictingSign);
+        assert_eq!(restarted.sign_state(), Some(&state));
+    }
+
+    #[test]
+    fn proposals() {
+        let config = ConsensusConfig {
+            rejected_log_size: 4,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        let first = Proposal::new(1, 0, Value::new([1; 32]), -1);
+        let second = Proposal::new(1, 0, Value::new([2; 32]), -1);
+
+        engine.execute(proposal(9, first.clone()));
+        engine.execute(proposal(1, first.clone()));
+        engine.execute(proposal(2, second));
+        assert_eq!(engine.proposal(0).unwrap().msg, first);
+        assert_eq!(engine.proposal(0).unwrap().signer, 1);
+        assert!(engine.proposal(1).is_none());
+
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                RejectReason::UnknownValidator,
+                RejectReason::DuplicateProposal
+            ]
+        );
+    }
+
+    #[test]
+    fn reconfigure() {
+        let timers = Timers::default();
+        let config = ConsensusConfig {
+            rejected_log_size: 4,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = test_engine(config, 1, 4, Box::new(timers.clone()));
+        let ms = Duration::from_millis;
+        engine.start_round(0, None);
+        assert_eq!(timers.next_deadline(), Some(ms(3000)));
+
+        let bad = Reconfig {
+            timeout_prevote: Some(ms(0)),
+            ..Reconfig::default()
+        };
+        assert!(engine.reconfigure(bad).is_err());
+        let reconfig = Reconfig {
+            timeout_propose: Some(ms(500)),
+            ..Reconfig::default()
+        };
+        engine.reconfigure(reconfig).unwrap();
+        let reconfig = Reconfig {
+            future_rounds: Some(1),
+            ..Reconfig::default()
+        };
+        engine.reconfigure(reconfig).unwrap();
+
+        // nothing changes until the next round
+        assert_eq!(engine.params().timeout_propose, ms(3000));
+        engine.execute(vote(1, Vote::new_prevote(1, 5, None)));
+        assert_eq!(engine.rejected().count(), 0);
+        for signer in 1..4 {
+            engine.execute(vote(signer, Vote::new_precommit(1, 0, None)));
+        }
+        for msg in timers.elapsed(ms(1000)) {
+            engine.execute(msg);
+        }
+        assert_eq!(engine.state().round(), 1);
+        assert_eq!(engine.params().timeout_propose, ms(500));
+        engine.start_round(1, None);
+        assert_eq!(timers.next_deadline(), Some(ms(1500)));
+        engine.execute(vote(1, Vote::new_prevote(1, 3, None)));
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::TooFarAhead]);
+
+        // the operator's timeouts are kept over the application's params
+        let params = ConsensusParams {
+            timeout_prevote: ms(2000),
+            ..ConsensusParams::default()
+        };
+        engine.update_params(params).unwrap();
+        let reconfig = Reconfig {
+            timeout_precommit: Some(ms(700)),
+            ..Reconfig::default()
+        };
+        engine.reconfigure(reconfig).unwrap();
+        for signer in 1..4 {
+            let value = Some(Value::new([1; 32]));
+            engine.execute(vote(signer, Vote::new_precommit(1, 1, value)));
+        }
+        assert_eq!(engine.height(), 2);
+        assert_eq!(engine.params().timeout_propose, ms(500));
+        assert_eq!(engine.params().timeout_prevote, ms(2000));
+
+        // and changes made before deciding apply from round 0 of the next height
+        assert_eq!(engine.params().timeout_precommit, ms(700));
+    }
+
+    #[test]
+    fn proposal_prefilter() {
+        let config = ConsensusConfig {
+            rejected_log_size: 8,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        assert_eq!(engine.expected_proposer(0), None);
+        engine.set_proposer_selector(Box::new(RoundRobin));
+        engine.start_round(0, None);
+        assert_eq!(engine.expected_proposer(0), Some(1));
+        assert_eq!(engine.expected_proposer(1), Some(2));
+        let header = |signer, height, round| Signed {
+            signer,
+            msg: Proposal::new(height, round, Value::new([1; 32]), -1),
+            signature: vec![signer as u8],
+        };
+
+        assert!(engine.prefilter_proposal(&header(1, 1, 0)));
+        assert!(engine.prefilter_proposal(&header(2, 1, 1)));
+        // the proposer of a future height isn't checked
+        assert!(engine.prefilter_proposal(&header(3, 2, 0)));
+        assert!(!engine.prefilter_proposal(&header(2, 1, 0)));
+        assert!(!engine.prefilter_proposal(&header(1, 3, 0)));
+        assert!(!engine.prefilter_proposal(&header(1, 1, 50)));
+        assert!(!engine.prefilter_proposal(&header(9, 1, 0)));
+
+        // executed proposals from another validator are rejected too
+        engine.execute(Message::Proposal(header(2, 1, 0)));
+        assert!(engine.proposal(0).is_none());
+        engine.execute(Message::Proposal(header(1, 1, 0)));
+        assert_eq!(engine.proposal(0).unwrap().signer, 1);
+        assert!(!engine.prefilter_proposal(&header(1, 1, 0)));
+
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                RejectReason::WrongProposer,
+                RejectReason::WrongHeight,
+                RejectReason::TooFarAhead,
+                RejectReason::UnknownValidator,
+                RejectReason::WrongProposer,
+                RejectReason::DuplicateProposal,
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_proposal_policy() {
+        // RejectValue rejects values of all 9s.
+        struct RejectValue;
+
+        impl ValueValidator for RejectValue {
+            fn is_valid(&self, _height: i64, _round: i64, value: &Value) -> bool {
+                *value != Value::new([9; 32])
+            }
+        }
+
+        let (good, bad) = (Value::new([1; 32]), Value::new([9; 32]));
+        let new_engine = |policy| {
+            let config = ConsensusConfig {
+                rejected_log_size: 8,
+                invalid_proposal: policy,
+                ..ConsensusConfig::default()
+            };
+            let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+            engine.set_proposer_selector(Box::new(RoundRobin));
+            engine.set_value_validator(Box::new(RejectValue));
+            engine.start_round(0, None);
+            engine
+        };
+        let prevotes = |outputs: Vec<Output>| -> Vec<Vote> {
+            let votes = outputs.into_iter().filter_map(|o| match o {
+                Output::Vote(v) => Some(v.msg),
+                _ => None,
+            });
+            votes.collect()
+        };
+        let nil = vec![Vote::new_prevote(1, 0, None)];
+        let for_good = vec![Vote::new_prevote(1, 0, Some(good))];
+        // validator 1 proposes in round 0
+        let from = |signer, value| proposal(signer, Proposal::new(1, 0, value, -1));
+        let forge = |msg| match msg {
+            Message::Proposal(p) => Message::Proposal(Signed {
+                signature: vec![7],
+                ..p
+            }),
+            _ => unreachable!(),
+        };
+        let forged = || forge(from(1, good));
+        let timeout_propose = Message::TimeoutElapsed(sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Propose,
+        });
+
+        // by default, an invalid value from the proposer is prevoted nil
+        // right away, and it's kept as the round's proposal
+        let mut engine = new_engine(InvalidProposalPolicy::default());
+        assert_eq!(prevotes(engine.execute(from(1, bad))), nil);
+        assert_eq!(engine.proposal(0).unwrap().msg.value, bad);
+
+        // while forged proposals, and ones from another validator, are
+        // ignored, so the proposer's may still be prevoted for
+        let mut engine = new_engine(InvalidProposalPolicy::default());
+        assert!(prevotes(engine.execute(forged())).is_empty());
+        assert!(prevotes(engine.execute(from(2, good))).is_empty());
+        assert_eq!(prevotes(engine.execute(from(1, good))), for_good);
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![RejectReason::InvalidSignature, RejectReason::WrongProposer]
+        );
+
+        // each may be prevoted nil right away instead
+        let prevote_nil = InvalidProposalPolicy {
+            invalid_value: ProposalAction::PrevoteNil,
+            invalid_signature: ProposalAction::PrevoteNil,
+            wrong_proposer: ProposalAction::PrevoteNil,
+        };
+        for msg in [forged(), from(2, good)] {
+            let mut engine = new_engine(prevote_nil.clone());
+            assert_eq!(prevotes(engine.execute(msg)), nil);
+            assert!(prevotes(engine.execute(from(1, good))).is_empty());
+        }
+
+        // or prevoted nil at timeout propose, even once a good proposal is in
+        let wait = InvalidProposalPolicy {
+            invalid_value: ProposalAction::WaitForTimeout,
+            invalid_signature: ProposalAction::WaitForTimeout,
+            wrong_proposer: ProposalAction::WaitForTimeout,
+        };
+        for msg in [forged(), from(2, good)] {
+            let mut engine = new_engine(wait.clone());
+            assert!(prevotes(engine.execute(msg)).is_empty());
+            assert!(prevotes(engine.execute(from(1, good))).is_empty());
+            assert_eq!(prevotes(engine.execute(timeout_propose.clone())), nil);
+        }
+        let mut engine = new_engine(wait);
+        assert!(prevotes(engine.execute(from(1, bad))).is_empty());
+        assert_eq!(prevotes(engine.execute(timeout_propose.clone())), nil);
+
+        // or ignored, which doesn't keep an invalid value as the proposal
+        let ignore = InvalidProposalPolicy {
+            invalid_value: ProposalAction::Ignore,
+            ..InvalidProposalPolicy::default()
+        };
+        let mut engine = new_engine(ignore);
+        assert!(prevotes(engine.execute(from(1, bad))).is_empty());
+        assert!(engine.proposal(0).is_none());
+        assert_eq!(prevotes(engine.execute(from(1, good))), for_good);
+
+        // a forged proposal for another height doesn't count, nor does
+        // one that wouldn't pass the other checks if it were signed
+        let mut engine = new_engine(prevote_nil.clone());
+        let other_height = proposal(1, Proposal::new(2, 0, good, -1));
+        assert!(prevotes(engine.execute(forge(other_height))).is_empty());
+        assert!(prevotes(engine.execute(forge(from(2, good)))).is_empty());
+        assert!(prevotes(engine.execute(forge(from(9, good)))).is_empty());
+        assert_eq!(prevotes(engine.execute(from(1, good))), for_good);
+
+        // nor does the proposer's own proposal with prevotes that don't
+        // verify, as anyone may have relayed them
+        let mut engine = new_engine(prevote_nil);
+        let (p, bad_vote) = match (from(1, good), vote(2, Vote::new_prevote(1, 0, None))) {
+            (Message::Proposal(p), Message::Vote(v)) => (
+                p,
+                Signed {
+                    signature: vec![7],
+                    ..v
+                },
+            ),
+            _ => unreachable!(),
+        };
+        let with_pol = Message::ProposalWithPol(p, vec![bad_vote]);
+        assert!(prevotes(engine.execute(with_pol)).is_empty());
+        let reason = engine.rejected().last().unwrap().reason;
+        assert_eq!(reason, RejectReason::InvalidPol);
+        assert_eq!(prevotes(engine.execute(from(1, good))), for_good);
+    }
+
+    #[test]
+    fn round_state() {
+        let config = ConsensusConfig {
+            late_vote_rounds: 0,
+            rejected_log_size: 4,
+            ..ConsensusConfig::default()
+        };
+        let scheduler = TestScheduler::default();
+        let mut engine = new_engine_with_config(config, 1, scheduler.clone());
+        let value = Value::new([1; 32]);
+        engine.start_round(0, None);
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        for signer in 1..3 {
+            engine.execute(vote(signer, Vote::new_prevote(1, 0, Some(value))));
+        }
+        for signer in 1..3 {
+            engine.execute(vote(signer, Vote::new_precommit(1, 0, None)));
+        }
+        assert_eq!(
+            engine.rounds(),
+            vec![RoundSummary {
+                round: 0,
+                has_proposal: true,
+                thresholds: vec![
+                    (VoteType::Prevote, Thresh::Value(value)),
+                    (VoteType::Precommit, Thresh::Any)
+                ],
+                step: Some(sm::Step::Precommit),
+                heartbeats: vec![],
+            }]
+        );
+
+        // moving on to round 1 prunes round 0, but its proposal
+        let precommit = sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Precommit,
+        };
+        assert_eq!(scheduler.pending().last(), Some(&precommit));
+        engine.execute(Message::TimeoutElapsed(precommit));
+        let rounds = engine.rounds();
+        assert_eq!(rounds.len(), 1);
+        assert_eq!(
+            (rounds[0].round, rounds[0].step),
+            (1, Some(sm::Step::NewRound))
+        );
+        assert_eq!(engine.proposal(0).unwrap().msg.value, value);
+
+        // a proof of lock doesn't start the pruned round again
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        let signed = |signer, msg| Signed {
+            signer,
+            msg,
+            signature: vec![signer as u8],
+        };
+        let pol = (1..4)
+            .map(|i| signed(i, Vote::new_prevote(1, 0, Some(value))))
+            .collect();
+        let reproposal = Signed {
+            signer: 2,
+            msg: Proposal::new(1, 1, value, 0),
+            signature: vec![2],
+        };
+        engine.execute(Message::ProposalWithPol(reproposal, pol));
+        assert!(engine.rounds().iter().all(|r| r.round == 1));
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::StaleRound; 4]);
+    }
+
+    #[test]
+    fn heartbeats() {
+        let config = ConsensusConfig {
+            rejected_log_size: 4,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = new_engine_with_config(config, 1, TestScheduler::default());
+        let heartbeat = |signer, sequence| {
+            let signature = vec![signer as u8];
+            Message::Heartbeat(Signed {
+                signer,
+                msg: Heartbeat::new(1, 0, sequence),
+                signature,
+            })
+        };
+        engine.start_round(0, None);
+        assert_eq!(engine.silent(0), vec![0, 1, 2, 3]);
+
+        let own = engine.heartbeat().unwrap();
+        assert_eq!((own.signer, own.msg.sequence()), (0, 1));
+        assert_eq!(engine.heartbeat().unwrap().msg.sequence(), 2);
+        engine.execute(heartbeat(1, 1));
+        engine.execute(heartbeat(1, 1));
+        engine.execute(vote(2, Vote::new_prevote(1, 0, None)));
+        assert_eq!(engine.silent(0), vec![3]);
+        assert_eq!(engine.silent(1), vec![0, 1, 2, 3]);
+        assert_eq!(engine.rounds()[0].heartbeats, vec![0, 1]);
+
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::OldHeartbeat]);
+        let rejected = engine.rejected().next().unwrap();
+        assert_eq!(rejected.kind, MessageKind::Heartbeat);
+    }
+
+    #[test]
+    fn repropose_valid_value() {
+        let value = Value::new([1; 32]);
+        // at height 3, we propose rounds 1 and 5
+        let mut engine = new_engine(3);
+        engine.set_proposer_selector(Box::new(RoundRobin));
+        let proposed = |outputs: Vec<Output>| {
+            outputs.into_iter().find_map(|o| match o {
+                Output::Proposal(p) => Some(p.msg),
+                _ => None,
+            })
+        };
+        let polka = |engine: &mut Engine, round| {
+            for signer in 1..3 {
+                engine.execute(vote(signer, Vote::new_prevote(3, round, Some(value))));
+            }
+        };
+        let end_round = |engine: &mut Engine, round| {
+            for signer in 1..4 {
+                engine.execute(vote(signer, Vote::new_precommit(3, round, None)));
+            }
+            engine.execute(Message::TimeoutElapsed(sm::Timeout {
+                height: 3,
+                round,
+                step: sm::TimeoutStep::Precommit,
+            }));
+        };
+        let valid = |round| Some(sm::RoundValue { round, value });
+
+        // a polka in round 0 makes the value valid
+        assert_eq!(proposed(engine.start_round(0, None)), None);
+        engine.execute(proposal(3, Proposal::new(3, 0, value, -1)));
+        polka(&mut engine, 0);
+        assert_eq!(engine.state().valid(), valid(0));
+        end_round(&mut engine, 0);
+
+        // we re-propose it in round 1 without a new value
+        let outputs = engine.start_round(1, None);
+        assert_eq!(proposed(outputs), Some(Proposal::new(3, 1, value, 0)));
+
+        // a polka in round 1 moves the valid round, which round 5 carries
+        polka(&mut engine, 1);
+        assert_eq!(engine.state().valid(), valid(1));
+        for round in 1..5 {
+            end_round(&mut engine, round);
+        }
+        assert_eq!(engine.state().round(), 5);
+        let outputs = engine.start_round(5, None);
+        assert_eq!(proposed(outputs), Some(Proposal::new(3, 5, value, 1)));
+    }
+
+    #[test]
+    fn proposal_with_pol() {
+        let value = Value::new([1; 32]);
+
+        // we keep the polka for our valid value to re-propose it with
+        let mut engine = new_engine(1);
+        engine.start_round(0, None);
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        engine.execute(vote(1, Vote::new_prevote(1, 0, Some(value))));
+        engine.execute(vote(2, Vote::new_prevote(1, 0, Some(value))));
+        let reproposal = Proposal::new(1, 1, value, 0);
+        let pol = engine.pol_votes(&reproposal);
+        let signers: Vec<_> = pol.iter().map(|v| v.signer).collect();
+        assert_eq!(signers, vec![0, 1, 2]);
+        assert!(engine.pol_votes(&Proposal::new(1, 2, value, 1)).is_empty());
+
+        // a validator that missed round 0 needs a polka for the re-proposal
+        use sm::TimeoutStep::*;
+        let vals = test_utils::validator_set(&[1, 1, 1, 1]);
+        let new_engine = |max_messages_per_call| {
+            let config = ConsensusConfig {
+                rejected_log_size: 4,
+                max_messages_per_call,
+                ..ConsensusConfig::default()
+            };
+            let signer = Box::new(test_utils::KeySigner::new(&vals, 0));
+            let verifier = Box::new(test_utils::KeyVerifier(vals.clone()));
+            let scheduler = Box::new(TestScheduler::default());
+            let mut engine =
+                Engine::new(config, 1, vals.clone(), signer, verifier, scheduler).unwrap();
+            engine.start_round(0, None);
+            for step in [Propose, Prevote, Precommit].iter().cloned() {
+                let timeout = sm::Timeout {
+                    height: 1,
+                    round: 0,
+                    step,
+                };
+                engine.execute(Message::TimeoutElapsed(timeout));
+            }
+            engine.start_round(1, None);
+            engine
+        };
+        let mut engine = new_engine(16);
+        let sign = |i, msg| test_utils::sign(&vals, i, msg);
+        let pol: Vec<_> = (1..4)
+            .map(|i| sign(i, Vote::new_prevote(1, 0, Some(value))))
+            .collect();
+        let outputs = engine.execute(Message::ProposalWithPol(
+            test_utils::sign(&vals, 2, reproposal.clone()),
+            pol[..2].to_vec(),
+        ));
+        assert!(outputs.is_empty());
+        assert!(engine.proposal(1).is_none());
+
+        let with_pol = Message::ProposalWithPol(test_utils::sign(&vals, 2, reproposal), pol);
+        let outputs = engine.execute(with_pol.clone());
+        let prevote = Vote::new_prevote(1, 1, Some(value));
+        let ours = test_utils::sign(&vals, 0, prevote);
+        assert_eq!(outputs, vec![Output::Vote(ours.clone())]);
+        let valid = sm::RoundValue { round: 0, value };
+        assert_eq!(engine.state().valid(), Some(valid));
+
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::InvalidPol]);
+
+        // the prevotes count towards max_messages_per_call: the ones
+        // over it are rejected, but the proposal still is applied, as the
+        // prevotes carried for it are a quorum
+        let mut engine = new_engine(3);
+        let outputs = engine.execute(with_pol);
+        assert_eq!(outputs, vec![Output::Vote(ours)]);
+        assert_eq!(engine.state().valid(), None);
+        let reasons: Vec<_> = engine.rejected().map(|r| r.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::WorkLimit]);
+    }
+
+    #[test]
+    fn observer() {
+        let verifier = Box::new(TestVerifier);
+        let scheduler = Box::new(TestScheduler::default());
+        let config = ConsensusConfig::default();
+        let mut engine =
+            Engine::new_observer(config, 1, validators(4), verifier, scheduler).unwrap();
+        assert!(engine.is_observer());
+        assert!(!new_engine(1).is_observer());
+
+        // it follows the round without proposing or voting
+        let value = Value::new([1; 32]);
+        let propose = |height| sm::Timeout {
+            height,
+            round: 0,
+            step: sm::TimeoutStep::Propose,
+        };
+        let outputs = engine.start_round(0, None);
+        assert_eq!(outputs, vec![Output::ScheduleTimeout(propose(1))]);
+        let mut outputs = engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        for i in 1..4 {
+            outputs.extend(engine.execute(vote(i, Vote::new_prevote(1, 0, Some(value)))));
+        }
+        assert_eq!(outputs, vec![]);
+        assert_eq!(engine.state().step(), sm::Step::Precommit);
+
+        for i in 1..4 {
+            outputs.extend(engine.execute(vote(i, Vote::new_precommit(1, 0, Some(value)))));
+        }
+        let decision = sm::RoundValue { round: 0, value };
+        assert_eq!(
+            outputs,
+            vec![Output::Decision(decision), Output::NewRound(0)]
+        );
+        assert_eq!(engine.height(), 2);
+        assert_eq!(engine.last_commit().unwrap().precommits.len(), 3);
+        assert!(engine.rebroadcast().is_empty());
+
+        // given a value, it still waits for the proposal until timeout propose
+        let outputs = engine.start_round(0, Some(Value::new([2; 32])));
+        assert_eq!(outputs, vec![Output::ScheduleTimeout(propose(2))]);
+        assert_eq!(engine.state().step(), sm::Step::Propose);
+        assert!(engine.proposal(0).is_none());
+    }
+}
@@ -0,0 +1,293 @@
+use super::light::LightBlock;
+use super::signature::Signed;
+use super::Vote;
+
+use std::time::Duration;
+
+// Evidence is proof that a validator misbehaved,
+// made of the messages it signed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Evidence {
+    // Two votes of the same type, height and round for different values,
+    // ordered by value, so the same two votes are the same evidence.
+    DuplicateVote(Signed<Vote>, Signed<Vote>),
+
+    // A precommit for a value, then a prevote for a different value in a
+    // later round, without a polka for that value since the precommit.
+    Amnesia {
+        precommit: Signed<Vote>,
+        prevote: Signed<Vote>,
+    },
+
+    // A commit for another value than the chain's at the same height, that a
+    // light client verified: how it was made, the last height the chain and
+    // it agree on, and the validators that signed it though they couldn't
+    // have honestly, by index in the set of the common height for a lunatic
+    // attack and of the height otherwise. Amnesia names none, as the commits
+    // alone don't tell who forgot their lock; detect_amnesia() in forensics
+    // does, from the audit logs of the validators that signed both.
+    LightClientAttack {
+        kind: AttackKind,
+        conflicting: Box<LightBlock>,
+        common_height: i64,
+        byzantine: Vec<usize>,
+    },
+}
+
+// AttackKind is how a conflicting light block was made.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AttackKind {
+    Equivocation, // The chain's validators signed both commits in the same round.
+    Lunatic,      // It names validators other than the chain's.
+    Amnesia,      // The chain's validators signed both commits in different rounds.
+}
+
+impl Evidence {
+    // validator returns the index of the validator that misbehaved. A light
+    // client attack may have several, or none: it returns the first, if any,
+    // and validators() returns them all.
+    pub fn validator(&self) -> Option<usize> {
+        match self {
+            Evidence::DuplicateVote(a, _) => Some(a.signer),
+            Evidence::Amnesia { precommit, .. } => Some(precommit.signer),
+            Evidence::LightClientAttack { byzantine, .. } => byzantine.first().copied(),
+        }
+    }
+
+    // validators returns the indices of the validators that misbehaved.
+    pub fn validators(&self) -> Vec<usize> {
+        match self {
+            Evidence::DuplicateVote(a, _) => vec![a.signer],
+            Evidence::Amnesia { precommit, .. } => vec![precommit.signer],
+            Evidence::LightClientAttack { byzantine, .. } => byzantine.clone(),
+        }
+    }
+
+    // height returns the height at which the validator misbehaved.
+    pub fn height(&self) -> i64 {
+        match self {
+            Evidence::DuplicateVote(a, _) => a.msg.height,
+            Evidence::Amnesia { precommit, .. } => precommit.msg.height,
+            Evidence::LightClientAttack { conflicting, .. } => conflicting.height,
+        }
+    }
+}
+
+// duplicate_vote returns evidence if the two votes are from the same validator,
+// for the same type, height and round, but for different values. The votes
+// are ordered by value, so nodes that saw them in either order store the
+// same evidence.
+pub fn duplicate_vote(a: &Signed<Vote>, b: &Signed<Vote>) -> Option<Evidence> {
+    let (va, vb) = (&a.msg, &b.msg);
+    let conflict = a.signer == b.signer
+        && va.typ == vb.typ
+        && va.height == vb.height
+        && va.round == vb.round
+        && va.value != vb.value;
+    if !conflict {
+        return None;
+    }
+    let (a, b) = if va.value < vb.value { (a, b) } else { (b, a) };
+    Some(Evidence::DuplicateVote(a.clone(), b.clone()))
+}
+
+//---------------------------------------------------------------------
+// Pool
+
+// EvidenceConfig limits how long evidence is kept, and how much.
+// Evidence expires once it's older than both max_age_heights and max_age,
+// so a halted chain doesn't expire it early, nor a fast one late.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvidenceConfig {
+    pub max_age_heights: i64,
+    pub max_age: Duration,
+    pub max_size: usize, // most pieces of evidence stored at once
+}
+
+impl Default for EvidenceConfig {
+    fn default() -> EvidenceConfig {
+        EvidenceConfig {
+            max_age_heights: 100_000,
+            max_age: Duration::from_secs(48 * 60 * 60),
+            max_size: 1000,
+        }
+    }
+}
+
+impl EvidenceConfig {
+    // validate returns an error if the limits can't be used.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_age_heights <= 0 {
+            return Err(format!("bad max_age_heights {}", self.max_age_heights));
+        }
+        Ok(())
+    }
+}
+
+// EvidenceError is why evidence wasn't added to the pool.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvidenceError {
+    Expired,   // Too old to be accepted.
+    Duplicate, // Already in the pool.
+    Full,      // The pool holds max_size pieces of evidence.
+}
+
+// EvidencePool stores the evidence that hasn't expired yet.
+pub struct EvidencePool {
+    config: EvidenceConfig,
+    height: i64,
+    now: Duration,
+    evidence: Vec<(Evidence, Duration)>, // with the time of its height
+}
+
+impl EvidencePool {
+    // new creates an empty pool at the given height and time.
+    pub fn new(config: EvidenceConfig, height: i64, now: Duration) -> EvidencePool {
+        EvidencePool {
+            config,
+            height,
+            now,
+            evidence: Vec::new(),
+        }
+    }
+
+    // add adds the evidence. time is the time of the height at which
+    // the validator misbehaved.
+    pub fn add(&mut self, evidence: Evidence, time: Duration) -> Result<(), EvidenceError> {
+        if self.is_expired(evidence.height(), time) {
+            return Err(EvidenceError::Expired);
+        }
+        if self.evidence.iter().any(|(e, _)| *e == evidence) {
+            return Err(EvidenceError::Duplicate);
+        }
+        if self.evidence.len() >= self.config.max_size {
+            return Err(EvidenceError::Full);
+        }
+        self.evidence.push((evidence, time));
+        Ok(())
+    }
+
+    // next_height moves the pool to the new height and time,
+    // dropping the evidence that expired.
+    pub fn next_height(&mut self, height: i64, now: Duration) {
+        self.height = height;
+        self.now = now;
+        let evidence = std::mem::take(&mut self.evidence);
+        self.evidence = evidence
+            .into_iter()
+            .filter(|(e, time)| !self.is_expired(e.height(), *time))
+            .collect();
+    }
+
+    // set_config replaces the limits, eg. with the evidence params of a new
+    // height. Stored evidence that's expired under them is dropped, and the
+    // oldest beyond max_size.
+    pub fn set_config(&mut self, config: EvidenceConfig) {
+        self.config = config;
+        self.next_height(self.height, self.now);
+        let excess = self.evidence.len().saturating_sub(self.config.max_size);
+        self.evidence.drain(..excess);
+    }
+
+    // evidence returns the stored evidence, oldest first.
+    pub fn evidence(&self) -> impl Iterator<Item = &Evidence> {
+        self.evidence.iter().map(|(e, _)| e)
+    }
+
+    pub fn len(&self) -> usize {
+        self.evidence.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.evidence.is_empty()
+    }
+
+    fn is_expired(&self, height: i64, time: Duration) -> bool {
+        self.height - height > self.config.max_age_heights
+            && self.now.saturating_sub(time) > self.config.max_age
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn vote(height: i64, id: u8) -> Signed<Vote> {
+        Signed {
+            signer: 0,
+            msg: Vote::new_prevote(height, 0, Some(Value::new([id; 32]))),
+            signature: vec![0],
+        }
+    }
+
+    fn duplicate(height: i64) -> Evidence {
+        duplicate_vote(&vote(height, 1), &vote(height, 2)).unwrap()
+    }
+
+    #[test]
+    fn duplicate_order() {
+        // the same equivocation seen in either order is the same evidence
+        let (a, b) = (vote(1, 1), vote(1, 2));
+        let evidence = duplicate_vote(&b, &a).unwrap();
+        assert_eq!(evidence, duplicate_vote(&a, &b).unwrap());
+        assert_eq!(evidence, Evidence::DuplicateVote(a.clone(), b));
+        assert_eq!(evidence.validator(), Some(0));
+        assert_eq!(duplicate_vote(&a, &a), None);
+
+        let mut pool = EvidencePool::new(EvidenceConfig::default(), 1, Duration::ZERO);
+        assert_eq!(pool.add(evidence, Duration::ZERO), Ok(()));
+        let nil = Signed {
+            msg: Vote::new_prevote(1, 0, None),
+            ..a.clone()
+        };
+        let reordered = duplicate_vote(&a, &nil).unwrap();
+        assert_eq!(reordered, Evidence::DuplicateVote(nil.clone(), a.clone()));
+        assert_eq!(pool.add(reordered, Duration::ZERO), Ok(()));
+        assert_eq!(
+            pool.add(duplicate_vote(&nil, &a).unwrap(), Duration::ZERO),
+            Err(EvidenceError::Duplicate)
+        );
+    }
+
+    #[test]
+    fn pool() {
+        let config = EvidenceConfig {
+            max_age_heights: 10,
+            max_age: Duration::from_secs(100),
+            max_size: 2,
+        };
+        let secs = Duration::from_secs;
+        let mut pool = EvidencePool::new(config, 20, secs(1000));
+
+        // too old by heights and time
+        assert_eq!(
+            pool.add(duplicate(5), secs(800)),
+            Err(EvidenceError::Expired)
+        );
+        // too old by heights only
+        assert_eq!(pool.add(duplicate(5), secs(950)), Ok(()));
+        assert_eq!(
+            pool.add(duplicate(5), secs(950)),
+            Err(EvidenceError::Duplicate)
+        );
+        assert_eq!(pool.add(duplicate(18), secs(990)), Ok(()));
+        assert_eq!(pool.add(duplicate(19), secs(995)), Err(EvidenceError::Full));
+
+        // moving on expires the oldest
+        pool.next_height(21, secs(1060));
+        assert_eq!(pool.evidence().collect::<Vec<_>>(), vec![&duplicate(18)]);
+        assert_eq!(pool.add(duplicate(19), secs(995)), Ok(()));
+
+        // tighter limits from new params drop what's beyond them
+        let config = EvidenceConfig {
+            max_size: 1,
+            ..EvidenceConfig::default()
+        };
+        pool.set_config(config);
+        assert_eq!(pool.evidence().collect::<Vec<_>>(), vec![&duplicate(19)]);
+    }
+}
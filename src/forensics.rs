@@ -0,0 +1,157 @@
+// Forensics looks for misbehavior that can only be seen across rounds,
+// by analyzing everything a validator signed and received.
+
+use super::evidence::Evidence;
+use super::round_votes::{RoundVotes, Thresh};
+use super::signature::Signed;
+use super::{Commit, Value, Vote, VoteType};
+
+use std::collections::HashSet;
+
+// detect_amnesia looks for amnesia by the validator at the given index.
+//
+// log is the validator's audit log: the votes it signed and the votes it received,
+// eg. replayed from its WAL. commits are the commits decided by the network;
+// only their heights are analyzed.
+//
+// A validator that precommitted v in round r may only prevote another value v'
+// in a later round r' if there was a polka for v' in some round between r and r'
+// (r <= vr < r'). Polkas are tallied from the prevotes in the log, using the weights
+// of the validators by index. A commit for v' in such a round also counts,
+// as correct validators only precommit after a polka.
+pub fn detect_amnesia(
+    validator: usize,
+    log: &[Signed<Vote>],
+    commits: &[Commit],
+    weights: &[i64],
+) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+    for commit in commits {
+        let votes: Vec<&Signed<Vote>> = log
+            .iter()
+            .filter(|v| v.msg.height == commit.height)
+            .collect();
+        let mut polkas = polkas(&votes, weights);
+        polkas.insert((commit.round, commit.value));
+
+        let own = |typ: VoteType| {
+            votes
+                .iter()
+                .filter(move |v| v.signer == validator && v.msg.typ == typ)
+                .filter_map(|v| v.msg.value.map(|value| (*v, value)))
+        };
+        for (prevote, value) in own(VoteType::Prevote) {
+            let locked = own(VoteType::Precommit).find(|(precommit, locked)| {
+                let (r, r2) = (precommit.msg.round, prevote.msg.round);
+                *locked != value && r < r2 && !(r..r2).any(|vr| polkas.contains(&(vr, value)))
+            });
+            if let Some((precommit, _)) = locked {
+                evidence.push(Evidence::Amnesia {
+                    precommit: precommit.clone(),
+                    prevote: prevote.clone(),
+                });
+            }
+        }
+    }
+    evidence
+}
+
+// polkas tallies the prevotes and returns the rounds with +2/3 prevotes for a value.
+// Only the first prevote of each validator in a round is counted.
+fn polkas(votes: &[&Signed<Vote>], weights: &[i64]) -> HashSet<(i64, Value)> {
+    let total = weights.iter().sum();
+    let mut rounds: Vec<RoundVotes> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut polkas = HashSet::new();
+    for v in votes.iter().filter(|v| v.msg.typ == VoteType::Prevote) {
+        let (round, signer) = (v.msg.round, v.signer);
+        if !seen.insert((round, signer)) {
+            continue;
+        }
+        let i = match rounds.iter().position(|rv| rv.round() == round) {
+            Some(i) => i,
+            None => {
+                rounds.push(RoundVotes::new(v.msg.height, round, total));
+                rounds.len() - 1
+            }
+        };
+        let weight = weights.get(signer).cloned().unwrap_or(0);
+        let outcome = rounds[i].add_vote(signer, v.msg, weight);
+        if let Some(Thresh::Value(value)) = outcome.new_threshold {
+            polkas.insert((round, value));
+        }
+    }
+    polkas
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: Value = Value::new([1; 32]);
+    const V2: Value = Value::new([2; 32]);
+    const WEIGHTS: [i64; 4] = [1, 1, 1, 1];
+
+    fn signed(signer: usize, msg: Vote) -> Signed<Vote> {
+        let signature = vec![signer as u8];
+        Signed {
+            signer,
+            msg,
+            signature,
+        }
+    }
+
+    fn commit(round: i64, value: Value) -> Commit {
+        Commit {
+            height: 1,
+            round,
+            value,
+            precommits: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn amnesia() {
+        let precommit = signed(0, Vote::new_precommit(1, 0, Some(V1)));
+        let prevote = signed(0, Vote::new_prevote(1, 1, Some(V2)));
+        let log = vec![precommit.clone(), prevote.clone()];
+
+        let evidence = detect_amnesia(0, &log, &[commit(1, V2)], &WEIGHTS);
+        assert_eq!(evidence, vec![Evidence::Amnesia { precommit, prevote }]);
+        assert_eq!(evidence[0].validators(), vec![0]);
+        assert_eq!(evidence[0].height(), 1);
+
+        // nothing to say about other validators or heights that weren't decided
+        assert!(detect_amnesia(1, &log, &[commit(1, V2)], &WEIGHTS).is_empty());
+        assert!(detect_amnesia(0, &log, &[], &WEIGHTS).is_empty());
+    }
+
+    #[test]
+    fn unlock_on_polka() {
+        // precommit V1 in round 0, see a polka for V2 in round 1, prevote V2 in round 2.
+        let mut log = vec![signed(0, Vote::new_precommit(1, 0, Some(V1)))];
+        for i in 1..4 {
+            log.push(signed(i, Vote::new_prevote(1, 1, Some(V2))));
+        }
+        log.push(signed(0, Vote::new_prevote(1, 2, Some(V2))));
+        assert!(detect_amnesia(0, &log, &[commit(2, V2)], &WEIGHTS).is_empty());
+
+        // duplicate prevotes don't make a polka
+        let mut log = vec![signed(0, Vote::new_precommit(1, 0, Some(V1)))];
+        for _ in 1..4 {
+            log.push(signed(1, Vote::new_prevote(1, 1, Some(V2))));
+        }
+        log.push(signed(0, Vote::new_prevote(1, 2, Some(V2))));
+        assert_eq!(detect_amnesia(0, &log, &[commit(2, V2)], &WEIGHTS).len(), 1);
+
+        // the commit is proof of a polka in its round
+        let log = vec![
+            signed(0, Vote::new_precommit(1, 0, Some(V1))),
+            signed(0, Vote::new_prevote(1, 2, Some(V2))),
+        ];
+        assert!(detect_amnesia(0, &log, &[commit(1, V2)], &WEIGHTS).is_empty());
+    }
+}
@@ -0,0 +1,618 @@
+// Gossip decides which votes to send to which peers.
+//
+// When we see +2/3 of the votes of some type in a round for a value (a "maj23"),
+// we announce it to our peers, along with which of those votes we have.
+// Peers respond with the votes for that value we're missing. Under partial
+// connectivity, this lets a node complete a quorum that it only saw part of.
+//
+// Peers may also exchange summaries of all the votes they have. The difference
+// between two summaries is exactly the votes one peer lacks, so only those are
+// sent, rather than rebroadcasting every vote.
+//
+// When only some of the votes a peer lacks can be sent at once, eg. one at a
+// time as bandwidth allows, the VotePriority decides which go first. Sending
+// the votes of validators with the most voting power first closes quorums in
+// the fewest votes.
+
+use super::round_votes::is_quorum;
+use super::signature::Signed;
+use super::store::Store;
+use super::{Value, Vote, VoteType};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub type PeerId = u64;
+
+// VoteSetId identifies the votes of one type in a round.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VoteSetId {
+    pub height: i64,
+    pub round: i64,
+    pub typ: VoteType,
+}
+
+impl VoteSetId {
+    pub fn of(vote: &Vote) -> VoteSetId {
+        VoteSetId {
+            height: vote.height,
+            round: vote.round,
+            typ: vote.typ,
+        }
+    }
+
+    // at returns the first vote set of the round: its prevotes.
+    fn at(height: i64, round: i64) -> VoteSetId {
+        VoteSetId {
+            height,
+            round,
+            typ: VoteType::Prevote,
+        }
+    }
+}
+
+// Maj23 announces that we've seen +2/3 of the votes of a vote set for the value.
+// bits are the validators, by index, we have votes for the value from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Maj23 {
+    pub id: VoteSetId,
+    pub value: Option<Value>,
+    pub bits: Vec<bool>,
+}
+
+// VoteSetSummary is which votes we have, as bits by validator index for each
+// vote set. Peers exchange summaries to send each other only the votes
+// the other lacks, rather than rebroadcasting all of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoteSetSummary {
+    pub sets: BTreeMap<VoteSetId, Vec<bool>>,
+}
+
+// MissingVotes are the votes, by vote set and validator index,
+// that a peer lacks, in vote set then validator order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MissingVotes(pub Vec<(VoteSetId, usize)>);
+
+impl MissingVotes {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl VoteSetSummary {
+    // has returns true if the summary has the validator's vote in the vote set.
+    pub fn has(&self, id: &VoteSetId, validator: usize) -> bool {
+        self.sets
+            .get(id)
+            .and_then(|bits| bits.get(validator).cloned())
+            .unwrap_or(false)
+    }
+
+    // diff returns the votes we have that the peer with the other summary lacks.
+    pub fn diff(&self, other: &VoteSetSummary) -> MissingVotes {
+        let mut missing = Vec::new();
+        for (id, bits) in &self.sets {
+            for (validator, _) in bits.iter().enumerate().filter(|(_, has)| **has) {
+                if !other.has(id, validator) {
+                    missing.push((*id, validator));
+                }
+            }
+        }
+        MissingVotes(missing)
+    }
+}
+
+// VotePriority is the order in which the votes of a vote set a peer lacks
+// are sent to it, or requested from peers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VotePriority {
+    Index, // By validator index.
+    Power, // Highest voting power first, then by validator index.
+}
+
+// Gossip holds the votes we can send to peers, and what we know peers have.
+pub struct Gossip {
+    weights: Vec<i64>, // voting power by validator index
+    priority: VotePriority,
+    votes: BTreeMap<VoteSetId, Vec<Signed<Vote>>>,
+    announced: HashSet<(VoteSetId, Option<Value>)>,
+    peers: HashMap<PeerId, PeerState>,
+}
+
+// PeerState is what we know about the votes a peer has.
+#[derive(Default)]
+struct PeerState {
+    height: i64, // the height and round the peer last reported being at
+    round: i64,
+    has: HashMap<VoteSetId, Vec<bool>>,
+}
+
+// PeerRecord is the part of a peer's state that's persisted, so gossip
+// resumes where it was after a restart rather than re-learning it: the
+// height and round it last reported, and the votes it has at that height
+// or later, including the ones we sent it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerRecord {
+    pub height: i64,
+    pub round: i64,
+    pub has: Vec<(VoteSetId, Vec<bool>)>, // in vote set order
+}
+
+impl PeerState {
+    fn has(&self, id: &VoteSetId, validator: usize) -> bool {
+        self.has
+            .get(id)
+            .and_then(|bits| bits.get(validator).cloned())
+            .unwrap_or(false)
+    }
+
+    // set_has records that the peer has the validator's vote in the vote
+    // set, if it's one of the set's validators.
+    fn set_has(&mut self, id: VoteSetId, validator: usize, validators: usize) {
+        if validator >= validators {
+            return;
+        }
+        let bits = self.has.entry(id).or_default();
+        if bits.len() <= validator {
+            bits.resize(validator + 1, false);
+        }
+        bits[validator] = true;
+    }
+
+    // prune forgets the votes the peer has below the height.
+    fn prune(&mut self, below_height: i64) {
+        self.has.retain(|id, _| id.height >= below_height);
+    }
+}
+
+impl Gossip {
+    pub fn new(weights: Vec<i64>) -> Gossip {
+        Gossip {
+            weights,
+            priority: VotePriority::Index,
+            votes: BTreeMap::new(),
+            announced: HashSet::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    // set_priority sets the order votes are sent and requested in.
+    // The default is by validator index.
+    pub fn set_priority(&mut self, priority: VotePriority) {
+        self.priority = priority;
+    }
+
+    // add_vote adds a vote we received or signed, keeping the first vote of each
+    // validator per vote set. If it completes +2/3 for a value (or nil) for the
+    // first time, it returns the announcement to send to our peers.
+    pub fn add_vote(&mut self, vote: Signed<Vote>) -> Option<Maj23> {
+        let id = VoteSetId::of(&vote.msg);
+        let value = vote.msg.value;
+        let votes = self.votes.entry(id).or_default();
+        if votes.iter().any(|v| v.signer == vote.signer) {
+            return None;
+        }
+        votes.push(vote);
+
+        let weights = &self.weights;
+        let total: i64 = weights.iter().sum();
+        let weight: i64 = votes
+            .iter()
+            .filter(|v| v.msg.value == value)
+            .map(|v| weights.get(v.signer).cloned().unwrap_or(0))
+            .sum();
+        if !is_quorum(weight, total) || !self.announced.insert((id, value)) {
+            return None;
+        }
+        Some(Maj23 {
+            id,
+            value,
+            bits: self.bits(&id, value),
+        })
+    }
+
+    // peer_has_vote records that the peer has the vote,
+    // eg. because it sent it to us or we sent it to it.
+    pub fn peer_has_vote(&mut self, peer: PeerId, vote: &Vote, validator: usize) {
+        let peer = self.peers.entry(peer).or_default();
+        peer.set_has(VoteSetId::of(vote), validator, self.weights.len());
+    }
+
+    // peer_progress records the height and round the peer reported being at.
+    pub fn peer_progress(&mut self, peer: PeerId, height: i64, round: i64) {
+        let peer = self.peers.entry(peer).or_default();
+        peer.height = height;
+        peer.round = round;
+    }
+
+    // peer_record returns what we know about the peer, to persist.
+    pub fn peer_record(&self, peer: PeerId) -> Option<PeerRecord> {
+        let state = self.peers.get(&peer)?;
+        let mut has: Vec<_> = state
+            .has
+            .iter()
+            .filter(|(id, _)| id.height >= state.height)
+            .map(|(id, bits)| (*id, bits.clone()))
+            .collect();
+        has.sort_by_key(|(id, _)| *id);
+        Some(PeerRecord {
+            height: state.height,
+            round: state.round,
+            has,
+        })
+    }
+
+    // save_peers saves the records of all peers to the store.
+    pub fn save_peers(&self, store: &mut dyn Store) -> Result<(), String> {
+        for peer in self.peers.keys() {
+            if let Some(record) = self.peer_record(*peer) {
+                store.save_peer(*peer, &record)?;
+            }
+        }
+        Ok(())
+    }
+
+    // load_peers preloads the peers' records from the store, eg. on restart,
+    // replacing what we know about those peers. Bits past the validator set
+    // are dropped.
+    pub fn load_peers(&mut self, store: &dyn Store) -> Result<(), String> {
+        let n = self.weights.len();
+        for (peer, record) in store.peers()? {
+            let has = record.has.into_iter().map(|(id, mut bits)| {
+                bits.truncate(n);
+                (id, bits)
+            });
+            self.peers.insert(
+                peer,
+                PeerState {
+                    height: record.height,
+                    round: record.round,
+                    has: has.collect(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // prune drops the votes and announcements of heights below the height,
+    // and what peers have of them, eg. as the driver moves to a new height,
+    // so a long-running node doesn't keep every height it has seen.
+    pub fn prune(&mut self, below_height: i64) {
+        self.votes.retain(|id, _| id.height >= below_height);
+        self.announced.retain(|(id, _)| id.height >= below_height);
+        for peer in self.peers.values_mut() {
+            peer.prune(below_height);
+        }
+    }
+
+    // on_maj23 handles an announcement from the peer, returning the votes
+    // for the announced value that the peer is missing and we have.
+    // The returned votes are recorded as sent. An announcement with more
+    // bits than the validator set is ignored.
+    pub fn on_maj23(&mut self, peer: PeerId, maj23: &Maj23) -> Vec<Signed<Vote>> {
+        let n = self.weights.len();
+        if maj23.bits.len() > n {
+            return Vec::new();
+        }
+        let state = self.peers.entry(peer).or_default();
+        for (validator, _) in maj23.bits.iter().enumerate().filter(|(_, has)| **has) {
+            state.set_has(maj23.id, validator, n);
+        }
+
+        let votes = match self.votes.get(&maj23.id) {
+            Some(votes) => votes,
+            None => return Vec::new(),
+        };
+        let mut missing: Vec<Signed<Vote>> = votes
+            .iter()
+            .filter(|v| v.msg.value == maj23.value && !state.has(&maj23.id, v.signer))
+            .cloned()
+            .collect();
+        let (priority, weights) = (self.priority, &self.weights);
+        missing.sort_by_key(|v| priority_key(priority, weights, v.signer));
+        for v in &missing {
+            state.set_has(maj23.id, v.signer, n);
+        }
+        missing
+    }
+
+    // summary returns which votes we have, for peers to diff against theirs.
+    pub fn summary(&self) -> VoteSetSummary {
+        let mut sets = BTreeMap::new();
+        for (id, votes) in &self.votes {
+            let mut bits = vec![false; self.weights.len()];
+            for v in votes {
+                if v.signer < bits.len() {
+                    bits[v.signer] = true;
+                }
+            }
+            sets.insert(*id, bits);
+        }
+        VoteSetSummary { sets }
+    }
+
+    // on_summary handles a summary from the peer, returning the votes it lacks
+    // and we have. The returned votes are recorded as sent. A summary with
+    // more bits for a vote set than the validator set is ignored.
+    pub fn on_summary(&mut self, peer: PeerId, summary: &VoteSetSummary) -> Vec<Signed<Vote>> {
+        let n = self.weights.len();
+        if summary.sets.values().any(|bits| bits.len() > n) {
+            return Vec::new();
+        }
+        let missing = self.prioritize(self.summary().diff(summary));
+        let state = self.peers.entry(peer).or_default();
+        for (id, bits) in &summary.sets {
+            for (validator, _) in bits.iter().enumerate().filter(|(_, has)| **has) {
+                state.set_has(*id, validator, n);
+            }
+        }
+
+        let mut votes = Vec::with_capacity(missing.len());
+        for (id, validator) in missing.0 {
+            if state.has(&id, validator) {
+                continue;
+            }
+            let vote = self.votes[&id].iter().find(|v| v.signer == validator);
+            if let Some(vote) = vote {
+                state.set_has(id, validator, n);
+                votes.push(vote.clone());
+            }
+        }
+        votes
+    }
+
+    // wanted returns the votes the peer has, by its summary, that we lack,
+    // in the order to request them in: vote set, then priority.
+    pub fn wanted(&self, summary: &VoteSetSummary) -> MissingVotes {
+        self.prioritize(summary.diff(&self.summary()))
+    }
+
+    // next_vote returns the vote of the peer's height and round that it
+    // lacks and comes first by priority, for a driver that sends a peer one
+    // vote at a time. Prevotes go before precommits. The returned vote is
+    // recorded as sent.
+    pub fn next_vote(&mut self, peer: PeerId) -> Option<Signed<Vote>> {
+        let state = self.peers.entry(peer).or_default();
+        let (height, round) = (state.height, state.round);
+        let (priority, weights) = (self.priority, &self.weights);
+        let (id, vote) = self
+            .votes
+            .range(VoteSetId::at(height, round)..)
+            .take_while(|(id, _)| (id.height, id.round) == (height, round))
+            .flat_map(|(id, votes)| votes.iter().map(move |v| (*id, v)))
+            .filter(|(id, v)| !state.has(id, v.signer))
+            .min_by_key(|(id, v)| (*id, priority_key(priority, weights, v.signer)))?;
+        state.set_has(id, vote.signer, weights.len());
+        Some(vote.clone())
+    }
+
+    // prioritize orders the votes of each vote set by priority.
+    fn prioritize(&self, mut missing: MissingVotes) -> MissingVotes {
+        let key = |(id, validator): &(VoteSetId, usize)| {
+            (*id, priority_key(self.priority, &self.weights, *validator))
+        };
+        missing.0.sort_by_key(key);
+        missing
+    }
+
+    // bits returns which validators we have votes for the value from.
+    fn bits(&self, id: &VoteSetId, value: Option<Value>) -> Vec<bool> {
+        let mut bits = vec![false; self.weights.len()];
+        for v in self.votes.get(id).into_iter().flatten() {
+            if v.msg.value == value && v.signer < bits.len() {
+                bits[v.signer] = true;
+            }
+        }
+        bits
+    }
+}
+
+// priority_key orders validators by the priority: the lowest key goes first.
+fn priority_key(
+    priority: VotePriority,
+    weights: &[i64],
+    validator: usize,
+) -> (Reverse<i64>, usize) {
+    let weight = match priority {
+        VotePriority::Index => 0,
+        VotePriority::Power => weights.get(validator).cloned().unwrap_or(0),
+    };
+    (Reverse(weight), validator)
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V: Value = Value::new([1; 32]);
+
+    fn prevote(signer: usize, value: Option<Value>) -> Signed<Vote> {
+        Signed {
+            signer,
+            msg: Vote::new_prevote(1, 0, value),
+            signature: vec![signer as u8],
+        }
+    }
+
+    #[test]
+    fn maj23() {
+        let mut a = Gossip::new(vec![1; 4]);
+        let mut b = Gossip::new(vec![1; 4]);
+
+        // a sees a polka from validators 0, 1 and 2 and announces it, once.
+        assert_eq!(a.add_vote(prevote(0, Some(V))), None);
+        assert_eq!(a.add_vote(prevote(0, Some(V))), None);
+        assert_eq!(a.add_vote(prevote(1, Some(V))), None);
+        assert_eq!(a.add_vote(prevote(3, None)), None);
+        let maj23 = a.add_vote(prevote(2, Some(V))).unwrap();
+        assert_eq!(maj23.value, Some(V));
+        assert_eq!(maj23.bits, vec![true, true, true, false]);
+
+        // b only has votes from 0 and another vote for the value from 3
+        b.add_vote(prevote(0, Some(V)));
+        b.add_vote(prevote(3, Some(V)));
+
+        // b sends a the vote it's missing, only once
+        assert_eq!(b.on_maj23(7, &maj23), vec![prevote(3, Some(V))]);
+        assert!(b.on_maj23(7, &maj23).is_empty());
+    }
+
+    #[test]
+    fn summary_diff() {
+        let mut a = Gossip::new(vec![1; 4]);
+        let mut b = Gossip::new(vec![1; 4]);
+        let precommit = |signer: usize| Signed {
+            signer,
+            msg: Vote::new_precommit(1, 0, Some(V)),
+            signature: vec![signer as u8],
+        };
+        for signer in 0..3 {
+            a.add_vote(prevote(signer, Some(V)));
+        }
+        a.add_vote(precommit(1));
+        b.add_vote(prevote(1, Some(V)));
+        b.add_vote(prevote(3, None));
+
+        let (prevotes, precommits) = (
+            VoteSetId::of(&prevote(0, None).msg),
+            VoteSetId::of(&precommit(0).msg),
+        );
+        let missing = a.summary().diff(&b.summary());
+        assert_eq!(
+            missing,
+            MissingVotes(vec![(prevotes, 0), (prevotes, 2), (precommits, 1)])
+        );
+        assert_eq!(
+            b.summary().diff(&a.summary()),
+            MissingVotes(vec![(prevotes, 3)])
+        );
+
+        // a sends b exactly the votes it lacks, once
+        let sent = a.on_summary(7, &b.summary());
+        assert_eq!(
+            sent,
+            vec![prevote(0, Some(V)), prevote(2, Some(V)), precommit(1)]
+        );
+        assert!(a.on_summary(7, &b.summary()).is_empty());
+
+        // summaries and announcements past the validator set are ignored,
+        // and the votes a peer has are only recorded for the set
+        let mut long = b.summary();
+        long.sets.insert(precommits, vec![false; 5]);
+        assert!(a.on_summary(8, &long).is_empty());
+        assert_eq!(a.peer_record(8), None);
+        let maj23 = Maj23 {
+            id: prevotes,
+            value: Some(V),
+            bits: vec![true; 5],
+        };
+        assert!(a.on_maj23(8, &maj23).is_empty());
+        a.peer_has_vote(8, &prevote(0, None).msg, 1 << 40);
+        assert_eq!(a.peer_record(8).unwrap().has, vec![]);
+    }
+
+    #[test]
+    fn prune() {
+        let mut a = Gossip::new(vec![1; 4]);
+        let vote = |height, signer| Signed {
+            signer,
+            msg: Vote::new_prevote(height, 0, Some(V)),
+            signature: vec![signer as u8],
+        };
+        for height in 1..3 {
+            for signer in 0..3 {
+                a.add_vote(vote(height, signer));
+                a.peer_has_vote(7, &vote(height, signer).msg, signer);
+            }
+        }
+        a.peer_progress(8, 1, 0);
+
+        // moving to height 2 forgets height 1's votes, announcements and
+        // what the peer has of them
+        a.prune(2);
+        let heights: Vec<_> = a.summary().sets.keys().map(|id| id.height).collect();
+        assert_eq!(heights, vec![2]);
+        assert_eq!(a.announced.len(), 1);
+        assert!(a.peers[&7].has.keys().all(|id| id.height == 2));
+        assert_eq!(a.next_vote(8), None);
+
+        // and height 1's polka is announced again if it comes back
+        assert!(a.add_vote(vote(1, 0)).is_none());
+        a.add_vote(vote(1, 1));
+        assert!(a.add_vote(vote(1, 2)).is_some());
+    }
+
+    #[test]
+    fn priority() {
+        // validator 3 alone has more power than the rest together
+        let weights = vec![1, 1, 1, 10];
+        let mut a = Gossip::new(weights.clone());
+        for signer in 0..4 {
+            a.add_vote(prevote(signer, Some(V)));
+        }
+        a.peer_progress(7, 1, 0);
+        a.peer_progress(8, 1, 0);
+        a.peer_progress(9, 1, 1);
+
+        // one at a time, by index unless set to go by power
+        assert_eq!(a.next_vote(7), Some(prevote(0, Some(V))));
+        a.set_priority(VotePriority::Power);
+        assert_eq!(a.next_vote(7), Some(prevote(3, Some(V))));
+        assert_eq!(a.next_vote(7), Some(prevote(1, Some(V))));
+        assert_eq!(a.next_vote(7), Some(prevote(2, Some(V))));
+        assert_eq!(a.next_vote(7), None);
+        // nothing for a peer at another round
+        assert_eq!(a.next_vote(9), None);
+
+        // the votes sent for an announcement and a summary are in order too
+        let maj23 = Maj23 {
+            id: VoteSetId::of(&prevote(0, None).msg),
+            value: Some(V),
+            bits: vec![false, true, false, false],
+        };
+        let signers = |votes: Vec<Signed<Vote>>| votes.iter().map(|v| v.signer).collect::<Vec<_>>();
+        assert_eq!(signers(a.on_maj23(8, &maj23)), vec![3, 0, 2]);
+        let mut c = Gossip::new(weights);
+        c.set_priority(VotePriority::Power);
+        assert_eq!(signers(a.on_summary(10, &c.summary())), vec![3, 0, 1, 2]);
+
+        // and the votes to request from a peer
+        c.add_vote(prevote(1, Some(V)));
+        let wanted = c.wanted(&a.summary());
+        let validators: Vec<usize> = wanted.0.iter().map(|(_, v)| *v).collect();
+        assert_eq!(validators, vec![3, 0, 2]);
+    }
+
+    #[test]
+    fn persist_peers() {
+        use crate::store::MemStore;
+
+        let mut a = Gossip::new(vec![1; 4]);
+        for signer in 0..3 {
+            a.add_vote(prevote(signer, Some(V)));
+        }
+        a.peer_progress(7, 1, 0);
+        a.peer_has_vote(7, &prevote(0, None).msg, 0);
+        let sent = a.on_summary(7, &VoteSetSummary::default());
+        assert_eq!(sent.len(), 2);
+
+        // after a restart, we don't send the peer the votes it has again
+        let mut store = MemStore::default();
+        a.save_peers(&mut store).unwrap();
+        let mut b = Gossip::new(vec![1; 4]);
+        for signer in 0..3 {
+            b.add_vote(prevote(signer, Some(V)));
+        }
+        b.load_peers(&store).unwrap();
+        assert_eq!(b.peer_record(7), a.peer_record(7));
+        assert_eq!(b.peer_record(7).unwrap().height, 1);
+        assert!(b.on_summary(7, &VoteSetSummary::default()).is_empty());
+    }
+}
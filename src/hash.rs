@@ -0,0 +1,103 @@
+// Hashing of value ids, validator addresses and validator set hashes.
+//
+// Chains choose their hash function by choosing a Hasher,
+// so they can interoperate with chains using eg. blake3 or keccak.
+// The default is SHA-256.
+
+// Hash is the output of a Hasher.
+pub type Hash = [u8; 32];
+
+// Hasher is a hash function with 32 byte outputs.
+pub trait Hasher {
+    fn hash(data: &[u8]) -> Hash;
+}
+
+// Sha256 is the default Hasher.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    fn hash(data: &[u8]) -> Hash {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+}
+
+// merkle_root computes the root of a binary Merkle tree over the items,
+// as in RFC 6962: leaves are hashed with a 0 prefix and inner nodes with a 1 prefix,
+// and the left subtree holds the largest power of two items less than the total.
+// The root of no items is the hash of nothing.
+pub fn merkle_root<H: Hasher>(items: &[Vec<u8>]) -> Hash {
+    match items.len() {
+        0 => H::hash(&[]),
+        1 => H::hash(&[&[0], &items[0][..]].concat()),
+        n => {
+            let k = split_point(n);
+            let left = merkle_root::<H>(&items[..k]);
+            let right = merkle_root::<H>(&items[k..]);
+            H::hash(&[&[1], &left[..], &right[..]].concat())
+        }
+    }
+}
+
+// split_point returns the largest power of two less than n, for n > 1.
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_hex as hex;
+
+    #[test]
+    fn sha256() {
+        assert_eq!(
+            hex(&Sha256::hash(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn merkle() {
+        assert_eq!(merkle_root::<Sha256>(&[]), Sha256::hash(&[]));
+        let items: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i]).collect();
+        let leaf = |i: u8| Sha256::hash(&[0, i]);
+        let inner = |l: Hash, r: Hash| Sha256::hash(&[&[1], &l[..], &r[..]].concat());
+        assert_eq!(merkle_root::<Sha256>(&items[..1]), leaf(0));
+        assert_eq!(
+            merkle_root::<Sha256>(&items),
+            inner(inner(leaf(0), leaf(1)), leaf(2))
+        );
+    }
+
+    // A chain can plug in its own hash function.
+    struct XorHasher;
+
+    impl Hasher for XorHasher {
+        fn hash(data: &[u8]) -> Hash {
+            let mut h = [0; 32];
+            for (i, b) in data.iter().enumerate() {
+                h[i % 32] ^= b;
+            }
+            h
+        }
+    }
+
+    #[test]
+    fn custom_hasher() {
+        let items = vec![vec![7], vec![9]];
+        assert_ne!(
+            merkle_root::<XorHasher>(&items),
+            merkle_root::<Sha256>(&items)
+        );
+        assert_eq!(crate::Value::from_bytes::<XorHasher>(&[3]).id()[0], 3);
+    }
+}
@@ -1,18 +1,105 @@
+use std::fmt;
+use std::str::FromStr;
+
+// ValueId identifies a Value, eg. it's the hash of a proposed block.
+pub type ValueId = hash::Hash;
+
 // Value is what the consensus algorithm seeks agreement on.
-// TODO: it should probably be a Trait - currently it's empty.
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub struct Value {}
+// Consensus only deals with the id of the value; the contents
+// of the value are up to the application.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value {
+    id: ValueId,
+}
+
+impl Value {
+    pub const fn new(id: ValueId) -> Value {
+        Value { id }
+    }
+
+    // from_bytes returns the Value whose id is the hash of the given bytes.
+    pub fn from_bytes<H: hash::Hasher>(bytes: &[u8]) -> Value {
+        Value { id: H::hash(bytes) }
+    }
+
+    pub fn id(&self) -> ValueId {
+        self.id
+    }
+}
+
+// to_hex formats bytes in lower case hex.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// from_hex parses hex, as formatted by to_hex.
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Values are formatted as their id in lower case hex.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&to_hex(&self.id))
+    }
+}
+
+impl FromStr for Value {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Value, String> {
+        let err = || format!("bad value id {:?}", s);
+        let bytes = from_hex(s).filter(|b| b.len() == 32).ok_or_else(err)?;
+        let mut id = [0; 32];
+        id.copy_from_slice(&bytes);
+        Ok(Value { id })
+    }
+}
 
 // Proposal proposes a value in a round.
 // pol_round is -1 or the last round this value got a polka.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Proposal {
+    height: i64,
     round: i64,
     value: Value,
     pol_round: i64,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+impl Proposal {
+    pub fn new(height: i64, round: i64, value: Value, pol_round: i64) -> Proposal {
+        Proposal {
+            height,
+            round,
+            value,
+            pol_round,
+        }
+    }
+
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+
+    pub fn round(&self) -> i64 {
+        self.round
+    }
+
+    pub fn value(&self) -> Value {
+        self.value
+    }
+
+    pub fn pol_round(&self) -> i64 {
+        self.pol_round
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum VoteType {
     Prevote,
     Precommit,
@@ -22,24 +109,174 @@ pub enum VoteType {
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vote {
     typ: VoteType,
+    height: i64,
     round: i64,
     value: Option<Value>,
 }
 
 impl Vote {
-    pub fn new_prevote(round: i64, value: Option<Value>) -> Vote {
+    pub fn new_prevote(height: i64, round: i64, value: Option<Value>) -> Vote {
         let typ = VoteType::Prevote;
-        Vote { typ, round, value }
+        Vote {
+            typ,
+            height,
+            round,
+            value,
+        }
     }
 
-    pub fn new_precommit(round: i64, value: Option<Value>) -> Vote {
+    pub fn new_precommit(height: i64, round: i64, value: Option<Value>) -> Vote {
         let typ = VoteType::Precommit;
-        Vote { typ, round, value }
+        Vote {
+            typ,
+            height,
+            round,
+            value,
+        }
+    }
+
+    pub fn typ(&self) -> VoteType {
+        self.typ
+    }
+
+    pub fn height(&self) -> i64 {
+        self.height
     }
+
+    pub fn round(&self) -> i64 {
+        self.round
+    }
+
+    pub fn value(&self) -> Option<Value> {
+        self.value
+    }
+}
+
+// Heartbeat is broadcast by a validator that's idle in a round, eg. a
+// proposer waiting for transactions, to show it's online. sequence increases
+// with each heartbeat the validator sends, so peers keep only the latest.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+    height: i64,
+    round: i64,
+    sequence: u64,
 }
 
-pub mod consensus_executor;
+impl Heartbeat {
+    pub fn new(height: i64, round: i64, sequence: u64) -> Heartbeat {
+        Heartbeat {
+            height,
+            round,
+            sequence,
+        }
+    }
+
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+
+    pub fn round(&self) -> i64 {
+        self.round
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+// Commit is the set of signed precommits that decided a value at a height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Commit {
+    pub height: i64,
+    pub round: i64,
+    pub value: Value,
+    pub precommits: Vec<signature::Signed<Vote>>,
+}
+
+impl Commit {
+    // verify checks that the precommits are for the commit's height, round
+    // and value, signed in the format by distinct validators of the set, and
+    // that their voting power adds up to a quorum. The verifier checks
+    // signatures for the set.
+    pub fn verify<H: hash::Hasher>(
+        &self,
+        validators: &validators::ValidatorSet<H>,
+        verifier: &dyn signature::Verifier,
+        format: &signature::SignFormat,
+    ) -> Result<(), String> {
+        let mut seen = std::collections::HashSet::new();
+        let mut weight = 0;
+        for signed in self.precommits.iter() {
+            let (validator, v) = (signed.signer, &signed.msg);
+            let for_commit = v.typ == VoteType::Precommit
+                && v.height == self.height
+                && v.round == self.round
+                && v.value == Some(self.value);
+            if !for_commit {
+                return Err(format!("precommit of {} isn't for the commit", validator));
+            }
+            if !seen.insert(validator) {
+                return Err(format!("duplicate precommit of {}", validator));
+            }
+            let power = match validators.get(validator) {
+                Some(val) => val.voting_power,
+                None => return Err(format!("unknown validator {}", validator)),
+            };
+            if !signed.is_valid_as(verifier, format) {
+                return Err(format!("bad signature of {}", validator));
+            }
+            weight += power;
+        }
+        if !round_votes::is_quorum(weight, validators.total_voting_power()) {
+            return Err(format!(
+                "no quorum: {} of {}",
+                weight,
+                validators.total_voting_power()
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub mod config;
+pub mod dev;
+pub mod divergence;
+pub mod engine;
+pub mod evidence;
+pub mod forensics;
+pub mod gossip;
+pub mod hash;
+pub mod light;
+pub mod metrics;
+pub mod otel;
+pub mod ratelimit;
+pub mod rejected;
+pub mod resend;
 pub mod round_votes;
+#[cfg(test)]
+mod scenario;
+pub mod scheduler;
+#[cfg(feature = "secp256k1")]
+pub mod secp256k1;
+pub mod signature;
+pub mod simulator;
 pub mod state_machine;
-// pub mod validators;
-pub mod vote_executor;
+pub mod store;
+#[cfg(any(test, feature = "test_utils"))]
+pub mod test_utils;
+pub mod timeline;
+pub mod validators;
+pub mod verify_pool;
+pub mod watchdog;
+pub mod wire;
+
+// The engine is the entry point for driving consensus.
+// The modules above stay public for consumers that only need
+// the state machine or the vote tally.
+pub use config::ConsensusConfig;
+pub use engine::{
+    CorrelationId, Engine, Input, Message, Output, RecoveryState, RoundStateSnapshot, RoundSummary,
+    Timestamp, TrustedState, UpgradePackage, ValueValidator, WalEntry,
+};
+pub use scheduler::{Scheduler, TimeoutHandle, Timers};
+pub use signature::{SignState, SignatureScheme, Signed, Signer, Verified, Verifier};
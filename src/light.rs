@@ -0,0 +1,579 @@
+// Light client verification of commits across heights.
+//
+// A light client trusts a block, eg. one it was given out of band, and
+// verifies later blocks from their commits, without running consensus. Each
+// block names the hash of the validator set of the next height, so a client
+// can follow validator set changes height by height (sequential verification).
+// To skip heights, it accepts a later block if validators it trusts hold
+// more than the trust level of the voting power in its commit (skipping
+// verification); if they don't, the caller verifies a block in between first.
+// A commit is for the hash of its block's header, so the header's time and
+// next validators are signed along with it.
+// Trust in a block lasts for the trusting period from its time. Commits are
+// verified with the chain's SignatureScheme, which creates the Verifier of
+// each block's validator set.
+//
+// A client that verified two blocks at the same height for different values,
+// eg. one from its primary and one from a witness, was attacked: detect_attack()
+// tells how, and returns the evidence to report to the chain's full nodes.
+
+use super::evidence::{AttackKind, Evidence};
+use super::hash::{Hash, Sha256};
+use super::signature::{SignFormat, SignatureScheme};
+use super::validators::ValidatorSet;
+use super::{Commit, Value, Vote, VoteType};
+
+use std::time::Duration;
+
+// LightBlock is what a light client verifies at a height: the header's
+// fields, the validators that signed it, and the commit for the height.
+// The header names the hash of the validators of the next height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightBlock {
+    pub height: i64,
+    pub time: Duration, // since the unix epoch
+    pub validators: ValidatorSet,
+    pub next_validators_hash: Hash,
+    pub data_hash: Hash, // of the rest of the block, eg. its transactions
+    pub commit: Commit,
+}
+
+impl LightBlock {
+    // value returns the value the block's commit must be for: the hash of
+    // its header, ie. the height, time, hash of the validators, hash of the
+    // next validators and data hash.
+    pub fn value(&self) -> Value {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.time.as_secs().to_be_bytes());
+        bytes.extend_from_slice(&self.time.subsec_nanos().to_be_bytes());
+        bytes.extend_from_slice(&self.validators.hash());
+        bytes.extend_from_slice(&self.next_validators_hash);
+        bytes.extend_from_slice(&self.data_hash);
+        Value::from_bytes::<Sha256>(&bytes)
+    }
+}
+
+// TrustLevel is a fraction of a trusted validator set's voting power: when
+// skipping, a commit is trusted if they signed it with more than that.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrustLevel {
+    pub numerator: i64,
+    pub denominator: i64,
+}
+
+// LightConfig holds the parameters of verification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightConfig {
+    pub trust_level: TrustLevel,
+    pub trusting_period: Duration,
+    pub sign_format: SignFormat, // the chain's, that precommits are signed in
+}
+
+impl LightConfig {
+    // validate returns an error if the trust level isn't a fraction from 1/3
+    // to 1. With less, fewer trusted validators than could be faulty would
+    // be enough to skip to any height.
+    pub fn validate(&self) -> Result<(), String> {
+        let TrustLevel {
+            numerator,
+            denominator,
+        } = self.trust_level;
+        let (n, d) = (numerator as i128, denominator as i128);
+        if d <= 0 || n * 3 < d || n > d {
+            return Err(format!("bad trust level {}/{}", numerator, denominator));
+        }
+        Ok(())
+    }
+}
+
+impl Default for LightConfig {
+    fn default() -> LightConfig {
+        LightConfig {
+            trust_level: TrustLevel {
+                numerator: 1,
+                denominator: 3,
+            },
+            trusting_period: Duration::from_secs(14 * 24 * 60 * 60),
+            sign_format: SignFormat::Binary,
+        }
+    }
+}
+
+// VerifyError is why a block couldn't be verified.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyError {
+    Expired,             // The trusted block is older than the trusting period.
+    BadHeight, // The block isn't after the trusted block, or its commit is for another height.
+    BadTime,   // The block isn't later than the trusted block.
+    ValidatorsMismatch, // The validators aren't the ones the trusted block named.
+    BadHeader, // The commit isn't for the block's header.
+    BadCommit(String), // The commit isn't a quorum of the block's validators.
+    NotEnoughTrust(i64), // Trusted validators signed with only this much voting power.
+}
+
+// verify_sequential verifies the block at the height after the trusted one.
+pub fn verify_sequential(
+    config: &LightConfig,
+    scheme: &dyn SignatureScheme,
+    trusted: &LightBlock,
+    block: &LightBlock,
+    now: Duration,
+) -> Result<(), VerifyError> {
+    if block.height != trusted.height + 1 {
+        return Err(VerifyError::BadHeight);
+    }
+    check_block(config, scheme, trusted, block, now)?;
+    if block.validators.hash() != trusted.next_validators_hash {
+        return Err(VerifyError::ValidatorsMismatch);
+    }
+    Ok(())
+}
+
+// verify_skipping verifies a block at any later height, if the trusted
+// block's validators signed its commit with more than the trust level of
+// their voting power. They're identified across sets by address. The config
+// must validate.
+pub fn verify_skipping(
+    config: &LightConfig,
+    scheme: &dyn SignatureScheme,
+    trusted: &LightBlock,
+    block: &LightBlock,
+    now: Duration,
+) -> Result<(), VerifyError> {
+    if block.height <= trusted.height {
+        return Err(VerifyError::BadHeight);
+    }
+    check_block(config, scheme, trusted, block, now)?;
+    if block.height == trusted.height + 1 && block.validators.hash() == trusted.next_validators_hash
+    {
+        return Ok(());
+    }
+    let weight: i64 = block
+        .commit
+        .precommits
+        .iter()
+        .filter_map(|v| block.validators.get(v.signer))
+        .filter_map(|val| trusted.validators.index_of(&val.address::<Sha256>()))
+        .filter_map(|index| trusted.validators.get(index))
+        .map(|val| val.voting_power)
+        .sum();
+    let TrustLevel {
+        numerator,
+        denominator,
+    } = config.trust_level;
+    let total = trusted.validators.total_voting_power();
+    if (weight as i128) * (denominator as i128) <= (total as i128) * (numerator as i128) {
+        return Err(VerifyError::NotEnoughTrust(weight));
+    }
+    Ok(())
+}
+
+// check_block checks what both kinds of verification need: that the trusted
+// block hasn't expired, and the block is later, with a valid commit for its
+// header.
+fn check_block(
+    config: &LightConfig,
+    scheme: &dyn SignatureScheme,
+    trusted: &LightBlock,
+    block: &LightBlock,
+    now: Duration,
+) -> Result<(), VerifyError> {
+    if trusted.time + config.trusting_period <= now {
+        return Err(VerifyError::Expired);
+    }
+    if block.time <= trusted.time {
+        return Err(VerifyError::BadTime);
+    }
+    if block.commit.height != block.height {
+        return Err(VerifyError::BadHeight);
+    }
+    if block.commit.value != block.value() {
+        return Err(VerifyError::BadHeader);
+    }
+    let verifier = scheme.verifier(&block.validators);
+    block
+        .commit
+        .verify(&block.validators, verifier.as_ref(), &config.sign_format)
+        .map_err(VerifyError::BadCommit)
+}
+
+//---------------------------------------------------------------------
+// Client
+
+// LightClient keeps the latest verified block, and verifies blocks from it.
+pub struct LightClient {
+    config: LightConfig,
+    scheme: Box<dyn SignatureScheme>,
+    trusted: LightBlock,
+}
+
+impl LightClient {
+    // new creates a client that trusts the block, and verifies commits
+    // with the scheme. Returns an error if the config doesn't validate.
+    pub fn new(
+        config: LightConfig,
+        scheme: Box<dyn SignatureScheme>,
+        trusted: LightBlock,
+    ) -> Result<LightClient, String> {
+        config.validate()?;
+        Ok(LightClient {
+            config,
+            scheme,
+            trusted,
+        })
+    }
+
+    pub fn trusted(&self) -> &LightBlock {
+        &self.trusted
+    }
+
+    // verify verifies the block from the trusted one, sequentially if it's
+    // for the next height and by skipping otherwise, and trusts it if it's
+    // valid. On NotEnoughTrust, verify a block in between first.
+    pub fn verify(&mut self, block: LightBlock, now: Duration) -> Result<(), VerifyError> {
+        if block.height == self.trusted.height + 1 {
+            verify_sequential(
+                &self.config,
+                self.scheme.as_ref(),
+                &self.trusted,
+                &block,
+                now,
+            )?;
+        } else {
+            verify_skipping(
+                &self.config,
+                self.scheme.as_ref(),
+                &self.trusted,
+                &block,
+                now,
+            )?;
+        }
+        self.trusted = block;
+        Ok(())
+    }
+
+    // verify_chain verifies the blocks in order, stopping at the first that
+    // fails. Returns the height of the latest trusted block.
+    pub fn verify_chain(
+        &mut self,
+        blocks: impl IntoIterator<Item = LightBlock>,
+        now: Duration,
+    ) -> Result<i64, VerifyError> {
+        for block in blocks {
+            self.verify(block, now)?;
+        }
+        Ok(self.trusted.height)
+    }
+}
+
+//---------------------------------------------------------------------
+// Misbehavior
+
+// detect_attack returns evidence of the attack if the conflicting block is
+// for another value than the chain's block at the same height. Both must
+// have been verified from the common block, the last one they agree on.
+//
+// The attack is lunatic if the conflicting block's validators aren't the
+// chain's: the ones of the common block that signed its commit are to blame.
+// Otherwise the chain's validators signed both commits. In the same round,
+// those that did equivocated. In different rounds, it's amnesia: some may
+// have been unlocked by a polka the light client didn't see.
+// Only validators whose precommits for the commits are signed, as the
+// scheme verifies, are to blame: anyone can name others in a commit.
+pub fn detect_attack(
+    config: &LightConfig,
+    scheme: &dyn SignatureScheme,
+    common: &LightBlock,
+    chain: &LightBlock,
+    conflicting: &LightBlock,
+) -> Option<Evidence> {
+    if chain.height != conflicting.height || chain.commit.value == conflicting.commit.value {
+        return None;
+    }
+    let signers = |block: &LightBlock| -> Vec<usize> {
+        let (commit, verifier) = (&block.commit, scheme.verifier(&block.validators));
+        let for_commit = |v: &Vote| {
+            v.typ == VoteType::Precommit
+                && v.height == commit.height
+                && v.round == commit.round
+                && v.value == Some(commit.value)
+        };
+        commit
+            .precommits
+            .iter()
+            .filter(|s| for_commit(&s.msg) && block.validators.get(s.signer).is_some())
+            .filter(|s| s.is_valid_as(verifier.as_ref(), &config.sign_format))
+            .map(|s| s.signer)
+            .collect()
+    };
+    let (kind, mut byzantine): (_, Vec<usize>) =
+        if conflicting.validators.hash() != chain.validators.hash() {
+            let byzantine = signers(conflicting)
+                .into_iter()
+                .filter_map(|i| conflicting.validators.get(i))
+                .filter_map(|val| common.validators.index_of(&val.address::<Sha256>()))
+                .collect();
+            (AttackKind::Lunatic, byzantine)
+        } else if conflicting.commit.round == chain.commit.round {
+            let both = signers(chain);
+            let byzantine = signers(conflicting)
+                .into_iter()
+                .filter(|i| both.contains(i))
+                .collect();
+            (AttackKind::Equivocation, byzantine)
+        } else {
+            (AttackKind::Amnesia, Vec::new())
+        };
+    byzantine.sort_unstable();
+    byzantine.dedup();
+    Some(Evidence::LightClientAttack {
+        kind,
+        conflicting: Box::new(conflicting.clone()),
+        common_height: common.height,
+        byzantine,
+    })
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{key, precommit, validator_set, KeyScheme};
+    use crate::validators::Validator;
+
+    // block returns a block committed by the first validators of the set.
+    fn block(
+        height: i64,
+        validators: &ValidatorSet,
+        signers: usize,
+        next: &ValidatorSet,
+    ) -> LightBlock {
+        let mut block = LightBlock {
+            height,
+            time: Duration::from_secs(height as u64),
+            validators: validators.clone(),
+            next_validators_hash: next.hash(),
+            data_hash: [height as u8; 32],
+            commit: Commit {
+                height,
+                round: 0,
+                value: Value::new([0; 32]),
+                precommits: Vec::new(),
+            },
+        };
+        sign(&mut block, 0, 0..signers);
+        block
+    }
+
+    // sign replaces the block's commit with one for its header in the round,
+    // signed by the validators of its set.
+    fn sign(block: &mut LightBlock, round: i64, signers: impl Iterator<Item = usize>) {
+        let (height, value) = (block.height, block.value());
+        let precommits = signers
+            .map(|validator| precommit(&block.validators, validator, height, round, Some(value)))
+            .collect();
+        block.commit = Commit {
+            height,
+            round,
+            value,
+            precommits,
+        };
+    }
+
+    #[test]
+    fn sequential() {
+        let config = LightConfig::default();
+        let (a, b) = (
+            validator_set(&[1, 1, 1, 1]),
+            validator_set(&[1, 1, 1, 1, 1]),
+        );
+        let now = Duration::from_secs(10);
+        let mut client =
+            LightClient::new(config.clone(), Box::new(KeyScheme), block(1, &a, 3, &a)).unwrap();
+
+        // the set changes at height 3
+        let chain = vec![
+            block(2, &a, 3, &b),
+            block(3, &b, 4, &b),
+            block(4, &b, 4, &b),
+        ];
+        assert_eq!(client.verify_chain(chain, now), Ok(4));
+
+        let trusted = client.trusted().clone();
+        assert_eq!(
+            verify_sequential(&config, &KeyScheme, &trusted, &block(5, &a, 3, &a), now),
+            Err(VerifyError::ValidatorsMismatch)
+        );
+        assert!(matches!(
+            verify_sequential(&config, &KeyScheme, &trusted, &block(5, &b, 3, &b), now),
+            Err(VerifyError::BadCommit(_))
+        ));
+        let mut forged = block(5, &b, 4, &b);
+        forged.commit.precommits[3].signature = forged.commit.precommits[2].signature.clone();
+        assert!(matches!(
+            verify_sequential(&config, &KeyScheme, &trusted, &forged, now),
+            Err(VerifyError::BadCommit(_))
+        ));
+
+        // the commit signs the header's time and next validators
+        let mut moved = block(5, &b, 4, &b);
+        moved.time += Duration::from_secs(1);
+        assert_eq!(
+            verify_sequential(&config, &KeyScheme, &trusted, &moved, now),
+            Err(VerifyError::BadHeader)
+        );
+        let mut handed_over = block(5, &b, 4, &b);
+        handed_over.next_validators_hash = a.hash();
+        assert_eq!(
+            verify_sequential(&config, &KeyScheme, &trusted, &handed_over, now),
+            Err(VerifyError::BadHeader)
+        );
+        assert_eq!(
+            verify_sequential(&config, &KeyScheme, &trusted, &block(6, &b, 4, &b), now),
+            Err(VerifyError::BadHeight)
+        );
+        let later = Duration::from_secs(4) + config.trusting_period;
+        assert_eq!(
+            verify_sequential(&config, &KeyScheme, &trusted, &block(5, &b, 4, &b), later),
+            Err(VerifyError::Expired)
+        );
+    }
+
+    #[test]
+    fn attacks() {
+        let config = LightConfig::default();
+        let a = validator_set(&[1, 1, 1, 1]);
+        let common = block(1, &a, 3, &a);
+        let chain = block(5, &a, 3, &a);
+        let detect = |conflicting: &LightBlock| {
+            detect_attack(&config, &KeyScheme, &common, &chain, conflicting)
+        };
+        let attack = |conflicting: &LightBlock| match detect(conflicting) {
+            Some(Evidence::LightClientAttack {
+                kind,
+                common_height,
+                byzantine,
+                ..
+            }) => {
+                assert_eq!(common_height, 1);
+                Some((kind, byzantine))
+            }
+            _ => None,
+        };
+
+        // validators 1 and 2 signed both commits in round 0
+        let mut conflicting = block(5, &a, 0, &a);
+        conflicting.data_hash = [9; 32];
+        sign(&mut conflicting, 0, 1..4);
+        assert_eq!(
+            attack(&conflicting),
+            Some((AttackKind::Equivocation, vec![1, 2]))
+        );
+
+        // a precommit that isn't validator 2's own doesn't blame it
+        let mut forged = conflicting.clone();
+        forged.commit.precommits[1].signature = forged.commit.precommits[0].signature.clone();
+        assert_eq!(attack(&forged), Some((AttackKind::Equivocation, vec![1])));
+
+        // in another round, they may have been unlocked
+        sign(&mut conflicting, 1, 1..4);
+        assert_eq!(attack(&conflicting), Some((AttackKind::Amnesia, vec![])));
+
+        // a set with the validators of seeds 0 and 1 of the chain's, and
+        // three others; those two are to blame
+        let validator = |seed| Validator {
+            public_key: key(seed),
+            voting_power: 1,
+        };
+        let fake = ValidatorSet::new([0, 1, 4, 5, 6].map(validator).to_vec());
+        let mut lunatic = block(5, &fake, 0, &fake);
+        lunatic.data_hash = [9; 32];
+        sign(&mut lunatic, 0, 0..5);
+        let mut blamed: Vec<usize> = [0, 1]
+            .map(|seed| a.index_of(&validator(seed).address::<Sha256>()).unwrap())
+            .to_vec();
+        blamed.sort_unstable();
+        assert_eq!(
+            attack(&lunatic),
+            Some((AttackKind::Lunatic, blamed.clone()))
+        );
+
+        // nothing conflicts with the chain's own block, or another height
+        assert_eq!(attack(&chain), None);
+        assert_eq!(attack(&block(6, &a, 3, &a)), None);
+        let evidence = detect(&conflicting).unwrap();
+        assert_eq!(evidence.height(), 5);
+        assert_eq!(evidence.validator(), None);
+        assert_eq!(detect(&lunatic).unwrap().validator(), Some(blamed[0]));
+    }
+
+    #[test]
+    fn skipping() {
+        let config = LightConfig::default();
+        let now = Duration::from_secs(100);
+        let old = validator_set(&[1, 1, 1]);
+        let trusted = block(1, &old, 3, &old);
+
+        // the old validators keep voting power 1, and three new ones have 3
+        let new = validator_set(&[1, 1, 1, 3, 3, 3]);
+        let is_old = |i: usize| {
+            let address = new.get(i).unwrap().address::<Sha256>();
+            old.index_of(&address).is_some()
+        };
+        let signed_by = |n| {
+            // all the new validators, and n of the old ones
+            let old_ones = (0..6).filter(|&i| is_old(i)).take(n);
+            let signers = (0..6).filter(|&i| !is_old(i)).chain(old_ones);
+            let mut b = block(50, &new, 0, &new);
+            sign(&mut b, 0, signers);
+            b
+        };
+
+        // one of three old validators isn't more than a third
+        assert_eq!(
+            verify_skipping(&config, &KeyScheme, &trusted, &signed_by(1), now),
+            Err(VerifyError::NotEnoughTrust(1))
+        );
+        assert_eq!(
+            verify_skipping(&config, &KeyScheme, &trusted, &signed_by(2), now),
+            Ok(())
+        );
+
+        // a trust level with large terms doesn't overflow
+        let halves = LightConfig {
+            trust_level: TrustLevel {
+                numerator: i64::MAX / 2,
+                denominator: i64::MAX - 1,
+            },
+            ..config.clone()
+        };
+        assert_eq!(halves.validate(), Ok(()));
+        assert_eq!(
+            verify_skipping(&halves, &KeyScheme, &trusted, &signed_by(1), now),
+            Err(VerifyError::NotEnoughTrust(1))
+        );
+        assert_eq!(
+            verify_skipping(&halves, &KeyScheme, &trusted, &signed_by(2), now),
+            Ok(())
+        );
+
+        // nor can it be less than a third, more than all, or not a fraction
+        for (numerator, denominator) in [(0, 1), (1, 4), (4, 3), (1, 0), (-1, -3)] {
+            let config = LightConfig {
+                trust_level: TrustLevel {
+                    numerator,
+                    denominator,
+                },
+                ..config.clone()
+            };
+            assert!(LightClient::new(config, Box::new(KeyScheme), trusted.clone()).is_err());
+        }
+
+        let mut client = LightClient::new(config, Box::new(KeyScheme), trusted).unwrap();
+        assert_eq!(client.verify(signed_by(3), now), Ok(()));
+        assert_eq!(client.trusted().height, 50);
+    }
+}
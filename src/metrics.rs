@@ -0,0 +1,332 @@
+// Metrics measures how long each phase of consensus takes.
+//
+// The engine has no clock, so the driver keeps a MetricsRecorder next to it
+// and calls observe() with the engine and its outputs after each call, like
+// the watchdog. The recorder turns what changed into measurements labeled by
+// height and round, and hands them to the driver's Metrics, eg. to record in
+// histograms, so operators can see which phase is slow on their network.
+//
+// ConsensusGauges instead reads where the engine is right now: its height,
+// round and step, the round's proposer, and the voting power tallied for it,
+// formatted for Prometheus to scrape.
+
+use super::engine::{Engine, Output};
+use super::round_votes::VoteCountDump;
+use super::state_machine as sm;
+
+use std::time::Duration;
+
+// Metrics receives the measurements.
+pub trait Metrics {
+    // step_duration is how long the engine spent in the step of the round.
+    // Only Propose, Prevote and Precommit are measured.
+    fn step_duration(&mut self, height: i64, round: i64, step: sm::Step, duration: Duration);
+
+    // rounds_per_height is how many rounds it took to decide the height.
+    fn rounds_per_height(&mut self, height: i64, rounds: i64);
+
+    // proposal_to_decision is how long it took to decide the height in the
+    // round, from when we first had a proposal for the height.
+    fn proposal_to_decision(&mut self, height: i64, round: i64, duration: Duration);
+
+    // future_evictions is how many messages for future heights were evicted
+    // from the engine's buffer since the last observation, eg. to tune the
+    // config's max_future_per_peer.
+    fn future_evictions(&mut self, _evicted: u64) {}
+}
+
+// MetricsRecorder measures the engine's progress into Metrics.
+pub struct MetricsRecorder<M> {
+    metrics: M,
+    step: Option<(i64, i64, sm::Step, Duration)>, // the current step, and when it started
+    first_proposal: Option<(i64, Duration)>,      // height, and when we had its first proposal
+    evictions: u64,                               // the engine's future evictions so far
+}
+
+impl<M: Metrics> MetricsRecorder<M> {
+    pub fn new(metrics: M) -> MetricsRecorder<M> {
+        MetricsRecorder {
+            metrics,
+            step: None,
+            first_proposal: None,
+            evictions: 0,
+        }
+    }
+
+    pub fn metrics(&self) -> &M {
+        &self.metrics
+    }
+
+    // observe records what changed in the engine, given the outputs of the
+    // call that changed it. Steps the engine moved through within one call
+    // are not measured.
+    pub fn observe(&mut self, engine: &Engine, outputs: &[Output], now: Duration) {
+        let state = engine.state();
+        for output in outputs {
+            if let Output::Decision(d) = output {
+                self.decided(&state, *d, now);
+            }
+        }
+
+        let current = (state.height(), state.round(), state.step());
+        match self.step {
+            Some((h, r, s, _)) if (h, r, s) == current => {}
+            Some((h, r, s, since)) => {
+                if let sm::Step::Propose | sm::Step::Prevote | sm::Step::Precommit = s {
+                    let duration = now.saturating_sub(since);
+                    self.metrics.step_duration(h, r, s, duration);
+                }
+                self.step = Some((current.0, current.1, current.2, now));
+            }
+            None => self.step = Some((current.0, current.1, current.2, now)),
+        }
+
+        let evictions = engine.future_evictions();
+        if evictions > self.evictions {
+            self.metrics.future_evictions(evictions - self.evictions);
+            self.evictions = evictions;
+        }
+
+        let height = state.height();
+        let have_first = self.first_proposal.map(|(h, _)| h) == Some(height);
+        if !have_first && engine.proposal(state.round()).is_some() {
+            self.first_proposal = Some((height, now));
+        }
+    }
+
+    fn decided(&mut self, state: &sm::State, decision: sm::RoundValue, now: Duration) {
+        // we're either waiting for timeout commit, or already at the next height
+        let height = if state.step() == sm::Step::Commit {
+            state.height()
+        } else {
+            state.height() - 1
+        };
+        self.metrics.rounds_per_height(height, decision.round + 1);
+        if let Some((h, since)) = self.first_proposal {
+            if h == height {
+                let duration = now.saturating_sub(since);
+                self.metrics
+                    .proposal_to_decision(height, decision.round, duration);
+            }
+        }
+    }
+}
+
+//---------------------------------------------------------------------
+// Gauges
+
+// ConsensusGauges is the engine's current progress, for dashboards to show
+// live. Unlike Metrics, it's read from the engine whenever it's scraped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusGauges {
+    pub height: i64,
+    pub round: i64,
+    pub step: sm::Step,
+    pub proposer: Option<usize>, // the round's proposer, if known
+    pub prevote_power: f64,      // percent of the voting power prevoted in the round
+    pub precommit_power: f64,    // percent of the voting power precommitted in the round
+}
+
+impl ConsensusGauges {
+    // of returns the gauges of the engine's current state. The proposer is
+    // the one the engine's ProposerSelector expects, if it has one, or else
+    // the signer of the round's proposal, once we have it.
+    pub fn of(engine: &Engine) -> ConsensusGauges {
+        let state = engine.state();
+        let round = state.round();
+        let votes = engine.round_votes().into_iter().find(|v| v.round == round);
+        let percent = |count: &VoteCountDump, total: i64| {
+            let power: i64 = count.votes.iter().map(|v| v.weight).sum();
+            100.0 * power as f64 / total.max(1) as f64
+        };
+        let (prevote_power, precommit_power) = match &votes {
+            Some(v) => (
+                percent(&v.prevotes, v.total),
+                percent(&v.precommits, v.total),
+            ),
+            None => (0.0, 0.0),
+        };
+        ConsensusGauges {
+            height: state.height(),
+            round,
+            step: state.step(),
+            proposer: engine
+                .expected_proposer(round)
+                .or_else(|| engine.proposal(round).map(|p| p.signer)),
+            prevote_power,
+            precommit_power,
+        }
+    }
+
+    // to_prometheus formats the gauges in the Prometheus text format.
+    // The step is its number in the round, from 0 for NewRound to 4 for
+    // Commit, and the proposer is -1 while it isn't known.
+    pub fn to_prometheus(&self) -> String {
+        let step = match self.step {
+            sm::Step::NewRound => 0,
+            sm::Step::Propose => 1,
+            sm::Step::Prevote => 2,
+            sm::Step::Precommit => 3,
+            sm::Step::Commit => 4,
+        };
+        let proposer = self.proposer.map_or(-1, |p| p as i64);
+        let gauges = [
+            ("height", "Current height.", self.height.to_string()),
+            ("round", "Current round.", self.round.to_string()),
+            (
+                "step",
+                "Current step, from 0 (NewRound) to 4 (Commit).",
+                step.to_string(),
+            ),
+            (
+                "proposer",
+                "Index of the round's proposer, or -1.",
+                proposer.to_string(),
+            ),
+            (
+                "prevote_power_percent",
+                "Percent of the voting power prevoted in the round.",
+                self.prevote_power.to_string(),
+            ),
+            (
+                "precommit_power_percent",
+                "Percent of the voting power precommitted in the round.",
+                self.precommit_power.to_string(),
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in gauges.iter() {
+            let name = format!("{}_{}", GAUGE_PREFIX, name);
+            out += &format!("# HELP {} {}\n", name, help);
+            out += &format!("# TYPE {} gauge\n", name);
+            out += &format!("{} {}\n", name, value);
+        }
+        out
+    }
+}
+
+const GAUGE_PREFIX: &str = "consensus";
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::scenario::{proposal, test_engine, vote};
+    use crate::scheduler::Timers;
+    use crate::validators::RoundRobin;
+    use crate::{Proposal, Value, Vote};
+
+    #[derive(Debug, PartialEq)]
+    enum Measured {
+        Step(i64, i64, sm::Step, u64),
+        Rounds(i64, i64),
+        ProposalToDecision(i64, i64, u64),
+        Evictions(u64),
+    }
+
+    #[derive(Default)]
+    struct TestMetrics(Vec<Measured>);
+
+    impl Metrics for TestMetrics {
+        fn step_duration(&mut self, height: i64, round: i64, step: sm::Step, d: Duration) {
+            let ms = d.as_millis() as u64;
+            self.0.push(Measured::Step(height, round, step, ms));
+        }
+
+        fn rounds_per_height(&mut self, height: i64, rounds: i64) {
+            self.0.push(Measured::Rounds(height, rounds));
+        }
+
+        fn proposal_to_decision(&mut self, height: i64, round: i64, d: Duration) {
+            let ms = d.as_millis() as u64;
+            self.0.push(Measured::ProposalToDecision(height, round, ms));
+        }
+
+        fn future_evictions(&mut self, evicted: u64) {
+            self.0.push(Measured::Evictions(evicted));
+        }
+    }
+
+    #[test]
+    fn gauges() {
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        let value = Value::new([1; 32]);
+        engine.start_round(0, None);
+        let gauges = ConsensusGauges::of(&engine);
+        assert_eq!((gauges.step, gauges.proposer), (sm::Step::Propose, None));
+        assert_eq!(gauges.prevote_power, 0.0);
+
+        engine.execute(proposal(1, Proposal::new(1, 0, value, -1)));
+        engine.execute(vote(2, Vote::new_prevote(1, 0, None)));
+
+        // our prevote for the proposal, and one for nil
+        let gauges = ConsensusGauges::of(&engine);
+        assert_eq!(gauges.proposer, Some(1));
+        assert_eq!((gauges.prevote_power, gauges.precommit_power), (50.0, 0.0));
+        let text = gauges.to_prometheus();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "# HELP consensus_height Current height.",
+                "# TYPE consensus_height gauge",
+                "consensus_height 1"
+            ]
+        );
+        assert!(lines.contains(&"consensus_step 2"));
+        assert!(lines.contains(&"consensus_proposer 1"));
+        assert!(lines.contains(&"consensus_prevote_power_percent 50"));
+
+        // with a selector, the proposer is known before its proposal
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        engine.set_proposer_selector(Box::new(RoundRobin));
+        engine.start_round(0, None);
+        assert_eq!(ConsensusGauges::of(&engine).proposer, Some(1));
+    }
+
+    #[test]
+    fn record() {
+        let config = ConsensusConfig {
+            max_future_per_peer: 0,
+            ..ConsensusConfig::default()
+        };
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        let mut recorder = MetricsRecorder::new(TestMetrics::default());
+        let ms = Duration::from_millis;
+        let value = Value::new([1; 32]);
+        let proposal = proposal(1, Proposal::new(1, 0, value, -1));
+
+        let outputs = engine.start_round(0, None);
+        recorder.observe(&engine, &outputs, ms(0));
+        let msgs = vec![
+            (proposal, 1000),
+            (vote(3, Vote::new_prevote(2, 0, None)), 1050),
+            (vote(1, Vote::new_prevote(1, 0, Some(value))), 1100),
+            (vote(2, Vote::new_prevote(1, 0, Some(value))), 1200),
+            (vote(1, Vote::new_precommit(1, 0, Some(value))), 1300),
+            (vote(2, Vote::new_precommit(1, 0, Some(value))), 1400),
+        ];
+        for (msg, at) in msgs {
+            let outputs = engine.execute(msg);
+            recorder.observe(&engine, &outputs, ms(at));
+        }
+
+        use sm::Step::*;
+        assert_eq!(
+            recorder.metrics().0,
+            vec![
+                Measured::Step(1, 0, Propose, 1000),
+                Measured::Evictions(1),
+                Measured::Step(1, 0, Prevote, 200),
+                Measured::Rounds(1, 1),
+                Measured::ProposalToDecision(1, 0, 400),
+                Measured::Step(1, 0, Precommit, 200),
+            ]
+        );
+    }
+}
@@ -0,0 +1,353 @@
+// Otel exports timelines as OpenTelemetry traces, so teams already running
+// an observability stack see consensus alongside the rest of their systems.
+//
+// Each height is a trace, whose id every validator of the chain derives the
+// same way, so the collector joins what each of them saw at the height into
+// one timeline across the fleet. A validator's part of it is a span for the
+// height, a child span for each round it recorded, and a child span of the
+// round for each step, with an event for each proposal, threshold, timeout,
+// received message and decision recorded in the step.
+//
+// The driver exports a height once it's decided, eg. when the
+// TimelineRecorder moves past it: to_otlp_json() encodes the traces as the
+// body of an OTLP/HTTP request, for the driver to send to its collector with
+// its own HTTP client. Timelines to export must be recorded with times since
+// the Unix epoch.
+
+use super::hash::{Hasher, Sha256};
+use super::signature::json_string;
+use super::timeline::{Timeline, TimelineEvent};
+use super::to_hex;
+
+use std::time::Duration;
+
+// TraceId identifies the trace of a height.
+pub type TraceId = [u8; 16];
+
+// SpanId identifies a span within a trace.
+pub type SpanId = [u8; 8];
+
+// Span is a height, round or step as one validator saw it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    pub parent: Option<SpanId>, // None for the height
+    pub name: String,
+    pub start: Duration,
+    pub end: Duration,
+    pub attributes: Vec<(&'static str, i64)>,
+    pub events: Vec<(Duration, String)>,
+}
+
+// TraceExporter turns the timelines of a validator into spans.
+pub struct TraceExporter {
+    chain_id: String,
+    node: String, // the validator's name in the traces, eg. its address
+}
+
+impl TraceExporter {
+    pub fn new(chain_id: &str, node: &str) -> TraceExporter {
+        TraceExporter {
+            chain_id: chain_id.to_string(),
+            node: node.to_string(),
+        }
+    }
+
+    // trace_id returns the id of the height's trace: the first 16 bytes of
+    // the SHA-256 of the chain id and the height, so it's the same on every
+    // validator, and differs between chains.
+    pub fn trace_id(&self, height: i64) -> TraceId {
+        let mut data = self.chain_id.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&height.to_be_bytes());
+        let mut id = [0; 16];
+        id.copy_from_slice(&Sha256::hash(&data)[..16]);
+        id
+    }
+
+    // spans returns the spans of the timeline: the height first, then each
+    // round as it started, each followed by its steps. Each step ends when
+    // the next one starts, and the last when the timeline does; each round
+    // spans its first event to its last.
+    pub fn spans(&self, timeline: &Timeline) -> Vec<Span> {
+        let entries = &timeline.entries;
+        let (start, end) = match (
+            entries.iter().map(|e| e.at).min(),
+            entries.iter().map(|e| e.at).max(),
+        ) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Vec::new(),
+        };
+        let trace_id = self.trace_id(timeline.height);
+        let height = Span {
+            trace_id,
+            span_id: self.span_id(timeline.height, "height", 0),
+            parent: None,
+            name: format!("height {}", timeline.height),
+            start,
+            end,
+            attributes: vec![("consensus.height", timeline.height)],
+            events: Vec::new(),
+        };
+
+        // the round of each round and step span, as they started
+        let mut rounds: Vec<(i64, Span)> = Vec::new();
+        let mut steps: Vec<(i64, Span)> = Vec::new();
+        let mut open: Option<usize> = None; // the step span that's open, by index in steps
+        for e in entries {
+            let i = match rounds.iter().position(|(r, _)| *r == e.round) {
+                Some(i) => i,
+                None => {
+                    let round = Span {
+                        trace_id,
+                        span_id: self.span_id(timeline.height, "round", e.round),
+                        parent: Some(height.span_id),
+                        name: format!("round {}", e.round),
+                        start: e.at,
+                        end: e.at,
+                        attributes: round_attributes(timeline.height, e.round),
+                        events: Vec::new(),
+                    };
+                    rounds.push((e.round, round));
+                    rounds.len() - 1
+                }
+            };
+            let round = &mut rounds[i].1;
+            round.start = round.start.min(e.at);
+            round.end = round.end.max(e.at);
+
+            if let TimelineEvent::Step(step) = e.event {
+                if let Some(j) = open {
+                    steps[j].1.end = e.at;
+                }
+                let name = format!("{:?}", step).to_lowercase();
+                let index = steps.len() as i64;
+                let step = Span {
+                    trace_id,
+                    span_id: self.span_id(timeline.height, "step", index),
+                    parent: Some(round.span_id),
+                    name,
+                    start: e.at,
+                    end,
+                    attributes: round_attributes(timeline.height, e.round),
+                    events: Vec::new(),
+                };
+                steps.push((e.round, step));
+                open = Some(steps.len() - 1);
+                continue;
+            }
+            // events of the round we're in go on its step, others on their round
+            let event = (e.at, e.event.to_string());
+            match open.filter(|j| steps[*j].0 == e.round) {
+                Some(j) => steps[j].1.events.push(event),
+                None => round.events.push(event),
+            }
+        }
+
+        let mut spans = vec![height];
+        for (r, round) in rounds {
+            spans.push(round);
+            let of_round = steps.iter().filter(|(s, _)| *s == r);
+            spans.extend(of_round.map(|(_, step)| step.clone()));
+        }
+        spans
+    }
+
+    // to_otlp_json encodes the spans of the timelines as the JSON body of an
+    // OTLP/HTTP export request, with the chain id and the validator's name
+    // as attributes of the resource.
+    pub fn to_otlp_json(&self, timelines: &[&Timeline]) -> String {
+        let spans: Vec<String> = timelines
+            .iter()
+            .flat_map(|t| self.spans(t))
+            .map(|s| span_json(&s))
+            .collect();
+        format!(
+            "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{},{},{}]}},\
+             \"scopeSpans\":[{{\"scope\":{{\"name\":\"tendermint-rs\"}},\"spans\":[{}]}}]}}]}}",
+            string_attribute("service.name", "tendermint-rs"),
+            string_attribute("service.instance.id", &self.node),
+            string_attribute("consensus.chain_id", &self.chain_id),
+            spans.join(",")
+        )
+    }
+
+    // span_id returns the id of a span of the height: the first 8 bytes of
+    // the SHA-256 of the validator's name, the height, the kind of span,
+    // and its round or index.
+    fn span_id(&self, height: i64, kind: &str, n: i64) -> SpanId {
+        let mut data = self.node.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(kind.as_bytes());
+        data.extend_from_slice(&n.to_be_bytes());
+        let mut id = [0; 8];
+        id.copy_from_slice(&Sha256::hash(&data)[..8]);
+        id
+    }
+}
+
+fn round_attributes(height: i64, round: i64) -> Vec<(&'static str, i64)> {
+    vec![("consensus.height", height), ("consensus.round", round)]
+}
+
+//---------------------------------------------------------------------
+// OTLP
+
+fn string_attribute(key: &str, value: &str) -> String {
+    format!(
+        "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+        json_string(key),
+        json_string(value)
+    )
+}
+
+// span_json encodes the span in the OTLP JSON mapping: ids in hex, times
+// in nanoseconds since the Unix epoch as decimal strings, and the kind as
+// internal, as consensus isn't a request between services.
+fn span_json(span: &Span) -> String {
+    let attributes: Vec<String> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{{\"key\":{},\"value\":{{\"intValue\":\"{}\"}}}}",
+                json_string(key),
+                value
+            )
+        })
+        .collect();
+    let events: Vec<String> = span
+        .events
+        .iter()
+        .map(|(at, name)| {
+            format!(
+                "{{\"timeUnixNano\":\"{}\",\"name\":{}}}",
+                at.as_nanos(),
+                json_string(name)
+            )
+        })
+        .collect();
+    let parent = span.parent.map(|p| to_hex(&p)).unwrap_or_default();
+    format!(
+        "{{\"traceId\":\"{}\",\"spanId\":\"{}\",\"parentSpanId\":\"{}\",\"name\":{},\"kind\":1,\
+         \"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}],\"events\":[{}]}}",
+        to_hex(&span.trace_id),
+        to_hex(&span.span_id),
+        parent,
+        json_string(&span.name),
+        span.start.as_nanos(),
+        span.end.as_nanos(),
+        attributes.join(","),
+        events.join(",")
+    )
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rejected::MessageKind;
+    use crate::state_machine::{Step, TimeoutStep};
+    use crate::timeline::TimelineEntry;
+    use crate::VoteType;
+
+    fn timeline(height: i64) -> Timeline {
+        let ms = Duration::from_millis;
+        let entry = |at, round, event| TimelineEntry {
+            at: ms(at),
+            round,
+            event,
+        };
+        Timeline {
+            height,
+            entries: vec![
+                entry(0, 0, TimelineEvent::Step(Step::Propose)),
+                entry(3000, 0, TimelineEvent::TimeoutFired(TimeoutStep::Propose)),
+                entry(3000, 0, TimelineEvent::Step(Step::Prevote)),
+                entry(3100, 1, TimelineEvent::Step(Step::Propose)),
+                entry(
+                    3150,
+                    0,
+                    TimelineEvent::Received(MessageKind::Vote(VoteType::Prevote), 2),
+                ),
+                entry(3200, 1, TimelineEvent::Proposal),
+                entry(3400, 1, TimelineEvent::Decision),
+            ],
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let a = TraceExporter::new("test-chain", "a");
+        let b = TraceExporter::new("test-chain", "b");
+        // the same trace on every validator, one per height and chain
+        assert_eq!(a.trace_id(7), b.trace_id(7));
+        assert_ne!(a.trace_id(7), a.trace_id(8));
+        assert_ne!(a.trace_id(7), TraceExporter::new("other", "a").trace_id(7));
+
+        let spans = a.spans(&timeline(7));
+        let names: Vec<&str> = spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["height 7", "round 0", "propose", "prevote", "round 1", "propose"]
+        );
+        assert!(spans.iter().all(|s| s.trace_id == a.trace_id(7)));
+        let id = |i: usize| Some(spans[i].span_id);
+        let parents: Vec<_> = spans.iter().map(|s| s.parent).collect();
+        assert_eq!(parents, vec![None, id(0), id(1), id(1), id(0), id(4)]);
+        let times: Vec<_> = spans
+            .iter()
+            .map(|s| (s.start.as_millis(), s.end.as_millis()))
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                (0, 3400),
+                (0, 3150),
+                (0, 3000),
+                (3000, 3100),
+                (3100, 3400),
+                (3100, 3400)
+            ]
+        );
+        // a late vote for round 0 goes on the round, as its step is over
+        let events =
+            |i: usize| -> Vec<&str> { spans[i].events.iter().map(|(_, e)| e.as_str()).collect() };
+        assert_eq!(events(1), vec!["received prevote 2"]);
+        assert_eq!(events(2), vec!["timeout propose"]);
+        assert_eq!(events(5), vec!["proposal", "decision"]);
+        let ids: Vec<_> = b.spans(&timeline(7)).iter().map(|s| s.span_id).collect();
+        assert!(spans.iter().all(|s| !ids.contains(&s.span_id)));
+
+        let empty = Timeline {
+            height: 8,
+            entries: Vec::new(),
+        };
+        assert!(a.spans(&empty).is_empty());
+    }
+
+    #[test]
+    fn export() {
+        let exporter = TraceExporter::new("test-chain", "a");
+        let timeline = timeline(7);
+        let body = exporter.to_otlp_json(&[&timeline]);
+        let trace_id = to_hex(&exporter.trace_id(7));
+        assert!(body.starts_with(
+            "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\""
+        ));
+        assert!(
+            body.contains("{\"key\":\"service.instance.id\",\"value\":{\"stringValue\":\"a\"}}")
+        );
+        assert_eq!(
+            body.matches(&format!("\"traceId\":\"{}\"", trace_id))
+                .count(),
+            6
+        );
+        assert!(body.contains("\"name\":\"height 7\",\"kind\":1,\"startTimeUnixNano\":\"0\",\"endTimeUnixNano\":\"3400000000\""));
+        assert!(body.contains("{\"timeUnixNano\":\"3150000000\",\"name\":\"received prevote 2\"}"));
+    }
+}
@@ -0,0 +1,208 @@
+// Rate limiting of the messages peers send us.
+//
+// Verifying and applying a message costs the engine work even when the
+// message turns out to be useless, eg. a duplicate vote or one for a stale
+// round. The driver asks the RateLimiter before passing a peer's message to
+// the engine, and drops it if the peer sent more messages of its kind than
+// its rate allows, so a single peer can't flood the engine.
+
+use super::engine::Message;
+use super::gossip::PeerId;
+use super::rejected::MessageKind;
+use super::VoteType;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Rate is how many messages may be taken per second on average,
+// and how many at once after a quiet period.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+// RateLimitConfig sets the rate of each kind of message, per peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub proposal: Rate,
+    pub prevote: Rate,
+    pub precommit: Rate,
+    pub heartbeat: Rate,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        // peers relay the votes of every validator, so votes get more room
+        RateLimitConfig {
+            proposal: Rate {
+                per_second: 10,
+                burst: 20,
+            },
+            prevote: Rate {
+                per_second: 200,
+                burst: 400,
+            },
+            precommit: Rate {
+                per_second: 200,
+                burst: 400,
+            },
+            heartbeat: Rate {
+                per_second: 10,
+                burst: 20,
+            },
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn rate(&self, kind: MessageKind) -> Rate {
+        match kind {
+            MessageKind::Proposal => self.proposal,
+            MessageKind::Vote(VoteType::Prevote) => self.prevote,
+            MessageKind::Vote(VoteType::Precommit) => self.precommit,
+            MessageKind::Heartbeat => self.heartbeat,
+        }
+    }
+}
+
+// Bucket is a token bucket: it refills at the rate, up to its burst,
+// and each message takes a token.
+struct Bucket {
+    tokens: f64,
+    last: Duration, // when it was last refilled
+}
+
+impl Bucket {
+    fn take(&mut self, rate: Rate, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        let tokens = self.tokens + elapsed * f64::from(rate.per_second);
+        self.tokens = tokens.min(f64::from(rate.burst));
+        self.last = self.last.max(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// RateLimiter keeps a bucket per peer and kind of message.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<(PeerId, MessageKind), Bucket>,
+    dropped: HashMap<PeerId, u64>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: HashMap::new(),
+            dropped: HashMap::new(),
+        }
+    }
+
+    // set_config changes the rates, eg. as an operator tunes a live node,
+    // from the next message on. Buckets keep their tokens, up to the new burst.
+    pub fn set_config(&mut self, config: RateLimitConfig) {
+        self.config = config;
+    }
+
+    // allow returns true if the message from the peer is within its rate,
+    // and should be passed to the engine. Timeouts are always allowed.
+    pub fn allow(&mut self, peer: PeerId, msg: &Message, now: Duration) -> bool {
+        let kind = match msg {
+            Message::Proposal(_) | Message::ProposalWithPol(..) => MessageKind::Proposal,
+            Message::Vote(v) => MessageKind::Vote(v.msg.typ),
+            Message::Heartbeat(_) => MessageKind::Heartbeat,
+            Message::TimeoutElapsed(_) => return true,
+        };
+        let rate = self.config.rate(kind);
+        let bucket = self.buckets.entry((peer, kind)).or_insert(Bucket {
+            tokens: f64::from(rate.burst),
+            last: now,
+        });
+        if bucket.take(rate, now) {
+            return true;
+        }
+        *self.dropped.entry(peer).or_insert(0) += 1;
+        false
+    }
+
+    // dropped returns how many messages from the peer weren't allowed,
+    // eg. to disconnect peers that keep going over their rate.
+    pub fn dropped(&self, peer: PeerId) -> u64 {
+        self.dropped.get(&peer).cloned().unwrap_or(0)
+    }
+
+    // remove_peer forgets the peer, once it disconnects.
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.buckets.retain(|(p, _), _| *p != peer);
+        self.dropped.remove(&peer);
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::Signed;
+    use crate::{Value, Vote};
+
+    #[test]
+    fn rate_limit() {
+        let rate = Rate {
+            per_second: 2,
+            burst: 3,
+        };
+        let config = RateLimitConfig {
+            proposal: rate,
+            prevote: rate,
+            precommit: rate,
+            heartbeat: rate,
+        };
+        let mut limiter = RateLimiter::new(config.clone());
+        let ms = Duration::from_millis;
+        let vote = |vote| {
+            Message::Vote(Signed {
+                signer: 1,
+                msg: vote,
+                signature: vec![],
+            })
+        };
+        let prevote = vote(Vote::new_prevote(1, 0, Some(Value::new([1; 32]))));
+        let precommit = vote(Vote::new_precommit(1, 0, None));
+
+        // a burst, then nothing until the bucket refills
+        for _ in 0..3 {
+            assert!(limiter.allow(1, &prevote, ms(0)));
+        }
+        assert!(!limiter.allow(1, &prevote, ms(0)));
+        assert!(!limiter.allow(1, &prevote, ms(400)));
+        assert!(limiter.allow(1, &prevote, ms(500)));
+        assert!(!limiter.allow(1, &prevote, ms(500)));
+        assert_eq!(limiter.dropped(1), 3);
+
+        // other kinds and peers have their own buckets
+        assert!(limiter.allow(1, &precommit, ms(500)));
+        assert!(limiter.allow(2, &prevote, ms(500)));
+
+        // a lower rate applies from the next message
+        limiter.set_config(RateLimitConfig {
+            prevote: Rate {
+                per_second: 1,
+                burst: 1,
+            },
+            ..config
+        });
+        assert!(limiter.allow(2, &prevote, ms(500)));
+        assert!(!limiter.allow(2, &prevote, ms(500)));
+
+        limiter.remove_peer(1);
+        assert_eq!(limiter.dropped(1), 0);
+        assert!(limiter.allow(1, &prevote, ms(500)));
+    }
+}
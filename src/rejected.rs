@@ -0,0 +1,80 @@
+use super::engine::CorrelationId;
+use super::gossip::PeerId;
+use super::VoteType;
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+// RejectReason is why the engine dropped a message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    InvalidSignature,  // The signature didn't verify.
+    WrongHeight,       // For a height other than the current one.
+    StaleRound,        // For a round before the late vote window.
+    NotForCommit,      // For the previous height, but not a missing precommit of its commit.
+    UnknownValidator,  // The signer isn't in the validator set.
+    DuplicateProposal, // We already have a proposal for the round.
+    WorkLimit,         // Over the config's max_messages_per_call.
+    DuplicateVote,     // We already counted the same vote from the signer.
+    Equivocation,      // The signer already voted differently; it's evidence.
+    Halted,            // We're past the halt height, or the upgrade height.
+    InvalidPol,        // The votes carried by the proposal aren't a polka for it.
+    TooFarAhead,       // For a round past the config's future_rounds.
+    Evicted,           // Buffered for a future height, then evicted to make room.
+    OldHeartbeat,      // Not newer than the signer's latest heartbeat in the round.
+    WrongProposer,     // A proposal from a validator other than the round's proposer.
+    ConflictingSign,   // Our own proposal or vote, not signed as it conflicts with the sign state.
+    InvalidValue,      // A proposal whose value the application rejected.
+}
+
+// MessageKind is the kind of a rejected message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Proposal,
+    Vote(VoteType),
+    Heartbeat,
+}
+
+// Rejected records a message the engine dropped, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejected {
+    pub reason: RejectReason,
+    pub kind: MessageKind,
+    pub signer: usize, // index of the validator that signed the message
+    pub height: i64,
+    pub round: i64,
+    pub at: SystemTime,
+    pub correlation_id: CorrelationId, // the call to the engine that rejected it
+    pub peer: Option<PeerId>,          // the peer it came from, if the caller said
+}
+
+// RejectedLog keeps the most recently rejected messages, up to its capacity.
+pub(crate) struct RejectedLog {
+    capacity: usize,
+    rejected: VecDeque<Rejected>,
+}
+
+impl RejectedLog {
+    pub(crate) fn new(capacity: usize) -> RejectedLog {
+        RejectedLog {
+            capacity,
+            rejected: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // push records the rejection, dropping the oldest one if the log is full.
+    pub(crate) fn push(&mut self, rejected: Rejected) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.rejected.len() == self.capacity {
+            self.rejected.pop_front();
+        }
+        self.rejected.push_back(rejected);
+    }
+
+    // iter returns the rejections, oldest first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Rejected> {
+        self.rejected.iter()
+    }
+}
@@ -0,0 +1,206 @@
+// Resend keeps sending our own proposal and votes until peers have them.
+//
+// The transport may lose messages. The driver records the proposals and votes
+// the engine outputs with sent(), and acknowledges them per peer as it learns
+// they propagated: when a peer relays them back to us, or announces them in
+// a Maj23's bits. due() returns the messages to send again to the peers that
+// haven't acknowledged them, every interval, until the engine's step advances.
+
+use super::engine::Output;
+use super::gossip::{Maj23, PeerId, VoteSetId};
+use super::state_machine as sm;
+use super::{Proposal, Vote};
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+// ResendConfig sets how often unacknowledged messages are sent again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResendConfig {
+    pub interval: Duration,
+}
+
+impl Default for ResendConfig {
+    fn default() -> ResendConfig {
+        ResendConfig {
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// Pending is one of our messages that not every peer acknowledged.
+struct Pending {
+    output: Output,
+    step: (i64, i64, sm::Step), // the engine's height, round and step when it was sent
+    acked: HashSet<PeerId>,
+    next: Duration, // when to send it again
+}
+
+// Resender tracks which peers have our latest messages.
+pub struct Resender {
+    config: ResendConfig,
+    peers: Vec<PeerId>,
+    pending: Vec<Pending>,
+}
+
+impl Resender {
+    pub fn new(config: ResendConfig, peers: Vec<PeerId>) -> Resender {
+        Resender {
+            config,
+            peers,
+            pending: Vec::new(),
+        }
+    }
+
+    // sent records the proposals and votes among the engine's outputs,
+    // which were just broadcast. state is the engine's state after
+    // producing them.
+    pub fn sent(&mut self, state: &sm::State, outputs: &[Output], now: Duration) {
+        let step = (state.height(), state.round(), state.step());
+        for output in outputs {
+            if let Output::Proposal(_) | Output::Vote(_) = output {
+                self.pending.push(Pending {
+                    output: output.clone(),
+                    step,
+                    acked: HashSet::new(),
+                    next: now + self.config.interval,
+                });
+            }
+        }
+    }
+
+    // acked_proposal records that the peer has our proposal,
+    // eg. because it relayed it back to us.
+    pub fn acked_proposal(&mut self, peer: PeerId, proposal: &Proposal) {
+        for p in self.pending.iter_mut() {
+            if let Output::Proposal(own) = &p.output {
+                if own.msg == *proposal {
+                    p.acked.insert(peer);
+                }
+            }
+        }
+    }
+
+    // acked_vote records that the peer has our vote,
+    // eg. because it relayed it back to us.
+    pub fn acked_vote(&mut self, peer: PeerId, vote: &Vote) {
+        for p in self.pending.iter_mut() {
+            if let Output::Vote(own) = &p.output {
+                if own.msg == *vote {
+                    p.acked.insert(peer);
+                }
+            }
+        }
+    }
+
+    // acked_maj23 records the peer as having our votes that are
+    // covered by the bits of its announcement.
+    pub fn acked_maj23(&mut self, peer: PeerId, maj23: &Maj23) {
+        for p in self.pending.iter_mut() {
+            if let Output::Vote(own) = &p.output {
+                let covered = VoteSetId::of(&own.msg) == maj23.id
+                    && own.msg.value == maj23.value
+                    && maj23.bits.get(own.signer).cloned().unwrap_or(false);
+                if covered {
+                    p.acked.insert(peer);
+                }
+            }
+        }
+    }
+
+    // is_propagated returns true if every peer acknowledged the output,
+    // or it's no longer tracked.
+    pub fn is_propagated(&self, output: &Output) -> bool {
+        let peers = &self.peers;
+        self.pending
+            .iter()
+            .filter(|p| p.output == *output)
+            .all(|p| peers.iter().all(|peer| p.acked.contains(peer)))
+    }
+
+    // due returns the messages to send again, with the peers to send them to.
+    // Messages are forgotten once every peer acknowledged them, or once the
+    // engine's step differs from the one they were sent in.
+    pub fn due(&mut self, state: &sm::State, now: Duration) -> Vec<(Output, Vec<PeerId>)> {
+        let step = (state.height(), state.round(), state.step());
+        let peers = &self.peers;
+        self.pending
+            .retain(|p| p.step == step && peers.iter().any(|peer| !p.acked.contains(peer)));
+
+        let mut due = Vec::new();
+        for p in self.pending.iter_mut().filter(|p| p.next <= now) {
+            let missing = peers
+                .iter()
+                .filter(|peer| !p.acked.contains(peer))
+                .cloned()
+                .collect();
+            due.push((p.output.clone(), missing));
+            p.next = now + self.config.interval;
+        }
+        due
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::scenario::{test_engine, vote};
+    use crate::scheduler::Timers;
+    use crate::Value;
+
+    #[test]
+    fn resend_until_acked() {
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        let mut resender = Resender::new(ResendConfig::default(), vec![1, 2, 3]);
+        let secs = Duration::from_secs;
+
+        let outputs = engine.start_round(0, Some(Value::new([1; 32])));
+        resender.sent(&engine.state(), &outputs, secs(0));
+        let (proposal, prevote) = match &outputs[..] {
+            [Output::Proposal(p), Output::Vote(v)] => (p.msg.clone(), v.msg),
+            _ => panic!("unexpected {:?}", outputs),
+        };
+        assert!(resender.due(&engine.state(), secs(0)).is_empty());
+
+        // peer 1 relays both back, peer 2 only announces our prevote
+        resender.acked_proposal(1, &proposal);
+        resender.acked_vote(1, &prevote);
+        resender.acked_maj23(
+            2,
+            &Maj23 {
+                id: VoteSetId::of(&prevote),
+                value: prevote.value,
+                bits: vec![true, true, true, false],
+            },
+        );
+        assert!(!resender.is_propagated(&outputs[0]));
+
+        let due = resender.due(&engine.state(), secs(1));
+        assert_eq!(
+            due,
+            vec![
+                (outputs[0].clone(), vec![2, 3]),
+                (outputs[1].clone(), vec![3])
+            ]
+        );
+        assert!(resender.due(&engine.state(), secs(1)).is_empty());
+
+        // once every peer has it, it's no longer sent
+        resender.acked_vote(3, &prevote);
+        assert!(resender.is_propagated(&outputs[1]));
+        let due = resender.due(&engine.state(), secs(2));
+        assert_eq!(due, vec![(outputs[0].clone(), vec![2, 3])]);
+
+        // nor once the step advances
+        for signer in 1..3 {
+            engine.execute(vote(signer, prevote));
+        }
+        assert_eq!(engine.state().step(), sm::Step::Precommit);
+        assert!(resender.due(&engine.state(), secs(3)).is_empty());
+    }
+}
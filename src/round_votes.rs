@@ -1,5 +1,12 @@
+use super::evidence::{self, Evidence};
+use super::signature::Signed;
 use super::{Value, Vote, VoteType};
 
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
 //-------------------------------------------------------------------------
 // Tally votes of the same type (eg. prevote or precommit)
 
@@ -9,17 +16,27 @@ struct ValueWeight {
     weight: i64,
 }
 
+// ValidatorVote is the vote of a single validator, as counted in a VoteCount.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ValidatorVote {
+    pub validator: usize, // index of the validator in the validator set
+    pub value: Option<Value>,
+    pub weight: i64,
+}
+
 // VoteCount tallys votes of the same type.
 // Votes are for nil or for some value.
-//(TODO: handle multiple values)
 struct VoteCount {
-    nil: i64,           // weight of votes for nil
-    value: ValueWeight, // weight of votes for the value
+    nil: i64,                 // weight of votes for nil
+    values: Vec<ValueWeight>, // weight of votes for each value
     total: i64,
+
+    votes: Vec<ValidatorVote>, // votes in the order they were counted
+    thresh: Thresh,            // highest threshold hit so far
 }
 
 // Thresh represents the different quorum thresholds.
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Thresh {
     Init,         // no quorum
     Any,          // quorum of votes but not for the same value
@@ -27,46 +44,162 @@ pub enum Thresh {
     Value(Value), // quorum for the value
 }
 
+// TallyOutcome is the result of adding a vote to the tally.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TallyOutcome {
+    // The threshold this vote crossed, if it raised the highest threshold.
+    // Each threshold is reported once, with the vote that crossed it.
+    pub new_threshold: Option<Thresh>,
+    pub current: Thresh,            // highest threshold hit so far
+    pub vote_accepted: bool,        // whether the vote was counted
+    pub evidence: Option<Evidence>, // if the vote conflicts with one counted before
+}
+
 // is_quorum returns true if value > (2/3)*total.
-fn is_quorum(value: i64, total: i64) -> bool {
+pub fn is_quorum(value: i64, total: i64) -> bool {
     3 * value > 2 * total
 }
 
+// remaining_to_quorum returns how much more weight value needs to be a quorum,
+// or 0 if it already is.
+pub fn remaining_to_quorum(value: i64, total: i64) -> i64 {
+    (2 * total / 3 + 1 - value).max(0)
+}
+
 impl VoteCount {
     fn new(total: i64) -> VoteCount {
         VoteCount {
             nil: 0,
-            value: ValueWeight {
-                value: Value {}, // TODO
-                weight: 0,
-            },
+            values: Vec::new(),
             total,
+            votes: Vec::new(),
+            thresh: Thresh::Init,
         }
     }
 
-    // Add vote to internal counters and return the highest threshold.
-    fn add_vote(&mut self, vote: Vote, weight: i64) -> Thresh {
+    // weight_for returns the weight of votes for the value.
+    fn weight_for(&self, value: &Value) -> i64 {
+        self.values
+            .iter()
+            .find(|vw| vw.value == *value)
+            .map_or(0, |vw| vw.weight)
+    }
+
+    // weight returns the weight of all votes, for nil or any value.
+    fn weight(&self) -> i64 {
+        self.nil + self.values.iter().map(|vw| vw.weight).sum::<i64>()
+    }
+
+    // Add vote to internal counters, and return the threshold it crossed, if any.
+    fn add_vote(&mut self, validator: usize, vote: Vote, weight: i64) -> TallyOutcome {
+        self.votes.push(ValidatorVote {
+            validator,
+            value: vote.value,
+            weight,
+        });
         match vote.value {
-            Some(v) => {
-                // TODO: handle multi values
-                self.value.weight += weight;
-                self.value.value = v;
-            }
+            Some(value) => match self.values.iter_mut().find(|vw| vw.value == value) {
+                Some(vw) => vw.weight += weight,
+                None => self.values.push(ValueWeight { value, weight }),
+            },
             None => self.nil += weight,
         }
 
-        if is_quorum(self.value.weight, self.total) {
-            Thresh::Value(self.value.value)
+        let sum: i64 = self.values.iter().map(|vw| vw.weight).sum();
+        let quorum = self
+            .values
+            .iter()
+            .find(|vw| is_quorum(vw.weight, self.total));
+        let thresh = if let Some(vw) = quorum {
+            Thresh::Value(vw.value)
         } else if is_quorum(self.nil, self.total) {
             Thresh::Nil
-        } else if is_quorum(self.value.weight + self.nil, self.total) {
+        } else if is_quorum(sum + self.nil, self.total) {
             Thresh::Any
         } else {
             Thresh::Init
+        };
+        let new_threshold = if thresh != Thresh::Init && thresh != self.thresh {
+            self.thresh = thresh;
+            Some(thresh)
+        } else {
+            None
+        };
+        TallyOutcome {
+            new_threshold,
+            current: self.thresh,
+            vote_accepted: true,
+            evidence: None,
+        }
+    }
+
+    fn dump(&self) -> VoteCountDump {
+        VoteCountDump {
+            thresh: self.thresh,
+            votes: self.votes.clone(),
         }
     }
 }
 
+//-------------------------------------------------------------------------
+// Signed votes
+
+// VoteKey identifies a vote each validator may only cast once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VoteKey {
+    pub validator: usize,
+    pub height: i64,
+    pub round: i64,
+    pub typ: VoteType,
+}
+
+impl VoteKey {
+    pub fn of(vote: &Signed<Vote>) -> VoteKey {
+        VoteKey {
+            validator: vote.signer,
+            height: vote.msg.height,
+            round: vote.msg.round,
+            typ: vote.msg.typ,
+        }
+    }
+}
+
+// Seen is how a signed vote relates to the votes already seen.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Seen {
+    First,              // The first vote for its key.
+    Duplicate,          // The same vote again.
+    Conflict(Evidence), // A different vote for the same key: the validator equivocated.
+}
+
+// VoteCache keeps the first signed vote seen for each key.
+#[derive(Default)]
+pub struct VoteCache {
+    votes: HashMap<VoteKey, Signed<Vote>>,
+}
+
+impl VoteCache {
+    // insert keeps the vote if it's the first for its key.
+    // Otherwise it returns whether it's a duplicate of the one we kept,
+    // or the evidence that it conflicts with it.
+    pub fn insert(&mut self, vote: &Signed<Vote>) -> Seen {
+        match self.votes.entry(VoteKey::of(vote)) {
+            Entry::Vacant(e) => {
+                e.insert(vote.clone());
+                Seen::First
+            }
+            Entry::Occupied(e) => match evidence::duplicate_vote(e.get(), vote) {
+                Some(evidence) => Seen::Conflict(evidence),
+                None => Seen::Duplicate,
+            },
+        }
+    }
+
+    pub fn get(&self, key: &VoteKey) -> Option<&Signed<Vote>> {
+        self.votes.get(key)
+    }
+}
+
 //-------------------------------------------------------------------------
 // RoundVotes
 
@@ -77,6 +210,7 @@ pub struct RoundVotes {
 
     prevotes: VoteCount,
     precommits: VoteCount,
+    signed: VoteCache, // the signed votes counted by add_signed_vote
 }
 
 impl RoundVotes {
@@ -86,14 +220,328 @@ impl RoundVotes {
             round,
             prevotes: VoteCount::new(total),
             precommits: VoteCount::new(total),
+            signed: VoteCache::default(),
         }
     }
 
-    pub fn add_vote(&mut self, vote: Vote, weight: i64) -> Thresh {
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+
+    pub fn round(&self) -> i64 {
+        self.round
+    }
+
+    // Add the vote of the validator at the given index in the validator set.
+    pub fn add_vote(&mut self, validator: usize, vote: Vote, weight: i64) -> TallyOutcome {
         match vote.typ {
-            VoteType::Prevote => self.prevotes.add_vote(vote, weight),
-            VoteType::Precommit => self.precommits.add_vote(vote, weight),
+            VoteType::Prevote => self.prevotes.add_vote(validator, vote, weight),
+            VoteType::Precommit => self.precommits.add_vote(validator, vote, weight),
+        }
+    }
+
+    // add_signed_vote counts the signed vote if it's the first from its signer
+    // for its type. Duplicates and conflicting votes are not counted,
+    // and a conflicting vote comes with the evidence.
+    pub fn add_signed_vote(&mut self, vote: &Signed<Vote>, weight: i64) -> TallyOutcome {
+        let evidence = match self.signed.insert(vote) {
+            Seen::First => return self.add_vote(vote.signer, vote.msg, weight),
+            Seen::Duplicate => None,
+            Seen::Conflict(evidence) => Some(evidence),
+        };
+        TallyOutcome {
+            new_threshold: None,
+            current: self.count(vote.msg.typ).thresh,
+            vote_accepted: false,
+            evidence,
+        }
+    }
+
+    // signed_vote returns the signed vote counted for the validator, if any.
+    pub fn signed_vote(&self, validator: usize, typ: VoteType) -> Option<&Signed<Vote>> {
+        self.signed.get(&VoteKey {
+            validator,
+            height: self.height,
+            round: self.round,
+            typ,
+        })
+    }
+
+    // votes returns the votes of the given type in the order they were counted.
+    pub fn votes(&self, typ: VoteType) -> &[ValidatorVote] {
+        &self.count(typ).votes
+    }
+
+    // weight_for returns the weight of votes of the given type for the value.
+    pub fn weight_for(&self, typ: VoteType, value: &Value) -> i64 {
+        self.count(typ).weight_for(value)
+    }
+
+    // weight_nil returns the weight of votes of the given type for nil.
+    pub fn weight_nil(&self, typ: VoteType) -> i64 {
+        self.count(typ).nil
+    }
+
+    // weight returns the weight of all votes of the given type.
+    pub fn weight(&self, typ: VoteType) -> i64 {
+        self.count(typ).weight()
+    }
+
+    // remaining_to_polka returns how much more prevote weight the round needs
+    // for +2/3 prevotes for anything, or 0 if it has them.
+    pub fn remaining_to_polka(&self) -> i64 {
+        remaining_to_quorum(self.prevotes.weight(), self.prevotes.total)
+    }
+
+    // remaining_to_quorum returns how much more weight of votes of the given
+    // type for the value (or nil) the round needs for +2/3 of them,
+    // or 0 if it has them.
+    pub fn remaining_to_quorum(&self, typ: VoteType, value: Option<Value>) -> i64 {
+        let count = self.count(typ);
+        let weight = match value {
+            Some(v) => count.weight_for(&v),
+            None => count.nil,
+        };
+        remaining_to_quorum(weight, count.total)
+    }
+
+    fn count(&self, typ: VoteType) -> &VoteCount {
+        match typ {
+            VoteType::Prevote => &self.prevotes,
+            VoteType::Precommit => &self.precommits,
+        }
+    }
+
+    // dump exports the tally so it can be compared with another node's view.
+    pub fn dump(&self) -> RoundVotesDump {
+        RoundVotesDump {
+            height: self.height,
+            round: self.round,
+            total: self.prevotes.total,
+            prevotes: self.prevotes.dump(),
+            precommits: self.precommits.dump(),
+        }
+    }
+
+    // from_dump rebuilds the tally by recounting the dumped votes.
+    pub fn from_dump(dump: &RoundVotesDump) -> RoundVotes {
+        let mut round_votes = RoundVotes::new(dump.height, dump.round, dump.total);
+        for v in &dump.prevotes.votes {
+            let vote = Vote::new_prevote(dump.height, dump.round, v.value);
+            round_votes.add_vote(v.validator, vote, v.weight);
+        }
+        for v in &dump.precommits.votes {
+            let vote = Vote::new_precommit(dump.height, dump.round, v.value);
+            round_votes.add_vote(v.validator, vote, v.weight);
+        }
+        round_votes
+    }
+}
+
+//-------------------------------------------------------------------------
+// Dump
+//
+// RoundVotesDump is a stable, line-based text encoding of a RoundVotes,
+// meant to be diffed between nodes stuck at the same height. For example:
+//
+//   round_votes v1
+//   height 1
+//   round 0
+//   total 4
+//   prevote thresh value 0101..01
+//   prevote bits x_xx
+//   prevote vote 0 0101..01 1
+//   prevote vote 2 nil 1
+//   prevote vote 3 0101..01 1
+//   precommit thresh init
+//   precommit bits ____
+//
+// Values are written as their id in hex (shortened above).
+// Votes are listed in the order they were counted. Bit arrays have one
+// entry per validator index up to the highest index that voted,
+// with 'x' for validators we have a vote from and '_' otherwise.
+
+pub(crate) const DUMP_HEADER: &str = "round_votes v1";
+
+// VoteCountDump is the exported tally for one vote type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoteCountDump {
+    pub thresh: Thresh,
+    pub votes: Vec<ValidatorVote>,
+}
+
+impl VoteCountDump {
+    // bits returns which validator indices we have a vote from.
+    pub fn bits(&self) -> Vec<bool> {
+        let len = self
+            .votes
+            .iter()
+            .map(|v| v.validator + 1)
+            .max()
+            .unwrap_or(0);
+        let mut bits = vec![false; len];
+        for v in &self.votes {
+            bits[v.validator] = true;
+        }
+        bits
+    }
+}
+
+// RoundVotesDump is the exported tally for a round.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoundVotesDump {
+    pub height: i64,
+    pub round: i64,
+    pub total: i64,
+    pub prevotes: VoteCountDump,
+    pub precommits: VoteCountDump,
+}
+
+// DumpError is returned when importing a malformed dump.
+#[derive(Debug, PartialEq)]
+pub struct DumpError {
+    pub line: usize, // 1-based line number
+    pub reason: String,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+pub(crate) fn fmt_value(value: Option<Value>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => "nil".to_string(),
+    }
+}
+
+pub(crate) fn fmt_thresh(thresh: Thresh) -> String {
+    match thresh {
+        Thresh::Init => "init".to_string(),
+        Thresh::Any => "any".to_string(),
+        Thresh::Nil => "nil".to_string(),
+        Thresh::Value(v) => format!("value {}", v),
+    }
+}
+
+fn write_vote_count(f: &mut fmt::Formatter, typ: &str, count: &VoteCountDump) -> fmt::Result {
+    writeln!(f, "{} thresh {}", typ, fmt_thresh(count.thresh))?;
+    let bits: String = count
+        .bits()
+        .iter()
+        .map(|&b| if b { 'x' } else { '_' })
+        .collect();
+    writeln!(f, "{} bits {}", typ, bits)?;
+    for v in &count.votes {
+        writeln!(
+            f,
+            "{} vote {} {} {}",
+            typ,
+            v.validator,
+            fmt_value(v.value),
+            v.weight
+        )?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for RoundVotesDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", DUMP_HEADER)?;
+        writeln!(f, "height {}", self.height)?;
+        writeln!(f, "round {}", self.round)?;
+        writeln!(f, "total {}", self.total)?;
+        write_vote_count(f, "prevote", &self.prevotes)?;
+        write_vote_count(f, "precommit", &self.precommits)
+    }
+}
+
+fn parse_value(s: &str) -> Result<Option<Value>, String> {
+    match s {
+        "nil" => Ok(None),
+        _ => s.parse().map(Some),
+    }
+}
+
+fn parse_thresh(s: &[&str]) -> Result<Thresh, String> {
+    match s {
+        ["init"] => Ok(Thresh::Init),
+        ["any"] => Ok(Thresh::Any),
+        ["nil"] => Ok(Thresh::Nil),
+        ["value", v] => Ok(Thresh::Value(v.parse()?)),
+        _ => Err(format!("bad threshold {:?}", s.join(" "))),
+    }
+}
+
+fn parse_int<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("bad number {:?}", s))
+}
+
+// parse_line parses a single line of the dump into the given dump.
+fn parse_line(dump: &mut RoundVotesDump, line: &str) -> Result<(), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        ["height", h] => dump.height = parse_int(h)?,
+        ["round", r] => dump.round = parse_int(r)?,
+        ["total", t] => dump.total = parse_int(t)?,
+        [typ, rest @ ..] => {
+            let count = match *typ {
+                "prevote" => &mut dump.prevotes,
+                "precommit" => &mut dump.precommits,
+                _ => return Err(format!("unknown field {:?}", typ)),
+            };
+            match rest {
+                ["thresh", t @ ..] => count.thresh = parse_thresh(t)?,
+                // bits are derived from the votes
+                ["bits", _] | ["bits"] => {}
+                ["vote", validator, value, weight] => count.votes.push(ValidatorVote {
+                    validator: parse_int(validator)?,
+                    value: parse_value(value)?,
+                    weight: parse_int(weight)?,
+                }),
+                _ => return Err(format!("malformed line {:?}", line)),
+            }
+        }
+        [] => {}
+    }
+    Ok(())
+}
+
+impl FromStr for RoundVotesDump {
+    type Err = DumpError;
+
+    fn from_str(s: &str) -> Result<RoundVotesDump, DumpError> {
+        let mut lines = s.lines().enumerate();
+        match lines.next() {
+            Some((_, header)) if header.trim() == DUMP_HEADER => {}
+            _ => {
+                return Err(DumpError {
+                    line: 1,
+                    reason: format!("expected header {:?}", DUMP_HEADER),
+                })
+            }
         }
+
+        let empty = VoteCountDump {
+            thresh: Thresh::Init,
+            votes: Vec::new(),
+        };
+        let mut dump = RoundVotesDump {
+            height: 0,
+            round: 0,
+            total: 0,
+            prevotes: empty.clone(),
+            precommits: empty,
+        };
+        for (i, line) in lines {
+            parse_line(&mut dump, line).map_err(|reason| DumpError {
+                line: i + 1,
+                reason,
+            })?;
+        }
+        Ok(dump)
     }
 }
 
@@ -106,28 +554,198 @@ mod tests {
 
     #[test]
     fn add_votes() {
-        let v = Value {};
+        let v = Value::new([1; 32]);
         let val = Some(v);
         let total = 4;
         let mut round_votes = RoundVotes::new(1, 0, total);
         let weight = 1;
 
         // add a vote. nothing changes.
-        let vote = Vote::new_prevote(0, val);
-        let thresh = round_votes.add_vote(vote, weight);
+        let vote = Vote::new_prevote(1, 0, val);
+        let thresh = round_votes.add_vote(0, vote, weight).current;
         assert_eq!(thresh, Thresh::Init);
 
         // add it again, nothing changes.
-        let thresh = round_votes.add_vote(vote, weight);
+        let thresh = round_votes.add_vote(1, vote, weight).current;
         assert_eq!(thresh, Thresh::Init);
 
         // add a vote for nil, get Thresh::Any
-        let vote_nil = Vote::new_prevote(0, None);
-        let thresh = round_votes.add_vote(vote_nil, weight);
+        let vote_nil = Vote::new_prevote(1, 0, None);
+        let thresh = round_votes.add_vote(2, vote_nil, weight).current;
         assert_eq!(thresh, Thresh::Any);
 
         // add vote for value, get Thresh::Value
-        let thresh = round_votes.add_vote(vote, weight);
+        let thresh = round_votes.add_vote(3, vote, weight).current;
         assert_eq!(thresh, Thresh::Value(v));
     }
+
+    #[test]
+    fn tiny_sets() {
+        // more than 2/3 of 1 is 1, and of 2 is 2
+        assert!(is_quorum(1, 1) && !is_quorum(0, 1));
+        assert!(is_quorum(2, 2) && !is_quorum(1, 2));
+        assert_eq!(
+            (remaining_to_quorum(0, 1), remaining_to_quorum(1, 2)),
+            (1, 1)
+        );
+
+        let v = Value::new([1; 32]);
+        let mut round_votes = RoundVotes::new(1, 0, 1);
+        let outcome = round_votes.add_vote(0, Vote::new_prevote(1, 0, Some(v)), 1);
+        assert_eq!(outcome.new_threshold, Some(Thresh::Value(v)));
+
+        // one of two votes crosses nothing; the other's, for nil, only any
+        let mut round_votes = RoundVotes::new(1, 0, 2);
+        let outcome = round_votes.add_vote(0, Vote::new_prevote(1, 0, Some(v)), 1);
+        assert_eq!(outcome.new_threshold, None);
+        let outcome = round_votes.add_vote(1, Vote::new_prevote(1, 0, None), 1);
+        assert_eq!(outcome.new_threshold, Some(Thresh::Any));
+    }
+
+    #[test]
+    fn crossed_once() {
+        let v = Value::new([1; 32]);
+        let mut round_votes = RoundVotes::new(1, 0, 5);
+        let mut add = |validator, value| {
+            let outcome = round_votes.add_vote(validator, Vote::new_prevote(1, 0, value), 1);
+            (outcome.new_threshold, outcome.current)
+        };
+
+        assert_eq!(add(0, Some(v)), (None, Thresh::Init));
+        assert_eq!(add(1, Some(v)), (None, Thresh::Init));
+        assert_eq!(add(2, None), (None, Thresh::Init));
+        assert_eq!(add(3, Some(v)), (Some(Thresh::Any), Thresh::Any));
+
+        // more votes without a new threshold don't cross it again
+        let (_, current) = add(3, None);
+        assert_eq!(current, Thresh::Any);
+
+        // the vote that makes the quorum for the value crosses it, once
+        let mut round_votes = RoundVotes::new(1, 0, 4);
+        for validator in 0..2 {
+            round_votes.add_vote(validator, Vote::new_prevote(1, 0, Some(v)), 1);
+        }
+        let outcome = round_votes.add_vote(2, Vote::new_prevote(1, 0, Some(v)), 1);
+        assert_eq!(outcome.new_threshold, Some(Thresh::Value(v)));
+        let outcome = round_votes.add_vote(3, Vote::new_prevote(1, 0, Some(v)), 1);
+        assert_eq!(outcome.new_threshold, None);
+        assert_eq!(outcome.current, Thresh::Value(v));
+    }
+
+    #[test]
+    fn add_votes_multiple_values() {
+        let (v1, v2) = (Value::new([1; 32]), Value::new([2; 32]));
+        let mut round_votes = RoundVotes::new(1, 0, 4);
+        round_votes.add_vote(0, Vote::new_prevote(1, 0, Some(v1)), 1);
+        round_votes.add_vote(1, Vote::new_prevote(1, 0, Some(v2)), 1);
+
+        // votes for different values add up to Thresh::Any
+        let thresh = round_votes.add_vote(2, Vote::new_prevote(1, 0, Some(v1)), 1);
+        assert_eq!(thresh.current, Thresh::Any);
+
+        let thresh = round_votes.add_vote(3, Vote::new_prevote(1, 0, Some(v1)), 1);
+        assert_eq!(thresh.current, Thresh::Value(v1));
+    }
+
+    #[test]
+    fn signed_votes() {
+        let (v1, v2) = (Value::new([1; 32]), Value::new([2; 32]));
+        let mut round_votes = RoundVotes::new(1, 0, 4);
+        let signed = |validator: usize, value| Signed {
+            signer: validator,
+            msg: Vote::new_prevote(1, 0, Some(value)),
+            signature: vec![validator as u8],
+        };
+
+        let outcome = round_votes.add_signed_vote(&signed(0, v1), 1);
+        assert!(outcome.vote_accepted);
+        assert_eq!(outcome.current, Thresh::Init);
+        let outcome = round_votes.add_signed_vote(&signed(0, v1), 1);
+        assert!(!outcome.vote_accepted);
+        assert_eq!(outcome.evidence, None);
+
+        // a conflicting vote is evidence, and the first vote still stands
+        let outcome = round_votes.add_signed_vote(&signed(0, v2), 1);
+        let evidence = Evidence::DuplicateVote(signed(0, v1), signed(0, v2));
+        assert!(!outcome.vote_accepted);
+        assert_eq!(outcome.evidence, Some(evidence));
+        assert_eq!(round_votes.weight_for(VoteType::Prevote, &v1), 1);
+        assert_eq!(round_votes.weight_for(VoteType::Prevote, &v2), 0);
+        assert_eq!(
+            round_votes.signed_vote(0, VoteType::Prevote),
+            Some(&signed(0, v1))
+        );
+        assert_eq!(round_votes.signed_vote(0, VoteType::Precommit), None);
+    }
+
+    #[test]
+    fn weights() {
+        let (v1, v2) = (Value::new([1; 32]), Value::new([2; 32]));
+        let mut round_votes = RoundVotes::new(1, 0, 10);
+        assert_eq!(round_votes.remaining_to_polka(), 7);
+        round_votes.add_vote(0, Vote::new_prevote(1, 0, Some(v1)), 3);
+        round_votes.add_vote(1, Vote::new_prevote(1, 0, Some(v2)), 1);
+        round_votes.add_vote(2, Vote::new_prevote(1, 0, None), 2);
+
+        let prevote = VoteType::Prevote;
+        assert_eq!(round_votes.weight_for(prevote, &v1), 3);
+        assert_eq!(round_votes.weight_for(prevote, &v2), 1);
+        assert_eq!(round_votes.weight_nil(prevote), 2);
+        assert_eq!(round_votes.weight(prevote), 6);
+        assert_eq!(round_votes.weight(VoteType::Precommit), 0);
+        assert_eq!(round_votes.remaining_to_polka(), 1);
+        assert_eq!(round_votes.remaining_to_quorum(prevote, Some(v1)), 4);
+        assert_eq!(round_votes.remaining_to_quorum(prevote, None), 5);
+
+        // remaining weight is exactly what it takes for the threshold
+        let outcome = round_votes.add_vote(3, Vote::new_prevote(1, 0, Some(v1)), 4);
+        assert_eq!(outcome.new_threshold, Some(Thresh::Value(v1)));
+        assert_eq!(round_votes.remaining_to_polka(), 0);
+        assert_eq!(round_votes.remaining_to_quorum(prevote, Some(v1)), 0);
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let val = Some(Value::new([1; 32]));
+        let mut round_votes = RoundVotes::new(1, 0, 4);
+        round_votes.add_vote(0, Vote::new_prevote(1, 0, val), 1);
+        round_votes.add_vote(2, Vote::new_prevote(1, 0, None), 1);
+        round_votes.add_vote(3, Vote::new_prevote(1, 0, val), 1);
+        round_votes.add_vote(1, Vote::new_precommit(1, 0, val), 1);
+
+        let dump = round_votes.dump();
+        let text = dump.to_string();
+        let id = "01".repeat(32);
+        assert_eq!(
+            text,
+            format!(
+                "round_votes v1\n\
+                 height 1\n\
+                 round 0\n\
+                 total 4\n\
+                 prevote thresh any\n\
+                 prevote bits x_xx\n\
+                 prevote vote 0 {id} 1\n\
+                 prevote vote 2 nil 1\n\
+                 prevote vote 3 {id} 1\n\
+                 precommit thresh init\n\
+                 precommit bits _x\n\
+                 precommit vote 1 {id} 1\n",
+                id = id
+            )
+        );
+
+        let imported: RoundVotesDump = text.parse().unwrap();
+        assert_eq!(imported, dump);
+        assert_eq!(RoundVotes::from_dump(&imported).dump(), dump);
+    }
+
+    #[test]
+    fn dump_parse_error() {
+        let err = "round_votes v1\nheight one\n"
+            .parse::<RoundVotesDump>()
+            .unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!("votes".parse::<RoundVotesDump>().is_err());
+    }
 }
@@ -0,0 +1,574 @@
+// A small DSL for scripting tests against a single engine.
+//
+// A scenario delivers proposals, votes and the passage of time to an engine,
+// and checks what it outputs in order. The engine is validator 0, so its own
+// votes count. Time is virtual: timeouts the engine schedules fire when
+// advance_time() moves the clock past them.
+//
+//   scenario(1, 4)
+//       .start_round(0, None)
+//       .expect_timeout(0, TimeoutStep::Propose)
+//       .advance_time(TIMEOUT_PROPOSE)
+//       .expect_prevote(0, None);
+
+use super::config::ConsensusConfig;
+use super::engine::{Engine, Message, Output};
+use super::scheduler::{Scheduler, TimeoutHandle};
+use super::signature::{Signature, Signed, Signer, Verified, Verifier};
+use super::state_machine as sm;
+use super::state_machine::TimeoutStep;
+use super::validators::{Validator, ValidatorSet};
+use super::{Proposal, Value, Vote};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+pub const TIMEOUT_PROPOSE: Duration = Duration::from_millis(3000);
+pub const TIMEOUT_PREVOTE: Duration = Duration::from_millis(1000);
+pub const TIMEOUT_PRECOMMIT: Duration = Duration::from_millis(1000);
+pub const TIMEOUT_COMMIT: Duration = Duration::from_millis(1000);
+
+// duration returns how long after being scheduled a timeout fires.
+fn duration(step: TimeoutStep) -> Duration {
+    match step {
+        TimeoutStep::Propose => TIMEOUT_PROPOSE,
+        TimeoutStep::Prevote => TIMEOUT_PREVOTE,
+        TimeoutStep::Precommit => TIMEOUT_PRECOMMIT,
+        TimeoutStep::Commit => TIMEOUT_COMMIT,
+    }
+}
+
+// TestSigner signs as the validator at its index.
+// Its signature is the index.
+pub struct TestSigner(pub usize);
+
+impl Signer for TestSigner {
+    fn index(&self) -> usize {
+        self.0
+    }
+
+    fn sign(&self, _msg: &[u8]) -> Signature {
+        vec![self.0 as u8]
+    }
+}
+
+// TestVerifier accepts signatures that are the signer's index,
+// as TestSigner signs them.
+pub struct TestVerifier;
+
+impl Verifier for TestVerifier {
+    fn verify(&self, signer: usize, _msg: &[u8], signature: &[u8]) -> bool {
+        signature == [signer as u8]
+    }
+}
+
+// validators returns n validators with voting power 1.
+pub fn validators(n: usize) -> ValidatorSet {
+    let vals = (0..n)
+        .map(|i| Validator {
+            public_key: vec![i as u8; 32],
+            voting_power: 1,
+        })
+        .collect();
+    ValidatorSet::new(vals)
+}
+
+// test_engine returns an engine for validator 0 of n validators with voting
+// power 1, signing with TestSigner and verifying with TestVerifier.
+pub fn test_engine(
+    config: ConsensusConfig,
+    height: i64,
+    n: usize,
+    scheduler: Box<dyn Scheduler>,
+) -> Engine {
+    let signer = Box::new(TestSigner(0));
+    let verifier = Box::new(TestVerifier);
+    Engine::new(config, height, validators(n), signer, verifier, scheduler).unwrap()
+}
+
+// proposal returns the proposal as a message signed by the signer.
+pub fn proposal(signer: usize, proposal: Proposal) -> Message {
+    Message::Proposal(Signed {
+        signer,
+        msg: proposal,
+        signature: vec![signer as u8],
+    })
+}
+
+// vote returns the vote as a message signed by the signer.
+pub fn vote(signer: usize, vote: Vote) -> Message {
+    Message::Vote(Signed {
+        signer,
+        msg: vote,
+        signature: vec![signer as u8],
+    })
+}
+
+//---------------------------------------------------------------------
+// Clock
+
+// TestClock is a deterministic clock holding the timeouts scheduled by the engine.
+#[derive(Default)]
+pub struct TestClock {
+    now: Duration,
+    next_handle: u64,
+    timeouts: Vec<(Duration, TimeoutHandle, sm::Timeout)>,
+}
+
+impl TestClock {
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    // pending returns the timeouts that haven't fired or been cancelled,
+    // in the order they were scheduled.
+    pub fn pending(&self) -> Vec<sm::Timeout> {
+        self.timeouts.iter().map(|(_, _, t)| *t).collect()
+    }
+
+    // schedule the timeout to fire after its step's duration.
+    pub fn schedule(&mut self, timeout: sm::Timeout) -> TimeoutHandle {
+        let handle = TimeoutHandle(self.next_handle);
+        self.next_handle += 1;
+        let deadline = self.now + duration(timeout.step);
+        self.timeouts.push((deadline, handle, timeout));
+        handle
+    }
+
+    pub fn cancel(&mut self, handle: TimeoutHandle) {
+        self.timeouts.retain(|(_, h, _)| *h != handle);
+    }
+
+    // advance moves the clock forward and returns the timeouts that fired,
+    // earliest first. Timeouts with the same deadline fire in the order
+    // they were scheduled.
+    pub fn advance(&mut self, d: Duration) -> Vec<sm::Timeout> {
+        self.now += d;
+        let now = self.now;
+        let (mut fired, pending): (Vec<_>, Vec<_>) = self
+            .timeouts
+            .drain(..)
+            .partition(|(deadline, _, _)| *deadline <= now);
+        self.timeouts = pending;
+        fired.sort_by_key(|(deadline, _, _)| *deadline);
+        fired.into_iter().map(|(_, _, t)| t).collect()
+    }
+}
+
+// SharedClock lets the engine schedule timeouts on the scenario's clock.
+#[derive(Clone, Default)]
+struct SharedClock(Rc<RefCell<TestClock>>);
+
+impl Scheduler for SharedClock {
+    fn schedule(&mut self, timeout: sm::Timeout) -> TimeoutHandle {
+        self.0.borrow_mut().schedule(timeout)
+    }
+
+    fn cancel(&mut self, handle: TimeoutHandle) {
+        self.0.borrow_mut().cancel(handle)
+    }
+}
+
+//---------------------------------------------------------------------
+// Scenario
+
+// scenario starts a scenario for an engine at the given height,
+// with n validators of voting power 1.
+pub fn scenario(height: i64, n: usize) -> Scenario {
+    scenario_with_config(ConsensusConfig::default(), height, n)
+}
+
+// scenario_with_config is like scenario, with the given engine config.
+pub fn scenario_with_config(config: ConsensusConfig, height: i64, n: usize) -> Scenario {
+    let clock = SharedClock::default();
+    let engine = test_engine(config, height, n, Box::new(clock.clone()));
+    Scenario {
+        height,
+        engine,
+        clock,
+        outputs: VecDeque::new(),
+    }
+}
+
+pub struct Scenario {
+    height: i64,
+    engine: Engine,
+    clock: SharedClock,
+    outputs: VecDeque<Output>,
+}
+
+impl Scenario {
+    pub fn start_round(mut self, round: i64, value: Option<Value>) -> Scenario {
+        let outputs = self.engine.start_round(round, value);
+        self.outputs.extend(outputs);
+        self
+    }
+
+    pub fn deliver_proposal(
+        self,
+        proposer: usize,
+        round: i64,
+        value: Value,
+        pol_round: i64,
+    ) -> Scenario {
+        let proposal = Proposal::new(self.height, round, value, pol_round);
+        self.deliver(Message::Proposal(signed(proposer, proposal)))
+    }
+
+    pub fn deliver_prevote(self, validator: usize, round: i64, value: Option<Value>) -> Scenario {
+        let vote = Vote::new_prevote(self.height, round, value);
+        self.deliver(Message::Vote(signed(validator, vote)))
+    }
+
+    pub fn deliver_precommit(self, validator: usize, round: i64, value: Option<Value>) -> Scenario {
+        let vote = Vote::new_precommit(self.height, round, value);
+        self.deliver(Message::Vote(signed(validator, vote)))
+    }
+
+    // advance_time moves the clock forward, delivering any timeouts that fire.
+    pub fn advance_time(mut self, d: Duration) -> Scenario {
+        let fired = self.clock.0.borrow_mut().advance(d);
+        for timeout in fired {
+            self = self.deliver(Message::TimeoutElapsed(timeout));
+        }
+        self
+    }
+
+    // expect_output checks the next output of the engine.
+    pub fn expect_output(mut self, expected: Output) -> Scenario {
+        match self.outputs.pop_front() {
+            Some(msg) => assert_eq!(msg, expected, "at {:?}", self.now()),
+            None => panic!("expected {:?} at {:?}, got nothing", expected, self.now()),
+        }
+        self
+    }
+
+    pub fn expect_proposal(self, round: i64, value: Value, pol_round: i64) -> Scenario {
+        let proposal = Proposal::new(self.height, round, value, pol_round);
+        self.expect_output(Output::Proposal(own(proposal)))
+    }
+
+    pub fn expect_prevote(self, round: i64, value: Option<Value>) -> Scenario {
+        let vote = Vote::new_prevote(self.height, round, value);
+        self.expect_output(Output::Vote(own(vote)))
+    }
+
+    pub fn expect_precommit(self, round: i64, value: Option<Value>) -> Scenario {
+        let vote = Vote::new_precommit(self.height, round, value);
+        self.expect_output(Output::Vote(own(vote)))
+    }
+
+    pub fn expect_timeout(self, round: i64, step: TimeoutStep) -> Scenario {
+        let height = self.height;
+        self.expect_output(Output::ScheduleTimeout(sm::Timeout {
+            height,
+            round,
+            step,
+        }))
+    }
+
+    // expect_pending checks the timeouts that are scheduled and not yet
+    // fired or cancelled, in the order they were scheduled.
+    pub fn expect_pending(self, expected: &[(i64, TimeoutStep)]) -> Scenario {
+        let pending: Vec<_> = self
+            .clock
+            .0
+            .borrow()
+            .pending()
+            .iter()
+            .map(|t| (t.round, t.step))
+            .collect();
+        assert_eq!(pending, expected, "at {:?}", self.now());
+        self
+    }
+
+    pub fn expect_new_round(self, round: i64) -> Scenario {
+        self.expect_output(Output::NewRound(round))
+    }
+
+    pub fn expect_decision(self, round: i64, value: Value) -> Scenario {
+        self.expect_output(Output::Decision(sm::RoundValue { round, value }))
+    }
+
+    // expect_valid checks the valid value and round of the engine.
+    pub fn expect_valid(self, round: i64, value: Value) -> Scenario {
+        let valid = self.engine.state().valid();
+        assert_eq!(valid, Some(sm::RoundValue { round, value }));
+        self
+    }
+
+    // expect_no_valid checks that the engine has no valid value.
+    pub fn expect_no_valid(self) -> Scenario {
+        assert_eq!(self.engine.state().valid(), None);
+        self
+    }
+
+    // expect_height checks the height the engine is deciding.
+    pub fn expect_height(self, height: i64) -> Scenario {
+        assert_eq!(self.engine.height(), height, "at {:?}", self.now());
+        self
+    }
+
+    // expect_nothing checks that all outputs were expected.
+    pub fn expect_nothing(self) -> Scenario {
+        assert!(self.outputs.is_empty(), "unexpected {:?}", self.outputs);
+        self
+    }
+
+    // deliver the message to the engine, recording its outputs.
+    // Timeouts were already scheduled on the clock by the engine.
+    fn deliver(mut self, msg: Message) -> Scenario {
+        let outputs = self.engine.execute_verified(Verified::new_unchecked(msg));
+        self.outputs.extend(outputs);
+        self
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.0.borrow().now()
+    }
+}
+
+fn signed<T>(signer: usize, msg: T) -> Signed<T> {
+    Signed {
+        signer,
+        msg,
+        signature: Vec::new(),
+    }
+}
+
+// own is the message as signed by the engine.
+fn own<T>(msg: T) -> Signed<T> {
+    Signed {
+        signer: 0,
+        msg,
+        signature: vec![0],
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V: Value = Value::new([1; 32]);
+
+    #[test]
+    fn proposer_happy_case() {
+        scenario(1, 4)
+            .start_round(0, Some(V))
+            .expect_proposal(0, V, -1)
+            .expect_prevote(0, Some(V))
+            .deliver_prevote(1, 0, Some(V))
+            .deliver_prevote(2, 0, Some(V))
+            .expect_precommit(0, Some(V))
+            .deliver_precommit(1, 0, Some(V))
+            .deliver_precommit(2, 0, Some(V))
+            .expect_decision(0, V)
+            .expect_new_round(0)
+            .expect_height(2)
+            .expect_nothing();
+    }
+
+    // 57: timeout propose - prevote nil
+    #[test]
+    fn timeout_propose() {
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .advance_time(TIMEOUT_PROPOSE - Duration::from_millis(1))
+            .expect_nothing()
+            .advance_time(Duration::from_millis(1))
+            .expect_prevote(0, None)
+            .expect_nothing();
+    }
+
+    // 44: polka for nil - precommit nil
+    #[test]
+    fn polka_nil() {
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .deliver_proposal(1, 0, V, -1)
+            .expect_prevote(0, Some(V))
+            .deliver_prevote(1, 0, None)
+            .deliver_prevote(2, 0, None)
+            .expect_timeout(0, TimeoutStep::Prevote)
+            .deliver_prevote(3, 0, None)
+            .expect_precommit(0, None)
+            .expect_nothing();
+    }
+
+    // 36/42: a polka completed by late prevotes from the previous round
+    // updates the valid value, if it's within the late vote window.
+    #[test]
+    fn late_polka() {
+        let run = |late_vote_rounds| {
+            let config = ConsensusConfig {
+                late_vote_rounds,
+                ..ConsensusConfig::default()
+            };
+            scenario_with_config(config, 1, 4)
+                .start_round(0, None)
+                .expect_timeout(0, TimeoutStep::Propose)
+                .advance_time(TIMEOUT_PROPOSE)
+                .expect_prevote(0, None)
+                .deliver_precommit(1, 0, Some(V))
+                .deliver_precommit(2, 0, None)
+                .deliver_precommit(3, 0, None)
+                .expect_timeout(0, TimeoutStep::Precommit)
+                .advance_time(TIMEOUT_PRECOMMIT)
+                .expect_new_round(1)
+                .deliver_prevote(1, 0, Some(V))
+                .deliver_prevote(2, 0, Some(V))
+                .deliver_prevote(3, 0, Some(V))
+        };
+        run(1).expect_valid(0, V).expect_nothing();
+        run(0).expect_no_valid().expect_nothing();
+    }
+
+    // a polka for another value in a round after the one we locked in
+    // releases the lock, so we can prevote for other values again
+    #[test]
+    fn unlock() {
+        const W: Value = Value::new([2; 32]);
+        const X: Value = Value::new([3; 32]);
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .deliver_proposal(1, 0, V, -1)
+            .expect_prevote(0, Some(V))
+            .deliver_prevote(1, 0, Some(V))
+            .deliver_prevote(2, 0, Some(V))
+            .expect_precommit(0, Some(V))
+            .deliver_precommit(1, 0, None)
+            .deliver_precommit(2, 0, None)
+            .expect_timeout(0, TimeoutStep::Precommit)
+            .advance_time(TIMEOUT_PRECOMMIT)
+            .expect_new_round(1)
+            .start_round(1, None)
+            .expect_timeout(1, TimeoutStep::Propose)
+            .advance_time(TIMEOUT_PROPOSE)
+            .expect_prevote(1, None)
+            .deliver_prevote(1, 1, Some(W))
+            .deliver_prevote(2, 1, Some(W))
+            .expect_timeout(1, TimeoutStep::Prevote)
+            .advance_time(TIMEOUT_PREVOTE)
+            .expect_precommit(1, None)
+            .deliver_prevote(3, 1, Some(W))
+            .expect_valid(1, W)
+            .deliver_precommit(1, 1, None)
+            .deliver_precommit(2, 1, None)
+            .expect_timeout(1, TimeoutStep::Precommit)
+            .advance_time(TIMEOUT_PRECOMMIT)
+            .expect_new_round(2)
+            .start_round(2, None)
+            .expect_timeout(2, TimeoutStep::Propose)
+            .deliver_proposal(3, 2, X, -1)
+            .expect_prevote(2, Some(X))
+            .expect_nothing();
+    }
+
+    // 47: precommits for nil are precommits for anything
+    #[test]
+    fn precommit_nil() {
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .deliver_precommit(1, 0, None)
+            .deliver_precommit(2, 0, None)
+            .deliver_precommit(3, 0, None)
+            .expect_timeout(0, TimeoutStep::Precommit)
+            .expect_nothing();
+    }
+
+    // 47, 65: precommits for anything - timeout precommit, then move to the next round
+    #[test]
+    fn timeout_precommit() {
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .advance_time(TIMEOUT_PROPOSE)
+            .expect_prevote(0, None)
+            .deliver_precommit(1, 0, Some(V))
+            .deliver_precommit(2, 0, None)
+            .deliver_precommit(3, 0, None)
+            .expect_timeout(0, TimeoutStep::Precommit)
+            .advance_time(TIMEOUT_PRECOMMIT)
+            .expect_new_round(1)
+            .start_round(1, Some(V))
+            .expect_proposal(1, V, -1)
+            .expect_prevote(1, Some(V))
+            .expect_nothing();
+    }
+
+    // timeouts are cancelled once we move past the step that scheduled them
+    #[test]
+    fn cancel_timeouts() {
+        scenario(1, 4)
+            .start_round(0, None)
+            .expect_timeout(0, TimeoutStep::Propose)
+            .expect_pending(&[(0, TimeoutStep::Propose)])
+            .deliver_proposal(1, 0, V, -1)
+            .expect_prevote(0, Some(V))
+            .expect_pending(&[])
+            .deliver_prevote(1, 0, Some(V))
+            .deliver_prevote(2, 0, None)
+            .expect_timeout(0, TimeoutStep::Prevote)
+            .deliver_precommit(1, 0, None)
+            .deliver_precommit(2, 0, Some(V))
+            .deliver_precommit(3, 0, Some(V))
+            .expect_timeout(0, TimeoutStep::Precommit)
+            .expect_pending(&[(0, TimeoutStep::Prevote), (0, TimeoutStep::Precommit)])
+            .advance_time(TIMEOUT_PREVOTE)
+            .expect_precommit(0, None)
+            // our own precommit counts as precommits for anything again
+            .expect_timeout(0, TimeoutStep::Precommit)
+            .expect_new_round(1)
+            .expect_pending(&[])
+            .expect_nothing();
+    }
+
+    // after deciding, wait for timeout commit before moving to the next height,
+    // unless skipping it and all precommits are in.
+    #[test]
+    fn timeout_commit() {
+        let run = |skip_timeout_commit| {
+            let config = ConsensusConfig {
+                wait_timeout_commit: true,
+                skip_timeout_commit,
+                ..ConsensusConfig::default()
+            };
+            scenario_with_config(config, 1, 4)
+                .start_round(0, Some(V))
+                .expect_proposal(0, V, -1)
+                .expect_prevote(0, Some(V))
+                .deliver_prevote(1, 0, Some(V))
+                .deliver_prevote(2, 0, Some(V))
+                .expect_precommit(0, Some(V))
+                .deliver_precommit(1, 0, Some(V))
+                .deliver_precommit(2, 0, Some(V))
+                .expect_decision(0, V)
+                .expect_pending(&[(0, TimeoutStep::Commit)])
+                .expect_height(1)
+        };
+
+        run(false)
+            .deliver_precommit(3, 0, Some(V))
+            .expect_height(1)
+            .advance_time(TIMEOUT_COMMIT)
+            .expect_new_round(0)
+            .expect_height(2)
+            .expect_nothing();
+
+        run(true)
+            .deliver_precommit(3, 0, Some(V))
+            .expect_new_round(0)
+            .expect_height(2)
+            .expect_pending(&[])
+            .expect_nothing();
+    }
+}
@@ -0,0 +1,188 @@
+use super::config::ConsensusParams;
+use super::engine::Message;
+use super::state_machine::Timeout;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+// TimeoutHandle identifies a scheduled timeout, so it can be cancelled.
+// Handles are assigned by the scheduler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TimeoutHandle(pub u64);
+
+// Scheduler schedules the timeouts output by the state machine.
+// When a timeout elapses, the scheduler's owner passes it back to
+// the engine as Message::TimeoutElapsed.
+pub trait Scheduler {
+    // schedule the timeout, returning a handle to cancel it.
+    fn schedule(&mut self, timeout: Timeout) -> TimeoutHandle;
+
+    // schedule_after schedules the timeout to fire after the duration,
+    // rather than its step's timeout, eg. to extend timeout propose.
+    // Schedulers that don't support it schedule it as usual.
+    fn schedule_after(&mut self, timeout: Timeout, _duration: Duration) -> TimeoutHandle {
+        self.schedule(timeout)
+    }
+
+    // cancel the timeout so it never fires.
+    // Cancelling a timeout that already fired does nothing.
+    fn cancel(&mut self, handle: TimeoutHandle);
+
+    // set_params is called with the consensus params when the engine is
+    // created, and again whenever they change at a new height, so the
+    // scheduler can use their timeouts from then on.
+    fn set_params(&mut self, _params: &ConsensusParams) {}
+}
+
+// Scheduled tracks the timeouts scheduled by the engine that haven't fired yet.
+#[derive(Default)]
+pub(crate) struct Scheduled {
+    timeouts: Vec<(Timeout, TimeoutHandle)>,
+}
+
+impl Scheduled {
+    pub(crate) fn add(&mut self, timeout: Timeout, handle: TimeoutHandle) {
+        self.timeouts.push((timeout, handle));
+    }
+
+    // fired forgets the timeout, which elapsed and no longer needs cancelling.
+    // The same timeout may be scheduled twice, so only the earliest is forgotten.
+    pub(crate) fn fired(&mut self, timeout: &Timeout) {
+        if let Some(i) = self.timeouts.iter().position(|(t, _)| t == timeout) {
+            self.timeouts.remove(i);
+        }
+    }
+
+    // drain_if removes and returns the handles of the timeouts matching f.
+    pub(crate) fn drain_if<F>(&mut self, f: F) -> Vec<TimeoutHandle>
+    where
+        F: Fn(&Timeout) -> bool,
+    {
+        let (drained, kept) = self.timeouts.drain(..).partition(|(t, _)| f(t));
+        self.timeouts = kept;
+        drained.into_iter().map(|(_, h)| h).collect()
+    }
+}
+
+//---------------------------------------------------------------------
+// Timers
+
+// Timers is a Scheduler for drivers that poll. It keeps the scheduled
+// timeouts in memory, with deadlines from the consensus params' timeouts,
+// and elapsed() returns the ones that are due as messages to pass back
+// through the engine's execute(). Clones share the same timers, so the
+// driver keeps one and gives the engine another:
+//
+//   let timers = Timers::default();
+//   let mut engine = Engine::new(config, height, vals, signer, verifier, Box::new(timers.clone()));
+//   loop {
+//       for msg in timers.elapsed(now()) {
+//           engine.execute(msg);
+//       }
+//       ...
+//   }
+#[derive(Clone, Default)]
+pub struct Timers(Rc<RefCell<TimersState>>);
+
+#[derive(Default)]
+struct TimersState {
+    params: ConsensusParams,
+    now: Duration,
+    next_handle: u64,
+    timeouts: Vec<(Duration, TimeoutHandle, Timeout)>,
+}
+
+impl Timers {
+    // elapsed moves the time to now and returns the timeouts that are due,
+    // earliest first. Timeouts scheduled from then on are due after now.
+    pub fn elapsed(&self, now: Duration) -> Vec<Message> {
+        let mut state = self.0.borrow_mut();
+        state.now = now;
+        let (mut due, pending): (Vec<_>, Vec<_>) = state
+            .timeouts
+            .drain(..)
+            .partition(|(deadline, _, _)| *deadline <= now);
+        state.timeouts = pending;
+        due.sort_by_key(|(deadline, _, _)| *deadline);
+        due.into_iter()
+            .map(|(_, _, t)| Message::TimeoutElapsed(t))
+            .collect()
+    }
+
+    // next_deadline returns when the next timeout is due, if any.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.0.borrow().timeouts.iter().map(|(d, _, _)| *d).min()
+    }
+}
+
+impl Scheduler for Timers {
+    fn schedule(&mut self, timeout: Timeout) -> TimeoutHandle {
+        let duration = self.0.borrow().params.timeout(timeout.step);
+        self.schedule_after(timeout, duration)
+    }
+
+    fn schedule_after(&mut self, timeout: Timeout, duration: Duration) -> TimeoutHandle {
+        let mut state = self.0.borrow_mut();
+        let handle = TimeoutHandle(state.next_handle);
+        state.next_handle += 1;
+        let deadline = state.now + duration;
+        state.timeouts.push((deadline, handle, timeout));
+        handle
+    }
+
+    fn cancel(&mut self, handle: TimeoutHandle) {
+        self.0
+            .borrow_mut()
+            .timeouts
+            .retain(|(_, h, _)| *h != handle);
+    }
+
+    fn set_params(&mut self, params: &ConsensusParams) {
+        self.0.borrow_mut().params = params.clone();
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::engine::Output;
+    use crate::scenario::test_engine;
+    use crate::state_machine::{Step, TimeoutStep};
+
+    #[test]
+    fn timers() {
+        let timers = Timers::default();
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(timers.clone()));
+        let ms = Duration::from_millis;
+        let timeout = |round, step| Timeout {
+            height: 1,
+            round,
+            step,
+        };
+
+        assert!(timers.elapsed(ms(100)).is_empty());
+        let outputs = engine.start_round(0, None);
+        let propose = timeout(0, TimeoutStep::Propose);
+        assert_eq!(outputs, vec![Output::ScheduleTimeout(propose)]);
+        assert_eq!(timers.next_deadline(), Some(ms(3100)));
+
+        // the elapsed timeout goes back through execute
+        assert!(timers.elapsed(ms(3099)).is_empty());
+        let elapsed = timers.elapsed(ms(3100));
+        assert_eq!(elapsed, vec![Message::TimeoutElapsed(propose)]);
+        for msg in elapsed {
+            engine.execute(msg);
+        }
+        assert_eq!(engine.state().step(), Step::Prevote);
+
+        // a stale one is ignored
+        assert!(engine.execute(Message::TimeoutElapsed(propose)).is_empty());
+        assert_eq!(engine.state().step(), Step::Prevote);
+    }
+}
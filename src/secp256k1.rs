@@ -0,0 +1,177 @@
+// Secp256k1 signs and verifies with ECDSA over secp256k1, enabled by the
+// secp256k1 feature, so chains whose validators already hold secp256k1 keys,
+// eg. from Cosmos or Bitcoin tooling, can use them as they are.
+//
+// Public keys are 33 byte compressed SEC1 points. Signatures are 64 bytes,
+// r then s, over the SHA-256 of the message, with s in the lower half of the
+// curve order as Cosmos chains require, so they can't be altered into another
+// valid signature. The validator set's addresses are still derived with its
+// Hasher; address() is the key's Cosmos and Bitcoin address, for drivers that
+// need to map validators to accounts.
+
+use super::signature::{Signature, SignatureScheme, Signer, Verifier};
+use super::validators::{Address, ValidatorSet};
+
+use k256::ecdsa::signature::{Signer as _, Verifier as _};
+use k256::ecdsa::{Signature as EcdsaSignature, SigningKey, VerifyingKey};
+use ripemd::{Digest, Ripemd160};
+
+// Secp256k1Signer signs as the validator at its index in a set.
+pub struct Secp256k1Signer {
+    index: usize,
+    key: SigningKey,
+}
+
+impl Secp256k1Signer {
+    // new returns the signer for the validator at the index,
+    // from its 32 byte secret key.
+    pub fn new(index: usize, secret_key: &[u8]) -> Result<Secp256k1Signer, String> {
+        let key = SigningKey::from_slice(secret_key).map_err(|e| e.to_string())?;
+        Ok(Secp256k1Signer { index, key })
+    }
+
+    // public_key returns the compressed public key, for the validator set.
+    pub fn public_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(true);
+        point.as_bytes().to_vec()
+    }
+}
+
+impl Signer for Secp256k1Signer {
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn sign(&self, msg: &[u8]) -> Signature {
+        let sig: EcdsaSignature = self.key.sign(msg);
+        sig.normalize_s().unwrap_or(sig).to_bytes().to_vec()
+    }
+}
+
+// Secp256k1Verifier checks signatures for the validator set, with the key
+// each validator signs with at the message's height.
+pub struct Secp256k1Verifier(pub ValidatorSet);
+
+impl Verifier for Secp256k1Verifier {
+    fn verify(&self, signer: usize, msg: &[u8], sig: &[u8]) -> bool {
+        match self.0.get(signer) {
+            Some(val) => verify(&val.public_key, msg, sig),
+            None => false,
+        }
+    }
+
+    fn verify_at(&self, signer: usize, height: i64, msg: &[u8], sig: &[u8]) -> bool {
+        match self.0.public_key_at(signer, height) {
+            Some(key) => verify(key, msg, sig),
+            None => false,
+        }
+    }
+}
+
+// Secp256k1Scheme creates Secp256k1Verifiers.
+pub struct Secp256k1Scheme;
+
+impl SignatureScheme for Secp256k1Scheme {
+    fn verifier(&self, validators: &ValidatorSet) -> Box<dyn Verifier> {
+        Box::new(Secp256k1Verifier(validators.clone()))
+    }
+}
+
+fn verify(public_key: &[u8], msg: &[u8], sig: &[u8]) -> bool {
+    let key = match VerifyingKey::from_sec1_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let sig = match EcdsaSignature::from_slice(sig) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    // a high s would normalize to another signature for the same message
+    sig.normalize_s().is_none() && key.verify(msg, &sig).is_ok()
+}
+
+// address returns the Cosmos and Bitcoin address of the public key: the
+// RIPEMD-160 of its SHA-256. It isn't the key's address in a ValidatorSet.
+pub fn address(public_key: &[u8]) -> Address {
+    let sha = sha2::Sha256::digest(public_key);
+    let mut address = [0; 20];
+    address.copy_from_slice(&Ripemd160::digest(sha));
+    address
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::{SignBytes, Signed};
+    use crate::validators::Validator;
+    use crate::{to_hex, Vote};
+
+    fn secret(b: u8) -> [u8; 32] {
+        let mut key = [0; 32];
+        key[31] = b;
+        key
+    }
+
+    #[test]
+    fn sign_verify() {
+        // the secret key 1 has the generator as its public key
+        let one = Secp256k1Signer::new(0, &secret(1)).unwrap();
+        let generator = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        assert_eq!(to_hex(&one.public_key()), generator);
+        let address = address(&one.public_key());
+        assert_eq!(to_hex(&address), "751e76e8199196d454941c45d1b3a323f1433bd6");
+        assert!(Secp256k1Signer::new(0, &[0; 32]).is_err());
+
+        // the set is sorted by address, so find the index of the key
+        let public_key = |b| Secp256k1Signer::new(0, &secret(b)).unwrap().public_key();
+        let validators = ValidatorSet::new(
+            (1..=4)
+                .map(|b| Validator {
+                    public_key: public_key(b),
+                    voting_power: 1,
+                })
+                .collect(),
+        );
+        let index = validators
+            .validators()
+            .iter()
+            .position(|v| v.public_key == public_key(3))
+            .unwrap();
+        let signer = Secp256k1Signer::new(index, &secret(3)).unwrap();
+        let other_index = (index + 1) % 4;
+
+        let vote = Vote::new_prevote(1, 0, None);
+        let signature = signer.sign(&vote.sign_bytes());
+        assert_eq!(signature.len(), 64);
+        let signed = Signed {
+            signer: index,
+            msg: vote,
+            signature: signature.clone(),
+        };
+        let verifier = Secp256k1Verifier(validators);
+        assert!(signed.is_valid(&verifier));
+        let wrong_signer = Signed {
+            signer: other_index,
+            ..signed.clone()
+        };
+        assert!(!wrong_signer.is_valid(&verifier));
+        let other = Signed {
+            msg: Vote::new_prevote(1, 1, None),
+            ..signed.clone()
+        };
+        assert!(!other.is_valid(&verifier));
+
+        // the same signature with a high s doesn't verify
+        let sig = EcdsaSignature::from_slice(&signature).unwrap();
+        let (r, s) = sig.split_scalars();
+        let high = EcdsaSignature::from_scalars(r, -*s).unwrap();
+        let malleated = Signed {
+            signature: high.to_bytes().to_vec(),
+            ..signed
+        };
+        assert!(!malleated.is_valid(&verifier));
+    }
+}
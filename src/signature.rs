@@ -0,0 +1,432 @@
+use super::validators::ValidatorSet;
+use super::{to_hex, Heartbeat, Proposal, Value, Vote, VoteType};
+
+use std::ops::Deref;
+
+//---------------------------------------------------------------------
+// Sign bytes
+
+// SignFormat is how messages are encoded for signing. It's chosen per
+// chain: all its validators must sign in the same format.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum SignFormat {
+    #[default]
+    Binary, // the sign bytes below
+    CanonicalJson(String), // canonical JSON for the chain id, for signers that only sign JSON
+}
+
+// SignBytes is implemented by messages that validators sign.
+pub trait SignBytes {
+    // sign_bytes returns the bytes the signature is computed over.
+    fn sign_bytes(&self) -> Vec<u8>;
+
+    // sign_json returns the canonical JSON the signature is computed over,
+    // for chains that sign in that format. By default, it's the chain id and
+    // the sign bytes in hex.
+    fn sign_json(&self, chain_id: &str) -> String {
+        format!(
+            "{{\"chain_id\":{},\"sign_bytes\":{}}}",
+            json_string(chain_id),
+            json_string(&to_hex(&self.sign_bytes()))
+        )
+    }
+
+    // sign_bytes_as returns the bytes to sign in the format.
+    fn sign_bytes_as(&self, format: &SignFormat) -> Vec<u8> {
+        match format {
+            SignFormat::Binary => self.sign_bytes(),
+            SignFormat::CanonicalJson(chain_id) => self.sign_json(chain_id).into_bytes(),
+        }
+    }
+
+    // height returns the height the message is for,
+    // which determines the key it's signed with.
+    fn height(&self) -> i64;
+}
+
+// Canonical JSON is an object with no whitespace, its keys sorted by their
+// bytes, and the chain id included. Integers are decimal strings, so they
+// survive 64-bit precision loss in JSON tooling, value ids are lower case hex,
+// and a vote for nil has a null value. Strings escape '"', '\\' and control
+// characters only: \b, \f, \n, \r and \t as such, like JSON encoders
+// commonly do, and the others as \u00XX.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_value(value: Option<Value>) -> String {
+    match value {
+        Some(v) => json_string(&to_hex(&v.id())),
+        None => "null".to_string(),
+    }
+}
+
+// Vote sign bytes: type (1 byte), height and round (8 bytes each, big endian),
+// then 0 if the vote is for nil, or 1 followed by the value id (32 bytes).
+impl SignBytes for Vote {
+    fn sign_bytes(&self) -> Vec<u8> {
+        let mut bz = Vec::with_capacity(50);
+        bz.push(match self.typ {
+            VoteType::Prevote => 1,
+            VoteType::Precommit => 2,
+        });
+        bz.extend_from_slice(&self.height.to_be_bytes());
+        bz.extend_from_slice(&self.round.to_be_bytes());
+        match self.value {
+            Some(v) => {
+                bz.push(1);
+                bz.extend_from_slice(&v.id());
+            }
+            None => bz.push(0),
+        }
+        bz
+    }
+
+    fn sign_json(&self, chain_id: &str) -> String {
+        let typ = match self.typ {
+            VoteType::Prevote => "prevote",
+            VoteType::Precommit => "precommit",
+        };
+        format!(
+            "{{\"chain_id\":{},\"height\":\"{}\",\"round\":\"{}\",\"type\":\"{}\",\"value\":{}}}",
+            json_string(chain_id),
+            self.height,
+            self.round,
+            typ,
+            json_value(self.value)
+        )
+    }
+
+    fn height(&self) -> i64 {
+        self.height
+    }
+}
+
+// Proposal sign bytes: type (1 byte, always 32), then height,
+// round and pol_round (8 bytes each, big endian), then the value id (32 bytes).
+impl SignBytes for Proposal {
+    fn sign_bytes(&self) -> Vec<u8> {
+        let mut bz = Vec::with_capacity(57);
+        bz.push(32);
+        bz.extend_from_slice(&self.height.to_be_bytes());
+        bz.extend_from_slice(&self.round.to_be_bytes());
+        bz.extend_from_slice(&self.pol_round.to_be_bytes());
+        bz.extend_from_slice(&self.value.id());
+        bz
+    }
+
+    fn sign_json(&self, chain_id: &str) -> String {
+        format!(
+            "{{\"chain_id\":{},\"height\":\"{}\",\"pol_round\":\"{}\",\"round\":\"{}\",\"type\":\"proposal\",\"value\":{}}}",
+            json_string(chain_id),
+            self.height,
+            self.pol_round,
+            self.round,
+            json_value(Some(self.value))
+        )
+    }
+
+    fn height(&self) -> i64 {
+        self.height
+    }
+}
+
+// Heartbeat sign bytes: type (1 byte, always 48), then height, round
+// and sequence (8 bytes each, big endian).
+impl SignBytes for Heartbeat {
+    fn sign_bytes(&self) -> Vec<u8> {
+        let mut bz = Vec::with_capacity(25);
+        bz.push(48);
+        bz.extend_from_slice(&self.height.to_be_bytes());
+        bz.extend_from_slice(&self.round.to_be_bytes());
+        bz.extend_from_slice(&self.sequence.to_be_bytes());
+        bz
+    }
+
+    fn sign_json(&self, chain_id: &str) -> String {
+        format!(
+            "{{\"chain_id\":{},\"height\":\"{}\",\"round\":\"{}\",\"sequence\":\"{}\",\"type\":\"heartbeat\"}}",
+            json_string(chain_id),
+            self.height,
+            self.round,
+            self.sequence
+        )
+    }
+
+    fn height(&self) -> i64 {
+        self.height
+    }
+}
+
+//---------------------------------------------------------------------
+// Signed and Verified messages
+
+pub type Signature = Vec<u8>;
+
+// Signed is a message along with the signature of the validator that sent it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signed<T> {
+    pub signer: usize, // index of the validator in the validator set
+    pub msg: T,
+    pub signature: Signature,
+}
+
+impl<T: SignBytes> Signed<T> {
+    // verify checks the signature, returning the verified message if it's valid.
+    pub fn verify(self, verifier: &dyn Verifier) -> Option<Verified<Signed<T>>> {
+        if self.is_valid(verifier) {
+            Some(Verified(self))
+        } else {
+            None
+        }
+    }
+
+    // is_valid returns true if the signature is valid.
+    pub fn is_valid(&self, verifier: &dyn Verifier) -> bool {
+        self.is_valid_as(verifier, &SignFormat::Binary)
+    }
+
+    // is_valid_as returns true if the signature is valid in the format.
+    pub fn is_valid_as(&self, verifier: &dyn Verifier, format: &SignFormat) -> bool {
+        let (signer, height) = (self.signer, self.msg.height());
+        let msg = self.msg.sign_bytes_as(format);
+        verifier.verify_at(signer, height, &msg, &self.signature)
+    }
+}
+
+// Verifier checks signatures of validators.
+// Implementations own the validators' public keys and signature scheme.
+pub trait Verifier {
+    // verify returns true if signature is a valid signature
+    // of msg by the validator at the given index.
+    fn verify(&self, signer: usize, msg: &[u8], signature: &[u8]) -> bool;
+
+    // verify_at is verify for a message at the height, for verifiers that
+    // support validators' key rotations: the signature must be by the key
+    // the validator signs with at the height.
+    fn verify_at(&self, signer: usize, _height: i64, msg: &[u8], signature: &[u8]) -> bool {
+        self.verify(signer, msg, signature)
+    }
+}
+
+// Signer signs messages as one of the validators.
+// Implementations own the validator's private key and signature scheme.
+pub trait Signer {
+    // index of the validator in the validator set.
+    fn index(&self) -> usize;
+
+    // sign returns the signature of msg.
+    fn sign(&self, msg: &[u8]) -> Signature;
+}
+
+// SignatureScheme creates Verifiers for any validator set, to check
+// signatures of sets the caller holds no Verifier for, eg. the commits
+// of the blocks a light client verifies.
+pub trait SignatureScheme {
+    fn verifier(&self, validators: &ValidatorSet) -> Box<dyn Verifier>;
+}
+
+// Verified is a message whose signatures have been checked.
+// It's produced by Signed::verify, or by new_unchecked when the
+// signatures were already checked elsewhere (eg. by the networking layer),
+// so they aren't checked twice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verified<T>(T);
+
+impl<T> Verified<T> {
+    // new_unchecked marks msg as verified without checking anything.
+    // The caller is responsible for having verified its signatures.
+    pub fn new_unchecked(msg: T) -> Verified<T> {
+        Verified(msg)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Verified<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+//---------------------------------------------------------------------
+// Sign state
+
+// SignStep orders what a validator signs in a round.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignStep {
+    Proposal,
+    Prevote,
+    Precommit,
+}
+
+// SignState is the last proposal or vote a validator signed: its height,
+// round and step, and its sign bytes and signature. Persisted before what it
+// signed is sent, it keeps a restarted validator from signing anything that
+// conflicts with what it signed before: nothing for an earlier height, round
+// or step, and for the same ones only the same bytes, with the same signature.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignState {
+    pub height: i64,
+    pub round: i64,
+    pub step: SignStep,
+    pub sign_bytes: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl SignState {
+    // check returns whether a message for the height, round and step with
+    // the sign bytes may be signed: Ok(None) if it's for a later one,
+    // Ok(Some(signature)) with the signature to use again if it's what was
+    // signed, and Err if it conflicts.
+    pub fn check(
+        &self,
+        height: i64,
+        round: i64,
+        step: SignStep,
+        sign_bytes: &[u8],
+    ) -> Result<Option<Signature>, String> {
+        let (hrs, last) = ((height, round, step), (self.height, self.round, self.step));
+        if hrs > last {
+            return Ok(None);
+        }
+        if hrs == last && sign_bytes == self.sign_bytes.as_slice() {
+            return Ok(Some(self.signature.clone()));
+        }
+        Err(format!(
+            "already signed {:?} at height {} round {}",
+            self.step, self.height, self.round
+        ))
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{from_hex, to_hex, Value};
+
+    fn value(b: &str) -> Value {
+        Value::new([b.parse().unwrap(); 32])
+    }
+
+    #[test]
+    fn sign_bytes_vectors() {
+        let vectors = include_str!("../testdata/sign_bytes.txt");
+        for line in vectors.lines().filter(|l| !l.starts_with('#')) {
+            let f: Vec<&str> = line.split(' ').collect();
+            let int = |i: usize| f[i].parse::<i64>().unwrap();
+            let (bytes, hex) = match f[0] {
+                "prevote" | "precommit" => {
+                    let v = if f[3] == "nil" {
+                        None
+                    } else {
+                        Some(value(f[3]))
+                    };
+                    let vote = if f[0] == "prevote" {
+                        Vote::new_prevote(int(1), int(2), v)
+                    } else {
+                        Vote::new_precommit(int(1), int(2), v)
+                    };
+                    (vote.sign_bytes(), f[4])
+                }
+                "proposal" => {
+                    let proposal = Proposal::new(int(1), int(2), value(f[4]), int(3));
+                    (proposal.sign_bytes(), f[5])
+                }
+                other => panic!("unknown message {}", other),
+            };
+            assert_eq!(to_hex(&bytes), hex, "{}", line);
+        }
+    }
+
+    #[test]
+    fn sign_json_vectors() {
+        let vectors = include_str!("../testdata/sign_json.txt");
+        for line in vectors.lines().filter(|l| !l.starts_with('#')) {
+            let f: Vec<&str> = line.split(' ').collect();
+            let int = |i: usize| f[i].parse::<i64>().unwrap();
+            let (json, expected) = match f[0] {
+                "prevote" | "precommit" => {
+                    let v = if f[3] == "nil" {
+                        None
+                    } else {
+                        Some(value(f[3]))
+                    };
+                    let vote = if f[0] == "prevote" {
+                        Vote::new_prevote(int(1), int(2), v)
+                    } else {
+                        Vote::new_precommit(int(1), int(2), v)
+                    };
+                    (vote.sign_json(f[4]), f[5])
+                }
+                "proposal" => {
+                    let proposal = Proposal::new(int(1), int(2), value(f[4]), int(3));
+                    (proposal.sign_json(f[5]), f[6])
+                }
+                "heartbeat" => {
+                    let heartbeat = Heartbeat::new(int(1), int(2), f[3].parse().unwrap());
+                    (heartbeat.sign_json(f[4]), f[5])
+                }
+                other => panic!("unknown message {}", other),
+            };
+            assert_eq!(json, expected, "{}", line);
+        }
+        let vote = Vote::new_prevote(1, 0, None);
+        let format = SignFormat::CanonicalJson("test-chain".to_string());
+        let json = vote.sign_json("test-chain").into_bytes();
+        assert_eq!(vote.sign_bytes_as(&format), json);
+        assert_eq!(vote.sign_bytes_as(&SignFormat::Binary), vote.sign_bytes());
+        assert_eq!(json_string("a\tb\u{1}é"), "\"a\\tb\\u0001é\"");
+
+        // messages without their own JSON sign their sign bytes
+        struct Raw;
+        impl SignBytes for Raw {
+            fn sign_bytes(&self) -> Vec<u8> {
+                vec![1, 255]
+            }
+            fn height(&self) -> i64 {
+                1
+            }
+        }
+        let json = "{\"chain_id\":\"test-chain\",\"sign_bytes\":\"01ff\"}";
+        assert_eq!(Raw.sign_json("test-chain"), json);
+    }
+
+    #[test]
+    fn json_string_vectors() {
+        let vectors = include_str!("../testdata/json_strings.txt");
+        for line in vectors.lines().filter(|l| !l.starts_with('#')) {
+            let (hex, expected) = line.split_once(' ').unwrap();
+            let bytes = if hex == "-" {
+                Vec::new()
+            } else {
+                from_hex(hex).unwrap()
+            };
+            let s = String::from_utf8(bytes).unwrap();
+            assert_eq!(json_string(&s), expected, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,977 @@
+// A deterministic discrete-event simulator for a network of engines.
+//
+// Every node runs an Engine. Message delays and timer jitter are
+// drawn from an RNG seeded by the caller, and events are processed in virtual
+// time, so a whole run is exactly reproducible from its seed.
+//
+// Each node may also have its own clock: it starts up to max_skew late, and
+// its timers run fast or slow by up to max_drift. min_timeout() is the
+// smallest timeout with which consensus still decides in the first round
+// led by a correct proposer, and min_viable_timeout() searches for the
+// smallest one that works in practice for a latency distribution.
+//
+//   let mut sim = Simulation::new(SimConfig::default(), seed)?;
+//   assert!(sim.run_until_height(3, 60_000));
+//   println!("{:?}", sim.trace());
+//
+// With record_corpus(), the simulation also keeps the wire encoding of every
+// distinct message the nodes receive, and write_corpus() writes them to a
+// directory, one file each, as realistic seeds for the fuzz targets:
+//
+//   sim.record_corpus(true);
+//   sim.run_until_height(3, 60_000);
+//   sim.write_corpus(Path::new("fuzz/corpus/decode_message"))?;
+//
+// Each node writes every input to a write-ahead log, and its sign state,
+// to its own MemStore before acting on the outputs, as a real driver would.
+// crash() kills a node at a point of a height, eg. right after it signed its
+// prevote, before it broadcasts it, and restarts it after a downtime from its
+// store alone. While it's down its peers keep what they send it, and send it
+// once it's back, as gossip would.
+
+use super::config::ConsensusConfig;
+use super::engine::{CorrelationId, Engine, Input, Message, Output, WalEntry};
+use super::gossip::{Gossip, PeerId, VotePriority};
+use super::hash::{Hasher, Sha256};
+use super::round_votes::is_quorum;
+use super::scheduler::{Scheduler, TimeoutHandle};
+use super::signature::{Signature, Signed, Signer, Verified, Verifier};
+use super::state_machine as sm;
+use super::store::{MemStore, Store};
+use super::validators::{Validator, ValidatorSet};
+use super::wire;
+use super::{to_hex, Proposal, Value, Vote, VoteType};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+//---------------------------------------------------------------------
+// Config
+
+// SimConfig holds the parameters of a simulation. Times are in virtual milliseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimConfig {
+    pub validators: usize,
+    pub consensus: ConsensusConfig,
+
+    // Messages are delivered after a delay drawn uniformly from [min_delay, max_delay].
+    pub min_delay: u64,
+    pub max_delay: u64,
+
+    pub timeout_propose: u64,
+    pub timeout_prevote: u64,
+    pub timeout_precommit: u64,
+    pub timeout_commit: u64,
+
+    // Timers fire up to timer_jitter after their duration.
+    pub timer_jitter: u64,
+
+    // Each node starts at a time drawn from [0, max_skew].
+    pub max_skew: u64,
+
+    // Each node's timers run fast or slow by a rate drawn from
+    // [-max_drift, max_drift], in parts per thousand.
+    pub max_drift: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            validators: 4,
+            consensus: ConsensusConfig {
+                wait_timeout_commit: true,
+                ..ConsensusConfig::default()
+            },
+            min_delay: 10,
+            max_delay: 200,
+            timeout_propose: 3000,
+            timeout_prevote: 1000,
+            timeout_precommit: 1000,
+            timeout_commit: 1000,
+            timer_jitter: 50,
+            max_skew: 0,
+            max_drift: 0,
+        }
+    }
+}
+
+impl SimConfig {
+    fn duration(&self, step: sm::TimeoutStep) -> u64 {
+        match step {
+            sm::TimeoutStep::Propose => self.timeout_propose,
+            sm::TimeoutStep::Prevote => self.timeout_prevote,
+            sm::TimeoutStep::Precommit => self.timeout_precommit,
+            sm::TimeoutStep::Commit => self.timeout_commit,
+        }
+    }
+
+    // min_timeout returns the smallest propose, prevote and precommit timeout
+    // with which every round led by a correct proposer decides, given the
+    // delays and clocks. By the fastest clock, it covers how far apart nodes
+    // may enter a round, plus delivering the messages of the step.
+    pub fn min_timeout(&self) -> u64 {
+        // after the first height, nodes start a height within a message
+        // delay of each other, plus how far apart their commit timers fire
+        let commit_spread = self.timeout_commit * 2 * self.max_drift / 1000;
+        let spread = self
+            .max_skew
+            .max(self.max_delay + self.timer_jitter + commit_spread);
+        let need = spread + self.max_delay;
+        let rate = 1000 - self.max_drift.min(999);
+        (need * 1000).div_ceil(rate)
+    }
+
+    // is_viable returns true if the timeouts are at least min_timeout().
+    pub fn is_viable(&self) -> bool {
+        let min = self.min_timeout();
+        self.timeout_propose >= min && self.timeout_prevote >= min && self.timeout_precommit >= min
+    }
+
+    // with_timeout returns the config with the propose, prevote and
+    // precommit timeouts all set to timeout.
+    pub fn with_timeout(&self, timeout: u64) -> SimConfig {
+        SimConfig {
+            timeout_propose: timeout,
+            timeout_prevote: timeout,
+            timeout_precommit: timeout,
+            ..self.clone()
+        }
+    }
+}
+
+// min_viable_timeout searches for the smallest timeout, as set by
+// with_timeout(), with which the simulations of every seed decide the height,
+// each height in at most max_round. The search assumes that if a timeout is
+// viable, longer ones are too, and doesn't look past 4 * min_timeout().
+pub fn min_viable_timeout(
+    config: &SimConfig,
+    seeds: &[u64],
+    height: i64,
+    max_round: i64,
+) -> Option<u64> {
+    let viable = |timeout: u64| {
+        let config = config.with_timeout(timeout);
+        let per_round = 3 * timeout + config.timeout_commit + 4 * config.max_delay;
+        let max_time = config.max_skew + (height as u64) * (max_round as u64 + 1) * per_round;
+        seeds.iter().all(|seed| {
+            Simulation::new(config.clone(), *seed).is_ok_and(|mut sim| {
+                sim.run_until_height(height, max_time)
+                    && sim.max_decision_round() <= Some(max_round)
+            })
+        })
+    };
+    let (mut lo, mut hi) = (1, 4 * config.min_timeout().max(1));
+    if !viable(hi) {
+        return None;
+    }
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if viable(mid) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Some(hi)
+}
+
+//---------------------------------------------------------------------
+// Rng
+
+// Rng is a SplitMix64 generator: small, fast, and the same on every platform.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // range returns a number in [lo, hi].
+    pub fn range(&mut self, lo: u64, hi: u64) -> u64 {
+        lo + self.next_u64() % (hi - lo + 1)
+    }
+}
+
+//---------------------------------------------------------------------
+// Events
+
+// Payload is a message in flight between nodes.
+enum Payload {
+    Proposal(Proposal),
+    Vote(Vote),
+}
+
+enum Event {
+    Deliver {
+        from: usize,
+        to: usize,
+        payload: Payload,
+    },
+    Timeout {
+        node: usize,
+        timeout: sm::Timeout,
+    },
+    Start {
+        node: usize,
+    },
+    Restart {
+        node: usize,
+    },
+}
+
+// Clock is how a node's clock differs from virtual time.
+#[derive(Copy, Clone, Debug)]
+struct Clock {
+    skew: u64,  // when the node starts
+    drift: i64, // how much slower its timers run, in parts per thousand
+}
+
+impl Clock {
+    // elapse returns how long a timer for the duration takes in virtual time.
+    fn elapse(&self, duration: u64) -> u64 {
+        (duration as i64 * (1000 + self.drift) / 1000) as u64
+    }
+}
+
+// Queue holds the pending events by virtual time. Events at the same
+// time are processed in the order they were queued.
+struct Queue {
+    config: SimConfig,
+    clocks: Vec<Clock>, // by node
+    rng: Rng,
+    now: u64,
+    seq: u64,
+    events: BTreeMap<(u64, u64), Event>,
+}
+
+impl Queue {
+    // push queues the event after delay, returning its sequence number.
+    fn push(&mut self, delay: u64, event: Event) -> u64 {
+        self.seq += 1;
+        self.events.insert((self.now + delay, self.seq), event);
+        self.seq
+    }
+
+    fn pop(&mut self, until: u64) -> Option<Event> {
+        let (&(time, seq), _) = self.events.iter().next()?;
+        if time > until {
+            return None;
+        }
+        self.now = time;
+        self.events.remove(&(time, seq))
+    }
+}
+
+// NodeScheduler queues a node's timeouts as events.
+struct NodeScheduler {
+    node: usize,
+    queue: Rc<RefCell<Queue>>,
+}
+
+impl Scheduler for NodeScheduler {
+    fn schedule(&mut self, timeout: sm::Timeout) -> TimeoutHandle {
+        let mut queue = self.queue.borrow_mut();
+        let delay = queue.config.duration(timeout.step);
+        let jitter = queue.config.timer_jitter;
+        let delay = delay + queue.rng.range(0, jitter);
+        let node = self.node;
+        let delay = queue.clocks[node].elapse(delay);
+        TimeoutHandle(queue.push(delay, Event::Timeout { node, timeout }))
+    }
+
+    fn cancel(&mut self, handle: TimeoutHandle) {
+        let mut queue = self.queue.borrow_mut();
+        queue.events.retain(|&(_, seq), _| seq != handle.0);
+    }
+}
+
+// Simulated messages are delivered as already verified, and NoVerifier
+// accepts them again when the engine applies the ones it buffered.
+struct NoVerifier;
+
+impl Verifier for NoVerifier {
+    fn verify(&self, _signer: usize, _msg: &[u8], _signature: &[u8]) -> bool {
+        true
+    }
+}
+
+// NodeSigner signs as the node's validator. Signatures are never checked, so they're empty.
+struct NodeSigner(usize);
+
+impl Signer for NodeSigner {
+    fn index(&self) -> usize {
+        self.0
+    }
+
+    fn sign(&self, _msg: &[u8]) -> Signature {
+        Vec::new()
+    }
+}
+
+//---------------------------------------------------------------------
+// Crashes
+
+// CrashPoint is where in a height a node crashes: right after the step in
+// which it signed its proposal, prevote or precommit, before sending it, or
+// after it decided, before acting on the decision.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CrashPoint {
+    Proposal,
+    Prevote,
+    Precommit,
+    Decision,
+}
+
+impl CrashPoint {
+    // reached returns true if the outputs of the height reach the point.
+    fn reached(&self, height: i64, outputs: &[Output]) -> bool {
+        outputs.iter().any(|output| match (self, output) {
+            (CrashPoint::Proposal, Output::Proposal(p)) => p.msg.height == height,
+            (CrashPoint::Prevote, Output::Vote(v)) => {
+                v.msg.height == height && v.msg.typ == VoteType::Prevote
+            }
+            (CrashPoint::Precommit, Output::Vote(v)) => {
+                v.msg.height == height && v.msg.typ == VoteType::Precommit
+            }
+            (CrashPoint::Decision, Output::Decision(_)) => true,
+            _ => false,
+        })
+    }
+}
+
+// Crash kills the node the first time it reaches the point at the height,
+// and restarts it downtime later. If lose_wal_entry, the WAL write of the
+// input it crashed on wasn't synced, so it's lost, while the sign state was.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Crash {
+    pub node: usize,
+    pub height: i64,
+    pub point: CrashPoint,
+    pub lose_wal_entry: bool,
+    pub downtime: u64,
+}
+
+//---------------------------------------------------------------------
+// Simulation
+
+// TraceEntry is an output of a node's engine.
+#[derive(Debug, PartialEq)]
+pub struct TraceEntry {
+    pub time: u64,
+    pub node: usize,
+    pub height: i64,
+    pub output: Output,
+    pub correlation_id: CorrelationId, // of the engine call that output it
+}
+
+pub struct Simulation {
+    nodes: Vec<Engine>,
+    queue: Rc<RefCell<Queue>>,
+    trace: Vec<TraceEntry>,
+    decisions: Vec<Vec<Value>>, // decided values by node, in height order
+    waiting: Vec<Option<Vec<Message>>>, // messages for nodes that haven't started yet
+    corpus: Option<BTreeSet<Vec<u8>>>, // encoded messages received, if recording
+    stores: Vec<MemStore>,      // by node, kept across restarts
+    crashes: Vec<Crash>,        // still to happen
+    validators: ValidatorSet,
+    consensus: ConsensusConfig,
+}
+
+impl Simulation {
+    // new creates the nodes at height 1 and starts round 0.
+    // Node i is the validator at index i of a set of validators with voting power 1.
+    // Returns an error if the consensus config doesn't validate.
+    pub fn new(config: SimConfig, seed: u64) -> Result<Simulation, String> {
+        let n = config.validators;
+        let validators: ValidatorSet = ValidatorSet::new(
+            (0..n)
+                .map(|i| Validator {
+                    public_key: (i as u64).to_be_bytes().to_vec(),
+                    voting_power: 1,
+                })
+                .collect(),
+        );
+        let mut rng = Rng::new(seed);
+        let drift = config.max_drift.min(999);
+        let clocks = (0..n)
+            .map(|_| Clock {
+                skew: rng.range(0, config.max_skew),
+                drift: rng.range(0, 2 * drift) as i64 - drift as i64,
+            })
+            .collect::<Vec<_>>();
+        let queue = Rc::new(RefCell::new(Queue {
+            config: config.clone(),
+            clocks: clocks.clone(),
+            rng,
+            now: 0,
+            seq: 0,
+            events: BTreeMap::new(),
+        }));
+        let nodes = (0..n)
+            .map(|node| new_node(&config.consensus, &validators, node, &queue))
+            .collect::<Result<_, _>>()?;
+        let mut sim = Simulation {
+            nodes,
+            queue,
+            trace: Vec::new(),
+            decisions: vec![Vec::new(); n],
+            waiting: vec![None; n],
+            corpus: None,
+            stores: vec![MemStore::default(); n],
+            crashes: Vec::new(),
+            validators,
+            consensus: config.consensus.clone(),
+        };
+        for (node, clock) in clocks.iter().enumerate() {
+            if clock.skew == 0 {
+                sim.start_round(node, 0);
+            } else {
+                sim.waiting[node] = Some(Vec::new());
+                let event = Event::Start { node };
+                sim.queue.borrow_mut().push(clock.skew, event);
+            }
+        }
+        Ok(sim)
+    }
+
+    // now returns the virtual time, in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.queue.borrow().now
+    }
+
+    // trace returns the messages output by the nodes so far, in order.
+    pub fn trace(&self) -> &[TraceEntry] {
+        &self.trace
+    }
+
+    // decisions returns the values decided by the node, in height order.
+    pub fn decisions(&self, node: usize) -> &[Value] {
+        &self.decisions[node]
+    }
+
+    // node returns the engine of the node, as restarted if it crashed.
+    pub fn node(&self, node: usize) -> &Engine {
+        &self.nodes[node]
+    }
+
+    // pending_crashes returns the crashes that haven't happened yet.
+    pub fn pending_crashes(&self) -> &[Crash] {
+        &self.crashes
+    }
+
+    // max_decision_round returns the highest round in which a node decided.
+    pub fn max_decision_round(&self) -> Option<i64> {
+        self.trace
+            .iter()
+            .filter_map(|e| match e.output {
+                Output::Decision(d) => Some(d.round),
+                _ => None,
+            })
+            .max()
+    }
+
+    // record_corpus starts or stops recording the wire encoding of each
+    // distinct message the nodes receive, including timeouts.
+    // Stopping discards what was recorded.
+    pub fn record_corpus(&mut self, enable: bool) {
+        self.corpus = if enable { Some(BTreeSet::new()) } else { None };
+    }
+
+    // corpus returns the recorded messages' encodings, in byte order.
+    pub fn corpus(&self) -> impl Iterator<Item = &[u8]> {
+        self.corpus.iter().flatten().map(|bz| bz.as_slice())
+    }
+
+    // write_corpus writes each recorded message to a file in the directory,
+    // named by the hex of its hash as fuzzers name their corpus entries,
+    // creating the directory if needed. Returns the number of files written.
+    pub fn write_corpus(&self, dir: &Path) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
+        let mut written = 0;
+        for bz in self.corpus() {
+            fs::write(dir.join(to_hex(&Sha256::hash(bz))), bz)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // crash schedules the crash of a node.
+    pub fn crash(&mut self, crash: Crash) {
+        self.crashes.push(crash);
+    }
+
+    // run_until_height processes events until every node decided the given height,
+    // or the virtual time passes max_time. Returns true if every node decided.
+    pub fn run_until_height(&mut self, height: i64, max_time: u64) -> bool {
+        let done = |sim: &Simulation| sim.decisions.iter().all(|d| d.len() as i64 >= height);
+        while !done(self) {
+            let event = self.queue.borrow_mut().pop(max_time);
+            match event {
+                Some(event) => self.process(event),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn process(&mut self, event: Event) {
+        let (node, msg) = match event {
+            Event::Deliver { from, to, payload } => {
+                let msg = match payload {
+                    Payload::Proposal(p) => Message::Proposal(signed(from, p)),
+                    Payload::Vote(v) => Message::Vote(signed(from, v)),
+                };
+                (to, msg)
+            }
+            Event::Timeout { node, timeout } => (node, Message::TimeoutElapsed(timeout)),
+            Event::Start { node } => {
+                self.start_round(node, 0);
+                for msg in self.waiting[node].take().unwrap_or_default() {
+                    self.apply(node, msg);
+                }
+                return;
+            }
+            Event::Restart { node } => {
+                self.restart(node);
+                for msg in self.waiting[node].take().unwrap_or_default() {
+                    self.apply(node, msg);
+                }
+                return;
+            }
+        };
+        match &mut self.waiting[node] {
+            // the node's peers send it what it missed once it's up
+            Some(waiting) => waiting.push(msg),
+            None => self.apply(node, msg),
+        }
+    }
+
+    fn apply(&mut self, node: usize, msg: Message) {
+        if let Some(corpus) = &mut self.corpus {
+            corpus.insert(wire::encode(&msg));
+        }
+        self.input(node, Input::Verified(Verified::new_unchecked(msg)));
+    }
+
+    // start_round starts the round on the node, proposing if it's the proposer.
+    fn start_round(&mut self, node: usize, round: i64) {
+        let height = self.nodes[node].height();
+        let value = if proposer(height, round, self.nodes.len()) == node {
+            Some(proposal_value(height, round))
+        } else {
+            None
+        };
+        self.input(node, Input::StartRound(round, value));
+    }
+
+    // input writes the input to the node's WAL, steps its engine with it,
+    // and saves its sign state, before acting on the outputs, unless the
+    // node crashes on them.
+    fn input(&mut self, node: usize, input: Input) {
+        let height = self.nodes[node].height();
+        let at = Duration::from_millis(self.now());
+        let entry = WalEntry {
+            height,
+            at,
+            input: input.clone(),
+        };
+        // the store as it was synced, if the entry may be lost
+        let lose = |c: &Crash| c.node == node && c.lose_wal_entry;
+        let synced = self
+            .crashes
+            .iter()
+            .any(lose)
+            .then(|| self.stores[node].clone());
+        self.stores[node].append_wal(&entry).unwrap();
+        let outputs = self.nodes[node].step(input, at);
+        if let Some(state) = self.nodes[node].sign_state() {
+            self.stores[node].save_sign_state(state).unwrap();
+        }
+        let crash = self.crashes.iter().position(|c| {
+            c.node == node && c.height == height && c.point.reached(height, &outputs)
+        });
+        match crash {
+            Some(i) => {
+                let crash = self.crashes.remove(i);
+                if let (true, Some(synced)) = (crash.lose_wal_entry, synced) {
+                    self.lose_wal_entry(node, synced, entry);
+                }
+                self.kill(crash, height, outputs);
+            }
+            None => self.output(node, height, outputs),
+        }
+    }
+
+    // lose_wal_entry restores the node's store to how it was synced before
+    // the entry, but with the sign state, which was synced. A peer's
+    // message that was lost is sent again once the node is back.
+    fn lose_wal_entry(&mut self, node: usize, mut synced: MemStore, entry: WalEntry) {
+        if let Some(state) = self.nodes[node].sign_state() {
+            synced.save_sign_state(state).unwrap();
+        }
+        self.stores[node] = synced;
+        let mut waiting = Vec::new();
+        if let Input::Verified(msg) = entry.input {
+            waiting.push(msg.into_inner());
+        }
+        self.waiting[node] = Some(waiting);
+    }
+
+    // kill crashes the node, which signed the outputs of the height but
+    // didn't act on them, and schedules its restart.
+    fn kill(&mut self, crash: Crash, height: i64, outputs: Vec<Output>) {
+        let node = crash.node;
+        self.trace_outputs(node, height, outputs);
+        self.waiting[node].get_or_insert_with(Vec::new);
+        let mut queue = self.queue.borrow_mut();
+        queue
+            .events
+            .retain(|_, e| !matches!(e, Event::Timeout { node: n, .. } if *n == node));
+        queue.push(crash.downtime, Event::Restart { node });
+    }
+
+    // restart replaces the node's engine with one restored from its store:
+    // its sign state, then the WAL replayed from the start. Decisions it
+    // hadn't acted on are recorded, the round it was about to start is
+    // started, and its latest proposal and votes are sent again.
+    fn restart(&mut self, node: usize) {
+        let engine = new_node(&self.consensus, &self.validators, node, &self.queue);
+        let mut engine = engine.expect("the config validated when the simulation started");
+        let store = &self.stores[node];
+        if let Some(state) = store.sign_state().unwrap() {
+            engine.restore_sign_state(state);
+        }
+        let wal = store.wal(0).unwrap();
+        let outputs = engine.replay(&wal);
+        self.nodes[node] = engine;
+
+        let decided = outputs.iter().filter_map(|output| match output {
+            Output::Decision(d) => Some(d.value),
+            _ => None,
+        });
+        for (height, value) in decided.enumerate() {
+            if height >= self.decisions[node].len() {
+                self.decisions[node].push(value);
+            }
+        }
+        let state = self.nodes[node].state();
+        if state.step() == sm::Step::NewRound {
+            self.start_round(node, state.round());
+        }
+        let height = self.nodes[node].height();
+        let outputs = self.nodes[node].rebroadcast();
+        self.output(node, height, outputs);
+    }
+
+    // output acts on the outputs of the node at the given height.
+    fn output(&mut self, node: usize, height: i64, outputs: Vec<Output>) {
+        let mut next = None;
+        for output in &outputs {
+            match &output {
+                Output::Proposal(p) => self.broadcast(node, || Payload::Proposal(p.msg.clone())),
+                Output::Vote(v) => self.broadcast(node, || Payload::Vote(v.msg)),
+                Output::ScheduleTimeout(_) => {} // already scheduled by the engine
+                Output::Evidence(_) => {}        // only traced
+                Output::Halt(_) => {}
+                Output::NewRound(round) => next = Some(*round),
+                Output::Decision(d) => {
+                    self.decisions[node].push(d.value);
+                    // without timeout commit we're already at the next height
+                    if self.nodes[node].height() > height {
+                        next = Some(0);
+                    }
+                }
+            }
+        }
+        self.trace_outputs(node, height, outputs);
+        if let Some(round) = next {
+            self.start_round(node, round);
+        }
+    }
+
+    fn trace_outputs(&mut self, node: usize, height: i64, outputs: Vec<Output>) {
+        let time = self.now();
+        let correlation_id = self.nodes[node].correlation_id();
+        for output in outputs {
+            self.trace.push(TraceEntry {
+                time,
+                node,
+                height,
+                output,
+                correlation_id,
+            });
+        }
+    }
+
+    // broadcast sends the message to every other node.
+    // The sender's engine already applied it.
+    fn broadcast<F>(&mut self, from: usize, payload: F)
+    where
+        F: Fn() -> Payload,
+    {
+        let mut queue = self.queue.borrow_mut();
+        let (lo, hi) = (queue.config.min_delay, queue.config.max_delay);
+        for to in (0..self.nodes.len()).filter(|to| *to != from) {
+            let delay = queue.rng.range(lo, hi);
+            let payload = payload();
+            queue.push(delay, Event::Deliver { from, to, payload });
+        }
+    }
+}
+
+// new_node creates the engine of the node at height 1.
+fn new_node(
+    config: &ConsensusConfig,
+    validators: &ValidatorSet,
+    node: usize,
+    queue: &Rc<RefCell<Queue>>,
+) -> Result<Engine, String> {
+    let scheduler = NodeScheduler {
+        node,
+        queue: queue.clone(),
+    };
+    Engine::new(
+        config.clone(),
+        1,
+        validators.clone(),
+        Box::new(NodeSigner(node)),
+        Box::new(NoVerifier),
+        Box::new(scheduler),
+    )
+}
+
+// proposer is chosen round robin.
+fn proposer(height: i64, round: i64, n: usize) -> usize {
+    ((height + round) as usize) % n
+}
+
+// proposal_value is the value proposed at the height and round.
+fn proposal_value(height: i64, round: i64) -> Value {
+    let bytes = [height.to_be_bytes(), round.to_be_bytes()].concat();
+    Value::from_bytes::<Sha256>(&bytes)
+}
+
+fn signed<T>(signer: usize, msg: T) -> Signed<T> {
+    Signed {
+        signer,
+        msg,
+        signature: Vec::new(),
+    }
+}
+
+//---------------------------------------------------------------------
+// Gossip
+
+// GossipEvent is an event of gossip_catch_up().
+enum GossipEvent {
+    Send(usize),           // The peer sends the node the next vote it lacks.
+    Deliver(Signed<Vote>), // The vote arrives at the node.
+    HasVote(usize, usize), // The peer learns the node has the validator's vote.
+}
+
+// gossip_catch_up measures how long a node that missed a round's prevotes
+// takes to gather a quorum of them by gossip, in virtual milliseconds, for
+// validators with the voting powers. Each validator is a peer of the node,
+// with its own prevote and each of the others' with probability 1/2. Every
+// interval, it sends the node the vote it lacks that comes first by the
+// priority, delivered after a delay drawn from the config's
+// [min_delay, max_delay], and the node tells every peer of each vote it
+// receives the same way, so they don't send it again. Returns None if the
+// interval is 0, or there are no validators, or their powers are negative or
+// sum to 0, as a quorum can't be gathered then.
+pub fn gossip_catch_up(
+    config: &SimConfig,
+    powers: &[i64],
+    priority: VotePriority,
+    interval: u64,
+    seed: u64,
+) -> Option<u64> {
+    const NODE: PeerId = 0;
+    if interval == 0 || powers.iter().any(|&p| p < 0) || powers.iter().sum::<i64>() <= 0 {
+        return None;
+    }
+    let mut rng = Rng::new(seed);
+    let value = Value::new([1; 32]);
+    let prevote = |validator| Signed {
+        signer: validator,
+        msg: Vote::new_prevote(1, 0, Some(value)),
+        signature: Vec::new(),
+    };
+    let n = powers.len();
+    let mut peers = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut gossip = Gossip::new(powers.to_vec());
+        gossip.set_priority(priority);
+        gossip.peer_progress(NODE, 1, 0);
+        for v in 0..n {
+            if v == i || rng.range(0, 1) == 0 {
+                gossip.add_vote(prevote(v));
+            }
+        }
+        peers.push(gossip);
+    }
+
+    // events by time, then the order they were queued
+    let mut events = BTreeMap::new();
+    let mut seq = 0;
+    for i in 0..n {
+        seq += 1;
+        events.insert((rng.range(0, interval - 1), seq), GossipEvent::Send(i));
+    }
+    let total: i64 = powers.iter().sum();
+    let mut have = vec![false; n];
+    let mut weight = 0;
+    while let Some(((now, _), event)) = events.pop_first() {
+        let mut queued = Vec::new();
+        match event {
+            GossipEvent::Send(i) => {
+                if let Some(vote) = peers[i].next_vote(NODE) {
+                    let delay = rng.range(config.min_delay, config.max_delay);
+                    queued.push((now + delay, GossipEvent::Deliver(vote)));
+                }
+                queued.push((now + interval, GossipEvent::Send(i)));
+            }
+            GossipEvent::Deliver(vote) => {
+                if have[vote.signer] {
+                    continue;
+                }
+                have[vote.signer] = true;
+                weight += powers[vote.signer];
+                if is_quorum(weight, total) {
+                    return Some(now);
+                }
+                for p in 0..n {
+                    let delay = rng.range(config.min_delay, config.max_delay);
+                    queued.push((now + delay, GossipEvent::HasVote(p, vote.signer)));
+                }
+            }
+            GossipEvent::HasVote(p, validator) => {
+                peers[p].peer_has_vote(NODE, &prevote(validator).msg, validator);
+            }
+        }
+        for (at, event) in queued {
+            seq += 1;
+            events.insert((at, seq), event);
+        }
+    }
+    unreachable!("peers keep sending until the node has every vote")
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agreement() {
+        let mut sim = Simulation::new(SimConfig::default(), 7).unwrap();
+        assert!(sim.run_until_height(3, 60_000));
+        for node in 1..4 {
+            assert_eq!(sim.decisions(node)[..3], sim.decisions(0)[..3]);
+        }
+    }
+
+    #[test]
+    fn reproducible() {
+        let run = |seed| {
+            let mut sim = Simulation::new(SimConfig::default(), seed).unwrap();
+            sim.run_until_height(2, 60_000);
+            sim.trace
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn corpus() {
+        let mut sim = Simulation::new(SimConfig::default(), 3).unwrap();
+        sim.run_until_height(1, 60_000);
+        assert_eq!(sim.corpus().count(), 0);
+        sim.record_corpus(true);
+        assert!(sim.run_until_height(2, 60_000));
+
+        // every recorded message decodes back, and votes outnumber proposals
+        let msgs: Vec<Message> = sim.corpus().map(|bz| wire::decode(bz).unwrap()).collect();
+        let count = |f: fn(&Message) -> bool| msgs.iter().filter(|m| f(m)).count();
+        let proposals = count(|m| matches!(m, Message::Proposal(_)));
+        assert!(proposals > 0);
+        assert!(count(|m| matches!(m, Message::Vote(_))) > proposals);
+
+        let dir = std::env::temp_dir().join(format!("agnes-corpus-{}", std::process::id()));
+        assert_eq!(sim.write_corpus(&dir).unwrap(), msgs.len());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), msgs.len());
+        let hash = to_hex(&Sha256::hash(sim.corpus().next().unwrap()));
+        assert_eq!(
+            fs::read(dir.join(hash)).unwrap(),
+            sim.corpus().next().unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gossip_priority() {
+        // eight validators with little power, then four with most of it
+        let powers = [1, 1, 1, 1, 1, 1, 1, 1, 10, 20, 30, 40];
+        let config = SimConfig::default();
+        let mean = |priority| {
+            let seeds = 0..20;
+            let times = seeds.map(|seed| gossip_catch_up(&config, &powers, priority, 10, seed));
+            times.map(Option::unwrap).sum::<u64>() / 20
+        };
+        let (by_index, by_power) = (mean(VotePriority::Index), mean(VotePriority::Power));
+        assert!(by_power * 3 < by_index * 2, "{} vs {}", by_power, by_index);
+
+        // no quorum to gather, or no time to gather it in
+        let catch_up = |powers: &[i64], interval| {
+            gossip_catch_up(&config, powers, VotePriority::Power, interval, 0)
+        };
+        assert_eq!(catch_up(&powers, 0), None);
+        assert_eq!(catch_up(&[], 10), None);
+        assert_eq!(catch_up(&[0, 0, 0], 10), None);
+        assert_eq!(catch_up(&[-1, 1, 1], 10), None);
+        assert!(catch_up(&[0, 1, 1], 10).is_some());
+    }
+
+    #[test]
+    fn clock_drift() {
+        let config = SimConfig {
+            max_skew: 500,
+            max_drift: 50,
+            ..SimConfig::default()
+        };
+        let min = config.min_timeout();
+        assert!(!config.with_timeout(min - 1).is_viable());
+
+        // with the minimum timeouts, every height decides in its first round
+        let config = config.with_timeout(min);
+        assert!(config.is_viable());
+        for seed in 0..10 {
+            let mut sim = Simulation::new(config.clone(), seed).unwrap();
+            assert!(sim.run_until_height(5, 60_000));
+            assert_eq!(sim.max_decision_round(), Some(0));
+            for node in 1..4 {
+                assert_eq!(sim.decisions(node)[..5], sim.decisions(0)[..5]);
+            }
+        }
+
+        // in practice, shorter timeouts do too
+        let viable = min_viable_timeout(&config, &[1, 2, 3], 3, 0).unwrap();
+        assert!(viable <= min);
+    }
+}
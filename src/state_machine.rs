@@ -6,8 +6,8 @@ use super::{Proposal, Value, Vote};
 // RoundValue contains a Value and associated Round.
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct RoundValue {
-    round: i64,
-    value: Value,
+    pub round: i64,
+    pub value: Value,
 }
 
 // Step is the step of the consensus in the round.
@@ -20,32 +20,115 @@ pub enum Step {
     Commit,
 }
 
+// Lock holds the value we're locked on and the valid value.
+// Locking on a value also makes it the valid value, and the valid value
+// only moves to later rounds, so when we're locked there's always a valid
+// value from the same round or later. The variants make any other
+// combination unrepresentable.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Lock {
+    None,
+    Valid(RoundValue),
+    Locked {
+        locked: RoundValue,
+        valid: RoundValue,
+    },
+}
+
 // State is the state of the consensus state machine.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct State {
     height: i64,
     round: i64,
     step: Step,
-    locked: Option<RoundValue>,
-    valid: Option<RoundValue>,
+    lock: Lock,
 }
 
 impl State {
     // new creates a new State at the given height.
     pub fn new(height: i64) -> State {
         State {
-            height: height,
+            height,
             round: 0,
             step: Step::NewRound,
-            locked: None,
-            valid: None,
+            lock: Lock::None,
+        }
+    }
+
+    // restore creates a State in the middle of the height, eg. from a
+    // snapshot. Returns an error if the lock couldn't have been reached:
+    // when locked there must be a valid value from the same round or later,
+    // and neither may be from a later round than the current one. Only built
+    // for tests, or with the test_utils feature.
+    #[cfg(any(test, feature = "test_utils"))]
+    pub fn restore(
+        height: i64,
+        round: i64,
+        step: Step,
+        locked: Option<RoundValue>,
+        valid: Option<RoundValue>,
+    ) -> Result<State, String> {
+        if height < 1 || round < 0 {
+            return Err(format!("bad height {} or round {}", height, round));
+        }
+        let outside = |rv: &RoundValue| rv.round < 0 || rv.round > round;
+        if [locked, valid].iter().flatten().any(outside) {
+            return Err(format!(
+                "locked or valid value outside rounds 0 to {}",
+                round
+            ));
+        }
+        let lock = match (locked, valid) {
+            (None, None) => Lock::None,
+            (None, Some(valid)) => Lock::Valid(valid),
+            (Some(locked), Some(valid)) if valid.round >= locked.round => {
+                Lock::Locked { locked, valid }
+            }
+            (Some(_), _) => return Err("locked without a valid value as recent".to_string()),
+        };
+        Ok(State {
+            height,
+            round,
+            step,
+            lock,
+        })
+    }
+
+    // height returns the height of the State.
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+
+    // round returns the current round of the State.
+    pub fn round(&self) -> i64 {
+        self.round
+    }
+
+    // step returns the current step of the State.
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    // locked returns the locked value and round, if any.
+    pub fn locked(&self) -> Option<RoundValue> {
+        match self.lock {
+            Lock::Locked { locked, .. } => Some(locked),
+            _ => None,
+        }
+    }
+
+    // valid returns the valid value and round, if any.
+    pub fn valid(&self) -> Option<RoundValue> {
+        match self.lock {
+            Lock::None => None,
+            Lock::Valid(valid) | Lock::Locked { valid, .. } => Some(valid),
         }
     }
 
     // set_round sets the State to step NewRound at the given round.
     fn set_round(self, round: i64) -> State {
         State {
-            round: round,
+            round,
             step: Step::NewRound,
             ..self
         }
@@ -62,7 +145,7 @@ impl State {
             Step::Prevote => Step::Precommit,
             _ => self.step,
         };
-        State { step: step, ..self }
+        State { step, ..self }
     }
 
     // commit_step sets State to the Commit step.
@@ -74,18 +157,33 @@ impl State {
         }
     }
 
-    // set_locked sets the locked value and round.
+    // set_locked sets the locked value and round, and the valid value and round
+    // to the same.
     fn set_locked(self, value: Value) -> State {
         let round = self.round;
-        let locked = Some(RoundValue { round, value });
-        State { locked, ..self }
+        let locked = RoundValue { round, value };
+        let lock = Lock::Locked {
+            locked,
+            valid: locked,
+        };
+        State { lock, ..self }
     }
 
-    // set_valid sets the valid value and round.
-    fn set_valid(self, value: Value) -> State {
-        let round = self.round;
-        let valid = Some(RoundValue { round, value });
-        State { valid, ..self }
+    // set_valid_at sets the valid value and round to a value that got a polka
+    // in the given round, unless we already have a valid value from a later round.
+    // A polka for another value in a round after the one we locked in releases
+    // the lock, since the rest of the validators have moved on from our value.
+    fn set_valid_at(self, round: i64, value: Value) -> State {
+        let valid = RoundValue { round, value };
+        let lock = match self.lock {
+            Lock::Valid(v) | Lock::Locked { valid: v, .. } if v.round > round => self.lock,
+            Lock::None | Lock::Valid(_) => Lock::Valid(valid),
+            Lock::Locked { locked, .. } if locked.round < round && locked.value != value => {
+                Lock::Valid(valid)
+            }
+            Lock::Locked { locked, .. } => Lock::Locked { locked, valid },
+        };
+        State { lock, ..self }
     }
 }
 
@@ -93,6 +191,7 @@ impl State {
 // Inputs (Events)
 
 // Event is a type of event. It carries any relevant data.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Event {
     NewRound,                // Start a new round, not as proposer.
     NewRoundProposer(Value), // Start a new round and propose the Value.
@@ -109,6 +208,55 @@ pub enum Event {
     TimeoutPrecommit,        // Timeout waiting for precommits.
 }
 
+// EventKind is the kind of an Event, without its data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EventKind {
+    NewRound,
+    NewRoundProposer,
+    Proposal,
+    ProposalInvalid,
+    PolkaAny,
+    PolkaNil,
+    PolkaValue,
+    PrecommitAny,
+    PrecommitValue,
+    RoundSkip,
+    TimeoutPropose,
+    TimeoutPrevote,
+    TimeoutPrecommit,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::NewRound => EventKind::NewRound,
+            Event::NewRoundProposer(_) => EventKind::NewRoundProposer,
+            Event::Proposal(_, _) => EventKind::Proposal,
+            Event::ProposalInvalid => EventKind::ProposalInvalid,
+            Event::PolkaAny => EventKind::PolkaAny,
+            Event::PolkaNil => EventKind::PolkaNil,
+            Event::PolkaValue(_) => EventKind::PolkaValue,
+            Event::PrecommitAny => EventKind::PrecommitAny,
+            Event::PrecommitValue(_) => EventKind::PrecommitValue,
+            Event::RoundSkip => EventKind::RoundSkip,
+            Event::TimeoutPropose => EventKind::TimeoutPropose,
+            Event::TimeoutPrevote => EventKind::TimeoutPrevote,
+            Event::TimeoutPrecommit => EventKind::TimeoutPrecommit,
+        }
+    }
+
+    // value returns the value carried by the event, if any.
+    pub fn value(&self) -> Option<Value> {
+        match *self {
+            Event::NewRoundProposer(v)
+            | Event::Proposal(_, v)
+            | Event::PolkaValue(v)
+            | Event::PrecommitValue(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
 //---------------------------------------------------------------------
 // Outputs (Messages)
 
@@ -125,22 +273,27 @@ pub enum Message {
 
 // convenience methods for creating new messages.
 impl Message {
-    fn proposal(round: i64, value: Value, pol_round: i64) -> Message {
+    fn proposal(height: i64, round: i64, value: Value, pol_round: i64) -> Message {
         let proposal = Proposal {
+            height,
             round,
             value,
             pol_round,
         };
         Message::Proposal(proposal)
     }
-    fn prevote(round: i64, value: Option<Value>) -> Message {
-        Message::Vote(Vote::new_prevote(round, value))
+    fn prevote(height: i64, round: i64, value: Option<Value>) -> Message {
+        Message::Vote(Vote::new_prevote(height, round, value))
     }
-    fn precommit(round: i64, value: Option<Value>) -> Message {
-        Message::Vote(Vote::new_precommit(round, value))
+    fn precommit(height: i64, round: i64, value: Option<Value>) -> Message {
+        Message::Vote(Vote::new_precommit(height, round, value))
     }
-    fn timeout(round: i64, step: TimeoutStep) -> Message {
-        Message::Timeout(Timeout { round, step })
+    fn timeout(height: i64, round: i64, step: TimeoutStep) -> Message {
+        Message::Timeout(Timeout {
+            height,
+            round,
+            step,
+        })
     }
     fn decision(round: i64, value: Value) -> Message {
         Message::Decision(RoundValue { round, value })
@@ -148,18 +301,38 @@ impl Message {
 }
 
 // Timeout is used to schedule timeouts at different steps in the round.
-#[derive(Debug, PartialEq)]
+// A timeout is identified by its height, round and step.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Timeout {
+    pub height: i64,
     pub round: i64,
     pub step: TimeoutStep,
 }
 
 // TimeoutStep is the step the timeout is for.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TimeoutStep {
     Propose,
     Prevote,
     Precommit,
+    Commit, // Wait after deciding before moving to the next height.
+}
+
+impl Timeout {
+    // is_outdated returns true once the state has moved past the step
+    // that scheduled the timeout, so it can no longer have any effect.
+    // Timeout precommit stays relevant for the whole round, until we decide.
+    pub fn is_outdated(&self, s: &State) -> bool {
+        if self.height != s.height || self.round != s.round {
+            return true;
+        }
+        match self.step {
+            TimeoutStep::Propose => s.step != Step::Propose,
+            TimeoutStep::Prevote => s.step != Step::Prevote,
+            TimeoutStep::Precommit => s.step == Step::Commit,
+            TimeoutStep::Commit => s.step != Step::Commit,
+        }
+    }
 }
 
 //---------------------------------------------------------------------
@@ -174,43 +347,228 @@ impl State {
     pub fn apply(self, round: i64, event: Event) -> (State, Option<Message>) {
         apply(self, round, event)
     }
+
+    // apply_with is like apply, but uses the given transition table,
+    // eg. to try out variants of the rules.
+    pub fn apply_with(
+        self,
+        transitions: &[Transition],
+        round: i64,
+        event: Event,
+    ) -> (State, Option<Message>) {
+        apply_with(transitions, self, round, event)
+    }
 }
 
 // apply transitions the state machine. It takes a state and an input event
 // and returns an updated state and output message.
 // Valid transitions result in at least a change to the state and/or an output message.
-// Commented numbers refer to line numbers in the spec paper.
+// The transitions are listed in TRANSITIONS.
 fn apply(s: State, round: i64, event: Event) -> (State, Option<Message>) {
-    let eqr = s.round == round;
-    match (s.step, event) {
-        // From NewRound. Event must be for current round.
-        (Step::NewRound, Event::NewRoundProposer(v)) if eqr => propose(s, v), // 11/14
-        (Step::NewRound, Event::NewRound) if eqr => schedule_timeout_propose(s), // 11/20
+    apply_with(TRANSITIONS, s, round, event)
+}
+
+// apply_with applies the first transition in the table that matches the state's
+// step and the event, and whose guard holds. If there is none, nothing happens.
+// Once in the Commit step, no more transitions can take place.
+fn apply_with(
+    transitions: &[Transition],
+    s: State,
+    round: i64,
+    event: Event,
+) -> (State, Option<Message>) {
+    if s.step == Step::Commit {
+        return (s, None);
+    }
+    let kind = event.kind();
+    let transition = transitions.iter().find(|t| {
+        t.event == kind
+            && t.from.is_none_or(|from| from == s.step)
+            && t.guard.check(s, round, &event)
+    });
+    match transition {
+        Some(t) => (t.action)(s, round, event),
+        None => (s, None),
+    }
+}
+
+// transition is the state transition function as a pure function: it reads
+// no clocks or storage and has no side effects, so formal verification tools
+// can target it directly. It outputs at most one message.
+pub fn transition(state: &State, round: i64, event: &Event) -> (State, Vec<Message>) {
+    let (s, msg) = apply(*state, round, *event);
+    (s, msg.into_iter().collect())
+}
+
+//---------------------------------------------------------------------
+// Transition table
+
+// Guard is a condition on the round of an event (and the event itself)
+// for a transition to apply.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Guard {
+    Any,                 // any round
+    CurrentRound,        // the current round
+    CurrentRoundValidVr, // the current round, with a valid pol_round
+    HigherRound,         // a round higher than the current round
+    PastRound,           // a round lower than the current round
+}
+
+impl Guard {
+    fn check(self, s: State, round: i64, event: &Event) -> bool {
+        match self {
+            Guard::Any => true,
+            Guard::CurrentRound => s.round == round,
+            Guard::CurrentRoundValidVr => match event {
+                Event::Proposal(vr, _) => s.round == round && s.valid_vr(*vr),
+                _ => false,
+            },
+            Guard::HigherRound => s.round < round,
+            Guard::PastRound => round < s.round,
+        }
+    }
+
+    // describe returns the guard as a condition on the event's round r.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Guard::Any => "",
+            Guard::CurrentRound => "r == round",
+            Guard::CurrentRoundValidVr => "r == round, valid vr",
+            Guard::HigherRound => "r > round",
+            Guard::PastRound => "r < round",
+        }
+    }
+}
 
-        // From Propose. Event must be for current round.
-        (Step::Propose, Event::Proposal(vr, v)) if eqr && s.valid_vr(vr) => prevote(s, vr, v), // 22, 28
-        (Step::Propose, Event::ProposalInvalid) if eqr => prevote_nil(s), // 22/25, 28/31
-        (Step::Propose, Event::TimeoutPropose) if eqr => prevote_nil(s),  // 57
+// Action applies a transition to the state, given the event and its round.
+pub type Action = fn(State, i64, Event) -> (State, Option<Message>);
+
+// Transition is a row of the transition table: if the state is at the from step,
+// and the event is of the given kind and satisfies the guard, apply the action.
+pub struct Transition {
+    pub from: Option<Step>, // None for all steps except Commit
+    pub event: EventKind,
+    pub guard: Guard,
+    pub action: Action,
+    pub to: Option<Step>, // step after the action; None if the step doesn't change
+    pub lines: &'static str, // line numbers in the spec paper
+}
 
-        // From Prevote. Event must be for current round.
-        (Step::Prevote, Event::PolkaAny) if eqr => schedule_timeout_prevote(s), // 34
-        (Step::Prevote, Event::PolkaNil) if eqr => precommit_nil(s),            // 44
-        (Step::Prevote, Event::PolkaValue(v)) if eqr => precommit(s, v), // 36/37 - NOTE: only once?
-        (Step::Prevote, Event::TimeoutPrevote) if eqr => precommit_nil(s), // 61
+const fn rule(
+    from: Option<Step>,
+    event: EventKind,
+    guard: Guard,
+    action: Action,
+    to: Option<Step>,
+    lines: &'static str,
+) -> Transition {
+    Transition {
+        from,
+        event,
+        guard,
+        action,
+        to,
+        lines,
+    }
+}
 
-        // From Precommit. Event must be for current round.
-        (Step::Precommit, Event::PolkaValue(v)) if eqr => set_valid_value(s, v), // 36/42 - NOTE: only once?
+// TRANSITIONS is the transition table of the state machine.
+// Transitions are tried in order. The `to` steps are checked against
+// the actions by the tests.
+// NOTE: 36/37 and 36/42 should only be taken once per round.
+#[rustfmt::skip]
+pub const TRANSITIONS: &[Transition] = &[
+    // From NewRound. Event must be for current round.
+    rule(Some(Step::NewRound), EventKind::NewRoundProposer, Guard::CurrentRound, propose, Some(Step::Propose), "11/14"),
+    rule(Some(Step::NewRound), EventKind::NewRound, Guard::CurrentRound, schedule_timeout_propose, Some(Step::Propose), "11/20"),
+
+    // From Propose. Event must be for current round.
+    rule(Some(Step::Propose), EventKind::Proposal, Guard::CurrentRoundValidVr, prevote, Some(Step::Prevote), "22, 28"),
+    rule(Some(Step::Propose), EventKind::ProposalInvalid, Guard::CurrentRound, prevote_nil, Some(Step::Prevote), "22/25, 28/31"),
+    rule(Some(Step::Propose), EventKind::TimeoutPropose, Guard::CurrentRound, prevote_nil, Some(Step::Prevote), "57"),
+
+    // From Prevote. Event must be for current round.
+    rule(Some(Step::Prevote), EventKind::PolkaAny, Guard::CurrentRound, schedule_timeout_prevote, None, "34"),
+    rule(Some(Step::Prevote), EventKind::PolkaNil, Guard::CurrentRound, precommit_nil, Some(Step::Precommit), "44"),
+    rule(Some(Step::Prevote), EventKind::PolkaValue, Guard::CurrentRound, precommit, Some(Step::Precommit), "36/37"),
+    rule(Some(Step::Prevote), EventKind::TimeoutPrevote, Guard::CurrentRound, precommit_nil, Some(Step::Precommit), "61"),
+
+    // From Precommit. Event must be for current round.
+    rule(Some(Step::Precommit), EventKind::PolkaValue, Guard::CurrentRound, set_valid_value, None, "36/42"),
+
+    // From all (except Commit). Various round guards.
+    // The paper only updates the valid value for polkas in the current round (36/42);
+    // polkas from past rounds are only seen if the engine tallies late votes.
+    rule(None, EventKind::PolkaValue, Guard::PastRound, set_valid_value, None, "36/42"),
+    rule(None, EventKind::PrecommitAny, Guard::CurrentRound, schedule_timeout_precommit, None, "47"),
+    rule(None, EventKind::TimeoutPrecommit, Guard::CurrentRound, next_round, Some(Step::NewRound), "65"),
+    rule(None, EventKind::RoundSkip, Guard::HigherRound, round_skip, Some(Step::NewRound), "55"),
+    rule(None, EventKind::PrecommitValue, Guard::Any, commit, Some(Step::Commit), "49"),
+];
+
+const STEPS: [Step; 5] = [
+    Step::NewRound,
+    Step::Propose,
+    Step::Prevote,
+    Step::Precommit,
+    Step::Commit,
+];
+
+// to_dot renders the transition table as a Graphviz DOT graph.
+pub fn to_dot() -> String {
+    let mut dot = String::from("digraph consensus {\n");
+    for step in STEPS.iter() {
+        dot.push_str(&format!("    {:?};\n", step));
+    }
+    for t in TRANSITIONS {
+        let from: Vec<Step> = match t.from {
+            Some(step) => vec![step],
+            None => STEPS
+                .iter()
+                .cloned()
+                .filter(|s| *s != Step::Commit)
+                .collect(),
+        };
+        let mut label = format!("{:?}", t.event);
+        if t.guard != Guard::Any {
+            label.push_str(&format!(" [{}]", t.guard.describe()));
+        }
+        label.push_str(&format!(" ({})", t.lines));
+        for step in from {
+            let to = t.to.unwrap_or(step);
+            dot.push_str(&format!(
+                "    {:?} -> {:?} [label=\"{}\"];\n",
+                step, to, label
+            ));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
 
-        // From Commit. No more state transitions.
-        (Step::Commit, _) => (s, None),
+// PathStep is a transition taken by the state machine at runtime.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PathStep {
+    pub height: i64,
+    pub from_round: i64,
+    pub from: Step,
+    pub event: EventKind,
+    pub to_round: i64,
+    pub to: Step,
+}
 
-        // From all (except Commit). Various round guards.
-        (_, Event::PrecommitAny) if eqr => schedule_timeout_precommit(s), // 47
-        (_, Event::TimeoutPrecommit) if eqr => round_skip(s, round + 1),  // 65
-        (_, Event::RoundSkip) if s.round < round => round_skip(s, round), // 55
-        (_, Event::PrecommitValue(v)) => commit(s, round, v),             // 49
-        _ => (s, None),
+// path_to_dot renders the transitions taken at runtime as a Graphviz DOT graph,
+// with edges numbered in the order they were taken.
+pub fn path_to_dot(path: &[PathStep]) -> String {
+    let mut dot = String::from("digraph path {\n");
+    for (i, p) in path.iter().enumerate() {
+        dot.push_str(&format!(
+            "    \"{}/{:?}\" -> \"{}/{:?}\" [label=\"{}: {:?}\"];\n",
+            p.from_round, p.from, p.to_round, p.to, i, p.event
+        ));
     }
+    dot.push_str("}\n");
+    dot
 }
 
 //---------------------------------------------------------------------
@@ -219,13 +577,20 @@ fn apply(s: State, round: i64, event: Event) -> (State, Option<Message>) {
 // We're the proposer - propose the valid value if it exists,
 // otherwise propose the given value.
 // 11/14
-fn propose(s: State, v: Value) -> (State, Option<Message>) {
+fn propose(s: State, _: i64, e: Event) -> (State, Option<Message>) {
+    let v = match e.value() {
+        Some(v) => v,
+        None => return (s, None),
+    };
     let s = s.next_step();
-    let (value, pol_round) = match s.valid {
+    let (value, pol_round) = match s.valid() {
         Some(v) => (v.value, v.round),
         None => (v, -1),
     };
-    (s, Some(Message::proposal(s.round, value, pol_round)))
+    (
+        s,
+        Some(Message::proposal(s.height, s.round, value, pol_round)),
+    )
 }
 
 //---------------------------------------------------------------------
@@ -234,22 +599,26 @@ fn propose(s: State, v: Value) -> (State, Option<Message>) {
 // Received a complete proposal - prevote the value,
 // unless we're locked on something else at a higher round.
 // 22, 28
-fn prevote(s: State, vr: i64, proposed: Value) -> (State, Option<Message>) {
+fn prevote(s: State, _: i64, e: Event) -> (State, Option<Message>) {
+    let (vr, proposed) = match e {
+        Event::Proposal(vr, v) => (vr, v),
+        _ => return (s, None),
+    };
     let s = s.next_step();
-    let value = match s.locked {
+    let value = match s.locked() {
         Some(locked) if locked.round <= vr => Some(proposed), // unlock and prevote
         Some(locked) if locked.value == proposed => Some(proposed), // already locked on value
         Some(_) => None, // we're locked on a higher round with a different value, prevote nil
         None => Some(proposed), // not locked, prevote the value
     };
-    (s, Some(Message::prevote(s.round, value)))
+    (s, Some(Message::prevote(s.height, s.round, value)))
 }
 
 // Received a complete proposal for an empty or invalid value, or timed out - prevote nil.
 // 22/25, 28/31, 57
-fn prevote_nil(s: State) -> (State, Option<Message>) {
+fn prevote_nil(s: State, _: i64, _: Event) -> (State, Option<Message>) {
     let s = s.next_step();
-    (s, Some(Message::prevote(s.round, None)))
+    (s, Some(Message::prevote(s.height, s.round, None)))
 }
 
 //---------------------------------------------------------------------
@@ -258,16 +627,20 @@ fn prevote_nil(s: State) -> (State, Option<Message>) {
 // Received a polka for a value - precommit the value.
 // 36
 // NOTE: only one of this and set_valid_value should be called once in a round
-fn precommit(s: State, v: Value) -> (State, Option<Message>) {
-    let s = s.set_locked(v).set_valid(v).next_step();
-    (s, Some(Message::precommit(s.round, Some(v))))
+fn precommit(s: State, _: i64, e: Event) -> (State, Option<Message>) {
+    let v = match e.value() {
+        Some(v) => v,
+        None => return (s, None),
+    };
+    let s = s.set_locked(v).next_step();
+    (s, Some(Message::precommit(s.height, s.round, Some(v))))
 }
 
 // Received a polka for nil or timed out of prevote - precommit nil.
 // 44, 61
-fn precommit_nil(s: State) -> (State, Option<Message>) {
+fn precommit_nil(s: State, _: i64, _: Event) -> (State, Option<Message>) {
     let s = s.next_step();
-    (s, Some(Message::precommit(s.round, None)))
+    (s, Some(Message::precommit(s.height, s.round, None)))
 }
 
 //---------------------------------------------------------------------
@@ -275,50 +648,171 @@ fn precommit_nil(s: State) -> (State, Option<Message>) {
 
 // We're not the proposer - schedule timeout propose.
 // 11/20
-fn schedule_timeout_propose(s: State) -> (State, Option<Message>) {
+fn schedule_timeout_propose(s: State, _: i64, _: Event) -> (State, Option<Message>) {
     let s = s.next_step();
-    (s, Some(Message::timeout(s.round, TimeoutStep::Propose)))
+    (
+        s,
+        Some(Message::timeout(s.height, s.round, TimeoutStep::Propose)),
+    )
 }
 
 // We received a polka for any - schedule timeout prevote.
 // 34
 // NOTE: this should only be called once in a round, per the spec,
 // but it's harmless to schedule more timeouts
-fn schedule_timeout_prevote(s: State) -> (State, Option<Message>) {
-    (s, Some(Message::timeout(s.round, TimeoutStep::Prevote)))
+fn schedule_timeout_prevote(s: State, _: i64, _: Event) -> (State, Option<Message>) {
+    (
+        s,
+        Some(Message::timeout(s.height, s.round, TimeoutStep::Prevote)),
+    )
 }
 
 // We received +2/3 precommits for any - schedule timeout precommit.
 // 47
-fn schedule_timeout_precommit(s: State) -> (State, Option<Message>) {
-    (s, Some(Message::timeout(s.round, TimeoutStep::Precommit)))
+fn schedule_timeout_precommit(s: State, _: i64, _: Event) -> (State, Option<Message>) {
+    (
+        s,
+        Some(Message::timeout(s.height, s.round, TimeoutStep::Precommit)),
+    )
 }
 
 //---------------------------------------------------------------------
 // Set the valid value.
 
-// We received a polka for a value after we already precommited.
-// Set the valid value and current round.
+// We received a polka for a value after we already precommited,
+// or a polka from a past round. Set the valid value and the polka's round,
+// releasing our lock if it's for another value in a later round.
 // 36/42
 // NOTE: only one of this and precommit should be called once in a round
-fn set_valid_value(s: State, v: Value) -> (State, Option<Message>) {
-    (s.set_valid(v), None)
+fn set_valid_value(s: State, r: i64, e: Event) -> (State, Option<Message>) {
+    match e.value() {
+        Some(v) => (s.set_valid_at(r, v), None),
+        None => (s, None),
+    }
 }
 
 //---------------------------------------------------------------------
 // New round or height
 
-// We finished a round (timeout precommit) or received +1/3 votes
-// from a higher round. Move to the higher round.
+// We finished a round (timeout precommit). Move to the next round.
 // 65
-fn round_skip(s: State, r: i64) -> (State, Option<Message>) {
+fn next_round(s: State, r: i64, _: Event) -> (State, Option<Message>) {
+    round_skip(s, r + 1, Event::RoundSkip)
+}
+
+// We received +1/3 votes from a higher round. Move to the higher round.
+// 55
+fn round_skip(s: State, r: i64, _: Event) -> (State, Option<Message>) {
     (s.set_round(r), Some(Message::NewRound(r)))
 }
 
 // We received +2/3 precommits for a value - commit and decide that value!
 // 49
-fn commit(s: State, r: i64, v: Value) -> (State, Option<Message>) {
-    (s.commit_step(), Some(Message::decision(r, v)))
+fn commit(s: State, r: i64, e: Event) -> (State, Option<Message>) {
+    match e.value() {
+        Some(v) => (s.commit_step(), Some(Message::decision(r, v))),
+        None => (s, None),
+    }
+}
+
+//---------------------------------------------------------------------
+// Proofs
+//
+// Harnesses for the kani model checker, run with `cargo kani --features kani`.
+
+#[cfg(all(kani, feature = "kani"))]
+mod proofs {
+    use super::*;
+
+    fn any_step() -> Step {
+        match kani::any::<u8>() % 5 {
+            0 => Step::NewRound,
+            1 => Step::Propose,
+            2 => Step::Prevote,
+            3 => Step::Precommit,
+            _ => Step::Commit,
+        }
+    }
+
+    fn any_round_value() -> Option<RoundValue> {
+        if kani::any() {
+            Some(RoundValue {
+                round: kani::any(),
+                value: Value::new(kani::any()),
+            })
+        } else {
+            None
+        }
+    }
+
+    // any_lock returns any lock that keeps the invariant: if locked, the valid
+    // value is from the same round or later.
+    fn any_lock() -> Lock {
+        match (any_round_value(), any_round_value()) {
+            (None, None) => Lock::None,
+            (Some(valid), None) => Lock::Valid(valid),
+            (locked, valid) => {
+                let locked = locked.or(valid).unwrap();
+                let valid = valid.unwrap_or(locked);
+                kani::assume(valid.round >= locked.round);
+                Lock::Locked { locked, valid }
+            }
+        }
+    }
+
+    fn any_state() -> State {
+        State {
+            height: kani::any(),
+            round: kani::any(),
+            step: any_step(),
+            lock: any_lock(),
+        }
+    }
+
+    fn any_event() -> Event {
+        let v = Value::new(kani::any());
+        match kani::any::<u8>() % 13 {
+            0 => Event::NewRound,
+            1 => Event::NewRoundProposer(v),
+            2 => Event::Proposal(kani::any(), v),
+            3 => Event::ProposalInvalid,
+            4 => Event::PolkaAny,
+            5 => Event::PolkaNil,
+            6 => Event::PolkaValue(v),
+            7 => Event::PrecommitAny,
+            8 => Event::PrecommitValue(v),
+            9 => Event::RoundSkip,
+            10 => Event::TimeoutPropose,
+            11 => Event::TimeoutPrevote,
+            _ => Event::TimeoutPrecommit,
+        }
+    }
+
+    // The height never changes, the round never decreases,
+    // Commit is final, and at most one message is output.
+    #[kani::proof]
+    fn transition_invariants() {
+        let s = any_state();
+        let round: i64 = kani::any();
+        kani::assume(s.round < i64::MAX && round < i64::MAX);
+        let (next, msgs) = transition(&s, round, &any_event());
+        assert!(next.height == s.height);
+        assert!(next.round >= s.round);
+        assert!(s.step != Step::Commit || next == s);
+        assert!(msgs.len() <= 1);
+    }
+
+    // Transitions keep the locked value no later than the valid value.
+    #[kani::proof]
+    fn lock_invariant() {
+        let s = any_state();
+        let round: i64 = kani::any();
+        kani::assume(s.round < i64::MAX && round < i64::MAX);
+        let (next, _) = transition(&s, round, &any_event());
+        if let Some(locked) = next.locked() {
+            assert!(next.valid().unwrap().round >= locked.round);
+        }
+    }
 }
 
 //---------------------------------------------------------------------
@@ -330,17 +824,139 @@ mod tests {
 
     #[test]
     fn happy_case() {
-        let val = Value {};
+        let val = Value::new([1; 32]);
         let v = Some(val);
         let s = State::new(1);
         let (s, m) = apply(s, 0, Event::NewRoundProposer(val));
-        assert_eq!(m.unwrap(), Message::proposal(0, val, -1));
+        assert_eq!(m.unwrap(), Message::proposal(1, 0, val, -1));
         let (s, m) = apply(s, 0, Event::Proposal(-1, val));
-        assert_eq!(m.unwrap(), Message::prevote(0, v));
+        assert_eq!(m.unwrap(), Message::prevote(1, 0, v));
         let (s, m) = apply(s, 0, Event::PolkaValue(val));
-        assert_eq!(m.unwrap(), Message::precommit(0, v));
+        assert_eq!(m.unwrap(), Message::precommit(1, 0, v));
         let (s, m) = apply(s, 0, Event::PrecommitValue(val));
         assert_eq!(m.unwrap(), Message::decision(0, val));
         assert_eq!(s.step, Step::Commit);
     }
+
+    #[test]
+    fn lock() {
+        let (a, b) = (Value::new([1; 32]), Value::new([2; 32]));
+        let rv = |round, value| Some(RoundValue { round, value });
+        let s = State::new(1).set_round(2);
+        let (s, _) = apply(s, 2, Event::NewRound);
+        let (s, _) = apply(s, 2, Event::TimeoutPropose);
+
+        // locking also sets the valid value
+        let (s, _) = apply(s, 2, Event::PolkaValue(a));
+        assert_eq!((s.locked(), s.valid()), (rv(2, a), rv(2, a)));
+
+        // a polka from an earlier round doesn't replace it
+        let (s, _) = apply(s, 1, Event::PolkaValue(b));
+        assert_eq!((s.locked(), s.valid()), (rv(2, a), rv(2, a)));
+
+        // one for the locked value from a later round updates the valid round only
+        let s = s.set_valid_at(3, a);
+        assert_eq!((s.locked(), s.valid()), (rv(2, a), rv(3, a)));
+
+        // one for another value from a later round releases the lock
+        let (s, _) = apply(s.set_round(4), 3, Event::PolkaValue(b));
+        assert_eq!((s.locked(), s.valid()), (None, rv(3, b)));
+    }
+
+    // transition is apply, with the output as a list.
+    #[test]
+    fn pure_transition() {
+        let s = State::new(1);
+        for event in events() {
+            let (next, msgs) = transition(&s, 0, &event);
+            let (expected, msg) = apply(s, 0, event);
+            assert_eq!(next, expected);
+            assert_eq!(msgs, msg.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        let val = Value::new([1; 32]);
+        vec![
+            Event::NewRound,
+            Event::NewRoundProposer(val),
+            Event::Proposal(-1, val),
+            Event::ProposalInvalid,
+            Event::PolkaAny,
+            Event::PolkaNil,
+            Event::PolkaValue(val),
+            Event::PrecommitAny,
+            Event::PrecommitValue(val),
+            Event::RoundSkip,
+            Event::TimeoutPropose,
+            Event::TimeoutPrevote,
+            Event::TimeoutPrecommit,
+        ]
+    }
+
+    // Check that the actions in TRANSITIONS move to the listed steps,
+    // and that anything not listed leaves the state unchanged.
+    #[test]
+    fn transition_table() {
+        for &step in STEPS.iter() {
+            for event in events() {
+                let kind = event.kind();
+                let s = State {
+                    step,
+                    round: 1,
+                    ..State::new(1)
+                };
+                let t = TRANSITIONS.iter().find(|t| {
+                    t.event == kind && t.from.map_or(step != Step::Commit, |from| from == step)
+                });
+                let round = match t {
+                    Some(t) if t.guard == Guard::HigherRound => 2,
+                    Some(t) if t.guard == Guard::PastRound => 0,
+                    _ => 1,
+                };
+                let (s2, m) = apply(s, round, event);
+                match t {
+                    Some(t) => {
+                        assert_eq!(s2.step, t.to.unwrap_or(step), "{:?} {:?}", step, kind);
+                        // valid transitions change the state and/or output a message
+                        let changed = format!("{:?}", s2) != format!("{:?}", s);
+                        assert!(changed || m.is_some(), "{:?} {:?}", step, kind);
+                    }
+                    None => {
+                        assert_eq!((s2.step, s2.round), (step, 1), "{:?} {:?}", step, kind);
+                        assert!(m.is_none(), "{:?} {:?}", step, kind);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rule_variant() {
+        // without 57, timeout propose is ignored
+        let rules: Vec<_> = TRANSITIONS
+            .iter()
+            .filter(|t| t.lines != "57")
+            .map(|t| rule(t.from, t.event, t.guard, t.action, t.to, t.lines))
+            .collect();
+        let (s, _) = State::new(1).apply(0, Event::NewRound);
+        let (s, m) = s.apply_with(&rules, 0, Event::TimeoutPropose);
+        assert_eq!((s.step, m), (Step::Propose, None));
+        let (s, m) = s.apply(0, Event::TimeoutPropose);
+        assert_eq!(
+            (s.step, m),
+            (Step::Prevote, Some(Message::prevote(1, 0, None)))
+        );
+    }
+
+    #[test]
+    fn dot() {
+        let dot = to_dot();
+        assert!(dot.starts_with("digraph consensus {"));
+        assert!(
+            dot.contains("NewRound -> Propose [label=\"NewRoundProposer [r == round] (11/14)\"];")
+        );
+        assert!(dot.contains("Precommit -> Commit [label=\"PrecommitValue (49)\"];"));
+        assert!(!dot.contains("Commit -> "));
+    }
 }
@@ -0,0 +1,373 @@
+// Store persists what a node needs to pick up where it left off after a
+// restart. The engine and gossip don't do any I/O themselves: the driver
+// saves their state to its Store, eg. backed by a database, and preloads
+// it on start.
+
+use super::engine::{UpgradePackage, WalEntry};
+use super::gossip::{PeerId, PeerRecord};
+use super::signature::SignState;
+use super::validators::{ValidatorSet, ValidatorSetDiff};
+use super::{Commit, ValueId};
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// CommitEntry is a decided height: its height, the id of the value decided,
+// and the commit that decided it.
+pub type CommitEntry = (i64, ValueId, Commit);
+
+// CommitPage is one page of commits, and the height range of the next page,
+// if there are more commits in the requested range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitPage {
+    pub commits: Vec<CommitEntry>,
+    pub next: Option<Range<i64>>,
+}
+
+// ValidatorSetEntry is the validator set of a height as it's stored:
+// in full at checkpoints, and as the diff from the set of the height
+// before it otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidatorSetEntry {
+    Checkpoint(ValidatorSet),
+    Diff(ValidatorSetDiff),
+}
+
+// Store is the driver's persistent storage.
+pub trait Store {
+    // save_peer replaces the record of the peer.
+    fn save_peer(&mut self, peer: PeerId, record: &PeerRecord) -> Result<(), String>;
+
+    // peers returns the records of all peers, by peer id.
+    fn peers(&self) -> Result<Vec<(PeerId, PeerRecord)>, String>;
+
+    // save_upgrade stores the package the engine output when it halted for
+    // an upgrade, for the replacement binary to start from.
+    fn save_upgrade(&mut self, package: &UpgradePackage) -> Result<(), String>;
+
+    // upgrade returns the stored upgrade package, if any.
+    fn upgrade(&self) -> Result<Option<UpgradePackage>, String>;
+
+    // save_commit stores the commit of a decided height.
+    fn save_commit(&mut self, commit: &Commit) -> Result<(), String>;
+
+    // iter_commits iterates over the stored commits for the heights in the
+    // range, by increasing height, or decreasing if reverse. Heights
+    // without a stored commit are skipped.
+    fn iter_commits(
+        &self,
+        heights: Range<i64>,
+        reverse: bool,
+    ) -> Result<Box<dyn Iterator<Item = CommitEntry> + '_>, String>;
+
+    // save_validators stores the validator set entry of the height.
+    fn save_validators(&mut self, height: i64, entry: &ValidatorSetEntry) -> Result<(), String>;
+
+    // validators returns the stored validator set entry of the height, if any.
+    fn validators(&self, height: i64) -> Result<Option<ValidatorSetEntry>, String>;
+
+    // The sign state and write-ahead log are for crash recovery. A store
+    // without it has nothing stored, and fails to save, so a driver that
+    // relies on it finds out before signing anything.
+
+    // save_sign_state replaces the last proposal or vote the engine signed.
+    // It must be durable before what was signed is sent.
+    fn save_sign_state(&mut self, _state: &SignState) -> Result<(), String> {
+        Err("no sign state in this store".to_string())
+    }
+
+    // sign_state returns the stored sign state, if any.
+    fn sign_state(&self) -> Result<Option<SignState>, String> {
+        Ok(None)
+    }
+
+    // append_wal appends the entry to the write-ahead log.
+    fn append_wal(&mut self, _entry: &WalEntry) -> Result<(), String> {
+        Err("no write-ahead log in this store".to_string())
+    }
+
+    // wal returns the entries of the write-ahead log from the height on, in order.
+    fn wal(&self, _from_height: i64) -> Result<Vec<WalEntry>, String> {
+        Ok(Vec::new())
+    }
+
+    // prune_wal drops the entries of the write-ahead log below the height,
+    // eg. once the application committed the heights before it.
+    fn prune_wal(&mut self, _height: i64) -> Result<(), String> {
+        Ok(())
+    }
+
+    // commits_page returns up to limit commits for the heights in the range,
+    // like iter_commits, along with the range to pass for the next page.
+    fn commits_page(
+        &self,
+        heights: Range<i64>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<CommitPage, String> {
+        let mut iter = self.iter_commits(heights.clone(), reverse)?;
+        let commits: Vec<CommitEntry> = iter.by_ref().take(limit).collect();
+        let next = match commits.last() {
+            Some((last, _, _)) if iter.next().is_some() => match reverse {
+                false => Some(last + 1..heights.end),
+                true => Some(heights.start..*last),
+            },
+            _ => None,
+        };
+        Ok(CommitPage { commits, next })
+    }
+}
+
+// MemStore is a Store that keeps everything in memory, eg. for tests
+// and the simulator, where a restart keeps the store.
+#[derive(Clone, Debug, Default)]
+pub struct MemStore {
+    peers: BTreeMap<PeerId, PeerRecord>,
+    commits: BTreeMap<i64, Commit>,
+    validators: BTreeMap<i64, ValidatorSetEntry>,
+    upgrade: Option<UpgradePackage>,
+    sign_state: Option<SignState>,
+    wal: Vec<WalEntry>,
+}
+
+impl Store for MemStore {
+    fn save_peer(&mut self, peer: PeerId, record: &PeerRecord) -> Result<(), String> {
+        self.peers.insert(peer, record.clone());
+        Ok(())
+    }
+
+    fn peers(&self) -> Result<Vec<(PeerId, PeerRecord)>, String> {
+        Ok(self.peers.iter().map(|(p, r)| (*p, r.clone())).collect())
+    }
+
+    fn save_upgrade(&mut self, package: &UpgradePackage) -> Result<(), String> {
+        self.upgrade = Some(package.clone());
+        Ok(())
+    }
+
+    fn upgrade(&self) -> Result<Option<UpgradePackage>, String> {
+        Ok(self.upgrade.clone())
+    }
+
+    fn save_commit(&mut self, commit: &Commit) -> Result<(), String> {
+        self.commits.insert(commit.height, commit.clone());
+        Ok(())
+    }
+
+    fn iter_commits(
+        &self,
+        heights: Range<i64>,
+        reverse: bool,
+    ) -> Result<Box<dyn Iterator<Item = CommitEntry> + '_>, String> {
+        if heights.start >= heights.end {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let range = self
+            .commits
+            .range(heights)
+            .map(|(h, c)| (*h, c.value.id(), c.clone()));
+        match reverse {
+            false => Ok(Box::new(range)),
+            true => Ok(Box::new(range.rev())),
+        }
+    }
+
+    fn save_validators(&mut self, height: i64, entry: &ValidatorSetEntry) -> Result<(), String> {
+        self.validators.insert(height, entry.clone());
+        Ok(())
+    }
+
+    fn validators(&self, height: i64) -> Result<Option<ValidatorSetEntry>, String> {
+        Ok(self.validators.get(&height).cloned())
+    }
+
+    fn save_sign_state(&mut self, state: &SignState) -> Result<(), String> {
+        self.sign_state = Some(state.clone());
+        Ok(())
+    }
+
+    fn sign_state(&self) -> Result<Option<SignState>, String> {
+        Ok(self.sign_state.clone())
+    }
+
+    fn append_wal(&mut self, entry: &WalEntry) -> Result<(), String> {
+        self.wal.push(entry.clone());
+        Ok(())
+    }
+
+    fn wal(&self, from_height: i64) -> Result<Vec<WalEntry>, String> {
+        let from = self.wal.partition_point(|e| e.height < from_height);
+        Ok(self.wal[from..].to_vec())
+    }
+
+    fn prune_wal(&mut self, height: i64) -> Result<(), String> {
+        self.wal.retain(|e| e.height >= height);
+        Ok(())
+    }
+}
+
+//---------------------------------------------------------------------
+// Validator set checkpoints
+
+// ValidatorCheckpoints saves the validator set of each height to a Store,
+// in full every interval heights and as diffs in between, so the set of any
+// height can be loaded from the checkpoint before it, without replaying
+// every change since genesis.
+pub struct ValidatorCheckpoints {
+    interval: i64,
+    last: Option<(i64, ValidatorSet)>, // the last height saved, and its set
+}
+
+impl ValidatorCheckpoints {
+    pub fn new(interval: i64) -> ValidatorCheckpoints {
+        ValidatorCheckpoints {
+            interval: interval.max(1),
+            last: None,
+        }
+    }
+
+    // save stores the set of the height: in full at multiples of the
+    // interval, or when the set of the height before wasn't saved by us,
+    // eg. after a restart, and as the diff from it otherwise.
+    pub fn save(
+        &mut self,
+        store: &mut dyn Store,
+        height: i64,
+        validators: &ValidatorSet,
+    ) -> Result<(), String> {
+        let entry = match &self.last {
+            Some((h, last)) if *h == height - 1 && height % self.interval != 0 => {
+                ValidatorSetEntry::Diff(last.diff(validators))
+            }
+            _ => ValidatorSetEntry::Checkpoint(validators.clone()),
+        };
+        store.save_validators(height, &entry)?;
+        self.last = Some((height, validators.clone()));
+        Ok(())
+    }
+}
+
+// load_validators returns the validator set of the height, from the last
+// checkpoint at or before it and the diffs since.
+pub fn load_validators(store: &dyn Store, height: i64) -> Result<ValidatorSet, String> {
+    let mut diffs = Vec::new();
+    let mut h = height;
+    let mut validators = loop {
+        match store.validators(h)? {
+            Some(ValidatorSetEntry::Checkpoint(validators)) => break validators,
+            Some(ValidatorSetEntry::Diff(diff)) => diffs.push(diff),
+            None => return Err(format!("no validator set stored for height {}", h)),
+        }
+        h -= 1;
+    };
+    for diff in diffs.iter().rev() {
+        validators.apply(diff)?;
+    }
+    Ok(validators)
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{key, validator_set};
+    use crate::validators::Validator;
+    use crate::Value;
+
+    fn commit(height: i64) -> Commit {
+        Commit {
+            height,
+            round: 0,
+            value: Value::new([height as u8; 32]),
+            precommits: Vec::new(),
+        }
+    }
+
+    fn heights(page: &CommitPage) -> Vec<i64> {
+        page.commits.iter().map(|(h, _, _)| *h).collect()
+    }
+
+    #[test]
+    fn validator_checkpoints() {
+        let validator = |seed, voting_power| Validator {
+            public_key: key(seed),
+            voting_power,
+        };
+        let mut sets = vec![validator_set(&[1, 2, 3])];
+        let mut change = |f: &dyn Fn(&mut ValidatorSet)| {
+            let mut next = sets.last().unwrap().clone();
+            f(&mut next);
+            sets.push(next);
+        };
+        change(&|_| {});
+        change(&|v| assert!(v.update(validator(1, 5))));
+        change(&|v| v.add(validator(3, 4)));
+        change(&|v| assert!(v.remove(&validator(0, 1))));
+        change(&|v| {
+            let address = v.get(0).unwrap().address::<crate::hash::Sha256>();
+            assert!(v.rotate_key(&address, 10, key(9)));
+        });
+        change(&|_| {});
+        change(&|v| v.add(validator(4, 1)));
+        change(&|_| {});
+
+        let mut store = MemStore::default();
+        let mut checkpoints = ValidatorCheckpoints::new(4);
+        for (i, validators) in sets.iter().enumerate() {
+            checkpoints
+                .save(&mut store, i as i64 + 1, validators)
+                .unwrap();
+        }
+        let checkpoint = |store: &MemStore, h| {
+            let entry = store.validators(h).unwrap();
+            matches!(entry, Some(ValidatorSetEntry::Checkpoint(_)))
+        };
+        let full: Vec<i64> = (1..=9).filter(|h| checkpoint(&store, *h)).collect();
+        assert_eq!(full, vec![1, 4, 8]);
+
+        for (i, validators) in sets.iter().enumerate() {
+            let loaded = load_validators(&store, i as i64 + 1).unwrap();
+            assert_eq!(&loaded, validators);
+            assert_eq!(loaded.hash(), validators.hash());
+        }
+        assert!(load_validators(&store, 10).is_err());
+
+        // a set whose height before wasn't saved is a checkpoint
+        let mut checkpoints = ValidatorCheckpoints::new(4);
+        checkpoints.save(&mut store, 11, &sets[0]).unwrap();
+        assert!(checkpoint(&store, 11));
+    }
+
+    #[test]
+    fn iter_commits() {
+        let mut store = MemStore::default();
+        for height in (1..=5).chain(7..=8) {
+            store.save_commit(&commit(height)).unwrap();
+        }
+
+        let all: Vec<_> = store.iter_commits(2..8, false).unwrap().collect();
+        assert_eq!(all[0], (2, [2; 32], commit(2)));
+        let heights_of = |reverse| -> Vec<i64> {
+            let iter = store.iter_commits(2..8, reverse).unwrap();
+            iter.map(|(h, _, _)| h).collect()
+        };
+        assert_eq!(heights_of(false), vec![2, 3, 4, 5, 7]);
+        assert_eq!(heights_of(true), vec![7, 5, 4, 3, 2]);
+        assert_eq!(store.iter_commits(5..5, false).unwrap().count(), 0);
+
+        // pages go on from where the last one stopped
+        let page = store.commits_page(1..100, false, 3).unwrap();
+        assert_eq!((heights(&page), page.next), (vec![1, 2, 3], Some(4..100)));
+        let page = store.commits_page(4..100, false, 3).unwrap();
+        assert_eq!((heights(&page), page.next), (vec![4, 5, 7], Some(8..100)));
+        let page = store.commits_page(8..100, false, 3).unwrap();
+        assert_eq!((heights(&page), page.next), (vec![8], None));
+
+        let page = store.commits_page(1..100, true, 4).unwrap();
+        assert_eq!((heights(&page), page.next), (vec![8, 7, 5, 4], Some(1..4)));
+        let page = store.commits_page(1..4, true, 4).unwrap();
+        assert_eq!((heights(&page), page.next), (vec![3, 2, 1], None));
+    }
+}
@@ -0,0 +1,486 @@
+// Utilities for applications to test their use of the engine,
+// enabled by the test_utils feature.
+//
+// Keys are derived from a seed and signatures are hashes, so tests get
+// deterministic validator sets and messages that pass verification without
+// a real signature scheme. They're not secure: anyone can sign for any key.
+// Fixtures start an engine in the middle of a height, from text.
+
+use super::config::ConsensusConfig;
+use super::engine::{Engine, RoundStateSnapshot};
+use super::hash::{Hasher, Sha256};
+use super::round_votes::{is_quorum, DumpError, RoundVotesDump, DUMP_HEADER};
+use super::scheduler::Scheduler;
+use super::signature::{SignBytes, Signature, SignatureScheme, Signed, Signer, Verifier};
+use super::state_machine as sm;
+use super::validators::{Validator, ValidatorSet};
+use super::{Proposal, Value, Vote};
+
+use std::fmt;
+use std::str::FromStr;
+
+//---------------------------------------------------------------------
+// Keys and validator sets
+
+// key returns the public key generated from the seed.
+pub fn key(seed: u64) -> Vec<u8> {
+    Sha256::hash(&seed.to_be_bytes()).to_vec()
+}
+
+// validator_set returns a set with a validator of each voting power,
+// with keys generated from the seeds 0, 1, 2, etc. The set is sorted by
+// address, so a validator's index isn't its seed.
+pub fn validator_set(powers: &[i64]) -> ValidatorSet {
+    let vals = powers
+        .iter()
+        .enumerate()
+        .map(|(seed, &voting_power)| Validator {
+            public_key: key(seed as u64),
+            voting_power,
+        })
+        .collect();
+    ValidatorSet::new(vals)
+}
+
+// KeySigner signs as the validator at its index in a set.
+// A signature is the hash of the public key and the message.
+pub struct KeySigner {
+    index: usize,
+    public_key: Vec<u8>,
+}
+
+impl KeySigner {
+    // new returns the signer for the validator at the index. Panics if
+    // there's no such validator.
+    pub fn new(validators: &ValidatorSet, index: usize) -> KeySigner {
+        let public_key = validators.get(index).unwrap().public_key.clone();
+        KeySigner { index, public_key }
+    }
+
+    // with_key returns the signer for the validator at the index,
+    // signing with the key, eg. one it rotated to.
+    pub fn with_key(index: usize, public_key: Vec<u8>) -> KeySigner {
+        KeySigner { index, public_key }
+    }
+}
+
+impl Signer for KeySigner {
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn sign(&self, msg: &[u8]) -> Signature {
+        signature(&self.public_key, msg)
+    }
+}
+
+// KeyVerifier checks signatures made by KeySigners for the validator set,
+// with the key each validator signs with at the message's height.
+pub struct KeyVerifier(pub ValidatorSet);
+
+impl Verifier for KeyVerifier {
+    fn verify(&self, signer: usize, msg: &[u8], sig: &[u8]) -> bool {
+        match self.0.get(signer) {
+            Some(val) => signature(&val.public_key, msg) == sig,
+            None => false,
+        }
+    }
+
+    fn verify_at(&self, signer: usize, height: i64, msg: &[u8], sig: &[u8]) -> bool {
+        match self.0.public_key_at(signer, height) {
+            Some(key) => signature(key, msg) == sig,
+            None => false,
+        }
+    }
+}
+
+// KeyScheme creates KeyVerifiers.
+pub struct KeyScheme;
+
+impl SignatureScheme for KeyScheme {
+    fn verifier(&self, validators: &ValidatorSet) -> Box<dyn Verifier> {
+        Box::new(KeyVerifier(validators.clone()))
+    }
+}
+
+fn signature(public_key: &[u8], msg: &[u8]) -> Signature {
+    Sha256::hash(&[public_key, msg].concat()).to_vec()
+}
+
+//---------------------------------------------------------------------
+// Messages
+
+// sign returns the message signed by the validator at the index.
+pub fn sign<T: SignBytes>(validators: &ValidatorSet, index: usize, msg: T) -> Signed<T> {
+    let signer = KeySigner::new(validators, index);
+    let signature = signer.sign(&msg.sign_bytes());
+    Signed {
+        signer: index,
+        msg,
+        signature,
+    }
+}
+
+// proposal returns the proposal signed by the validator at the index.
+pub fn proposal(
+    validators: &ValidatorSet,
+    index: usize,
+    height: i64,
+    round: i64,
+    value: Value,
+    pol_round: i64,
+) -> Signed<Proposal> {
+    let proposal = Proposal::new(height, round, value, pol_round);
+    sign(validators, index, proposal)
+}
+
+// prevote returns the prevote signed by the validator at the index.
+pub fn prevote(
+    validators: &ValidatorSet,
+    index: usize,
+    height: i64,
+    round: i64,
+    value: Option<Value>,
+) -> Signed<Vote> {
+    sign(validators, index, Vote::new_prevote(height, round, value))
+}
+
+// precommit returns the precommit signed by the validator at the index.
+pub fn precommit(
+    validators: &ValidatorSet,
+    index: usize,
+    height: i64,
+    round: i64,
+    value: Option<Value>,
+) -> Signed<Vote> {
+    sign(validators, index, Vote::new_precommit(height, round, value))
+}
+
+// make_polka returns prevotes for the value from the first validators
+// of the set, by index, that make up just over 2/3 of the voting power.
+pub fn make_polka(
+    validators: &ValidatorSet,
+    height: i64,
+    round: i64,
+    value: Option<Value>,
+) -> Vec<Signed<Vote>> {
+    let total = validators.total_voting_power();
+    let mut weight = 0;
+    let mut votes = Vec::new();
+    for (index, val) in validators.validators().iter().enumerate() {
+        if is_quorum(weight, total) {
+            break;
+        }
+        weight += val.voting_power;
+        votes.push(prevote(validators, index, height, round, value));
+    }
+    votes
+}
+
+//---------------------------------------------------------------------
+// Fixtures
+//
+// Fixture is an engine's state in the middle of a height, as text, so
+// tests of late-round behavior start from it with Engine::from_snapshot()
+// rather than replaying the messages that led there:
+//
+//   fixture v1
+//   height 1
+//   round 2
+//   step propose
+//   locked 1 0101..01
+//   valid 1 0101..01
+//   round_votes v1
+//   height 1
+//   round 1
+//   ...
+//
+// The tally of each round follows in the round_votes dump format. A missing
+// locked or valid value is "none". Our own proposal and votes and the last
+// commit aren't written out, so fixtures parsed from text have none.
+
+const FIXTURE_HEADER: &str = "fixture v1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fixture {
+    pub snapshot: RoundStateSnapshot,
+    pub tallies: Vec<RoundVotesDump>, // by round
+}
+
+impl Fixture {
+    // of returns the fixture of the engine's current state.
+    pub fn of(engine: &Engine) -> Fixture {
+        Fixture {
+            snapshot: engine.snapshot(),
+            tallies: engine.round_votes(),
+        }
+    }
+
+    // engine creates an engine in the fixture's state.
+    pub fn engine(
+        &self,
+        config: ConsensusConfig,
+        validators: ValidatorSet,
+        signer: Box<dyn Signer>,
+        verifier: Box<dyn Verifier>,
+        scheduler: Box<dyn Scheduler>,
+    ) -> Result<Engine, String> {
+        let snapshot = self.snapshot.clone();
+        Engine::from_snapshot(
+            config,
+            snapshot,
+            &self.tallies,
+            validators,
+            signer,
+            verifier,
+            scheduler,
+        )
+    }
+}
+
+fn fmt_round_value(rv: Option<sm::RoundValue>) -> String {
+    match rv {
+        Some(rv) => format!("{} {}", rv.round, rv.value),
+        None => "none".to_string(),
+    }
+}
+
+impl fmt::Display for Fixture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = &self.snapshot;
+        writeln!(f, "{}", FIXTURE_HEADER)?;
+        writeln!(f, "height {}", s.height)?;
+        writeln!(f, "round {}", s.round)?;
+        writeln!(f, "step {}", format!("{:?}", s.step).to_lowercase())?;
+        writeln!(f, "locked {}", fmt_round_value(s.locked))?;
+        writeln!(f, "valid {}", fmt_round_value(s.valid))?;
+        for tally in &self.tallies {
+            write!(f, "{}", tally)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_round_value(fields: &[&str]) -> Result<Option<sm::RoundValue>, String> {
+    match fields {
+        ["none"] => Ok(None),
+        [round, value] => Ok(Some(sm::RoundValue {
+            round: round
+                .parse()
+                .map_err(|_| format!("bad round {:?}", round))?,
+            value: value.parse()?,
+        })),
+        _ => Err(format!("bad round value {:?}", fields.join(" "))),
+    }
+}
+
+fn parse_step(s: &str) -> Result<sm::Step, String> {
+    let steps = [
+        sm::Step::NewRound,
+        sm::Step::Propose,
+        sm::Step::Prevote,
+        sm::Step::Precommit,
+        sm::Step::Commit,
+    ];
+    let name = |step: &sm::Step| format!("{:?}", step).to_lowercase();
+    let step = steps.iter().find(|step| name(step) == s);
+    step.copied().ok_or_else(|| format!("bad step {:?}", s))
+}
+
+impl FromStr for Fixture {
+    type Err = String;
+
+    // from_str parses the fixture. Errors name the line, counted from 1.
+    fn from_str(s: &str) -> Result<Fixture, String> {
+        let lines: Vec<&str> = s.lines().collect();
+        if lines.first().map(|l| l.trim()) != Some(FIXTURE_HEADER) {
+            return Err(format!("line 1: expected header {:?}", FIXTURE_HEADER));
+        }
+        let mut snapshot = RoundStateSnapshot {
+            height: 0,
+            round: 0,
+            step: sm::Step::NewRound,
+            locked: None,
+            valid: None,
+            own_proposal: None,
+            own_votes: Vec::new(),
+            last_commit: None,
+        };
+        let header_end = (1..lines.len())
+            .find(|i| lines[*i].trim() == DUMP_HEADER)
+            .unwrap_or(lines.len());
+        for (i, line) in lines.iter().enumerate().take(header_end).skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                ["height", h] => h
+                    .parse()
+                    .map(|h| snapshot.height = h)
+                    .map_err(|_| format!("bad height {:?}", h)),
+                ["round", r] => r
+                    .parse()
+                    .map(|r| snapshot.round = r)
+                    .map_err(|_| format!("bad round {:?}", r)),
+                ["step", step] => parse_step(step).map(|step| snapshot.step = step),
+                ["locked", rest @ ..] => parse_round_value(rest).map(|rv| snapshot.locked = rv),
+                ["valid", rest @ ..] => parse_round_value(rest).map(|rv| snapshot.valid = rv),
+                [] => Ok(()),
+                _ => Err(format!("malformed line {:?}", line)),
+            };
+            parsed.map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+
+        // each tally runs from its header to the next
+        let mut tallies = Vec::new();
+        let mut starts: Vec<usize> = (header_end..lines.len())
+            .filter(|i| lines[*i].trim() == DUMP_HEADER)
+            .collect();
+        starts.push(lines.len());
+        for w in starts.windows(2) {
+            let tally: RoundVotesDump = lines[w[0]..w[1]]
+                .join("\n")
+                .parse()
+                .map_err(|e: DumpError| format!("line {}: {}", w[0] + e.line, e.reason))?;
+            tallies.push(tally);
+        }
+        Ok(Fixture { snapshot, tallies })
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Message, Output};
+    use crate::scheduler::Timers;
+
+    #[test]
+    fn polka() {
+        let vals = validator_set(&[10, 20, 30, 40]);
+        let value = Value::new([1; 32]);
+        let polka = make_polka(&vals, 1, 0, Some(value));
+        let weight: i64 = polka
+            .iter()
+            .map(|v| vals.get(v.signer).unwrap().voting_power)
+            .sum();
+        assert!(is_quorum(weight, 100));
+
+        let vals = validator_set(&[1, 1, 1, 1]);
+        let polka = make_polka(&vals, 1, 0, Some(value));
+        assert_eq!(polka.len(), 3);
+
+        // an engine for a validator outside the polka precommits once it's in
+        let us = (0..4)
+            .find(|i| polka.iter().all(|v| v.signer != *i))
+            .unwrap();
+        let mut engine = Engine::new(
+            ConsensusConfig::default(),
+            1,
+            vals.clone(),
+            Box::new(KeySigner::new(&vals, us)),
+            Box::new(KeyVerifier(vals.clone())),
+            Box::new(Timers::default()),
+        )
+        .unwrap();
+        engine.start_round(0, None);
+        engine.execute(Message::Proposal(proposal(&vals, 0, 1, 0, value, -1)));
+        let mut outputs = Vec::new();
+        for vote in polka {
+            outputs.extend(engine.execute(Message::Vote(vote)));
+        }
+        let precommit = precommit(&vals, us, 1, 0, Some(value));
+        assert!(outputs.contains(&Output::Vote(precommit)));
+        assert!(engine.rejected().next().is_none());
+    }
+
+    #[test]
+    fn fixture() {
+        let vals = validator_set(&[1, 1, 1, 1]);
+        let (a, b) = (Value::new([1; 32]), Value::new([2; 32]));
+        let new_engine = |fixture: &Fixture| {
+            fixture.engine(
+                ConsensusConfig::default(),
+                vals.clone(),
+                Box::new(KeySigner::new(&vals, 0)),
+                Box::new(KeyVerifier(vals.clone())),
+                Box::new(Timers::default()),
+            )
+        };
+        // locked on a in round 1, from a polka we precommitted,
+        // then on to round 2 without a decision
+        let text = format!(
+            "fixture v1\n\
+             height 1\n\
+             round 2\n\
+             step propose\n\
+             locked 1 {a}\n\
+             valid 1 {a}\n\
+             round_votes v1\n\
+             height 1\n\
+             round 1\n\
+             total 4\n\
+             prevote thresh value {a}\n\
+             prevote vote 0 {a} 1\n\
+             prevote vote 1 {a} 1\n\
+             prevote vote 2 {a} 1\n\
+             precommit thresh init\n\
+             precommit vote 0 {a} 1\n",
+            a = a
+        );
+        let fixture: Fixture = text.parse().unwrap();
+        let mut engine = new_engine(&fixture).unwrap();
+        let state = engine.state();
+        assert_eq!((state.round(), state.step()), (2, sm::Step::Propose));
+        assert_eq!(state.locked().map(|l| l.value), Some(a));
+
+        // the lock holds: a new value is prevoted nil
+        let msg = Message::Proposal(proposal(&vals, 1, 1, 2, b, -1));
+        let nil = prevote(&vals, 0, 1, 2, None);
+        assert_eq!(engine.execute(msg), vec![Output::Vote(nil.clone())]);
+
+        // the engine's state prints back as the fixture it's in
+        let mut fixture = Fixture::of(&engine);
+        assert_eq!(fixture.snapshot.own_votes, vec![nil]);
+        let text = fixture.to_string();
+        fixture.snapshot.own_votes.clear();
+        assert_eq!(text.parse(), Ok(fixture));
+
+        let broken = |from: &str, to: &str| {
+            let fixture: Fixture = text.replacen(from, to, 1).parse()?;
+            new_engine(&fixture).map(|_| ())
+        };
+        let err = broken(&format!("valid 1 {}", a), "valid none").unwrap_err();
+        assert!(err.contains("locked"), "{}", err);
+        assert!(broken("total 4", "total 5").is_err());
+        let thresh = format!("prevote thresh value {}", a);
+        let err = broken(&thresh, "prevote thresh init").unwrap_err();
+        assert!(err.contains("threshold"), "{}", err);
+        assert!(broken("step prevote", "step waiting")
+            .unwrap_err()
+            .starts_with("line 4:"));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let mut vals = validator_set(&[1, 1, 1, 1]);
+        let address = vals.get(1).unwrap().address::<Sha256>();
+        vals.rotate_key(&address, 3, key(10));
+        let verifier = KeyVerifier(vals.clone());
+        let (old, new) = (KeySigner::new(&vals, 1), KeySigner::with_key(1, key(10)));
+        let signed = |signer: &KeySigner, height| {
+            let msg = Vote::new_prevote(height, 0, None);
+            Signed {
+                signer: 1,
+                msg,
+                signature: signer.sign(&msg.sign_bytes()),
+            }
+        };
+
+        // the old key is accepted until the activation height, the new one after
+        assert!(signed(&old, 2).is_valid(&verifier));
+        assert!(!signed(&new, 2).is_valid(&verifier));
+        assert!(!signed(&old, 3).is_valid(&verifier));
+        assert!(signed(&new, 3).is_valid(&verifier));
+    }
+}
@@ -0,0 +1,325 @@
+// Timeline records what happened at each height, and when, for postmortems:
+// the steps the engine entered, when proposals arrived, the thresholds votes
+// crossed, the timeouts that fired, and optionally each message received.
+//
+// Like the metrics recorder, the driver keeps a TimelineRecorder next to the
+// engine, calls observe() with the engine and its outputs after each call,
+// fired() with each timeout it passes back to the engine, and received()
+// with each message from a peer, if it wants them in the timeline. The timelines
+// of the last heights are kept, for the driver to serve, eg. over RPC, so
+// operators can tell what happened at a height well after it was decided.
+// Each prints compactly, one event per line, with its time in milliseconds:
+//
+//   height 7
+//   0 0 step propose
+//   850 0 proposal
+//   1020 0 threshold prevote value 0101..01
+//   1030 0 step precommit
+//   1300 0 decision
+
+use super::engine::{Engine, Message, Output};
+use super::rejected::MessageKind;
+use super::round_votes::{fmt_thresh, Thresh};
+use super::state_machine as sm;
+use super::VoteType;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+// TimelineEvent is something that happened in a round.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimelineEvent {
+    Step(sm::Step),              // Entered the step.
+    Proposal,                    // Received the round's proposal.
+    Threshold(VoteType, Thresh), // The round's votes crossed the threshold.
+    TimeoutFired(sm::TimeoutStep),
+    Decision,                     // Decided the round's value.
+    Received(MessageKind, usize), // Received a message of the kind from the validator.
+}
+
+// TimelineEntry is an event, in a round, at a time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineEntry {
+    pub at: Duration,
+    pub round: i64,
+    pub event: TimelineEvent,
+}
+
+// Timeline is what happened at a height, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline {
+    pub height: i64,
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl Timeline {
+    fn push(&mut self, at: Duration, round: i64, event: TimelineEvent) {
+        self.entries.push(TimelineEntry { at, round, event });
+    }
+}
+
+// TimelineRecorder records the timelines of the last max_heights heights.
+pub struct TimelineRecorder {
+    max_heights: usize,
+    timelines: VecDeque<Timeline>, // oldest first; the last is the current height
+    seen: BTreeMap<i64, (bool, usize)>, // by round: had a proposal, thresholds recorded
+    step: Option<(i64, sm::Step)>, // the last round and step recorded
+}
+
+impl TimelineRecorder {
+    pub fn new(max_heights: usize) -> TimelineRecorder {
+        TimelineRecorder {
+            max_heights: max_heights.max(1),
+            timelines: VecDeque::new(),
+            seen: BTreeMap::new(),
+            step: None,
+        }
+    }
+
+    // observe records what changed in the engine, given the outputs of the
+    // call that changed it. If the call decided the height and moved on to
+    // the next, the rounds of the decided height are gone from the engine,
+    // so its precommit threshold and Commit step are recorded from the
+    // decision.
+    pub fn observe(&mut self, engine: &Engine, outputs: &[Output], now: Duration) {
+        let height = engine.state().height();
+        let switched = self.timelines.back().map(|t| t.height) != Some(height);
+        if !switched {
+            self.record(engine, now);
+        }
+        for output in outputs {
+            if let Output::Decision(d) = output {
+                self.decided(d, now);
+            }
+        }
+        if switched {
+            self.start(height);
+            self.record(engine, now);
+        }
+    }
+
+    // record the engine's rounds and step at the current height.
+    fn record(&mut self, engine: &Engine, now: Duration) {
+        let state = engine.state();
+        let timeline = match self.timelines.back_mut() {
+            Some(timeline) => timeline,
+            None => return,
+        };
+        for summary in engine.rounds() {
+            let seen = self.seen.entry(summary.round).or_insert((false, 0));
+            if summary.has_proposal && !seen.0 {
+                timeline.push(now, summary.round, TimelineEvent::Proposal);
+            }
+            for (typ, thresh) in summary.thresholds.iter().skip(seen.1) {
+                let event = TimelineEvent::Threshold(*typ, *thresh);
+                timeline.push(now, summary.round, event);
+            }
+            *seen = (summary.has_proposal, summary.thresholds.len());
+        }
+        let step = (state.round(), state.step());
+        if self.step != Some(step) {
+            timeline.push(now, step.0, TimelineEvent::Step(step.1));
+            self.step = Some(step);
+        }
+    }
+
+    // decided records the decision of the current height, after the precommit
+    // threshold and Commit step it implies, unless they're recorded already.
+    fn decided(&mut self, decision: &sm::RoundValue, now: Duration) {
+        let timeline = match self.timelines.back_mut() {
+            Some(timeline) => timeline,
+            None => return,
+        };
+        let round = decision.round;
+        let thresh = TimelineEvent::Threshold(VoteType::Precommit, Thresh::Value(decision.value));
+        if !timeline
+            .entries
+            .iter()
+            .any(|e| e.round == round && e.event == thresh)
+        {
+            timeline.push(now, round, thresh);
+        }
+        let step = (round, sm::Step::Commit);
+        if self.step != Some(step) {
+            timeline.push(now, round, TimelineEvent::Step(step.1));
+            self.step = Some(step);
+        }
+        timeline.push(now, round, TimelineEvent::Decision);
+    }
+
+    // fired records that the timeout fired, as it's passed back to the engine.
+    pub fn fired(&mut self, timeout: &sm::Timeout, now: Duration) {
+        let event = TimelineEvent::TimeoutFired(timeout.step);
+        let height = timeout.height;
+        if let Some(t) = self.timelines.iter_mut().find(|t| t.height == height) {
+            t.push(now, timeout.round, event);
+        }
+    }
+
+    // received records the message from a peer, as it's passed to the engine.
+    // Messages for heights whose timelines aren't kept are left out.
+    pub fn received(&mut self, msg: &Message, now: Duration) {
+        let (kind, signer, height, round) = match msg.header() {
+            Some(header) => header,
+            None => return,
+        };
+        let event = TimelineEvent::Received(kind, signer);
+        if let Some(t) = self.timelines.iter_mut().find(|t| t.height == height) {
+            t.push(now, round, event);
+        }
+    }
+
+    // timeline returns the timeline of the height, if it's still kept.
+    pub fn timeline(&self, height: i64) -> Option<&Timeline> {
+        self.timelines.iter().find(|t| t.height == height)
+    }
+
+    // timelines returns the kept timelines, oldest first.
+    pub fn timelines(&self) -> impl Iterator<Item = &Timeline> {
+        self.timelines.iter()
+    }
+
+    // start a timeline for the height, dropping the oldest one to make room.
+    fn start(&mut self, height: i64) {
+        if self.timelines.len() == self.max_heights {
+            self.timelines.pop_front();
+        }
+        self.timelines.push_back(Timeline {
+            height,
+            entries: Vec::new(),
+        });
+        self.seen.clear();
+        self.step = None;
+    }
+}
+
+//---------------------------------------------------------------------
+// Text
+
+fn fmt_step(step: sm::Step) -> String {
+    format!("{:?}", step).to_lowercase()
+}
+
+fn fmt_vote_type(typ: VoteType) -> &'static str {
+    match typ {
+        VoteType::Prevote => "prevote",
+        VoteType::Precommit => "precommit",
+    }
+}
+
+impl fmt::Display for TimelineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimelineEvent::Step(step) => write!(f, "step {}", fmt_step(*step)),
+            TimelineEvent::Proposal => write!(f, "proposal"),
+            TimelineEvent::Threshold(typ, thresh) => {
+                write!(
+                    f,
+                    "threshold {} {}",
+                    fmt_vote_type(*typ),
+                    fmt_thresh(*thresh)
+                )
+            }
+            TimelineEvent::TimeoutFired(step) => {
+                write!(f, "timeout {}", format!("{:?}", step).to_lowercase())
+            }
+            TimelineEvent::Decision => write!(f, "decision"),
+            TimelineEvent::Received(kind, signer) => {
+                let kind = match kind {
+                    MessageKind::Proposal => "proposal",
+                    MessageKind::Vote(typ) => fmt_vote_type(*typ),
+                    MessageKind::Heartbeat => "heartbeat",
+                };
+                write!(f, "received {} {}", kind, signer)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "height {}", self.height)?;
+        for e in &self.entries {
+            writeln!(f, "{} {} {}", e.at.as_millis(), e.round, e.event)?;
+        }
+        Ok(())
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::scenario::{proposal, test_engine, vote};
+    use crate::scheduler::Timers;
+    use crate::{Proposal, Value, Vote};
+
+    #[test]
+    fn record() {
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        let mut recorder = TimelineRecorder::new(2);
+        let ms = Duration::from_millis;
+        let value = Value::new([1; 32]);
+        let proposal = proposal(1, Proposal::new(1, 0, value, -1));
+
+        let outputs = engine.start_round(0, None);
+        recorder.observe(&engine, &outputs, ms(0));
+        let msgs = vec![
+            (proposal, 800),
+            (vote(1, Vote::new_prevote(1, 0, Some(value))), 900),
+            (vote(2, Vote::new_prevote(1, 0, Some(value))), 1000),
+            (vote(1, Vote::new_precommit(1, 0, Some(value))), 1100),
+            (vote(2, Vote::new_precommit(1, 0, Some(value))), 1200),
+        ];
+        for (msg, at) in msgs {
+            if at == 900 {
+                recorder.received(&msg, ms(at));
+            }
+            let outputs = engine.execute(msg);
+            recorder.observe(&engine, &outputs, ms(at));
+        }
+        let prevote = sm::Timeout {
+            height: 1,
+            round: 0,
+            step: sm::TimeoutStep::Prevote,
+        };
+        recorder.fired(&prevote, ms(1300));
+
+        let id = "01".repeat(32);
+        let expected = format!(
+            "height 1\n\
+             0 0 step propose\n\
+             800 0 proposal\n\
+             800 0 step prevote\n\
+             900 0 received prevote 1\n\
+             1000 0 threshold prevote value {}\n\
+             1000 0 step precommit\n\
+             1200 0 threshold precommit value {}\n\
+             1200 0 step commit\n\
+             1200 0 decision\n\
+             1300 0 timeout prevote\n",
+            id, id
+        );
+        assert_eq!(recorder.timeline(1).unwrap().to_string(), expected);
+        let heights: Vec<i64> = recorder.timelines().map(|t| t.height).collect();
+        assert_eq!(heights, vec![1, 2]);
+
+        // only the last heights are kept
+        let outputs = engine.start_round(0, None);
+        recorder.observe(&engine, &outputs, ms(1400));
+        for signer in 1..4 {
+            let precommit = vote(signer, Vote::new_precommit(2, 0, Some(value)));
+            let outputs = engine.execute(precommit);
+            recorder.observe(&engine, &outputs, ms(1500));
+        }
+        let heights: Vec<i64> = recorder.timelines().map(|t| t.height).collect();
+        assert_eq!(heights, vec![2, 3]);
+        let timeline = recorder.timeline(2).unwrap().to_string();
+        assert!(timeline.ends_with("1500 0 step commit\n1500 0 decision\n"));
+    }
+}
@@ -0,0 +1,896 @@
+use super::config::ValidatorSetLimits;
+use super::hash::{merkle_root, Hash, Hasher, Sha256};
+use super::{from_hex, to_hex};
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::marker::PhantomData;
+
+//----------------------------------
+// Validator
+
+// Address identifies a validator. It's the first 20 bytes of the hash of its public key.
+pub type Address = [u8; 20];
+
+// Validator is a public key and voting power
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validator {
+    pub public_key: Vec<u8>, // TODO: trait?
+    pub voting_power: i64,
+}
+
+impl Validator {
+    // bytes encodes the validator: voting power (8 bytes, big endian), then the public key.
+    pub fn bytes(&self) -> Vec<u8> {
+        [&self.voting_power.to_be_bytes()[..], &self.public_key[..]].concat()
+    }
+
+    pub fn hash<H: Hasher>(&self) -> Hash {
+        H::hash(&self.bytes())
+    }
+
+    pub fn address<H: Hasher>(&self) -> Address {
+        let mut address = [0; 20];
+        address.copy_from_slice(&H::hash(&self.public_key)[..20]);
+        address
+    }
+}
+
+// KeyRotation is a new public key for a validator, that it signs with
+// from the activation height on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyRotation {
+    pub height: i64,
+    pub public_key: Vec<u8>,
+}
+
+impl KeyRotation {
+    // bytes encodes the rotation of the validator at the index: the index
+    // (4 bytes, big endian), the height (8 bytes, big endian), then the key.
+    fn bytes(&self, index: usize) -> Vec<u8> {
+        let (index, height) = ((index as u32).to_be_bytes(), self.height.to_be_bytes());
+        [&index[..], &height[..], &self.public_key[..]].concat()
+    }
+}
+
+//--------------------------------
+
+// ROTATIONS_TAG prefixes the hash of a set with key rotations, after the
+// Merkle tree's leaf and inner node prefixes.
+const ROTATIONS_TAG: u8 = 2;
+
+// ValidatorSet contains a list of validators sorted by address.
+// Addresses and the set hash are computed with H.
+//
+// Validators may rotate their keys: a validator keeps the address of the
+// key it joined with, so its index doesn't change, but signs with the key
+// of its latest rotation activated at the height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatorSet<H: Hasher = Sha256> {
+    validators: Vec<Validator>,
+    rotations: BTreeMap<Address, Vec<KeyRotation>>, // by activation height
+    hasher: PhantomData<H>,
+}
+
+impl<H: Hasher> ValidatorSet<H> {
+    // new creates a set from the validators.
+    // If there are several validators with the same address, the first is kept.
+    pub fn new(mut vals: Vec<Validator>) -> ValidatorSet<H> {
+        ValidatorSet::<H>::sort(&mut vals);
+        ValidatorSet {
+            validators: vals,
+            rotations: BTreeMap::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    // add the validator to the set, unless its address is already in it.
+    pub fn add(&mut self, val: Validator) {
+        if self.index_of(&val.address::<H>()).is_none() {
+            self.validators.push(val);
+            ValidatorSet::<H>::sort(&mut self.validators);
+        }
+    }
+
+    // update the voting power of the validator with the same public key.
+    // Returns false if there is none.
+    pub fn update(&mut self, val: Validator) -> bool {
+        match self.index_of(&val.address::<H>()) {
+            Some(i) => {
+                self.validators[i].voting_power = val.voting_power;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // remove the validator with the same public key, and its key rotations.
+    // Returns false if there is none.
+    pub fn remove(&mut self, val: &Validator) -> bool {
+        let address = val.address::<H>();
+        match self.index_of(&address) {
+            Some(i) => {
+                self.validators.remove(i);
+                self.rotations.remove(&address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // rotate_key registers the validator's new public key, effective from the
+    // activation height. Rotations registered before for that height or later
+    // are replaced. Returns false if there's no validator with the address.
+    pub fn rotate_key(&mut self, address: &Address, height: i64, public_key: Vec<u8>) -> bool {
+        if self.index_of(address).is_none() {
+            return false;
+        }
+        let rotations = self.rotations.entry(*address).or_default();
+        rotations.retain(|r| r.height < height);
+        rotations.push(KeyRotation { height, public_key });
+        true
+    }
+
+    // key_rotations returns the key rotations of the validator at the index,
+    // by activation height.
+    pub fn key_rotations(&self, index: usize) -> &[KeyRotation] {
+        self.validators
+            .get(index)
+            .and_then(|v| self.rotations.get(&v.address::<H>()))
+            .map_or(&[], |r| &r[..])
+    }
+
+    // public_key_at returns the key the validator at the index signs with at
+    // the height: the key of its latest rotation activated by then, if any.
+    pub fn public_key_at(&self, index: usize, height: i64) -> Option<&[u8]> {
+        let val = self.validators.get(index)?;
+        let rotated = self
+            .key_rotations(index)
+            .iter()
+            .rev()
+            .find(|r| r.height <= height);
+        Some(rotated.map_or(&val.public_key[..], |r| &r.public_key[..]))
+    }
+
+    // rotation_items returns the key rotations of the set, in set order,
+    // encoded with KeyRotation::bytes.
+    fn rotation_items(&self) -> Vec<(usize, &KeyRotation)> {
+        (0..self.validators.len())
+            .flat_map(|i| self.key_rotations(i).iter().map(move |r| (i, r)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn validators(&self) -> &[Validator] {
+        &self.validators
+    }
+
+    // get returns the validator at the given index.
+    pub fn get(&self, index: usize) -> Option<&Validator> {
+        self.validators.get(index)
+    }
+
+    // index_of returns the index of the validator with the given address.
+    pub fn index_of(&self, address: &Address) -> Option<usize> {
+        self.validators
+            .binary_search_by(|v| v.address::<H>().cmp(address))
+            .ok()
+    }
+
+    pub fn total_voting_power(&self) -> i64 {
+        self.validators.iter().map(|v| v.voting_power).sum()
+    }
+
+    // hash is the root of the Merkle tree of the validators' bytes, in order.
+    // A set with key rotations hashes that root and the root of the tree of
+    // the rotations' bytes together, under its own tag, so a rotation can't
+    // pass for a validator.
+    pub fn hash(&self) -> Hash {
+        let validators: Vec<Vec<u8>> = self.validators.iter().map(|v| v.bytes()).collect();
+        let root = merkle_root::<H>(&validators);
+        let rotations: Vec<Vec<u8>> = self
+            .rotation_items()
+            .iter()
+            .map(|(i, r)| r.bytes(*i))
+            .collect();
+        if rotations.is_empty() {
+            return root;
+        }
+        let rotations = merkle_root::<H>(&rotations);
+        H::hash(&[&[ROTATIONS_TAG], &root[..], &rotations[..]].concat())
+    }
+
+    // sample_by_stake picks k distinct validators at random, each draw weighted
+    // by voting power. Randomness is derived from the seed with H, so nodes
+    // sampling with the same seed pick the same validators in the same order.
+    // Validators without voting power are never picked, so fewer than k
+    // may be returned.
+    pub fn sample_by_stake(&self, seed: &[u8], k: usize) -> Vec<&Validator> {
+        let mut remaining: Vec<&Validator> = self
+            .validators
+            .iter()
+            .filter(|v| v.voting_power > 0)
+            .collect();
+        let mut total: u64 = remaining.iter().map(|v| v.voting_power as u64).sum();
+        let mut rng = SeedRng::<H>::new(seed);
+        let mut sample = Vec::with_capacity(k.min(remaining.len()));
+        while sample.len() < k && !remaining.is_empty() {
+            let mut r = rng.below(total);
+            let i = remaining
+                .iter()
+                .position(|v| {
+                    let power = v.voting_power as u64;
+                    if r < power {
+                        return true;
+                    }
+                    r -= power;
+                    false
+                })
+                .unwrap();
+            total -= remaining[i].voting_power as u64;
+            sample.push(remaining.remove(i));
+        }
+        sample
+    }
+
+    // in place sort a list of validators
+    fn sort(vals: &mut Vec<Validator>) {
+        vals.sort_by_cached_key(|v| v.address::<H>());
+        vals.dedup_by_key(|v| v.address::<H>());
+    }
+}
+
+//--------------------------------
+// Validation
+
+// ValidatorSetError is why a validator set is outside the limits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidatorSetError {
+    Empty,
+    PowerTooLow {
+        index: usize,
+        power: i64,
+    },
+    TotalOverflow,
+    TotalTooHigh {
+        total: i64,
+        max: i64,
+    },
+    PowerShareTooHigh {
+        index: usize,
+        power: i64,
+        total: i64,
+    },
+}
+
+impl fmt::Display for ValidatorSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidatorSetError::Empty => write!(f, "empty validator set"),
+            ValidatorSetError::PowerTooLow { index, power } => {
+                write!(
+                    f,
+                    "validator {} has too little voting power {}",
+                    index, power
+                )
+            }
+            ValidatorSetError::TotalOverflow => write!(f, "total voting power overflows"),
+            ValidatorSetError::TotalTooHigh { total, max } => {
+                write!(f, "total voting power {} is over {}", total, max)
+            }
+            ValidatorSetError::PowerShareTooHigh {
+                index,
+                power,
+                total,
+            } => write!(
+                f,
+                "validator {} has too much voting power {} of {}",
+                index, power, total
+            ),
+        }
+    }
+}
+
+impl<H: Hasher> ValidatorSet<H> {
+    // validate returns the first way the set is outside the limits, if any:
+    // it's empty, a validator has less than the least voting power, the
+    // total is too high or overflows, or a validator has too large a share.
+    pub fn validate(&self, limits: &ValidatorSetLimits) -> Result<(), ValidatorSetError> {
+        if self.is_empty() {
+            return Err(ValidatorSetError::Empty);
+        }
+        let mut total: i64 = 0;
+        for (index, v) in self.validators.iter().enumerate() {
+            if v.voting_power < limits.min_power.max(1) {
+                let power = v.voting_power;
+                return Err(ValidatorSetError::PowerTooLow { index, power });
+            }
+            total = total
+                .checked_add(v.voting_power)
+                .ok_or(ValidatorSetError::TotalOverflow)?;
+        }
+        if total > limits.max_total_power {
+            let max = limits.max_total_power;
+            return Err(ValidatorSetError::TotalTooHigh { total, max });
+        }
+        if let Some(percent) = limits.max_power_percent {
+            // power / total > percent / 100, without overflowing
+            let over = |power: i64| (power as i128) * 100 > (total as i128) * (percent as i128);
+            if let Some(index) = self.validators.iter().position(|v| over(v.voting_power)) {
+                let power = self.validators[index].voting_power;
+                return Err(ValidatorSetError::PowerShareTooHigh {
+                    index,
+                    power,
+                    total,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+//--------------------------------
+// Proposers
+
+// ProposerSelector picks the validator expected to propose in a round.
+// Every node of the chain must pick the same one.
+pub trait ProposerSelector {
+    // proposer returns the index in the set of the round's proposer.
+    fn proposer(&self, validators: &ValidatorSet, height: i64, round: i64) -> usize;
+}
+
+// RoundRobin picks each validator in turn, one round after another.
+pub struct RoundRobin;
+
+impl ProposerSelector for RoundRobin {
+    fn proposer(&self, validators: &ValidatorSet, height: i64, round: i64) -> usize {
+        ((height + round) as usize) % validators.len().max(1)
+    }
+}
+
+//--------------------------------
+// Diffs
+
+// ValidatorSetDiff is the change from one validator set to the next:
+// the validators added or whose voting power changed, the ones removed,
+// and the key rotations of validators whose rotations changed, by address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidatorSetDiff {
+    pub updated: Vec<Validator>,
+    pub removed: Vec<Validator>,
+    pub rotations: Vec<(Address, Vec<KeyRotation>)>,
+}
+
+impl<H: Hasher> ValidatorSet<H> {
+    // diff returns the change from this set to the next one.
+    pub fn diff(&self, next: &ValidatorSet<H>) -> ValidatorSetDiff {
+        let updated = next
+            .validators
+            .iter()
+            .filter(|v| self.find(v) != Some(v))
+            .cloned()
+            .collect();
+        let removed = self
+            .validators
+            .iter()
+            .filter(|v| next.find(v).is_none())
+            .cloned()
+            .collect();
+        let rotations = next
+            .validators
+            .iter()
+            .map(|v| v.address::<H>())
+            .filter(|a| self.rotations.get(a) != next.rotations.get(a))
+            .map(|a| (a, next.rotations.get(&a).cloned().unwrap_or_default()))
+            .collect();
+        ValidatorSetDiff {
+            updated,
+            removed,
+            rotations,
+        }
+    }
+
+    // find returns the validator of the set with the same address.
+    fn find(&self, val: &Validator) -> Option<&Validator> {
+        let i = self.index_of(&val.address::<H>())?;
+        Some(&self.validators[i])
+    }
+
+    // apply changes the set by the diff, into the next set. Returns an error
+    // if the diff doesn't apply to this set.
+    pub fn apply(&mut self, diff: &ValidatorSetDiff) -> Result<(), String> {
+        for val in &diff.removed {
+            if !self.remove(val) {
+                return Err(format!(
+                    "removing unknown validator {}",
+                    to_hex(&val.public_key)
+                ));
+            }
+        }
+        for val in &diff.updated {
+            if !self.update(val.clone()) {
+                self.add(val.clone());
+            }
+        }
+        for (address, rotations) in &diff.rotations {
+            if self.index_of(address).is_none() {
+                return Err(format!(
+                    "key rotations for unknown validator {}",
+                    to_hex(address)
+                ));
+            }
+            match rotations.is_empty() {
+                true => self.rotations.remove(address),
+                false => self.rotations.insert(*address, rotations.clone()),
+            };
+        }
+        Ok(())
+    }
+}
+
+// SeedRng derives random numbers from a seed: the i-th number is
+// the first 8 bytes (big endian) of H(seed || i), with i as 8 bytes big endian.
+struct SeedRng<'a, H> {
+    seed: &'a [u8],
+    counter: u64,
+    hasher: PhantomData<H>,
+}
+
+impl<'a, H: Hasher> SeedRng<'a, H> {
+    fn new(seed: &'a [u8]) -> SeedRng<'a, H> {
+        SeedRng {
+            seed,
+            counter: 0,
+            hasher: PhantomData,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let hash = H::hash(&[self.seed, &self.counter.to_be_bytes()].concat());
+        self.counter += 1;
+        u64::from_be_bytes(hash[..8].try_into().unwrap())
+    }
+
+    // below returns a number in [0, n), for n > 0.
+    // Numbers past the largest multiple of n are redrawn, so there is no modulo bias.
+    fn below(&mut self, n: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n;
+            }
+        }
+    }
+}
+
+//---------------------------------------------------------------------
+// Encoding
+//
+// Validator sets have a canonical JSON and binary encoding, so nodes
+// agree on them byte for byte. Validators are in set order (by address).
+//
+// JSON has no whitespace, and voting power is a decimal string:
+//
+//   {"validators":[{"public_key":"<hex>","voting_power":"10"}]}
+//
+// Binary is the number of validators (4 bytes, big endian), then for each
+// validator the length of its public key (4 bytes, big endian) and its bytes().
+//
+// Sets with key rotations add them in set order, by activation height. In
+// JSON, after the validators:
+//
+//   ,"key_rotations":[{"index":"0","height":"100","public_key":"<hex>"}]
+//
+// In binary, after the validators, the number of rotations (4 bytes, big
+// endian), then for each the length of its key (4 bytes, big endian) and its
+// bytes: validator index, activation height, then the key.
+//
+// Decoding only accepts the canonical encoding of a set.
+
+const JSON_PREFIX: &str = "{\"validators\":[";
+const JSON_SUFFIX: &str = "]}";
+const JSON_ROTATIONS: &str = "],\"key_rotations\":[";
+
+impl<H: Hasher> ValidatorSet<H> {
+    pub fn to_json(&self) -> String {
+        let vals: Vec<String> = self
+            .validators
+            .iter()
+            .map(|v| {
+                format!(
+                    "{{\"public_key\":\"{}\",\"voting_power\":\"{}\"}}",
+                    to_hex(&v.public_key),
+                    v.voting_power
+                )
+            })
+            .collect();
+        let rotations: Vec<String> = self
+            .rotation_items()
+            .iter()
+            .map(|(i, r)| {
+                format!(
+                    "{{\"index\":\"{}\",\"height\":\"{}\",\"public_key\":\"{}\"}}",
+                    i,
+                    r.height,
+                    to_hex(&r.public_key)
+                )
+            })
+            .collect();
+        let mut json = format!("{}{}", JSON_PREFIX, vals.join(","));
+        if !rotations.is_empty() {
+            json = format!("{}{}{}", json, JSON_ROTATIONS, rotations.join(","));
+        }
+        json + JSON_SUFFIX
+    }
+
+    pub fn from_json(s: &str) -> Result<ValidatorSet<H>, String> {
+        let body = s
+            .strip_prefix(JSON_PREFIX)
+            .and_then(|s| s.strip_suffix(JSON_SUFFIX))
+            .ok_or("expected {\"validators\":[...]}")?;
+        let (body, rotations) = body.split_once(JSON_ROTATIONS).unwrap_or((body, ""));
+        let objects = |body: &'_ str| -> Vec<String> {
+            if body.is_empty() {
+                return Vec::new();
+            }
+            body.split("},{")
+                .map(|o| o.trim_start_matches('{').trim_end_matches('}').to_string())
+                .collect()
+        };
+        let mut vals = Vec::new();
+        for v in objects(body) {
+            vals.push(parse_json_validator(&v)?);
+        }
+        let mut set = ValidatorSet::new(vals);
+        for r in objects(rotations) {
+            let (index, rotation) = parse_json_rotation(&r)?;
+            set.add_rotation(index, rotation)?;
+        }
+        if set.to_json() != s {
+            return Err("not canonical".to_string());
+        }
+        Ok(set)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bz = (self.validators.len() as u32).to_be_bytes().to_vec();
+        for v in &self.validators {
+            bz.extend_from_slice(&(v.public_key.len() as u32).to_be_bytes());
+            bz.extend_from_slice(&v.bytes());
+        }
+        let rotations = self.rotation_items();
+        if !rotations.is_empty() {
+            bz.extend_from_slice(&(rotations.len() as u32).to_be_bytes());
+            for (i, r) in rotations {
+                bz.extend_from_slice(&(r.public_key.len() as u32).to_be_bytes());
+                bz.extend_from_slice(&r.bytes(i));
+            }
+        }
+        bz
+    }
+
+    pub fn from_bytes(bz: &[u8]) -> Result<ValidatorSet<H>, String> {
+        let mut rest = bz;
+        let n = read_u32(&mut rest)?;
+        let mut vals = Vec::new();
+        for _ in 0..n {
+            let key_len = read_u32(&mut rest)? as usize;
+            let voting_power = i64::from_be_bytes(read(&mut rest, 8)?.try_into().unwrap());
+            let public_key = read(&mut rest, key_len)?.to_vec();
+            vals.push(Validator {
+                public_key,
+                voting_power,
+            });
+        }
+        let mut set = ValidatorSet::new(vals);
+        if !rest.is_empty() {
+            for _ in 0..read_u32(&mut rest)? {
+                let key_len = read_u32(&mut rest)? as usize;
+                let index = read_u32(&mut rest)? as usize;
+                let height = i64::from_be_bytes(read(&mut rest, 8)?.try_into().unwrap());
+                let public_key = read(&mut rest, key_len)?.to_vec();
+                set.add_rotation(index, KeyRotation { height, public_key })?;
+            }
+        }
+        if set.to_bytes() != bz {
+            return Err("not canonical".to_string());
+        }
+        Ok(set)
+    }
+}
+
+impl<H: Hasher> ValidatorSet<H> {
+    // add_rotation adds a decoded rotation for the validator at the index.
+    // Rotations must come in order, as encoded.
+    fn add_rotation(&mut self, index: usize, rotation: KeyRotation) -> Result<(), String> {
+        let address = match self.validators.get(index) {
+            Some(val) => val.address::<H>(),
+            None => return Err(format!("key rotation for unknown validator {}", index)),
+        };
+        self.rotations.entry(address).or_default().push(rotation);
+        Ok(())
+    }
+}
+
+// parse_json_rotation parses the fields of a key rotation object, without its braces.
+fn parse_json_rotation(s: &str) -> Result<(usize, KeyRotation), String> {
+    let err = || format!("bad key rotation {:?}", s);
+    let mut fields = Vec::new();
+    for (field, name) in s.split(',').zip(["index", "height", "public_key"]) {
+        let prefix = format!("\"{}\":\"", name);
+        let value = field
+            .strip_prefix(prefix.as_str())
+            .and_then(|f| f.strip_suffix('"'))
+            .ok_or_else(err)?;
+        fields.push(value);
+    }
+    match fields[..] {
+        [index, height, key] => Ok((
+            index.parse().map_err(|_| err())?,
+            KeyRotation {
+                height: height.parse().map_err(|_| err())?,
+                public_key: from_hex(key).ok_or_else(err)?,
+            },
+        )),
+        _ => Err(err()),
+    }
+}
+
+// parse_json_validator parses the fields of a validator object, without its braces.
+fn parse_json_validator(s: &str) -> Result<Validator, String> {
+    let err = || format!("bad validator {:?}", s);
+    let rest = s.strip_prefix("\"public_key\":\"").ok_or_else(err)?;
+    let (key, rest) = rest.split_once('"').ok_or_else(err)?;
+    let power = rest
+        .strip_prefix(",\"voting_power\":\"")
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(err)?;
+    Ok(Validator {
+        public_key: from_hex(key).ok_or_else(err)?,
+        voting_power: power.parse().map_err(|_| err())?,
+    })
+}
+
+// read returns the next n bytes of bz and advances it past them.
+fn read<'a>(bz: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if bz.len() < n {
+        return Err("unexpected end of input".to_string());
+    }
+    let (head, tail) = bz.split_at(n);
+    *bz = tail;
+    Ok(head)
+}
+
+fn read_u32(bz: &mut &[u8]) -> Result<u32, String> {
+    Ok(u32::from_be_bytes(read(bz, 4)?.try_into().unwrap()))
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn val(key: u8, voting_power: i64) -> Validator {
+        Validator {
+            public_key: vec![key; 32],
+            voting_power,
+        }
+    }
+
+    #[test]
+    fn validator_set() {
+        let mut vals: ValidatorSet = ValidatorSet::new(vec![val(1, 10), val(2, 20), val(1, 30)]);
+        assert_eq!(vals.len(), 2);
+        assert_eq!(vals.total_voting_power(), 30);
+        let addresses: Vec<Address> = vals
+            .validators()
+            .iter()
+            .map(|v| v.address::<Sha256>())
+            .collect();
+        let mut sorted = addresses.clone();
+        sorted.sort();
+        assert_eq!(addresses, sorted);
+
+        let hash = vals.hash();
+        vals.add(val(3, 5));
+        assert_eq!(vals.len(), 3);
+        assert_ne!(vals.hash(), hash);
+
+        assert!(vals.update(val(3, 7)));
+        assert_eq!(vals.total_voting_power(), 37);
+        assert!(vals.remove(&val(3, 0)));
+        assert!(!vals.remove(&val(3, 0)));
+        assert_eq!(vals.hash(), hash);
+
+        let i = vals.index_of(&val(2, 0).address::<Sha256>()).unwrap();
+        assert_eq!(vals.get(i), Some(&val(2, 20)));
+    }
+
+    #[test]
+    fn encoding() {
+        let vals: ValidatorSet = ValidatorSet::new(vec![val(1, 10), val(2, 20)]);
+        let json = vals.to_json();
+        assert_eq!(ValidatorSet::from_json(&json), Ok(vals.clone()));
+        assert_eq!(ValidatorSet::from_bytes(&vals.to_bytes()), Ok(vals.clone()));
+
+        // non-canonical encodings are rejected
+        let spaced = json.replace(",", ", ");
+        assert!(ValidatorSet::<Sha256>::from_json(&spaced).is_err());
+        let mut bz = vals.to_bytes();
+        bz.push(0);
+        assert!(ValidatorSet::<Sha256>::from_bytes(&bz).is_err());
+        assert!(ValidatorSet::<Sha256>::from_bytes(&bz[..10]).is_err());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let mut vals: ValidatorSet = ValidatorSet::new(vec![val(1, 10), val(2, 20)]);
+        let hash = vals.hash();
+        let address = val(2, 0).address::<Sha256>();
+        let i = vals.index_of(&address).unwrap();
+        assert!(!vals.rotate_key(&val(3, 0).address::<Sha256>(), 10, vec![3; 32]));
+        assert!(vals.rotate_key(&address, 10, vec![4; 32]));
+        assert!(vals.rotate_key(&address, 20, vec![5; 32]));
+
+        // the validator keeps its index, and signs with the rotated keys
+        assert_eq!(vals.index_of(&address), Some(i));
+        assert_eq!(vals.public_key_at(i, 9), Some(&[2; 32][..]));
+        assert_eq!(vals.public_key_at(i, 10), Some(&[4; 32][..]));
+        assert_eq!(vals.public_key_at(i, 25), Some(&[5; 32][..]));
+        assert_ne!(vals.hash(), hash);
+
+        // rotations are carried through updates, and encoded
+        vals.add(val(3, 5));
+        assert!(vals.update(val(2, 25)));
+        let i = vals.index_of(&address).unwrap();
+        assert_eq!(vals.key_rotations(i).len(), 2);
+        let json = vals.to_json();
+        assert!(json.contains("\"key_rotations\":[{\"index\""));
+        assert_eq!(ValidatorSet::from_json(&json), Ok(vals.clone()));
+        assert_eq!(ValidatorSet::from_bytes(&vals.to_bytes()), Ok(vals.clone()));
+
+        // a later registration replaces the ones it precedes
+        assert!(vals.rotate_key(&address, 15, vec![6; 32]));
+        assert_eq!(vals.public_key_at(i, 25), Some(&[6; 32][..]));
+        assert!(vals.remove(&val(2, 0)));
+        assert_eq!(ValidatorSet::from_json(&vals.to_json()), Ok(vals.clone()));
+        assert!(!vals.to_json().contains("key_rotations"));
+    }
+
+    #[test]
+    fn rotation_hash() {
+        // a rotation of validator 0 at height 10 has the bytes of a
+        // validator with no voting power and the key 0000000a0707..07
+        let mut rotated: ValidatorSet = ValidatorSet::new(vec![val(1, 10)]);
+        assert!(rotated.rotate_key(&val(1, 0).address::<Sha256>(), 10, vec![7; 32]));
+        let fake = Validator {
+            public_key: [&[0, 0, 0, 10][..], &[7; 32][..]].concat(),
+            voting_power: 0,
+        };
+        let (rotation, _) = rotated.rotation_items()[0];
+        assert_eq!(fake.bytes(), rotated.key_rotations(rotation)[0].bytes(0));
+
+        // the sets' items are the same, but not their hashes
+        let grown: ValidatorSet = ValidatorSet::new(vec![val(1, 10), fake]);
+        let items = |vals: &ValidatorSet| -> Vec<Vec<u8>> {
+            let mut items: Vec<_> = vals.validators().iter().map(|v| v.bytes()).collect();
+            items.extend(vals.rotation_items().iter().map(|(i, r)| r.bytes(*i)));
+            items.sort();
+            items
+        };
+        assert_eq!(items(&rotated), items(&grown));
+        assert_ne!(rotated.hash(), grown.hash());
+    }
+
+    #[test]
+    fn hash_vectors() {
+        let vectors = include_str!("../testdata/validator_sets.txt");
+        for line in vectors.lines().filter(|l| !l.starts_with('#')) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let (json, bytes, hash) = (fields[0], fields[1], fields[2]);
+            let vals: ValidatorSet = ValidatorSet::from_json(json).unwrap();
+            assert_eq!(to_hex(&vals.to_bytes()), bytes);
+            assert_eq!(
+                ValidatorSet::from_bytes(&from_hex(bytes).unwrap()),
+                Ok(vals.clone())
+            );
+            assert_eq!(to_hex(&vals.hash()), hash);
+        }
+    }
+
+    #[test]
+    fn sample_by_stake() {
+        let vals: ValidatorSet =
+            ValidatorSet::new(vec![val(1, 1), val(2, 1000), val(3, 0), val(4, 1)]);
+        let sample = vals.sample_by_stake(b"seed", 2);
+        assert_eq!(sample.len(), 2);
+        assert_ne!(sample[0], sample[1]);
+        assert_eq!(sample, vals.sample_by_stake(b"seed", 2));
+
+        // validators without voting power are never picked
+        let all = vals.sample_by_stake(b"seed", 10);
+        assert_eq!(all.len(), 3);
+        assert!(!all.contains(&&val(3, 0)));
+
+        // heavier validators are picked first more often
+        let heavy = (0..100u8)
+            .filter(|i| vals.sample_by_stake(&[*i], 1)[0] == &val(2, 1000))
+            .count();
+        assert!(heavy > 90);
+    }
+
+    #[test]
+    fn validate() {
+        let limits = ValidatorSetLimits::default();
+        let set = |vals| -> ValidatorSet { ValidatorSet::new(vals) };
+        let index = |vals: &ValidatorSet, v: Validator| vals.index_of(&v.address::<Sha256>());
+        assert_eq!(set(vec![val(1, 1), val(2, 5)]).validate(&limits), Ok(()));
+        assert_eq!(set(vec![]).validate(&limits), Err(ValidatorSetError::Empty));
+
+        let vals = set(vec![val(1, 10), val(2, 0)]);
+        assert_eq!(
+            vals.validate(&limits),
+            Err(ValidatorSetError::PowerTooLow {
+                index: index(&vals, val(2, 0)).unwrap(),
+                power: 0,
+            })
+        );
+        let negative = set(vec![val(1, -1)]);
+        assert!(matches!(
+            negative.validate(&limits),
+            Err(ValidatorSetError::PowerTooLow { .. })
+        ));
+
+        let huge = set(vec![val(1, i64::MAX), val(2, 1)]);
+        assert_eq!(
+            huge.validate(&limits),
+            Err(ValidatorSetError::TotalOverflow)
+        );
+        let high = set(vec![val(1, i64::MAX / 8), val(2, 1)]);
+        let max = i64::MAX / 8;
+        assert_eq!(
+            high.validate(&limits),
+            Err(ValidatorSetError::TotalTooHigh {
+                total: max + 1,
+                max
+            })
+        );
+
+        // a validator may have at most a third of the power
+        let capped = ValidatorSetLimits {
+            max_power_percent: Some(33),
+            ..limits.clone()
+        };
+        let vals = set(vec![val(1, 1), val(2, 1), val(3, 2)]);
+        assert_eq!(
+            vals.validate(&capped),
+            Err(ValidatorSetError::PowerShareTooHigh {
+                index: index(&vals, val(3, 2)).unwrap(),
+                power: 2,
+                total: 4,
+            })
+        );
+        assert_eq!(
+            vals.validate(&capped).unwrap_err().to_string(),
+            format!(
+                "validator {} has too much voting power 2 of 4",
+                index(&vals, val(3, 2)).unwrap()
+            )
+        );
+        let even = set((1..=3).map(|k| val(k, 1)).collect());
+        assert!(even.validate(&capped).is_err());
+        let spread = set((1..=5).map(|k| val(k, 1)).collect());
+        assert_eq!(spread.validate(&capped), Ok(()));
+    }
+}
@@ -0,0 +1,196 @@
+// VerifyPool checks the signatures of messages from peers on a pool of
+// worker threads, so verification uses all cores while the engine stays
+// single-threaded and deterministic.
+//
+// The driver submits messages as they arrive, and passes the ones the pool
+// returns to Engine::execute_verified(). Messages are returned in the order
+// they were submitted, whichever worker finishes first, so the engine sees
+// the same sequence it would have without the pool. Messages with an invalid
+// signature, or signed by a validator not in the set, are dropped.
+
+use super::engine::Message;
+use super::signature::{SignFormat, Verified, Verifier};
+use super::validators::ValidatorSet;
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+// Job is a message to verify, and its sequence number.
+type Job = (u64, Message);
+
+// Done is a verified message, or None if it was dropped, by sequence number.
+type Done = (u64, Option<Verified<Message>>);
+
+pub struct VerifyPool {
+    jobs: Option<Sender<Job>>, // None once the pool is dropped
+    done: Receiver<Done>,
+    workers: Vec<JoinHandle<()>>,
+    validators: Arc<RwLock<ValidatorSet>>,
+    submitted: u64, // sequence number of the next message
+    returned: u64,  // sequence number of the next to return
+    pending: BTreeMap<u64, Option<Verified<Message>>>, // done out of order
+    dropped: u64,
+}
+
+impl VerifyPool {
+    // new starts the workers, which check signatures in the chain's format
+    // with the verifier, and signers against the validator set.
+    pub fn new(
+        workers: usize,
+        verifier: Arc<dyn Verifier + Send + Sync>,
+        format: SignFormat,
+        validators: ValidatorSet,
+    ) -> VerifyPool {
+        let (jobs, queue) = channel::<Job>();
+        let (results, done) = channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let validators = Arc::new(RwLock::new(validators));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let (queue, results) = (queue.clone(), results.clone());
+                let (verifier, validators) = (verifier.clone(), validators.clone());
+                let format = format.clone();
+                thread::spawn(move || loop {
+                    // hold the lock only to take the job, not to verify it
+                    let job = queue.lock().unwrap().recv();
+                    let (seq, msg) = match job {
+                        Ok(job) => job,
+                        Err(_) => return, // the pool was dropped
+                    };
+                    let known = {
+                        let validators = validators.read().unwrap();
+                        msg.signers().iter().all(|s| validators.get(*s).is_some())
+                    };
+                    let verified = match known && msg.is_valid(verifier.as_ref(), &format) {
+                        true => Some(Verified::new_unchecked(msg)),
+                        false => None,
+                    };
+                    if results.send((seq, verified)).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        VerifyPool {
+            jobs: Some(jobs),
+            done,
+            workers,
+            validators,
+            submitted: 0,
+            returned: 0,
+            pending: BTreeMap::new(),
+            dropped: 0,
+        }
+    }
+
+    // set_validators replaces the validator set signers are checked against,
+    // eg. when the engine moves to a height with a new set. Messages already
+    // submitted may be checked against either set.
+    pub fn set_validators(&self, validators: ValidatorSet) {
+        *self.validators.write().unwrap() = validators;
+    }
+
+    // submit queues the message to be verified.
+    pub fn submit(&mut self, msg: Message) {
+        if let Some(jobs) = &self.jobs {
+            // the workers only stop once jobs is dropped
+            let _ = jobs.send((self.submitted, msg));
+            self.submitted += 1;
+        }
+    }
+
+    // poll returns the verified messages that are ready, in the order they
+    // were submitted, without waiting for the rest.
+    pub fn poll(&mut self) -> Vec<Verified<Message>> {
+        while let Ok((seq, verified)) = self.done.try_recv() {
+            self.pending.insert(seq, verified);
+        }
+        self.ready()
+    }
+
+    // wait waits until all submitted messages are verified, and returns the
+    // ones that haven't been returned yet, in the order they were submitted.
+    pub fn wait(&mut self) -> Vec<Verified<Message>> {
+        while self.returned + (self.pending.len() as u64) < self.submitted {
+            match self.done.recv() {
+                Ok((seq, verified)) => self.pending.insert(seq, verified),
+                Err(_) => break,
+            };
+        }
+        self.ready()
+    }
+
+    // dropped returns how many messages were dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // ready takes the done messages that are next in order.
+    fn ready(&mut self) -> Vec<Verified<Message>> {
+        let mut ready = Vec::new();
+        while let Some(verified) = self.pending.remove(&self.returned) {
+            self.returned += 1;
+            match verified {
+                Some(msg) => ready.push(msg),
+                None => self.dropped += 1,
+            }
+        }
+        ready
+    }
+}
+
+impl Drop for VerifyPool {
+    // drop stops the workers once they finish the messages they're on.
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::{validators, TestVerifier};
+    use crate::signature::Signed;
+    use crate::Vote;
+
+    fn vote(signer: usize, round: i64, signature: u8) -> Message {
+        Message::Vote(Signed {
+            signer,
+            msg: Vote::new_prevote(1, round, None),
+            signature: vec![signature],
+        })
+    }
+
+    #[test]
+    fn verify_in_order() {
+        let mut pool =
+            VerifyPool::new(4, Arc::new(TestVerifier), SignFormat::Binary, validators(4));
+        let mut expected = Vec::new();
+        for round in 0..100 {
+            let signer = round as usize % 4;
+            let msg = match round % 10 {
+                3 => vote(signer, round, 9), // bad signature
+                7 => vote(9, round, 9),      // unknown validator
+                _ => {
+                    expected.push(vote(signer, round, signer as u8));
+                    vote(signer, round, signer as u8)
+                }
+            };
+            pool.submit(msg);
+        }
+        let mut verified = pool.poll();
+        verified.extend(pool.wait());
+        let msgs: Vec<Message> = verified.into_iter().map(|m| m.into_inner()).collect();
+        assert_eq!(msgs, expected);
+        assert_eq!(pool.dropped(), 20);
+        assert!(pool.wait().is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use super::engine::{Engine, Output};
+use super::state_machine as sm;
+
+use std::time::Duration;
+
+// WatchdogConfig sets when a round counts as stalled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchdogConfig {
+    // How long a round is expected to take.
+    pub round_time: Duration,
+
+    // A round stalls if the step doesn't change for this many round times.
+    pub stall_rounds: u32,
+
+    // Whether to include our latest proposal and votes in StalledRound,
+    // for the driver to send again in case peers missed them.
+    pub rebroadcast: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            round_time: Duration::from_secs(5),
+            stall_rounds: 3,
+            rebroadcast: true,
+        }
+    }
+}
+
+// StalledRound is reported by the watchdog when the engine made no progress.
+#[derive(Debug, PartialEq)]
+pub struct StalledRound {
+    pub state: sm::State,
+    pub stalled_for: Duration,
+    pub rebroadcast: Vec<Output>,
+}
+
+// Watchdog notices when the engine stops making progress.
+// The driver calls check periodically with the current time.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    last: Option<(i64, i64, sm::Step)>,
+    since: Duration,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Watchdog {
+        Watchdog {
+            config,
+            last: None,
+            since: Duration::from_secs(0),
+        }
+    }
+
+    // check returns StalledRound if the engine's height, round and step
+    // haven't changed for stall_rounds round times. While it stays stalled,
+    // it's reported again every stall_rounds round times.
+    pub fn check(&mut self, engine: &Engine, now: Duration) -> Option<StalledRound> {
+        let state = engine.state();
+        let current = (state.height(), state.round(), state.step());
+        if self.last != Some(current) {
+            self.last = Some(current);
+            self.since = now;
+            return None;
+        }
+        let stalled_for = now.saturating_sub(self.since);
+        if stalled_for < self.config.round_time * self.config.stall_rounds {
+            return None;
+        }
+        self.since = now;
+        let rebroadcast = if self.config.rebroadcast {
+            engine.rebroadcast()
+        } else {
+            Vec::new()
+        };
+        Some(StalledRound {
+            state,
+            stalled_for,
+            rebroadcast,
+        })
+    }
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConsensusConfig;
+    use crate::scenario::test_engine;
+    use crate::scheduler::Timers;
+    use crate::signature::Signed;
+    use crate::{Proposal, Value, Vote};
+
+    #[test]
+    fn stalled_round() {
+        let config = ConsensusConfig::default();
+        let mut engine = test_engine(config, 1, 4, Box::new(Timers::default()));
+        let mut watchdog = Watchdog::new(WatchdogConfig::default());
+        let secs = Duration::from_secs;
+
+        let value = Value::new([1; 32]);
+        engine.start_round(0, Some(value));
+        assert_eq!(watchdog.check(&engine, secs(0)), None);
+        assert_eq!(watchdog.check(&engine, secs(14)), None);
+
+        // we proposed and prevoted, but nobody else did
+        let stalled = watchdog.check(&engine, secs(15)).unwrap();
+        assert_eq!(stalled.stalled_for, secs(15));
+        assert_eq!(stalled.state.step(), sm::Step::Prevote);
+        let proposal = Signed {
+            signer: 0,
+            msg: Proposal::new(1, 0, value, -1),
+            signature: vec![0],
+        };
+        let prevote = Signed {
+            signer: 0,
+            msg: Vote::new_prevote(1, 0, Some(value)),
+            signature: vec![0],
+        };
+        assert_eq!(
+            stalled.rebroadcast,
+            vec![Output::Proposal(proposal), Output::Vote(prevote)]
+        );
+
+        // reported again only after another stall period
+        assert_eq!(watchdog.check(&engine, secs(20)), None);
+        assert!(watchdog.check(&engine, secs(30)).is_some());
+    }
+}
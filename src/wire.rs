@@ -0,0 +1,697 @@
+// Wire encodes engine messages as bytes, for transports that have no encoding
+// of their own, and as the input of the fuzz targets.
+//
+// A message is its kind (1 byte), then:
+//
+//   1 proposal:           a signed proposal
+//   2 vote:               a signed vote
+//   3 timeout elapsed:    height and round (8 bytes each, big endian),
+//                         then the step (1 byte: propose 1, prevote 2,
+//                         precommit 3, commit 4)
+//   4 proposal with pol:  a signed proposal, the number of votes
+//                         (4 bytes, big endian), then the signed votes
+//   5 heartbeat:          a signed heartbeat
+//
+// A signed message is the signer's index and the length of the signature
+// (4 bytes each, big endian), the signature, then the message's sign bytes,
+// whose first byte tells its type and so its length.
+// Only the canonical encoding of a message decodes.
+//
+// Transports send messages in frames. A frame is its flags (1 byte), then its
+// body: a single message or, with BATCH, the number of messages (4 bytes, big
+// endian) then each message's length (4 bytes, big endian) and encoding. With
+// ZSTD, the body is compressed. Peers announce the FrameFeatures they support
+// when they connect, and only use the ones both do, so a peer without them
+// still gets one plain message per frame.
+//
+// Drivers write WalEntries and the SignState to their store as bytes too.
+// A WAL entry is the height and the time's seconds (8 bytes each, big endian)
+// and nanoseconds (4 bytes, big endian), then the input's kind (1 byte):
+//
+//   1 message:      an encoded message
+//   2 verified:     an encoded message
+//   3 timeout:      an encoded timeout elapsed message
+//   4 start round:  the round (8 bytes, big endian), then 0, or 1 followed by
+//                   the value id (32 bytes)
+//   5 commit done:  the height (8 bytes, big endian)
+//   6 tick
+//
+// A sign state is the height and round (8 bytes each, big endian), the step
+// (1 byte: proposal 1, prevote 2, precommit 3), then the sign bytes and the
+// signature, each its length (4 bytes, big endian) then its bytes.
+
+use super::engine::{Input, Message, WalEntry};
+use super::signature::{SignBytes, SignState, SignStep, Signed, Verified};
+use super::state_machine as sm;
+use super::{Heartbeat, Proposal, Value, Vote};
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+// encode returns the bytes of the message.
+pub fn encode(msg: &Message) -> Vec<u8> {
+    let mut bz = Vec::new();
+    match msg {
+        Message::Proposal(p) => {
+            bz.push(1);
+            put_signed(&mut bz, p);
+        }
+        Message::Vote(v) => {
+            bz.push(2);
+            put_signed(&mut bz, v);
+        }
+        Message::TimeoutElapsed(t) => {
+            bz.push(3);
+            bz.extend_from_slice(&t.height.to_be_bytes());
+            bz.extend_from_slice(&t.round.to_be_bytes());
+            bz.push(match t.step {
+                sm::TimeoutStep::Propose => 1,
+                sm::TimeoutStep::Prevote => 2,
+                sm::TimeoutStep::Precommit => 3,
+                sm::TimeoutStep::Commit => 4,
+            });
+        }
+        Message::ProposalWithPol(p, votes) => {
+            bz.push(4);
+            put_signed(&mut bz, p);
+            bz.extend_from_slice(&(votes.len() as u32).to_be_bytes());
+            for v in votes {
+                put_signed(&mut bz, v);
+            }
+        }
+        Message::Heartbeat(h) => {
+            bz.push(5);
+            put_signed(&mut bz, h);
+        }
+    }
+    bz
+}
+
+// decode returns the message encoded in bz, which must be all of it.
+pub fn decode(bz: &[u8]) -> Result<Message, String> {
+    let mut rest = bz;
+    let msg = match read(&mut rest, 1)?[0] {
+        1 => Message::Proposal(read_signed(&mut rest, read_proposal)?),
+        2 => Message::Vote(read_signed(&mut rest, read_vote)?),
+        3 => {
+            let height = read_i64(&mut rest)?;
+            let round = read_i64(&mut rest)?;
+            let step = match read(&mut rest, 1)?[0] {
+                1 => sm::TimeoutStep::Propose,
+                2 => sm::TimeoutStep::Prevote,
+                3 => sm::TimeoutStep::Precommit,
+                4 => sm::TimeoutStep::Commit,
+                b => return Err(format!("bad timeout step {}", b)),
+            };
+            Message::TimeoutElapsed(sm::Timeout {
+                height,
+                round,
+                step,
+            })
+        }
+        4 => {
+            let proposal = read_signed(&mut rest, read_proposal)?;
+            let n = read_u32(&mut rest)?;
+            // each vote takes at least 17 bytes, so a bogus count can't
+            // make us allocate more than the input is worth
+            let mut votes = Vec::with_capacity((n as usize).min(rest.len() / 17));
+            for _ in 0..n {
+                votes.push(read_signed(&mut rest, read_vote)?);
+            }
+            Message::ProposalWithPol(proposal, votes)
+        }
+        5 => Message::Heartbeat(read_signed(&mut rest, read_heartbeat)?),
+        b => return Err(format!("bad message kind {}", b)),
+    };
+    if !rest.is_empty() {
+        return Err("trailing bytes".to_string());
+    }
+    if encode(&msg) != bz {
+        return Err("not canonical".to_string());
+    }
+    Ok(msg)
+}
+
+fn put_signed<T: SignBytes>(bz: &mut Vec<u8>, signed: &Signed<T>) {
+    bz.extend_from_slice(&(signed.signer as u32).to_be_bytes());
+    bz.extend_from_slice(&(signed.signature.len() as u32).to_be_bytes());
+    bz.extend_from_slice(&signed.signature);
+    bz.extend_from_slice(&signed.msg.sign_bytes());
+}
+
+fn read_signed<T, F>(bz: &mut &[u8], read_msg: F) -> Result<Signed<T>, String>
+where
+    F: Fn(&mut &[u8]) -> Result<T, String>,
+{
+    let signer = read_u32(bz)? as usize;
+    let len = read_u32(bz)? as usize;
+    let signature = read(bz, len)?.to_vec();
+    let msg = read_msg(bz)?;
+    Ok(Signed {
+        signer,
+        msg,
+        signature,
+    })
+}
+
+fn read_proposal(bz: &mut &[u8]) -> Result<Proposal, String> {
+    if read(bz, 1)?[0] != 32 {
+        return Err("not a proposal".to_string());
+    }
+    let (height, round, pol_round) = (read_i64(bz)?, read_i64(bz)?, read_i64(bz)?);
+    let value = read_value(bz)?;
+    Ok(Proposal::new(height, round, value, pol_round))
+}
+
+fn read_vote(bz: &mut &[u8]) -> Result<Vote, String> {
+    let typ = read(bz, 1)?[0];
+    let (height, round) = (read_i64(bz)?, read_i64(bz)?);
+    let value = match read(bz, 1)?[0] {
+        0 => None,
+        1 => Some(read_value(bz)?),
+        b => return Err(format!("bad vote value flag {}", b)),
+    };
+    match typ {
+        1 => Ok(Vote::new_prevote(height, round, value)),
+        2 => Ok(Vote::new_precommit(height, round, value)),
+        _ => Err(format!("bad vote type {}", typ)),
+    }
+}
+
+fn read_heartbeat(bz: &mut &[u8]) -> Result<Heartbeat, String> {
+    if read(bz, 1)?[0] != 48 {
+        return Err("not a heartbeat".to_string());
+    }
+    let (height, round) = (read_i64(bz)?, read_i64(bz)?);
+    let sequence = u64::from_be_bytes(read(bz, 8)?.try_into().unwrap());
+    Ok(Heartbeat::new(height, round, sequence))
+}
+
+fn read_value(bz: &mut &[u8]) -> Result<Value, String> {
+    Ok(Value::new(read(bz, 32)?.try_into().unwrap()))
+}
+
+// read returns the next n bytes of bz and advances it past them.
+fn read<'a>(bz: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if bz.len() < n {
+        return Err("unexpected end of input".to_string());
+    }
+    let (head, tail) = bz.split_at(n);
+    *bz = tail;
+    Ok(head)
+}
+
+fn read_u32(bz: &mut &[u8]) -> Result<u32, String> {
+    Ok(u32::from_be_bytes(read(bz, 4)?.try_into().unwrap()))
+}
+
+fn read_i64(bz: &mut &[u8]) -> Result<i64, String> {
+    Ok(i64::from_be_bytes(read(bz, 8)?.try_into().unwrap()))
+}
+
+//---------------------------------------------------------------------
+// Frames
+
+// FrameFeatures are the optional frame encodings a peer supports, as flags.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameFeatures(pub u8);
+
+impl FrameFeatures {
+    pub const NONE: FrameFeatures = FrameFeatures(0);
+    pub const BATCH: FrameFeatures = FrameFeatures(1); // several messages per frame
+    pub const ZSTD: FrameFeatures = FrameFeatures(2); // zstd compressed bodies
+
+    // supported returns the features this build supports, to announce to
+    // peers. ZSTD needs the zstd feature.
+    pub fn supported() -> FrameFeatures {
+        match cfg!(feature = "zstd") {
+            true => FrameFeatures(FrameFeatures::BATCH.0 | FrameFeatures::ZSTD.0),
+            false => FrameFeatures::BATCH,
+        }
+    }
+
+    // negotiate returns the features to use with a peer that announced theirs.
+    pub fn negotiate(self, theirs: FrameFeatures) -> FrameFeatures {
+        FrameFeatures(self.0 & theirs.0)
+    }
+
+    pub fn has(self, feature: FrameFeatures) -> bool {
+        self.0 & feature.0 == feature.0
+    }
+}
+
+// FrameConfig is how messages to a peer are framed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameConfig {
+    // The features negotiated with the peer.
+    pub features: FrameFeatures,
+
+    // Most messages batched in a frame.
+    pub max_batch: usize,
+
+    // Bodies smaller than this aren't worth compressing.
+    pub min_compress_bytes: usize,
+
+    // Largest body a frame may have, compressed or not, so a small
+    // compressed frame can't expand into an unbounded one.
+    pub max_frame_bytes: usize,
+}
+
+impl Default for FrameConfig {
+    fn default() -> FrameConfig {
+        FrameConfig {
+            features: FrameFeatures::NONE,
+            max_batch: 256,
+            min_compress_bytes: 512,
+            max_frame_bytes: 1 << 20,
+        }
+    }
+}
+
+// encode_frames returns the frames to send the messages in, in order:
+// batches of up to max_batch messages if batching was negotiated, and one
+// message each otherwise. A body is compressed if that was negotiated and
+// makes it smaller. A batch that would exceed max_frame_bytes is split.
+pub fn encode_frames(config: &FrameConfig, msgs: &[Message]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    if !config.features.has(FrameFeatures::BATCH) {
+        for msg in msgs {
+            frames.push(frame(config, FrameFeatures::NONE, encode(msg)));
+        }
+        return frames;
+    }
+    let mut batch: Vec<Vec<u8>> = Vec::new();
+    let mut size = 4;
+    for bz in msgs.iter().map(encode) {
+        let full = batch.len() == config.max_batch.max(1);
+        if !batch.is_empty() && (full || size + 4 + bz.len() > config.max_frame_bytes) {
+            frames.push(batch_frame(config, &batch));
+            batch.clear();
+            size = 4;
+        }
+        size += 4 + bz.len();
+        batch.push(bz);
+    }
+    if !batch.is_empty() {
+        frames.push(batch_frame(config, &batch));
+    }
+    frames
+}
+
+// decode_frame returns the messages in the frame. Features that weren't
+// negotiated with the peer are an error.
+pub fn decode_frame(config: &FrameConfig, bz: &[u8]) -> Result<Vec<Message>, String> {
+    let mut rest = bz;
+    let flags = FrameFeatures(read(&mut rest, 1)?[0]);
+    if !config.features.has(flags) {
+        return Err(format!("frame flags {} not negotiated", flags.0));
+    }
+    if rest.len() > config.max_frame_bytes {
+        return Err("frame too large".to_string());
+    }
+    let body = match flags.has(FrameFeatures::ZSTD) {
+        true => decompress(rest, config.max_frame_bytes)?,
+        false => rest.to_vec(),
+    };
+    if !flags.has(FrameFeatures::BATCH) {
+        return Ok(vec![decode(&body)?]);
+    }
+    let mut rest = body.as_slice();
+    let n = read_u32(&mut rest)?;
+    // each message takes at least 4 bytes for its length
+    let mut msgs = Vec::with_capacity((n as usize).min(rest.len() / 4));
+    for _ in 0..n {
+        let len = read_u32(&mut rest)? as usize;
+        msgs.push(decode(read(&mut rest, len)?)?);
+    }
+    if !rest.is_empty() {
+        return Err("trailing bytes".to_string());
+    }
+    Ok(msgs)
+}
+
+fn batch_frame(config: &FrameConfig, batch: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = (batch.len() as u32).to_be_bytes().to_vec();
+    for bz in batch {
+        body.extend_from_slice(&(bz.len() as u32).to_be_bytes());
+        body.extend_from_slice(bz);
+    }
+    frame(config, FrameFeatures::BATCH, body)
+}
+
+// frame returns the frame of the body, compressed if that's negotiated
+// and worth it.
+fn frame(config: &FrameConfig, mut flags: FrameFeatures, mut body: Vec<u8>) -> Vec<u8> {
+    if config.features.has(FrameFeatures::ZSTD) && body.len() >= config.min_compress_bytes {
+        if let Some(compressed) = compress(&body).filter(|c| c.len() < body.len()) {
+            flags = FrameFeatures(flags.0 | FrameFeatures::ZSTD.0);
+            body = compressed;
+        }
+    }
+    let mut bz = vec![flags.0];
+    bz.extend_from_slice(&body);
+    bz
+}
+
+#[cfg(feature = "zstd")]
+fn compress(body: &[u8]) -> Option<Vec<u8>> {
+    zstd::bulk::compress(body, 3).ok()
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress(_body: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(body: &[u8], max: usize) -> Result<Vec<u8>, String> {
+    zstd::bulk::decompress(body, max).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_body: &[u8], _max: usize) -> Result<Vec<u8>, String> {
+    Err("zstd not supported".to_string())
+}
+
+//---------------------------------------------------------------------
+// Write-ahead log
+
+// encode_wal_entry returns the bytes of the WAL entry.
+pub fn encode_wal_entry(entry: &WalEntry) -> Vec<u8> {
+    let mut bz = Vec::new();
+    bz.extend_from_slice(&entry.height.to_be_bytes());
+    bz.extend_from_slice(&entry.at.as_secs().to_be_bytes());
+    bz.extend_from_slice(&entry.at.subsec_nanos().to_be_bytes());
+    match &entry.input {
+        Input::Message(msg) => {
+            bz.push(1);
+            bz.extend_from_slice(&encode(msg));
+        }
+        Input::Verified(msg) => {
+            bz.push(2);
+            bz.extend_from_slice(&encode(msg));
+        }
+        Input::Timeout(t) => {
+            bz.push(3);
+            bz.extend_from_slice(&encode(&Message::TimeoutElapsed(*t)));
+        }
+        Input::StartRound(round, value) => {
+            bz.push(4);
+            bz.extend_from_slice(&round.to_be_bytes());
+            match value {
+                Some(v) => {
+                    bz.push(1);
+                    bz.extend_from_slice(&v.id());
+                }
+                None => bz.push(0),
+            }
+        }
+        Input::CommitDone(height) => {
+            bz.push(5);
+            bz.extend_from_slice(&height.to_be_bytes());
+        }
+        Input::Tick => bz.push(6),
+    }
+    bz
+}
+
+// decode_wal_entry returns the WAL entry encoded in bz, which must be all of
+// it. Its messages were verified before they were written, so they aren't
+// verified again.
+pub fn decode_wal_entry(bz: &[u8]) -> Result<WalEntry, String> {
+    let mut rest = bz;
+    let height = read_i64(&mut rest)?;
+    let secs = u64::from_be_bytes(read(&mut rest, 8)?.try_into().unwrap());
+    let nanos = read_u32(&mut rest)?;
+    if nanos >= 1_000_000_000 {
+        return Err(format!("bad nanoseconds {}", nanos));
+    }
+    let input = match read(&mut rest, 1)?[0] {
+        1 => Input::Message(decode(std::mem::take(&mut rest))?),
+        2 => Input::Verified(Verified::new_unchecked(decode(std::mem::take(&mut rest))?)),
+        3 => match decode(std::mem::take(&mut rest))? {
+            Message::TimeoutElapsed(t) => Input::Timeout(t),
+            _ => return Err("not a timeout".to_string()),
+        },
+        4 => {
+            let round = read_i64(&mut rest)?;
+            let value = match read(&mut rest, 1)?[0] {
+                0 => None,
+                1 => Some(read_value(&mut rest)?),
+                b => return Err(format!("bad value flag {}", b)),
+            };
+            Input::StartRound(round, value)
+        }
+        5 => Input::CommitDone(read_i64(&mut rest)?),
+        6 => Input::Tick,
+        b => return Err(format!("bad input kind {}", b)),
+    };
+    if !rest.is_empty() {
+        return Err("trailing bytes".to_string());
+    }
+    Ok(WalEntry {
+        height,
+        at: Duration::new(secs, nanos),
+        input,
+    })
+}
+
+// encode_sign_state returns the bytes of the sign state.
+pub fn encode_sign_state(state: &SignState) -> Vec<u8> {
+    let mut bz = Vec::new();
+    bz.extend_from_slice(&state.height.to_be_bytes());
+    bz.extend_from_slice(&state.round.to_be_bytes());
+    bz.push(match state.step {
+        SignStep::Proposal => 1,
+        SignStep::Prevote => 2,
+        SignStep::Precommit => 3,
+    });
+    for bytes in [&state.sign_bytes, &state.signature] {
+        bz.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        bz.extend_from_slice(bytes);
+    }
+    bz
+}
+
+// decode_sign_state returns the sign state encoded in bz, which must be all of it.
+pub fn decode_sign_state(bz: &[u8]) -> Result<SignState, String> {
+    let mut rest = bz;
+    let (height, round) = (read_i64(&mut rest)?, read_i64(&mut rest)?);
+    let step = match read(&mut rest, 1)?[0] {
+        1 => SignStep::Proposal,
+        2 => SignStep::Prevote,
+        3 => SignStep::Precommit,
+        b => return Err(format!("bad sign step {}", b)),
+    };
+    let len = read_u32(&mut rest)? as usize;
+    let sign_bytes = read(&mut rest, len)?.to_vec();
+    let len = read_u32(&mut rest)? as usize;
+    let signature = read(&mut rest, len)?.to_vec();
+    if !rest.is_empty() {
+        return Err("trailing bytes".to_string());
+    }
+    Ok(SignState {
+        height,
+        round,
+        step,
+        sign_bytes,
+        signature,
+    })
+}
+
+//---------------------------------------------------------------------
+// Test
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = Value::new([7; 32]);
+        fn signed<T>(signer: usize, msg: T) -> Signed<T> {
+            let signature = vec![signer as u8; 3];
+            Signed {
+                signer,
+                msg,
+                signature,
+            }
+        }
+        let msgs = vec![
+            Message::Proposal(signed(1, Proposal::new(2, 0, value, -1))),
+            Message::Vote(signed(2, Vote::new_prevote(2, 1, None))),
+            Message::Vote(signed(3, Vote::new_precommit(2, 1, Some(value)))),
+            Message::TimeoutElapsed(sm::Timeout {
+                height: 2,
+                round: 3,
+                step: sm::TimeoutStep::Precommit,
+            }),
+            Message::ProposalWithPol(
+                signed(1, Proposal::new(2, 1, value, 0)),
+                vec![
+                    signed(1, Vote::new_prevote(2, 0, Some(value))),
+                    signed(2, Vote::new_prevote(2, 0, Some(value))),
+                ],
+            ),
+            Message::Heartbeat(signed(0, Heartbeat::new(2, 1, 9))),
+        ];
+        for msg in msgs {
+            let bz = encode(&msg);
+            assert_eq!(decode(&bz), Ok(msg));
+            assert!(decode(&bz[..bz.len() - 1]).is_err());
+            assert!(decode(&[bz.clone(), vec![0]].concat()).is_err());
+        }
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[9]).is_err());
+
+        // a huge vote count only fails
+        let mut bz = encode(&Message::Proposal(signed(
+            1,
+            Proposal::new(2, 0, value, -1),
+        )));
+        bz[0] = 4;
+        bz.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode(&bz).is_err());
+    }
+
+    #[test]
+    fn wal() {
+        let value = Value::new([7; 32]);
+        let vote = Message::Vote(Signed {
+            signer: 2,
+            msg: Vote::new_prevote(2, 1, Some(value)),
+            signature: vec![2; 3],
+        });
+        let timeout = sm::Timeout {
+            height: 2,
+            round: 1,
+            step: sm::TimeoutStep::Prevote,
+        };
+        let inputs = vec![
+            Input::Message(vote.clone()),
+            Input::Verified(Verified::new_unchecked(vote)),
+            Input::Timeout(timeout),
+            Input::StartRound(1, Some(value)),
+            Input::StartRound(0, None),
+            Input::CommitDone(1),
+            Input::Tick,
+        ];
+        for input in inputs {
+            let entry = WalEntry {
+                height: 2,
+                at: Duration::new(1_700_000_000, 999_999_999),
+                input,
+            };
+            let bz = encode_wal_entry(&entry);
+            assert_eq!(decode_wal_entry(&bz), Ok(entry));
+            assert!(decode_wal_entry(&bz[..bz.len() - 1]).is_err());
+            assert!(decode_wal_entry(&[bz.clone(), vec![0]].concat()).is_err());
+        }
+        let mut bz = encode_wal_entry(&WalEntry {
+            height: 2,
+            at: Duration::from_secs(1),
+            input: Input::Tick,
+        });
+        bz[20] = 7;
+        assert!(decode_wal_entry(&bz).is_err());
+
+        let state = SignState {
+            height: 2,
+            round: 1,
+            step: SignStep::Precommit,
+            sign_bytes: Vote::new_precommit(2, 1, Some(value)).sign_bytes(),
+            signature: vec![1; 64],
+        };
+        let bz = encode_sign_state(&state);
+        assert_eq!(decode_sign_state(&bz), Ok(state));
+        assert!(decode_sign_state(&bz[..bz.len() - 1]).is_err());
+        assert!(decode_sign_state(&[bz.clone(), vec![0]].concat()).is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let votes: Vec<Message> = (0..100)
+            .map(|signer| {
+                Message::Vote(Signed {
+                    signer,
+                    msg: Vote::new_prevote(2, 0, Some(Value::new([7; 32]))),
+                    signature: vec![signer as u8; 64],
+                })
+            })
+            .collect();
+        let config = |features| FrameConfig {
+            features,
+            max_batch: 40,
+            ..FrameConfig::default()
+        };
+
+        // without batching, one message per frame
+        let plain = config(FrameFeatures::NONE);
+        let frames = encode_frames(&plain, &votes);
+        assert_eq!(frames.len(), 100);
+        assert_eq!(decode_frame(&plain, &frames[3]), Ok(vec![votes[3].clone()]));
+
+        // batches of up to max_batch, which a peer that didn't negotiate
+        // batching rejects
+        let features = FrameFeatures::supported().negotiate(FrameFeatures::BATCH);
+        assert_eq!(features, FrameFeatures::BATCH);
+        let batched = config(features);
+        let frames = encode_frames(&batched, &votes);
+        assert_eq!(frames.len(), 3);
+        let decoded: Vec<Message> = frames
+            .iter()
+            .flat_map(|f| decode_frame(&batched, f).unwrap())
+            .collect();
+        assert_eq!(decoded, votes);
+        assert!(decode_frame(&plain, &frames[0]).is_err());
+        assert!(decode_frame(&batched, &frames[0][..frames[0].len() - 1]).is_err());
+
+        // batches split to stay within max_frame_bytes
+        let small = FrameConfig {
+            max_frame_bytes: 1000,
+            ..batched.clone()
+        };
+        let frames = encode_frames(&small, &votes);
+        assert!(frames.iter().all(|f| f.len() <= 1001));
+        let decoded: Vec<Message> = frames
+            .iter()
+            .flat_map(|f| decode_frame(&small, f).unwrap())
+            .collect();
+        assert_eq!(decoded, votes);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_frames() {
+        let votes: Vec<Message> = (0..100)
+            .map(|signer| {
+                Message::Vote(Signed {
+                    signer,
+                    msg: Vote::new_precommit(2, 0, Some(Value::new([7; 32]))),
+                    signature: vec![0; 64],
+                })
+            })
+            .collect();
+        let config = FrameConfig {
+            features: FrameFeatures::supported(),
+            ..FrameConfig::default()
+        };
+        let frames = encode_frames(&config, &votes);
+        assert_eq!(frames.len(), 1);
+        assert!(FrameFeatures(frames[0][0]).has(FrameFeatures::ZSTD));
+        let plain_size: usize = votes.iter().map(|v| encode(v).len() + 4).sum();
+        assert!(frames[0].len() * 4 < plain_size);
+        assert_eq!(decode_frame(&config, &frames[0]), Ok(votes.clone()));
+
+        // a body that would expand past max_frame_bytes is an error
+        let small = FrameConfig {
+            max_frame_bytes: 1000,
+            ..config.clone()
+        };
+        assert!(decode_frame(&small, &frames[0]).is_err());
+
+        // small bodies aren't compressed
+        let frames = encode_frames(&config, &votes[..1]);
+        assert!(!FrameFeatures(frames[0][0]).has(FrameFeatures::ZSTD));
+    }
+}
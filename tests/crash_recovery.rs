@@ -0,0 +1,83 @@
+// Crash recovery: a validator that crashes at any point of a height and
+// restarts from its write-ahead log and sign state alone decides what its
+// peers decided, and never signs anything conflicting with what it signed
+// before the crash, even if its last WAL entry was lost.
+
+use std::collections::BTreeMap;
+use tendermint_rs::rejected::RejectReason;
+use tendermint_rs::signature::SignBytes;
+use tendermint_rs::simulator::{Crash, CrashPoint, SimConfig, Simulation};
+use tendermint_rs::Output;
+
+#[test]
+fn crash_recovery() {
+    // the restarted node buffers what its peers send it for the heights
+    // they decided while it was down, to catch up
+    let mut config = SimConfig::default();
+    config.consensus.future_heights = 10;
+    config.consensus.max_future_per_peer = 100;
+    config.consensus.rejected_log_size = 100;
+
+    // node 3 proposes height 3 in round 0, node 1 only votes
+    let mut crashes = Vec::new();
+    for point in [
+        CrashPoint::Proposal,
+        CrashPoint::Prevote,
+        CrashPoint::Precommit,
+        CrashPoint::Decision,
+    ] {
+        for (node, lose_wal_entry) in [(3, false), (3, true), (1, false), (1, true)] {
+            if node == 1 && point == CrashPoint::Proposal {
+                continue;
+            }
+            for seed in 0..4 {
+                let downtime = 1500 + 500 * seed;
+                let crash = Crash {
+                    node,
+                    height: 3,
+                    point,
+                    lose_wal_entry,
+                    downtime,
+                };
+                crashes.push((crash, seed));
+            }
+        }
+    }
+
+    let mut refused = 0;
+    for (crash, seed) in crashes {
+        let mut sim = Simulation::new(config.clone(), seed).unwrap();
+        sim.crash(crash);
+        assert!(sim.run_until_height(5, 60_000), "{:?} stalled", crash);
+        assert!(
+            sim.pending_crashes().is_empty(),
+            "{:?} didn't happen",
+            crash
+        );
+        for node in 1..4 {
+            assert_eq!(sim.decisions(node)[..5], sim.decisions(0)[..5]);
+        }
+
+        // nothing a node signed conflicts with anything else it signed
+        let mut signed = BTreeMap::new();
+        for e in sim.trace() {
+            let (key, bytes) = match &e.output {
+                Output::Proposal(p) => ((p.msg.height(), p.msg.round(), None), p.msg.sign_bytes()),
+                Output::Vote(v) => {
+                    let key = (v.msg.height(), v.msg.round(), Some(v.msg.typ()));
+                    (key, v.msg.sign_bytes())
+                }
+                _ => continue,
+            };
+            let first = signed.entry((e.node, key)).or_insert_with(|| bytes.clone());
+            assert_eq!(*first, bytes, "{:?} double signed {:?}", crash, key);
+        }
+        let engine = sim.node(crash.node);
+        refused += engine
+            .rejected()
+            .filter(|r| r.reason == RejectReason::ConflictingSign)
+            .count();
+    }
+    // with the lost WAL entries, the sign state is what kept some safe
+    assert!(refused > 0);
+}